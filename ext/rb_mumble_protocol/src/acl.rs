@@ -0,0 +1,622 @@
+//! Evaluation of channel ACLs and groups, as done by Murmur
+//!
+//! Every channel may define groups and a list of ACL entries. The effective permissions of a
+//! user in a channel are computed by walking from the root down to the channel, applying the
+//! entries matching the user in order.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/ACL.cpp and
+//! https://github.com/mumble-voip/mumble/blob/v1.5.634/src/Group.cpp
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::channel_tree::{ChannelTree, ROOT_ID};
+use crate::mumble_proto as msgs;
+use crate::permissions::Permissions;
+
+/// Permissions everyone has unless an ACL says otherwise.
+pub const DEFAULT: Permissions = Permissions::from_bits(
+    Permissions::TRAVERSE.bits()
+        | Permissions::ENTER.bits()
+        | Permissions::SPEAK.bits()
+        | Permissions::WHISPER.bits()
+        | Permissions::TEXT_MESSAGE.bits()
+        | Permissions::LISTEN.bits(),
+);
+
+/// Registered user id of the server's SuperUser, who bypasses all ACLs.
+pub const SUPERUSER_ID: u32 = 0;
+
+/// Who a user is, as far as ACLs are concerned.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// Registered user id, `None` for unregistered users.
+    pub user_id: Option<u32>,
+    /// The channel the user is in.
+    pub channel_id: u32,
+    /// Hex encoded SHA-1 of the user's certificate.
+    pub cert_hash: Option<String>,
+    /// Whether the certificate was verified by a trusted CA.
+    pub strong: bool,
+    pub access_tokens: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    /// Whether members of the groups of the same name in parent channels are members too.
+    pub inherit: bool,
+    /// Whether sub-channels may inherit this group.
+    pub inheritable: bool,
+    pub add: BTreeSet<u32>,
+    pub remove: BTreeSet<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AclEntry {
+    pub apply_here: bool,
+    pub apply_subs: bool,
+    /// The entry applies to either a registered user or the members of a group.
+    pub user_id: Option<u32>,
+    pub group: Option<String>,
+    pub grant: Permissions,
+    pub deny: Permissions,
+}
+
+/// The ACL defined in a channel itself, without inherited entries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelAcl {
+    pub inherit_acls: bool,
+    pub groups: HashMap<String, Group>,
+    pub entries: Vec<AclEntry>,
+}
+
+impl Default for ChannelAcl {
+    fn default() -> Self {
+        ChannelAcl { inherit_acls: true, groups: HashMap::new(), entries: Vec::new() }
+    }
+}
+
+impl From<&msgs::Acl> for ChannelAcl {
+    /// Takes the groups and entries of the channel itself, skipping those marked inherited.
+    fn from(message: &msgs::Acl) -> Self {
+        let groups = message
+            .groups
+            .iter()
+            .filter(|group| !group.inherited())
+            .map(|group| {
+                let group = Group {
+                    name: group.name.clone(),
+                    inherit: group.inherit(),
+                    inheritable: group.inheritable(),
+                    add: group.add.iter().copied().collect(),
+                    remove: group.remove.iter().copied().collect(),
+                };
+                (group.name.clone(), group)
+            })
+            .collect();
+        let entries = message
+            .acls
+            .iter()
+            .filter(|acl| !acl.inherited())
+            .map(|acl| AclEntry {
+                apply_here: acl.apply_here(),
+                apply_subs: acl.apply_subs(),
+                user_id: acl.user_id,
+                group: acl.group.clone(),
+                grant: Permissions::from_bits(acl.grant()),
+                deny: Permissions::from_bits(acl.deny()),
+            })
+            .collect();
+
+        ChannelAcl { inherit_acls: message.inherit_acls(), groups, entries }
+    }
+}
+
+/// The ACLs of all channels of a server.
+#[derive(Clone, Debug, Default)]
+pub struct AclStore {
+    channels: HashMap<u32, ChannelAcl>,
+}
+
+impl AclStore {
+    pub fn new() -> Self {
+        AclStore::default()
+    }
+
+    /// Returns the ACL of a channel, `None` if it has none (inheriting everything).
+    pub fn get(&self, channel_id: u32) -> Option<&ChannelAcl> {
+        self.channels.get(&channel_id)
+    }
+
+    pub fn set(&mut self, channel_id: u32, acl: ChannelAcl) {
+        self.channels.insert(channel_id, acl);
+    }
+
+    /// Replaces the ACL of the channel the message is about.
+    pub fn apply(&mut self, message: &msgs::Acl) {
+        self.set(message.channel_id, ChannelAcl::from(message));
+    }
+
+    pub fn remove(&mut self, channel_id: u32) -> Option<ChannelAcl> {
+        self.channels.remove(&channel_id)
+    }
+
+    /// Computes the permissions the user has in the channel.
+    ///
+    /// Returns no permissions for channels missing from the tree.
+    pub fn effective_permissions(&self, tree: &ChannelTree, user: &Identity, channel_id: u32) -> Permissions {
+        if user.user_id == Some(SUPERUSER_ID) {
+            return Permissions::ALL - Permissions::SPEAK - Permissions::WHISPER;
+        }
+        let path = match tree.path(channel_id) {
+            Some(path) => path,
+            None => return Permissions::NONE,
+        };
+
+        let mut granted = DEFAULT;
+        let mut traverse = true;
+        let mut write = false;
+        let default_acl = ChannelAcl::default();
+
+        for &acl_channel in &path {
+            let acl = self.channels.get(&acl_channel).unwrap_or(&default_acl);
+            if !acl.inherit_acls {
+                granted = DEFAULT;
+            }
+
+            for entry in &acl.entries {
+                let matches_user = entry.user_id.is_some() && entry.user_id == user.user_id;
+                let matches_group = entry
+                    .group
+                    .as_deref()
+                    .is_some_and(|group| self.is_member(tree, channel_id, acl_channel, group, user));
+                if !matches_user && !matches_group {
+                    continue;
+                }
+
+                if entry.grant.contains(Permissions::TRAVERSE) {
+                    traverse = true;
+                }
+                if entry.deny.contains(Permissions::TRAVERSE) {
+                    traverse = false;
+                }
+                if entry.grant.contains(Permissions::WRITE) {
+                    write = true;
+                }
+                if entry.deny.contains(Permissions::WRITE) {
+                    write = false;
+                }
+                let here = acl_channel == channel_id;
+                if (here && entry.apply_here) || (!here && entry.apply_subs) {
+                    granted |= entry.grant;
+                    granted -= entry.deny;
+                }
+            }
+
+            if !traverse && !write {
+                return Permissions::NONE;
+            }
+        }
+
+        if granted.contains(Permissions::WRITE) {
+            granted |= Permissions::TRAVERSE
+                | Permissions::ENTER
+                | Permissions::MUTE_DEAFEN
+                | Permissions::MOVE
+                | Permissions::MAKE_CHANNEL
+                | Permissions::LINK_CHANNEL
+                | Permissions::TEXT_MESSAGE
+                | Permissions::MAKE_TEMP_CHANNEL
+                | Permissions::LISTEN;
+            if channel_id == ROOT_ID {
+                granted |= Permissions::KICK
+                    | Permissions::BAN
+                    | Permissions::REGISTER
+                    | Permissions::SELF_REGISTER
+                    | Permissions::RESET_USER_CONTENT;
+            }
+        }
+        granted
+    }
+
+    /// Whether the user belongs to a group, evaluated for an ACL entry of `acl_channel` while
+    /// computing the permissions in `channel_id`.
+    ///
+    /// Besides the groups defined in channels, these special groups exist:
+    ///
+    /// * `all`, `none`: everyone and no one.
+    /// * `auth`: registered users. `strong`: users with a verified certificate.
+    /// * `in`, `out`: users in, or not in, the channel.
+    /// * `sub,<minpath>,<mindesc>,<maxdesc>`: users in sub-channels, all arguments are optional.
+    /// * `#<token>`: users who provided the access token. `$<hash>`: the user with this certificate.
+    ///
+    /// Prefixing a name with `~` evaluates it in the channel defining the ACL entry rather than
+    /// the channel the permissions are computed for, prefixing it with `!` inverts it.
+    pub fn is_member(&self, tree: &ChannelTree, channel_id: u32, acl_channel: u32, name: &str, user: &Identity) -> bool {
+        let mut name = name;
+        let mut context = channel_id;
+        let (mut invert, mut token, mut hash) = (false, false, false);
+
+        loop {
+            if let Some(rest) = name.strip_prefix('!') {
+                invert = true;
+                name = rest;
+            } else if let Some(rest) = name.strip_prefix('~') {
+                context = acl_channel;
+                name = rest;
+            } else if let Some(rest) = name.strip_prefix('#') {
+                token = true;
+                name = rest;
+            } else if let Some(rest) = name.strip_prefix('$') {
+                hash = true;
+                name = rest;
+            } else {
+                break;
+            }
+        }
+        if name.is_empty() {
+            return false;
+        }
+
+        let member = if token {
+            user.access_tokens.iter().any(|access_token| access_token.to_lowercase() == name.to_lowercase())
+        } else if hash {
+            user.cert_hash.as_deref() == Some(name)
+        } else {
+            match name {
+                "none" => false,
+                "all" => true,
+                "auth" => user.user_id.is_some(),
+                "strong" => user.strong,
+                "in" => user.channel_id == context,
+                "out" => user.channel_id != context,
+                _ if name.starts_with("sub") => is_in_sub(tree, channel_id, context, name, user),
+                _ => self.group_members(tree, context, name).contains(&user.user_id),
+            }
+        };
+
+        member != invert
+    }
+
+    /// Registered users in a group of the channel, taking inherited members into account. Like
+    /// Murmur, inheritance stops at a parent channel whose group isn't inheritable.
+    ///
+    /// The result contains `Some(user_id)` entries only, so it can be checked against
+    /// unregistered users as well.
+    fn group_members(&self, tree: &ChannelTree, channel_id: u32, name: &str) -> BTreeSet<Option<u32>> {
+        let path = tree.path(channel_id).unwrap_or_default();
+        let mut groups = Vec::new();
+
+        for &current in path.iter().rev() {
+            if let Some(group) = self.channels.get(&current).and_then(|acl| acl.groups.get(name)) {
+                if current != channel_id && !group.inheritable {
+                    break;
+                }
+                groups.push(group);
+                if !group.inherit {
+                    break;
+                }
+            }
+        }
+
+        let mut members = BTreeSet::new();
+        for group in groups.into_iter().rev() {
+            members.extend(group.add.iter().map(|&id| Some(id)));
+            for id in &group.remove {
+                members.remove(&Some(*id));
+            }
+        }
+        members
+    }
+}
+
+/// Evaluates `sub,<minpath>,<mindesc>,<maxdesc>` relative to the `context` channel.
+///
+/// The user has to be in the channel `minpath` levels below `context` (towards `channel_id`),
+/// or in one of its sub-channels between `mindesc` and `maxdesc` levels deeper. Arguments are
+/// 32-bit integers, invalid ones count as 0 like in Murmur.
+fn is_in_sub(tree: &ChannelTree, channel_id: u32, context: u32, name: &str, user: &Identity) -> bool {
+    let args: Vec<&str> = name.get(4..).unwrap_or_default().split(',').collect();
+    let arg = |index: usize, default: i32| {
+        let arg = args.get(index)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.trim().parse().unwrap_or(0))
+            .unwrap_or(default);
+        i64::from(arg)
+    };
+    let minpath = arg(0, 0);
+    let mindesc = arg(1, 1);
+    let maxdesc = arg(2, 1000);
+
+    let user_chain = match tree.path(user.channel_id) {
+        Some(chain) => chain,
+        None => return false,
+    };
+    let group_chain = match tree.path(channel_id) {
+        Some(chain) => chain,
+        None => return false,
+    };
+    let offset = match group_chain.iter().position(|&id| id == context) {
+        Some(offset) => offset as i64 + minpath,
+        None => return false,
+    };
+    if offset >= group_chain.len() as i64 {
+        return false;
+    }
+    let offset = offset.max(0);
+
+    let needed = group_chain[offset as usize];
+    if !user_chain.contains(&needed) {
+        return false;
+    }
+
+    let depth = user_chain.len() as i64 - 1;
+    depth >= offset + mindesc && depth <= offset + maxdesc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NONE: Permissions = Permissions::NONE;
+
+    /// Root (0)
+    /// ├── Lobby (1)
+    /// │   └── Team (3)
+    /// │       └── Squad (4)
+    /// └── Admin (2)
+    fn tree() -> ChannelTree {
+        let mut tree = ChannelTree::new();
+        for (id, parent) in [(0, None), (1, Some(0)), (2, Some(0)), (3, Some(1)), (4, Some(3))] {
+            tree.apply_state(&msgs::ChannelState { channel_id: Some(id), parent, ..Default::default() })
+                .unwrap();
+        }
+        tree
+    }
+
+    fn entry(group: &str, grant: Permissions, deny: Permissions) -> AclEntry {
+        AclEntry { apply_here: true, apply_subs: true, user_id: None, group: Some(group.to_owned()), grant, deny }
+    }
+
+    fn group(name: &str, add: &[u32]) -> Group {
+        Group { name: name.to_owned(), inherit: true, inheritable: true, add: add.iter().copied().collect(), remove: BTreeSet::new() }
+    }
+
+    fn acl(entries: Vec<AclEntry>, groups: Vec<Group>) -> ChannelAcl {
+        ChannelAcl {
+            inherit_acls: true,
+            groups: groups.into_iter().map(|group| (group.name.clone(), group)).collect(),
+            entries,
+        }
+    }
+
+    fn user(user_id: Option<u32>, channel_id: u32) -> Identity {
+        Identity { user_id, channel_id, ..Default::default() }
+    }
+
+    /// Murmur's default root ACL: admins may do anything, registered users may register.
+    fn murmur_defaults() -> AclStore {
+        let mut store = AclStore::new();
+        store.set(
+            0,
+            acl(
+                vec![
+                    entry("admin", Permissions::WRITE, NONE),
+                    AclEntry { apply_subs: false, ..entry("auth", Permissions::MAKE_TEMP_CHANNEL, NONE) },
+                    AclEntry { apply_subs: false, ..entry("all", Permissions::SELF_REGISTER, NONE) },
+                ],
+                vec![group("admin", &[10])],
+            ),
+        );
+        store
+    }
+
+    #[test]
+    fn default_permissions() {
+        let store = AclStore::new();
+        let tree = tree();
+
+        assert_eq!(DEFAULT, store.effective_permissions(&tree, &user(None, 0), 3));
+        assert_eq!(NONE, store.effective_permissions(&tree, &user(None, 0), 99));
+    }
+
+    #[test]
+    fn superuser_bypasses_acls() {
+        let mut store = murmur_defaults();
+        store.set(1, acl(vec![entry("all", NONE, Permissions::TRAVERSE | Permissions::ENTER)], vec![]));
+
+        let permissions = store.effective_permissions(&tree(), &user(Some(SUPERUSER_ID), 0), 1);
+        assert_eq!(Permissions::ALL & !(Permissions::SPEAK | Permissions::WHISPER), permissions);
+    }
+
+    #[test]
+    fn murmur_default_acl() {
+        let store = murmur_defaults();
+        let tree = tree();
+
+        // admins get everything Write implies, the root-only permissions only in the root
+        let admin = user(Some(10), 0);
+        let write = DEFAULT
+            | Permissions::WRITE
+            | Permissions::MUTE_DEAFEN
+            | Permissions::MOVE
+            | Permissions::MAKE_CHANNEL
+            | Permissions::LINK_CHANNEL
+            | Permissions::MAKE_TEMP_CHANNEL;
+        let server = Permissions::SELF_REGISTER
+            | Permissions::KICK
+            | Permissions::BAN
+            | Permissions::REGISTER
+            | Permissions::RESET_USER_CONTENT;
+        assert_eq!(write | server, store.effective_permissions(&tree, &admin, 0));
+        assert_eq!(write, store.effective_permissions(&tree, &admin, 3));
+
+        assert_eq!(DEFAULT | Permissions::MAKE_TEMP_CHANNEL | Permissions::SELF_REGISTER, store.effective_permissions(&tree, &user(Some(11), 0), 0));
+        assert_eq!(DEFAULT | Permissions::SELF_REGISTER, store.effective_permissions(&tree, &user(None, 0), 0));
+        assert_eq!(DEFAULT, store.effective_permissions(&tree, &user(None, 0), 1));
+    }
+
+    #[test]
+    fn denied_traverse_hides_subchannels() {
+        let mut store = AclStore::new();
+        store.set(1, acl(vec![entry("all", NONE, Permissions::TRAVERSE), entry("auth", Permissions::TRAVERSE, NONE)], vec![]));
+        let tree = tree();
+
+        assert_eq!(NONE, store.effective_permissions(&tree, &user(None, 0), 1));
+        assert_eq!(NONE, store.effective_permissions(&tree, &user(None, 0), 4));
+        assert_eq!(DEFAULT, store.effective_permissions(&tree, &user(Some(5), 0), 4));
+        assert_eq!(DEFAULT, store.effective_permissions(&tree, &user(None, 0), 2));
+    }
+
+    #[test]
+    fn apply_here_and_subs() {
+        let mut store = AclStore::new();
+        store.set(1, acl(vec![AclEntry { apply_here: false, ..entry("all", NONE, Permissions::SPEAK) }], vec![]));
+        store.set(3, acl(vec![AclEntry { apply_subs: false, ..entry("all", Permissions::SPEAK, Permissions::ENTER) }], vec![]));
+        let tree = tree();
+
+        assert_eq!(DEFAULT, store.effective_permissions(&tree, &user(None, 0), 1));
+        assert_eq!(DEFAULT & !Permissions::ENTER, store.effective_permissions(&tree, &user(None, 0), 3));
+        assert_eq!(DEFAULT & !Permissions::SPEAK, store.effective_permissions(&tree, &user(None, 0), 4));
+    }
+
+    #[test]
+    fn inherit_acls_resets_to_defaults() {
+        let mut store = AclStore::new();
+        store.set(1, acl(vec![entry("all", NONE, Permissions::SPEAK)], vec![]));
+        store.set(3, ChannelAcl { inherit_acls: false, ..Default::default() });
+        let tree = tree();
+
+        assert_eq!(DEFAULT & !Permissions::SPEAK, store.effective_permissions(&tree, &user(None, 0), 1));
+        assert_eq!(DEFAULT, store.effective_permissions(&tree, &user(None, 0), 4));
+    }
+
+    #[test]
+    fn user_entries() {
+        let mut store = AclStore::new();
+        store.set(2, acl(vec![entry("all", NONE, Permissions::ENTER), AclEntry { user_id: Some(7), group: None, ..entry("", Permissions::ENTER, NONE) }], vec![]));
+        let tree = tree();
+
+        assert_eq!(DEFAULT & !Permissions::ENTER, store.effective_permissions(&tree, &user(Some(8), 0), 2));
+        assert_eq!(DEFAULT, store.effective_permissions(&tree, &user(Some(7), 0), 2));
+    }
+
+    #[test]
+    fn in_out_and_acl_channel_context() {
+        let mut store = AclStore::new();
+        // only users inside the lobby may talk in its sub-channels
+        store.set(1, acl(vec![AclEntry { apply_here: false, ..entry("!~in", NONE, Permissions::SPEAK) }], vec![]));
+        let tree = tree();
+
+        assert_eq!(DEFAULT, store.effective_permissions(&tree, &user(None, 1), 3));
+        assert_eq!(DEFAULT & !Permissions::SPEAK, store.effective_permissions(&tree, &user(None, 3), 3));
+
+        assert!(store.is_member(&tree, 3, 1, "in", &user(None, 3)));
+        assert!(!store.is_member(&tree, 3, 1, "~in", &user(None, 3)));
+        assert!(store.is_member(&tree, 3, 1, "out", &user(None, 2)));
+        assert!(!store.is_member(&tree, 3, 1, "none", &user(None, 2)));
+        assert!(!store.is_member(&tree, 3, 1, "", &user(None, 2)));
+    }
+
+    #[test]
+    fn sub_groups() {
+        let store = AclStore::new();
+        let tree = tree();
+        let member = |group: &str, user_channel: u32| store.is_member(&tree, 1, 1, group, &user(None, user_channel));
+
+        // anywhere below the lobby
+        assert!(member("sub", 3));
+        assert!(member("sub", 4));
+        assert!(!member("sub", 1));
+        assert!(!member("sub", 2));
+        // exactly one level below
+        assert!(member("sub,0,1,1", 3));
+        assert!(!member("sub,0,1,1", 4));
+        // the lobby itself and below
+        assert!(member("sub,0,0", 1));
+        // relative to the root
+        assert!(member("sub,-1", 2));
+        // out of range arguments count as 0
+        assert!(member("sub,9223372036854775807", 3));
+        assert!(member("sub,2147483648,9223372036854775807", 1));
+        assert!(!member("sub,2147483647", 3));
+    }
+
+    #[test]
+    fn group_inheritance() {
+        let mut store = AclStore::new();
+        store.set(0, acl(vec![], vec![group("mods", &[1, 2]), Group { inheritable: false, ..group("private", &[3]) }]));
+        store.set(1, acl(vec![], vec![Group { remove: BTreeSet::from([2]), ..group("mods", &[4]) }]));
+        store.set(3, acl(vec![], vec![Group { inherit: false, ..group("mods", &[5]) }]));
+        let tree = tree();
+        let member = |channel: u32, group: &str, user_id: u32| store.is_member(&tree, channel, channel, group, &user(Some(user_id), 0));
+
+        assert!(member(0, "mods", 2));
+        assert!(member(1, "mods", 1));
+        assert!(!member(1, "mods", 2));
+        assert!(member(1, "mods", 4));
+        assert!(!member(3, "mods", 1));
+        assert!(member(3, "mods", 5));
+        assert!(member(0, "private", 3));
+        assert!(!member(1, "private", 3));
+        assert!(!store.is_member(&tree, 0, 0, "mods", &user(None, 0)));
+
+        // A parent's group which isn't inheritable hides those further up
+        store.set(0, acl(vec![], vec![group("vip", &[1])]));
+        store.set(1, acl(vec![], vec![Group { inheritable: false, ..group("vip", &[2]) }]));
+        store.set(3, acl(vec![], vec![group("vip", &[3])]));
+        let member = |channel: u32, group: &str, user_id: u32| store.is_member(&tree, channel, channel, group, &user(Some(user_id), 0));
+        assert!(member(1, "vip", 1));
+        assert!(member(1, "vip", 2));
+        assert!(member(3, "vip", 3));
+        assert!(!member(3, "vip", 2));
+        assert!(!member(3, "vip", 1));
+        assert!(!member(4, "vip", 1));
+    }
+
+    #[test]
+    fn tokens_and_certificates() {
+        let store = AclStore::new();
+        let tree = tree();
+        let identity = Identity {
+            access_tokens: vec!["Secret".into()],
+            cert_hash: Some("abcdef".into()),
+            strong: true,
+            ..Default::default()
+        };
+
+        assert!(store.is_member(&tree, 0, 0, "#secret", &identity));
+        assert!(!store.is_member(&tree, 0, 0, "#other", &identity));
+        assert!(store.is_member(&tree, 0, 0, "$abcdef", &identity));
+        assert!(store.is_member(&tree, 0, 0, "strong", &identity));
+        assert!(!store.is_member(&tree, 0, 0, "auth", &identity));
+        assert!(store.is_member(&tree, 0, 0, "!auth", &identity));
+    }
+
+    #[test]
+    fn from_message_skips_inherited_entries() {
+        let message = msgs::Acl {
+            channel_id: 3,
+            inherit_acls: Some(false),
+            groups: vec![
+                msgs::acl::ChanGroup { name: "admin".into(), inherited: Some(true), ..Default::default() },
+                msgs::acl::ChanGroup { name: "team".into(), inherited: Some(false), add: vec![1], ..Default::default() },
+            ],
+            acls: vec![
+                msgs::acl::ChanAcl { inherited: Some(true), group: Some("admin".into()), grant: Some(Permissions::WRITE.bits()), ..Default::default() },
+                msgs::acl::ChanAcl { inherited: Some(false), group: Some("team".into()), deny: Some(Permissions::SPEAK.bits()), ..Default::default() },
+            ],
+            query: None,
+        };
+        let mut store = AclStore::new();
+        store.apply(&message);
+
+        let acl = store.get(3).unwrap();
+        assert!(!acl.inherit_acls);
+        assert_eq!(vec!["team"], acl.groups.keys().collect::<Vec<_>>());
+        assert_eq!(
+            vec![AclEntry { apply_here: true, apply_subs: true, user_id: None, group: Some("team".into()), grant: NONE, deny: Permissions::SPEAK }],
+            acl.entries
+        );
+    }
+}
@@ -0,0 +1,470 @@
+//! Server bans, as exchanged with `BanList` messages
+//!
+//! A ban covers a subnet (an IPv6 address and a prefix length, IPv4 addresses being
+//! IPv4-mapped) and optionally a certificate hash. Bans with a duration expire, permanent ones
+//! have a duration of 0.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/1.4.287/src/Ban.cpp
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv6Addr};
+
+use crate::mumble_proto as msgs;
+
+/// Murmur rejects bans covering more than a /8.
+pub const MIN_MASK: u8 = 8;
+pub const MAX_MASK: u8 = 128;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BanError {
+    /// The address is not 16 bytes long.
+    InvalidAddress(usize),
+    /// The prefix length is outside of `MIN_MASK..=MAX_MASK`.
+    InvalidMask(u32),
+    /// The start is not an ISO 8601 date and time.
+    InvalidStart(String),
+}
+
+impl std::fmt::Display for BanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanError::InvalidAddress(len) => write!(f, "ban address of {len} bytes, expected 16"),
+            BanError::InvalidMask(mask) => write!(f, "invalid ban mask /{mask}"),
+            BanError::InvalidStart(start) => write!(f, "invalid ban start {start:?}"),
+        }
+    }
+}
+
+impl std::error::Error for BanError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ban {
+    /// Network address, with the bits after the mask cleared.
+    pub address: Ipv6Addr,
+    /// Prefix length, relative to the IPv6 address.
+    pub mask: u8,
+    pub name: Option<String>,
+    /// Hex encoded SHA-1 of the banned certificate.
+    pub hash: Option<String>,
+    pub reason: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub start: u64,
+    /// In seconds, 0 for permanent bans.
+    pub duration: u32,
+}
+
+impl Ban {
+    /// Bans the subnet of the address, `mask` being relative to the address' family.
+    pub fn new(address: IpAddr, mask: u8, start: u64) -> Result<Self, BanError> {
+        let (address, mask) = match address {
+            IpAddr::V4(address) if mask <= 32 => (address.to_ipv6_mapped(), mask + 96),
+            IpAddr::V6(address) if mask <= 128 => (address, mask),
+            _ => return Err(BanError::InvalidMask(mask.into())),
+        };
+        if mask < MIN_MASK {
+            return Err(BanError::InvalidMask(mask.into()));
+        }
+
+        Ok(Ban {
+            address: network(address, mask),
+            mask,
+            name: None,
+            hash: None,
+            reason: None,
+            start,
+            duration: 0,
+        })
+    }
+
+    /// Returns the address and prefix length, as IPv4 for IPv4-mapped addresses.
+    pub fn subnet(&self) -> (IpAddr, u8) {
+        match self.address.to_ipv4_mapped() {
+            Some(address) if self.mask >= 96 => (IpAddr::V4(address), self.mask - 96),
+            _ => (IpAddr::V6(self.address), self.mask),
+        }
+    }
+
+    /// Seconds since the Unix epoch at which the ban expires, `None` for permanent bans.
+    /// Saturates for starts too late to be represented.
+    pub fn expires_at(&self) -> Option<u64> {
+        (self.duration > 0).then(|| self.start.saturating_add(u64::from(self.duration)))
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at().is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn covers(&self, address: IpAddr) -> bool {
+        network(to_ipv6(address), self.mask) == self.address
+    }
+
+    pub fn to_entry(&self) -> msgs::ban_list::BanEntry {
+        msgs::ban_list::BanEntry {
+            address: self.address.octets().to_vec(),
+            mask: self.mask.into(),
+            name: self.name.clone(),
+            hash: self.hash.clone(),
+            reason: self.reason.clone(),
+            start: Some(format_iso8601(self.start)),
+            duration: Some(self.duration),
+        }
+    }
+}
+
+impl TryFrom<&msgs::ban_list::BanEntry> for Ban {
+    type Error = BanError;
+
+    fn try_from(entry: &msgs::ban_list::BanEntry) -> Result<Self, BanError> {
+        let octets: [u8; 16] = entry
+            .address
+            .as_slice()
+            .try_into()
+            .map_err(|_| BanError::InvalidAddress(entry.address.len()))?;
+        let mask = match u8::try_from(entry.mask) {
+            Ok(mask @ MIN_MASK..=MAX_MASK) => mask,
+            _ => return Err(BanError::InvalidMask(entry.mask)),
+        };
+        let start = match entry.start.as_deref() {
+            Some(start) => parse_iso8601(start).ok_or_else(|| BanError::InvalidStart(start.to_owned()))?,
+            None => 0,
+        };
+        let non_empty = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+
+        Ok(Ban {
+            address: network(Ipv6Addr::from(octets), mask),
+            mask,
+            name: non_empty(&entry.name),
+            hash: non_empty(&entry.hash),
+            reason: non_empty(&entry.reason),
+            start,
+            duration: entry.duration(),
+        })
+    }
+}
+
+/// The bans of a server, indexed by subnet and certificate hash.
+#[derive(Clone, Debug, Default)]
+pub struct BanList {
+    bans: Vec<Ban>,
+    /// Positions in `bans` by prefix length and network address.
+    by_subnet: BTreeMap<u8, BTreeMap<u128, Vec<usize>>>,
+    by_hash: BTreeMap<String, Vec<usize>>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bans.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Ban> {
+        self.bans.iter()
+    }
+
+    pub fn insert(&mut self, ban: Ban) {
+        let index = self.bans.len();
+        self.by_subnet
+            .entry(ban.mask)
+            .or_default()
+            .entry(u128::from(ban.address))
+            .or_default()
+            .push(index);
+        if let Some(hash) = &ban.hash {
+            self.by_hash.entry(hash.clone()).or_default().push(index);
+        }
+        self.bans.push(ban);
+    }
+
+    /// Replaces all bans with those of the message, failing without changes on an invalid entry.
+    pub fn apply(&mut self, message: &msgs::BanList) -> Result<(), BanError> {
+        let bans = message.bans.iter().map(Ban::try_from).collect::<Result<Vec<_>, _>>()?;
+        self.replace(bans);
+        Ok(())
+    }
+
+    pub fn replace(&mut self, bans: Vec<Ban>) {
+        *self = BanList::new();
+        for ban in bans {
+            self.insert(ban);
+        }
+    }
+
+    /// Removes the ban at the given position of `iter`, returns it.
+    pub fn remove(&mut self, index: usize) -> Option<Ban> {
+        if index >= self.bans.len() {
+            return None;
+        }
+        let mut bans = std::mem::take(&mut self.bans);
+        let ban = bans.remove(index);
+        self.replace(bans);
+        Some(ban)
+    }
+
+    /// Removes the bans expired at `now` (seconds since the Unix epoch), returns them.
+    pub fn remove_expired(&mut self, now: u64) -> Vec<Ban> {
+        let (expired, active) = std::mem::take(&mut self.bans).into_iter().partition(|ban| ban.is_expired(now));
+        self.replace(active);
+        expired
+    }
+
+    /// Returns the first active ban covering the address or the certificate hash.
+    ///
+    /// Looks up each prefix length in use, so this takes O(log n) for n bans.
+    pub fn find(&self, address: Option<IpAddr>, hash: Option<&str>, now: u64) -> Option<&Ban> {
+        let mut found: Option<usize> = None;
+        let mut consider = |indices: &[usize]| {
+            let active = indices.iter().copied().find(|&index| !self.bans[index].is_expired(now));
+            found = match (found, active) {
+                (Some(found), Some(active)) => Some(found.min(active)),
+                (found, active) => found.or(active),
+            };
+        };
+
+        if let Some(address) = address {
+            let address = to_ipv6(address);
+            for (&mask, networks) in &self.by_subnet {
+                if let Some(indices) = networks.get(&u128::from(network(address, mask))) {
+                    consider(indices);
+                }
+            }
+        }
+        if let Some(indices) = hash.filter(|hash| !hash.is_empty()).and_then(|hash| self.by_hash.get(hash)) {
+            consider(indices);
+        }
+
+        found.map(|index| &self.bans[index])
+    }
+
+    pub fn to_message(&self) -> msgs::BanList {
+        msgs::BanList { bans: self.bans.iter().map(Ban::to_entry).collect(), query: None }
+    }
+}
+
+fn to_ipv6(address: IpAddr) -> Ipv6Addr {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped(),
+        IpAddr::V6(address) => address,
+    }
+}
+
+/// Clears the bits after the prefix.
+fn network(address: Ipv6Addr, mask: u8) -> Ipv6Addr {
+    let bits = u128::from(address);
+    let mask = u128::MAX.checked_shl(128 - u32::from(mask)).unwrap_or(0);
+    Ipv6Addr::from(bits & mask)
+}
+
+/// Formats seconds since the Unix epoch like Qt's `Qt::ISODate`, in UTC.
+pub fn format_iso8601(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Parses `YYYY-MM-DDTHH:MM:SS`, with optional fractional seconds and a `Z` or `±HH:MM` offset.
+/// Times without offset are taken as UTC, as Murmur sends them.
+pub fn parse_iso8601(value: &str) -> Option<u64> {
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = value.get(range)?;
+        if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let bytes = value.as_bytes();
+    if bytes.len() < 19 || bytes[4] != b'-' || bytes[7] != b'-' || !matches!(bytes[10], b'T' | b' ') {
+        return None;
+    }
+    if bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut rest = &value[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        rest = &fraction[digits..];
+    }
+    let offset = match rest {
+        "" | "Z" => 0,
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let offset = &rest[1..];
+            let (hours, minutes) = offset.split_once(':').unwrap_or((offset.get(..2)?, offset.get(2..)?));
+            let digits = |part: &str| part.len() == 2 && part.bytes().all(|byte| byte.is_ascii_digit());
+            if !digits(hours) || !digits(minutes) {
+                return None;
+            }
+            sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60)
+        }
+    };
+
+    let timestamp = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(timestamp).ok()
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+///
+/// See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ban(subnet: &str, start: u64, duration: u32) -> Ban {
+        let (address, mask) = subnet.split_once('/').unwrap();
+        Ban { duration, ..Ban::new(address.parse().unwrap(), mask.parse().unwrap(), start).unwrap() }
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_subnets() {
+        let mut list = BanList::new();
+        list.insert(ban("192.168.1.0/24", 0, 0));
+        list.insert(ban("10.0.0.7/32", 0, 0));
+        list.insert(ban("2001:db8::/32", 0, 0));
+
+        assert_eq!("192.168.1.0".parse::<IpAddr>().unwrap(), list.find(ip("192.168.1.77"), None, 0).unwrap().subnet().0);
+        assert!(list.find(ip("192.168.2.1"), None, 0).is_none());
+        assert!(list.find(ip("10.0.0.7"), None, 0).is_some());
+        assert!(list.find(ip("::ffff:10.0.0.7"), None, 0).is_some());
+        assert!(list.find(ip("10.0.0.8"), None, 0).is_none());
+        assert!(list.find(ip("2001:db8:1::1"), None, 0).is_some());
+        assert!(list.find(ip("2001:db9::1"), None, 0).is_none());
+        assert!(list.find(None, None, 0).is_none());
+    }
+
+    #[test]
+    fn matches_certificate_hashes() {
+        let mut list = BanList::new();
+        list.insert(Ban { hash: Some("abc".into()), ..ban("10.0.0.1/32", 0, 0) });
+
+        assert!(list.find(ip("10.9.9.9"), Some("abc"), 0).is_some());
+        assert!(list.find(ip("10.9.9.9"), Some("def"), 0).is_none());
+        assert!(list.find(None, Some(""), 0).is_none());
+    }
+
+    #[test]
+    fn expiry() {
+        let mut list = BanList::new();
+        list.insert(ban("10.0.0.0/8", 1000, 60));
+        list.insert(ban("10.1.0.0/16", 1000, 0));
+
+        assert_eq!(8 + 96, list.find(ip("10.1.2.3"), None, 1059).unwrap().mask);
+        assert_eq!(16 + 96, list.find(ip("10.1.2.3"), None, 1060).unwrap().mask);
+        assert!(list.find(ip("10.2.0.1"), None, 1060).is_none());
+
+        let expired = list.remove_expired(1060);
+        assert_eq!(vec![Some(1060)], expired.iter().map(Ban::expires_at).collect::<Vec<_>>());
+        assert_eq!(1, list.len());
+        assert!(list.find(ip("10.1.0.1"), None, 5000).is_some());
+
+        let late = ban("10.0.0.0/8", u64::MAX - 10, 60);
+        assert_eq!(Some(u64::MAX), late.expires_at());
+        assert!(!late.is_expired(u64::MAX - 1));
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(Err(BanError::InvalidMask(33)), Ban::new("10.0.0.0".parse().unwrap(), 33, 0));
+        assert_eq!(Err(BanError::InvalidMask(4)), Ban::new("::".parse().unwrap(), 4, 0));
+        assert_eq!("10.0.0.0/8", {
+            let (address, mask) = ban("10.20.30.40/8", 0, 0).subnet();
+            format!("{address}/{mask}")
+        });
+
+        let entry = msgs::ban_list::BanEntry { address: vec![0; 4], mask: 32, ..Default::default() };
+        assert_eq!(Err(BanError::InvalidAddress(4)), Ban::try_from(&entry));
+        let entry = msgs::ban_list::BanEntry { address: vec![0; 16], mask: 129, ..Default::default() };
+        assert_eq!(Err(BanError::InvalidMask(129)), Ban::try_from(&entry));
+        let entry = msgs::ban_list::BanEntry { address: vec![0; 16], mask: 128, start: Some("yesterday".into()), ..Default::default() };
+        assert_eq!(Err(BanError::InvalidStart("yesterday".into())), Ban::try_from(&entry));
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let message = msgs::BanList {
+            bans: vec![msgs::ban_list::BanEntry {
+                address: Ipv6Addr::from([0, 0, 0, 0, 0, 0xffff, 0xc0a8, 0x0100]).octets().to_vec(),
+                mask: 120,
+                name: Some("mallory".into()),
+                hash: Some("0123456789abcdef0123456789abcdef01234567".into()),
+                reason: Some("spam".into()),
+                start: Some("2024-02-29T23:59:30".into()),
+                duration: Some(3600),
+            }],
+            query: None,
+        };
+        let mut list = BanList::new();
+        list.apply(&message).unwrap();
+
+        let ban = list.iter().next().unwrap();
+        assert_eq!(1709251170, ban.start);
+        assert_eq!(Some(1709254770), ban.expires_at());
+        assert_eq!(message, list.to_message());
+    }
+
+    #[test]
+    fn iso8601() {
+        assert_eq!(Some(0), parse_iso8601("1970-01-01T00:00:00"));
+        assert_eq!(Some(951782400), parse_iso8601("2000-02-29T00:00:00Z"));
+        assert_eq!(Some(951782400), parse_iso8601("2000-02-29T02:00:00.123+02:00"));
+        assert_eq!(Some(951782400), parse_iso8601("2000-02-28T19:00:00-0500"));
+        assert_eq!(None, parse_iso8601("2000-13-01T00:00:00"));
+        assert_eq!(None, parse_iso8601("2000-01-01"));
+        assert_eq!(None, parse_iso8601("1969-12-31T23:59:59"));
+        assert_eq!(None, parse_iso8601("2000-01-01T00:00:00+2"));
+        assert_eq!(None, parse_iso8601("2000-01-01T00:00:00+-1:00"));
+
+        assert_eq!("2000-02-29T00:00:00", format_iso8601(951782400));
+        assert_eq!("2100-03-01T12:34:56", format_iso8601(parse_iso8601("2100-03-01T12:34:56").unwrap()));
+    }
+}
@@ -0,0 +1,187 @@
+//! Voice bandwidth accounting
+//!
+//! `ServerSync` announces the bandwidth clients may use for voice, in bits per second. Murmur
+//! measures it over the last 360 packets a client sent, counting each packet with its IP, UDP and
+//! crypt overhead, and drops those which would bring the rate above the limit. Clients choose
+//! their bitrate and frames per packet to stay within it, estimating the overhead the same way.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/ServerUser.cpp
+//! (`BandwidthRecord`) and https://github.com/mumble-voip/mumble/blob/v1.5.634/src/mumble/AudioInput.cpp
+//! (`AudioInput::getNetworkBandwidth`)
+
+use crate::mumble_proto as msgs;
+
+/// Amount of packets the bandwidth is measured over.
+pub const SLOTS: usize = 360;
+/// Bytes of IPv4 and UDP headers.
+pub const IP_UDP_OVERHEAD: usize = 20 + 8;
+/// Bytes added by `CryptState::encrypt`.
+pub const CRYPT_OVERHEAD: usize = 4;
+/// Bytes clients add to their estimate when tunneling voice through the control channel.
+pub const TUNNEL_OVERHEAD: u32 = 12;
+/// Bytes clients add to their estimate when sending positional data.
+pub const POSITIONAL_OVERHEAD: u32 = 12;
+
+/// The bandwidth a client expects to use, in bits per second, for an audio `bitrate` and
+/// `frames` 10 ms audio frames per packet.
+pub fn network_bandwidth(bitrate: u32, frames: u32, positional: bool, tunnel: bool) -> u32 {
+    let frames = frames.max(1);
+    // IP, UDP, crypt, header, sequence and frame headers
+    let mut overhead = 20 + 8 + 4 + 1 + 2 + frames;
+    if positional {
+        overhead += POSITIONAL_OVERHEAD;
+    }
+    if tunnel {
+        overhead += TUNNEL_OVERHEAD;
+    }
+
+    overhead * (800 / frames) + bitrate
+}
+
+/// Measures the voice bandwidth of one session.
+#[derive(Clone, Debug)]
+pub struct BandwidthMeter {
+    /// Limit in bytes per second, 0 for none.
+    max_bytes_per_sec: u64,
+    /// Size and time of the last packets, `next` being the oldest.
+    sizes: [u32; SLOTS],
+    times_ms: [u64; SLOTS],
+    next: usize,
+    sum: u64,
+    created_ms: u64,
+    /// When the last packet was accepted, `None` before the first.
+    last_ms: Option<u64>,
+}
+
+impl BandwidthMeter {
+    /// Creates a meter enforcing `max_bandwidth` bits per second, as announced in `ServerSync`.
+    /// 0 disables the limit.
+    pub fn new(max_bandwidth: u32, now_ms: u64) -> Self {
+        BandwidthMeter {
+            max_bytes_per_sec: max_bandwidth as u64 / 8,
+            sizes: [0; SLOTS],
+            times_ms: [now_ms; SLOTS],
+            next: 0,
+            sum: 0,
+            created_ms: now_ms,
+            last_ms: None,
+        }
+    }
+
+    /// The size a voice packet counts for, from its size before encryption.
+    pub fn packet_size(plain_len: usize) -> u32 {
+        (IP_UDP_OVERHEAD + CRYPT_OVERHEAD + plain_len) as u32
+    }
+
+    /// Accounts a voice packet received from the session, by its size before encryption (or as
+    /// tunneled). Returns false if it exceeds the limit and should be dropped, it isn't
+    /// accounted then.
+    pub fn add(&mut self, plain_len: usize, now_ms: u64) -> bool {
+        let size = Self::packet_size(plain_len);
+        // The oldest packet leaves the window, the rate is measured since it was received
+        let sum = self.sum - self.sizes[self.next] as u64 + size as u64;
+        let elapsed_ms = now_ms.saturating_sub(self.times_ms[self.next]).max(1);
+
+        if self.max_bytes_per_sec != 0 && sum * 1000 / elapsed_ms > self.max_bytes_per_sec {
+            return false;
+        }
+
+        self.sizes[self.next] = size;
+        self.times_ms[self.next] = now_ms;
+        self.next = (self.next + 1) % SLOTS;
+        self.sum = sum;
+        self.last_ms = Some(now_ms);
+        true
+    }
+
+    /// Like `add`, by the size of the encrypted datagram.
+    pub fn add_encrypted(&mut self, encrypted_len: usize, now_ms: u64) -> bool {
+        self.add(encrypted_len.saturating_sub(CRYPT_OVERHEAD), now_ms)
+    }
+
+    /// The bandwidth used over the last second in bytes per second, 0 if measured over less than
+    /// a quarter of a second.
+    pub fn bandwidth(&self, now_ms: u64) -> u32 {
+        let mut sum = 0u64;
+        let mut elapsed_ms = 0;
+
+        for age in 1..SLOTS {
+            let index = (self.next + SLOTS - age) % SLOTS;
+            let elapsed = now_ms.saturating_sub(self.times_ms[index]);
+            if elapsed > 1000 {
+                break;
+            }
+            sum += self.sizes[index] as u64;
+            elapsed_ms = elapsed;
+        }
+
+        if elapsed_ms < 250 {
+            return 0;
+        }
+        (sum * 1000 / elapsed_ms) as u32
+    }
+
+    pub fn online_ms(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.created_ms)
+    }
+
+    /// Time since the last accepted packet, or since the meter was created.
+    pub fn idle_ms(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.last_ms.unwrap_or(self.created_ms))
+    }
+
+    /// Fills `bandwidth`, `onlinesecs` and `idlesecs` of a `UserStats` reply like Murmur.
+    pub fn fill_user_stats(&self, stats: &mut msgs::UserStats, now_ms: u64) {
+        stats.bandwidth = Some(self.bandwidth(now_ms));
+        stats.onlinesecs = Some((self.online_ms(now_ms) / 1000) as u32);
+        stats.idlesecs = Some((self.idle_ms(now_ms) / 1000) as u32);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn client_estimate() {
+        // 40 kbit/s Opus in 20 ms packets: (20 + 8 + 4 + 1 + 2 + 2) * 400 = 14800 bits of overhead
+        assert_eq!(54_800, network_bandwidth(40_000, 2, false, false));
+        assert_eq!(64_400, network_bandwidth(40_000, 2, true, true));
+        assert_eq!(28_800, network_bandwidth(0, 1, false, false));
+    }
+
+    #[test]
+    fn measures_bandwidth() {
+        let mut meter = BandwidthMeter::new(0, 0);
+        assert_eq!(0, meter.bandwidth(0));
+
+        // 50 packets per second, 68 bytes each with overhead
+        for packet in 0..50 {
+            assert!(meter.add(36, 1000 + packet * 20));
+        }
+        assert_eq!(68 * 50 * 1000 / 980, meter.bandwidth(1980));
+        assert_eq!(0, meter.bandwidth(1200));
+        assert_eq!(0, meter.bandwidth(5000));
+
+        let mut stats = msgs::UserStats::default();
+        meter.fill_user_stats(&mut stats, 5000);
+        assert_eq!((Some(0), Some(5), Some(3)), (stats.bandwidth, stats.onlinesecs, stats.idlesecs));
+    }
+
+    #[test]
+    fn drops_packets_over_the_limit() {
+        // 8 kbit/s is 1000 bytes per second
+        let mut meter = BandwidthMeter::new(8_000, 0);
+        for packet in 0..SLOTS as u64 {
+            assert!(meter.add_encrypted(4, 1000 + packet * 100));
+        }
+
+        // The oldest packet was received 36 s ago: 360 * 32 bytes over 36 s are within the limit
+        assert!(meter.add(0, 1000 + 360 * 100));
+
+        let mut burst = BandwidthMeter::new(8_000, 0);
+        assert!(burst.add(968, 1000));
+        assert!(!burst.add(968, 1000));
+        assert_eq!(4000, burst.bandwidth(1250));
+    }
+}
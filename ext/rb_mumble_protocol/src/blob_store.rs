@@ -0,0 +1,248 @@
+//! Storage of large textures, comments and channel descriptions
+//!
+//! `UserState` and `ChannelState` carry a SHA-1 hash instead of a texture, comment or description
+//! of 128 bytes or more. Clients fetch the data they don't have cached with `RequestBlob`, which
+//! the server answers with `UserState` and `ChannelState` messages carrying the full data.
+//! Blobs are stored by hash, identical ones only once.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Messages.cpp
+//! (`Server::msgRequestBlob`) and https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Server.cpp
+//! (`Server::hashAssign`)
+
+use std::collections::{HashMap, HashSet};
+
+use crate::channel_tree::ChannelTree;
+use crate::control::ControlMessage;
+use crate::mumble_proto as msgs;
+use crate::user_registry::UserRegistry;
+
+/// Size from which Murmur replaces data by its hash.
+pub const DEFAULT_INLINE_LIMIT: usize = 128;
+
+/// SHA-1 of a blob.
+pub type BlobHash = [u8; 20];
+
+pub fn hash(data: &[u8]) -> BlobHash {
+    openssl::sha::sha1(data)
+}
+
+#[derive(Clone, Debug)]
+pub struct BlobStore {
+    /// Data of this size or larger is sent as a hash.
+    inline_limit: usize,
+    blobs: HashMap<BlobHash, Vec<u8>>,
+}
+
+impl Default for BlobStore {
+    fn default() -> Self {
+        BlobStore::new(DEFAULT_INLINE_LIMIT)
+    }
+}
+
+impl BlobStore {
+    pub fn new(inline_limit: usize) -> Self {
+        BlobStore { inline_limit, blobs: HashMap::new() }
+    }
+
+    pub fn inline_limit(&self) -> usize {
+        self.inline_limit
+    }
+
+    pub fn len(&self) -> usize {
+        self.blobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<&[u8]> {
+        self.blobs.get(hash).map(Vec::as_slice)
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.blobs.contains_key(hash)
+    }
+
+    /// Stores a blob whatever its size, e.g. one received in answer to `RequestBlob`.
+    pub fn insert(&mut self, data: &[u8]) -> BlobHash {
+        let hash = hash(data);
+        self.blobs.entry(hash).or_insert_with(|| data.to_vec());
+        hash
+    }
+
+    /// Stores the data if it is too large to be sent along and returns its hash, `None` if it
+    /// should be sent as is.
+    pub fn store(&mut self, data: &[u8]) -> Option<BlobHash> {
+        if data.len() < self.inline_limit {
+            return None;
+        }
+        Some(self.insert(data))
+    }
+
+    /// Replaces a large texture or comment by its hash, before the message is broadcast.
+    pub fn compact_user_state(&mut self, state: &mut msgs::UserState) {
+        if let Some(hash) = state.texture.as_deref().and_then(|texture| self.store(texture)) {
+            state.texture = None;
+            state.texture_hash = Some(hash.to_vec());
+        }
+        if let Some(hash) = state.comment.as_deref().and_then(|comment| self.store(comment.as_bytes())) {
+            state.comment = None;
+            state.comment_hash = Some(hash.to_vec());
+        }
+    }
+
+    /// Replaces a large description by its hash, before the message is broadcast.
+    pub fn compact_channel_state(&mut self, state: &mut msgs::ChannelState) {
+        if let Some(hash) = state.description.as_deref().and_then(|description| self.store(description.as_bytes())) {
+            state.description = None;
+            state.description_hash = Some(hash.to_vec());
+        }
+    }
+
+    /// Answers a `RequestBlob` with the stored data of the hashes the users and channels refer
+    /// to. Unknown sessions and channels, and data not hashed, are skipped.
+    pub fn answer(&self, request: &msgs::RequestBlob, users: &UserRegistry, channels: &ChannelTree) -> Vec<ControlMessage> {
+        let mut messages: Vec<ControlMessage> = Vec::new();
+
+        for &session in &request.session_texture {
+            let texture = users.get(session).and_then(|user| self.get(user.texture_hash.as_deref()?));
+            if let Some(texture) = texture {
+                messages.push(msgs::UserState { session: Some(session), texture: Some(texture.to_vec()), ..Default::default() }.into());
+            }
+        }
+        for &session in &request.session_comment {
+            let comment = users.get(session).and_then(|user| self.get(user.comment_hash.as_deref()?));
+            if let Some(comment) = comment {
+                let comment = String::from_utf8_lossy(comment).into_owned();
+                messages.push(msgs::UserState { session: Some(session), comment: Some(comment), ..Default::default() }.into());
+            }
+        }
+        for &channel_id in &request.channel_description {
+            let description = channels.get(channel_id).and_then(|channel| self.get(channel.description_hash.as_deref()?));
+            if let Some(description) = description {
+                let description = String::from_utf8_lossy(description).into_owned();
+                messages.push(msgs::ChannelState { channel_id: Some(channel_id), description: Some(description), ..Default::default() }.into());
+            }
+        }
+
+        messages
+    }
+
+    /// Requests the blobs the users and channels refer to which aren't stored, `None` if all are.
+    pub fn request_missing(&self, users: &UserRegistry, channels: &ChannelTree) -> Option<msgs::RequestBlob> {
+        let missing = |hash: &Option<Vec<u8>>| hash.as_deref().is_some_and(|hash| !self.contains(hash));
+        let request = msgs::RequestBlob {
+            session_texture: users.iter().filter(|user| missing(&user.texture_hash)).map(|user| user.session).collect(),
+            session_comment: users.iter().filter(|user| missing(&user.comment_hash)).map(|user| user.session).collect(),
+            channel_description: channels
+                .iter()
+                .filter(|channel| missing(&channel.description_hash))
+                .map(|channel| channel.id)
+                .collect(),
+        };
+
+        let empty = request.session_texture.is_empty() && request.session_comment.is_empty() && request.channel_description.is_empty();
+        if empty { None } else { Some(request) }
+    }
+
+    /// Drops the blobs no user or channel refers to anymore, returns how many.
+    pub fn prune(&mut self, users: &UserRegistry, channels: &ChannelTree) -> usize {
+        let referenced: HashSet<&[u8]> = users
+            .iter()
+            .flat_map(|user| [user.texture_hash.as_deref(), user.comment_hash.as_deref()])
+            .chain(channels.iter().map(|channel| channel.description_hash.as_deref()))
+            .flatten()
+            .collect();
+
+        let before = self.blobs.len();
+        self.blobs.retain(|hash, _| referenced.contains(hash.as_slice()));
+        before - self.blobs.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user(session: u32) -> msgs::UserState {
+        msgs::UserState { session: Some(session), name: Some(format!("user{session}")), channel_id: Some(0), ..Default::default() }
+    }
+
+    #[test]
+    fn hashes_large_data() {
+        let mut store = BlobStore::default();
+        let mut state = msgs::UserState { texture: Some(vec![1; 128]), comment: Some("short".into()), ..user(1) };
+        store.compact_user_state(&mut state);
+
+        assert_eq!(None, state.texture);
+        assert_eq!(Some(hash(&[1; 128]).to_vec()), state.texture_hash);
+        assert_eq!(Some("short"), state.comment.as_deref());
+        assert_eq!(None, state.comment_hash);
+
+        // Identical blobs are stored once
+        let mut other = msgs::UserState { comment: Some("\x01".repeat(128)), ..user(2) };
+        store.compact_user_state(&mut other);
+        assert_eq!(state.texture_hash, other.comment_hash);
+        assert_eq!(1, store.len());
+
+        let mut channel = msgs::ChannelState { channel_id: Some(0), description: Some("d".repeat(200)), ..Default::default() };
+        store.compact_channel_state(&mut channel);
+        assert_eq!(None, channel.description);
+        assert_eq!(Some("d".repeat(200).as_bytes()), channel.description_hash.as_deref().and_then(|hash| store.get(hash)));
+    }
+
+    #[test]
+    fn answers_requests() {
+        let mut store = BlobStore::default();
+        let mut users = UserRegistry::new();
+        let mut channels = ChannelTree::new();
+
+        let mut state = msgs::UserState { texture: Some(vec![7; 500]), comment: Some("c".repeat(300)), ..user(1) };
+        store.compact_user_state(&mut state);
+        users.apply_state(&state).unwrap();
+        users.apply_state(&msgs::UserState { comment: Some("hi".into()), ..user(2) }).unwrap();
+        let mut root = msgs::ChannelState { channel_id: Some(0), description: Some("r".repeat(128)), ..Default::default() };
+        store.compact_channel_state(&mut root);
+        channels.apply_state(&root).unwrap();
+
+        let request = msgs::RequestBlob { session_texture: vec![1, 2, 3], session_comment: vec![1, 2], channel_description: vec![0, 1] };
+        let answer = store.answer(&request, &users, &channels);
+        assert_eq!(3, answer.len());
+        match &answer[0] {
+            ControlMessage::UserState(state) => assert_eq!((Some(1), Some(vec![7; 500])), (state.session, state.texture.clone())),
+            message => panic!("unexpected {message:?}"),
+        }
+        match &answer[2] {
+            ControlMessage::ChannelState(state) => assert_eq!(Some("r".repeat(128)), state.description),
+            message => panic!("unexpected {message:?}"),
+        }
+
+        // A client without any cached blob
+        let mut client = BlobStore::default();
+        let missing = client.request_missing(&users, &channels).unwrap();
+        assert_eq!((vec![1], vec![1], vec![0]), (missing.session_texture, missing.session_comment, missing.channel_description));
+        client.insert(&[7; 500]);
+        client.insert("c".repeat(300).as_bytes());
+        client.insert("r".repeat(128).as_bytes());
+        assert_eq!(None, client.request_missing(&users, &channels));
+    }
+
+    #[test]
+    fn prunes_unreferenced_blobs() {
+        let mut store = BlobStore::new(4);
+        let mut users = UserRegistry::new();
+        let channels = ChannelTree::new();
+
+        let mut state = msgs::UserState { comment: Some("first".into()), ..user(1) };
+        store.compact_user_state(&mut state);
+        users.apply_state(&state).unwrap();
+        let mut state = msgs::UserState { comment: Some("second".into()), ..user(1) };
+        store.compact_user_state(&mut state);
+        users.apply_state(&state).unwrap();
+
+        assert_eq!(1, store.prune(&users, &channels));
+        assert!(store.contains(&hash(b"second")));
+        assert_eq!(None, store.store(b"abc"));
+    }
+}
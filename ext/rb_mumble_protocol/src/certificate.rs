@@ -0,0 +1,363 @@
+//! X.509 certificates identifying users and servers
+//!
+//! Mumble identifies a certificate by the hex encoded SHA-1 of its DER encoding, which is the
+//! `hash` of `UserState` messages and of registered users.
+//!
+//! Clients without a certificate generate a self-signed one, see `Bundle::generate`.
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, PKeyRef, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, SubjectAlternativeName, SubjectKeyIdentifier};
+use openssl::x509::{X509Builder, X509NameBuilder, X509VerifyResult, X509};
+
+/// Validity of generated certificates, 20 years like those of Mumble clients.
+pub const VALIDITY_SECS: i64 = 20 * 365 * 86400;
+
+#[derive(Clone, Debug)]
+pub enum CertificateError {
+    /// The data is neither a DER nor a PEM encoded certificate.
+    Malformed(ErrorStack),
+    /// The validity of a generated certificate would end after the largest representable time.
+    InvalidTime(i64),
+    OpenSsl(ErrorStack),
+}
+
+impl std::fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CertificateError::Malformed(e) => write!(f, "malformed certificate: {e}"),
+            CertificateError::InvalidTime(now) => write!(f, "certificate validity from {now} is out of range"),
+            CertificateError::OpenSsl(e) => write!(f, "OpenSSL error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CertificateError {}
+
+impl From<ErrorStack> for CertificateError {
+    fn from(e: ErrorStack) -> Self {
+        CertificateError::OpenSsl(e)
+    }
+}
+
+#[derive(Clone)]
+pub struct Certificate {
+    x509: X509,
+}
+
+impl Certificate {
+    /// Parses a DER or PEM encoded certificate. PEM data may contain a chain, the first
+    /// certificate is taken.
+    pub fn parse(data: &[u8]) -> Result<Self, CertificateError> {
+        let x509 = if is_pem(data) { X509::from_pem(data) } else { X509::from_der(data) };
+
+        x509.map(Certificate::from).map_err(CertificateError::Malformed)
+    }
+
+    pub fn x509(&self) -> &X509 {
+        &self.x509
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, CertificateError> {
+        Ok(self.x509.to_der()?)
+    }
+
+    pub fn to_pem(&self) -> Result<Vec<u8>, CertificateError> {
+        Ok(self.x509.to_pem()?)
+    }
+
+    /// Returns the Mumble hash: the lowercase hex SHA-1 of the DER encoding.
+    pub fn hash(&self) -> Result<String, CertificateError> {
+        let digest = self.x509.digest(MessageDigest::sha1())?;
+
+        Ok(digest.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    pub fn common_name(&self) -> Option<String> {
+        self.x509
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok())
+            .map(|name| name.to_string())
+    }
+
+    /// The email addresses of the subject alternative name extension, which Murmur uses to
+    /// tell certificates apart when checking strong (CA verified) certificates.
+    pub fn emails(&self) -> Vec<String> {
+        self.x509
+            .subject_alt_names()
+            .map(|names| names.iter().filter_map(|name| name.email().map(str::to_owned)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether the certificate is its own issuer and signed with its own key, like those Mumble
+    /// clients generate.
+    pub fn is_self_signed(&self) -> bool {
+        if self.x509.issued(&self.x509) != X509VerifyResult::OK {
+            return false;
+        }
+        self.x509
+            .public_key()
+            .and_then(|key| self.x509.verify(&key))
+            .unwrap_or(false)
+    }
+
+    /// Start of the validity period, in seconds since the Unix epoch.
+    pub fn not_before(&self) -> Result<i64, CertificateError> {
+        unix_time(self.x509.not_before())
+    }
+
+    /// End of the validity period, in seconds since the Unix epoch.
+    pub fn not_after(&self) -> Result<i64, CertificateError> {
+        unix_time(self.x509.not_after())
+    }
+
+    /// Whether `now` (seconds since the Unix epoch) is within the validity period.
+    pub fn is_valid_at(&self, now: i64) -> Result<bool, CertificateError> {
+        Ok(self.not_before()? <= now && now <= self.not_after()?)
+    }
+}
+
+impl From<X509> for Certificate {
+    fn from(x509: X509) -> Self {
+        Certificate { x509 }
+    }
+}
+
+impl std::fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Certificate")
+            .field("common_name", &self.common_name())
+            .field("hash", &self.hash().ok())
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    Rsa2048,
+    Ed25519,
+}
+
+impl KeyType {
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyType::Rsa2048 => "rsa",
+            KeyType::Ed25519 => "ed25519",
+        }
+    }
+}
+
+/// A certificate with its private key.
+#[derive(Clone)]
+pub struct Bundle {
+    pub certificate: Certificate,
+    key: PKey<Private>,
+}
+
+impl Bundle {
+    /// Generates a self-signed certificate like Mumble clients do, with the user name as common
+    /// name and the optional email as subject alternative name. It is valid from `now` (seconds
+    /// since the Unix epoch) for `VALIDITY_SECS`.
+    pub fn generate(common_name: &str, email: Option<&str>, key_type: KeyType, now: i64) -> Result<Self, CertificateError> {
+        let expiry = now.checked_add(VALIDITY_SECS).ok_or(CertificateError::InvalidTime(now))?;
+        let (key, digest) = match key_type {
+            KeyType::Rsa2048 => (PKey::from_rsa(Rsa::generate(2048)?)?, MessageDigest::sha256()),
+            // Ed25519 signatures hash the data themselves
+            KeyType::Ed25519 => (PKey::generate_ed25519()?, MessageDigest::null()),
+        };
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+        let serial = serial.to_asn1_integer()?;
+        let not_before = Asn1Time::from_unix(now as _)?;
+        let not_after = Asn1Time::from_unix(expiry as _)?;
+
+        let mut builder = X509Builder::new()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+
+        builder.append_extension(BasicConstraints::new().critical().build()?)?;
+        builder.append_extension(ExtendedKeyUsage::new().client_auth().server_auth().build()?)?;
+        let key_identifier = SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(key_identifier)?;
+        if let Some(email) = email {
+            let alt_name = SubjectAlternativeName::new().email(email).build(&builder.x509v3_context(None, None))?;
+            builder.append_extension(alt_name)?;
+        }
+
+        builder.sign(&key, digest)?;
+
+        Ok(Bundle { certificate: Certificate::from(builder.build()), key })
+    }
+
+    /// Reads a PKCS#12 archive, such as those exported by Mumble clients.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Self, CertificateError> {
+        let parsed = Pkcs12::from_der(der)
+            .and_then(|pkcs12| pkcs12.parse2(password))
+            .map_err(CertificateError::Malformed)?;
+
+        match (parsed.cert, parsed.pkey) {
+            (Some(x509), Some(key)) => Ok(Bundle { certificate: Certificate::from(x509), key }),
+            _ => Err(CertificateError::Malformed(ErrorStack::get())),
+        }
+    }
+
+    pub fn key(&self) -> &PKeyRef<Private> {
+        &self.key
+    }
+
+    /// Returns the private key as unencrypted PKCS#8 PEM.
+    pub fn private_key_pem(&self) -> Result<Vec<u8>, CertificateError> {
+        Ok(self.key.private_key_to_pem_pkcs8()?)
+    }
+
+    /// Returns a PKCS#12 archive which Mumble clients can import, named after the common name.
+    pub fn to_pkcs12(&self, password: &str) -> Result<Vec<u8>, CertificateError> {
+        let name = self.certificate.common_name().unwrap_or_default();
+        let mut builder = Pkcs12::builder();
+        builder.name(&name).pkey(&self.key).cert(self.certificate.x509());
+
+        Ok(builder.build2(password)?.to_der()?)
+    }
+}
+
+impl std::fmt::Debug for Bundle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Bundle").field("certificate", &self.certificate).finish_non_exhaustive()
+    }
+}
+
+/// Returns the Mumble hash of a DER or PEM encoded certificate.
+pub fn hash(data: &[u8]) -> Result<String, CertificateError> {
+    Certificate::parse(data)?.hash()
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    let start = data.iter().position(|byte| !byte.is_ascii_whitespace()).unwrap_or(data.len());
+    data[start..].starts_with(b"-----BEGIN")
+}
+
+fn unix_time(time: &openssl::asn1::Asn1TimeRef) -> Result<i64, CertificateError> {
+    let epoch = openssl::asn1::Asn1Time::from_unix(0)?;
+    let diff = epoch.diff(time)?;
+
+    Ok(i64::from(diff.days) * 86400 + i64::from(diff.secs))
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::ec::{EcGroup, EcKey};
+
+    use super::*;
+
+    fn build(name: &str, email: Option<&str>, issuer_key: Option<&PKey<openssl::pkey::Private>>) -> X509 {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::from_unix(1_000_000_000).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::from_unix(2_000_000_000).unwrap()).unwrap();
+        if let Some(email) = email {
+            let san = SubjectAlternativeName::new().email(email).build(&builder.x509v3_context(None, None)).unwrap();
+            builder.append_extension(san).unwrap();
+        }
+        builder.sign(issuer_key.unwrap_or(&key), MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn hashes_der_and_pem() {
+        let x509 = build("alice", None, None);
+        let der = x509.to_der().unwrap();
+        let pem = x509.to_pem().unwrap();
+
+        let expected: String = openssl::sha::sha1(&der).iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(40, expected.len());
+        assert_eq!(expected, hash(&der).unwrap());
+        assert_eq!(expected, hash(&pem).unwrap());
+        assert!(matches!(hash(b"garbage"), Err(CertificateError::Malformed(_))));
+    }
+
+    #[test]
+    fn details() {
+        let certificate = Certificate::from(build("alice", Some("alice@example.com"), None));
+
+        assert_eq!(Some("alice".to_owned()), certificate.common_name());
+        assert_eq!(vec!["alice@example.com".to_owned()], certificate.emails());
+        assert_eq!(1_000_000_000, certificate.not_before().unwrap());
+        assert_eq!(2_000_000_000, certificate.not_after().unwrap());
+        assert!(certificate.is_valid_at(1_500_000_000).unwrap());
+        assert!(!certificate.is_valid_at(2_000_000_001).unwrap());
+        assert!(!certificate.is_valid_at(999_999_999).unwrap());
+    }
+
+    #[test]
+    fn self_signed_detection() {
+        assert!(Certificate::from(build("alice", None, None)).is_self_signed());
+
+        // same subject and issuer, but signed with another key
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let other = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        assert!(!Certificate::from(build("alice", None, Some(&other))).is_self_signed());
+    }
+
+    #[test]
+    fn generates_self_signed_certificates() {
+        for key_type in [KeyType::Ed25519, KeyType::Rsa2048] {
+            let bundle = Bundle::generate("bot", Some("bot@example.com"), key_type, 1_700_000_000).unwrap();
+            let certificate = &bundle.certificate;
+
+            assert!(certificate.is_self_signed(), "{key_type:?}");
+            assert_eq!(Some("bot".to_owned()), certificate.common_name());
+            assert_eq!(vec!["bot@example.com".to_owned()], certificate.emails());
+            assert_eq!(1_700_000_000, certificate.not_before().unwrap());
+            assert_eq!(1_700_000_000 + VALIDITY_SECS, certificate.not_after().unwrap());
+            assert!(certificate.x509().public_key().unwrap().public_eq(bundle.key()));
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_validity() {
+        assert!(matches!(
+            Bundle::generate("bot", None, KeyType::Ed25519, i64::MAX - 1),
+            Err(CertificateError::InvalidTime(_))
+        ));
+    }
+
+    #[test]
+    fn pkcs12_round_trip() {
+        let bundle = Bundle::generate("bot", None, KeyType::Ed25519, 1_700_000_000).unwrap();
+        let archive = bundle.to_pkcs12("secret").unwrap();
+
+        let imported = Bundle::from_pkcs12(&archive, "secret").unwrap();
+        assert_eq!(bundle.certificate.hash().unwrap(), imported.certificate.hash().unwrap());
+        assert_eq!(bundle.private_key_pem().unwrap(), imported.private_key_pem().unwrap());
+        assert!(imported.certificate.emails().is_empty());
+        assert!(matches!(Bundle::from_pkcs12(&archive, "wrong"), Err(CertificateError::Malformed(_))));
+    }
+}
@@ -0,0 +1,488 @@
+//! The channel hierarchy of a server, built from `ChannelState` and `ChannelRemove` messages
+//!
+//! Both the server and its clients keep the same tree: the server applies the changes it makes and
+//! broadcasts them, clients apply whatever they receive. Changes are validated before anything is
+//! modified, so a rejected message leaves the tree as it was.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::mumble_proto as msgs;
+
+/// Id of the root channel, the only channel without a parent.
+pub const ROOT_ID: u32 = 0;
+
+/// The reason a change could not be applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeError {
+    /// A `ChannelState` without `channel_id`.
+    MissingChannelId,
+    /// A new channel other than the root comes without a parent.
+    MissingParent(u32),
+    UnknownChannel(u32),
+    UnknownParent(u32),
+    /// The root channel can't be moved or removed.
+    Root,
+    /// Moving the channel below this parent would make it its own ancestor.
+    Cycle { channel_id: u32, parent: u32 },
+}
+
+impl std::fmt::Display for TreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreeError::MissingChannelId => write!(f, "channel state without channel_id"),
+            TreeError::MissingParent(id) => write!(f, "new channel {id} has no parent"),
+            TreeError::UnknownChannel(id) => write!(f, "unknown channel {id}"),
+            TreeError::UnknownParent(id) => write!(f, "unknown parent channel {id}"),
+            TreeError::Root => write!(f, "the root channel can't be moved or removed"),
+            TreeError::Cycle { channel_id, parent } => {
+                write!(f, "moving channel {channel_id} below channel {parent} creates a cycle")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+/// What applying a `ChannelState` did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Created,
+    Updated,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Channel {
+    pub id: u32,
+    /// `None` only for the root channel.
+    pub parent: Option<u32>,
+    pub name: String,
+    pub description: Option<String>,
+    /// SHA-1 of a description too large to be sent along, see `RequestBlob`.
+    pub description_hash: Option<Vec<u8>>,
+    pub position: i32,
+    /// 0 for no limit.
+    pub max_users: u32,
+    pub temporary: bool,
+    pub is_enter_restricted: bool,
+    pub can_enter: bool,
+    /// Directly linked channels. Links are symmetric.
+    pub links: BTreeSet<u32>,
+    children: BTreeSet<u32>,
+}
+
+impl Channel {
+    fn new(id: u32) -> Self {
+        Channel { id, can_enter: true, ..Default::default() }
+    }
+
+    pub fn children(&self) -> &BTreeSet<u32> {
+        &self.children
+    }
+
+    /// Describes the channel completely, links included or not.
+    pub fn to_state(&self, with_links: bool) -> msgs::ChannelState {
+        msgs::ChannelState {
+            channel_id: Some(self.id),
+            parent: self.parent,
+            name: Some(self.name.clone()),
+            links: if with_links { self.links.iter().copied().collect() } else { Vec::new() },
+            description: if self.description_hash.is_some() { None } else { self.description.clone() },
+            temporary: Some(self.temporary),
+            position: Some(self.position),
+            description_hash: self.description_hash.clone(),
+            max_users: Some(self.max_users),
+            is_enter_restricted: Some(self.is_enter_restricted),
+            can_enter: Some(self.can_enter),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChannelTree {
+    channels: BTreeMap<u32, Channel>,
+}
+
+impl ChannelTree {
+    pub fn new() -> Self {
+        ChannelTree::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Channel> {
+        self.channels.get(&id)
+    }
+
+    /// Iterates over the channels ordered by id.
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.values()
+    }
+
+    /// Applies a `ChannelState`, creating the channel if it doesn't exist yet.
+    ///
+    /// Fields which are not set are left unchanged. `links` replaces all links of the channel,
+    /// `links_add` and `links_remove` change them one by one.
+    pub fn apply_state(&mut self, state: &msgs::ChannelState) -> Result<Change, TreeError> {
+        let id = state.channel_id.ok_or(TreeError::MissingChannelId)?;
+        let exists = self.channels.contains_key(&id);
+
+        match state.parent {
+            Some(_) if id == ROOT_ID => return Err(TreeError::Root),
+            Some(parent) => {
+                if !self.channels.contains_key(&parent) {
+                    return Err(TreeError::UnknownParent(parent));
+                }
+                if exists && (parent == id || self.is_ancestor(id, parent)) {
+                    return Err(TreeError::Cycle { channel_id: id, parent });
+                }
+            }
+            None if !exists && id != ROOT_ID => return Err(TreeError::MissingParent(id)),
+            None => {}
+        }
+        let linked = state.links.iter().chain(&state.links_add).chain(&state.links_remove);
+        if let Some(&unknown) = linked.filter(|&&link| link != id).find(|link| !self.channels.contains_key(link)) {
+            return Err(TreeError::UnknownChannel(unknown));
+        }
+
+        let channel = self.channels.entry(id).or_insert_with(|| Channel::new(id));
+        let old_parent = channel.parent;
+        if let Some(parent) = state.parent {
+            channel.parent = Some(parent);
+        }
+        if let Some(name) = &state.name {
+            channel.name = name.clone();
+        }
+        if let Some(description) = &state.description {
+            channel.description = Some(description.clone());
+            channel.description_hash = None;
+        }
+        if let Some(hash) = &state.description_hash {
+            channel.description_hash = Some(hash.clone());
+        }
+        if let Some(temporary) = state.temporary {
+            channel.temporary = temporary;
+        }
+        if let Some(position) = state.position {
+            channel.position = position;
+        }
+        if let Some(max_users) = state.max_users {
+            channel.max_users = max_users;
+        }
+        if let Some(is_enter_restricted) = state.is_enter_restricted {
+            channel.is_enter_restricted = is_enter_restricted;
+        }
+        if let Some(can_enter) = state.can_enter {
+            channel.can_enter = can_enter;
+        }
+
+        if let (Some(parent), true) = (state.parent, old_parent != state.parent) {
+            if let Some(old_parent) = old_parent.and_then(|old| self.channels.get_mut(&old)) {
+                old_parent.children.remove(&id);
+            }
+            if let Some(parent) = self.channels.get_mut(&parent) {
+                parent.children.insert(id);
+            }
+        }
+
+        if !state.links.is_empty() {
+            let old_links = self.channels[&id].links.clone();
+            for link in old_links.difference(&state.links.iter().copied().collect()) {
+                self.unlink(id, *link);
+            }
+            for &link in &state.links {
+                self.link(id, link);
+            }
+        }
+        for &link in &state.links_add {
+            self.link(id, link);
+        }
+        for &link in &state.links_remove {
+            self.unlink(id, link);
+        }
+
+        Ok(if exists { Change::Updated } else { Change::Created })
+    }
+
+    /// Removes a channel together with all its descendants.
+    ///
+    /// Returns the ids of the removed channels, children before their parents.
+    pub fn apply_remove(&mut self, id: u32) -> Result<Vec<u32>, TreeError> {
+        if id == ROOT_ID {
+            return Err(TreeError::Root);
+        }
+        let channel = self.channels.get(&id).ok_or(TreeError::UnknownChannel(id))?;
+        if let Some(parent) = channel.parent.and_then(|parent| self.channels.get_mut(&parent)) {
+            parent.children.remove(&id);
+        }
+
+        let mut removed = self.descendants(id);
+        removed.reverse();
+        removed.push(id);
+
+        for removed_id in &removed {
+            if let Some(channel) = self.channels.remove(removed_id) {
+                for link in channel.links {
+                    if let Some(linked) = self.channels.get_mut(&link) {
+                        linked.links.remove(removed_id);
+                    }
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Applies a `ChannelRemove`.
+    pub fn apply_channel_remove(&mut self, remove: &msgs::ChannelRemove) -> Result<Vec<u32>, TreeError> {
+        self.apply_remove(remove.channel_id)
+    }
+
+    /// Returns the ids from the root down to the channel.
+    pub fn path(&self, id: u32) -> Option<Vec<u32>> {
+        let mut path = vec![id];
+        let mut channel = self.channels.get(&id)?;
+        while let Some(parent) = channel.parent {
+            path.push(parent);
+            channel = self.channels.get(&parent)?;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Returns the ids of all channels below the channel, parents before their children and
+    /// siblings in display order (by position, then name).
+    pub fn descendants(&self, id: u32) -> Vec<u32> {
+        let mut descendants = Vec::new();
+        let mut stack = self.sorted_children(id);
+        stack.reverse();
+        while let Some(child) = stack.pop() {
+            descendants.push(child);
+            stack.extend(self.sorted_children(child).into_iter().rev());
+        }
+        descendants
+    }
+
+    /// Returns the channel and every channel linked to it, directly or through other links.
+    ///
+    /// This is the set of channels which hear someone talking in the channel.
+    pub fn linked(&self, id: u32) -> BTreeSet<u32> {
+        let mut linked = BTreeSet::new();
+        if !self.channels.contains_key(&id) {
+            return linked;
+        }
+        let mut pending = vec![id];
+        while let Some(current) = pending.pop() {
+            if linked.insert(current) {
+                pending.extend(self.channels[&current].links.iter().copied());
+            }
+        }
+        linked
+    }
+
+    /// Returns the messages describing the whole tree to a new client.
+    ///
+    /// Like Murmur, all channels are sent first, parents before their children, followed by
+    /// the links once every linked channel is known.
+    pub fn full_sync(&self) -> Vec<msgs::ChannelState> {
+        let mut order = Vec::with_capacity(self.channels.len());
+        if self.channels.contains_key(&ROOT_ID) {
+            order.push(ROOT_ID);
+            order.extend(self.descendants(ROOT_ID));
+        }
+
+        let mut messages: Vec<_> = order.iter().map(|id| self.channels[id].to_state(false)).collect();
+        messages.extend(
+            order
+                .iter()
+                .map(|id| &self.channels[id])
+                .filter(|channel| !channel.links.is_empty())
+                .map(|channel| msgs::ChannelState {
+                    channel_id: Some(channel.id),
+                    links: channel.links.iter().copied().collect(),
+                    ..Default::default()
+                }),
+        );
+        messages
+    }
+
+    fn sorted_children(&self, id: u32) -> Vec<u32> {
+        let children = match self.channels.get(&id) {
+            Some(channel) => &channel.children,
+            None => return Vec::new(),
+        };
+        let mut sorted: Vec<&Channel> = children.iter().filter_map(|child| self.channels.get(child)).collect();
+        sorted.sort_by(|a, b| (a.position, &a.name, a.id).cmp(&(b.position, &b.name, b.id)));
+        sorted.into_iter().map(|channel| channel.id).collect()
+    }
+
+    /// Whether `ancestor` is above `id` in the tree.
+    fn is_ancestor(&self, ancestor: u32, id: u32) -> bool {
+        let mut current = self.channels.get(&id).and_then(|channel| channel.parent);
+        while let Some(parent) = current {
+            if parent == ancestor {
+                return true;
+            }
+            current = self.channels.get(&parent).and_then(|channel| channel.parent);
+        }
+        false
+    }
+
+    fn link(&mut self, a: u32, b: u32) {
+        if a == b || !self.channels.contains_key(&a) || !self.channels.contains_key(&b) {
+            return;
+        }
+        self.channels.get_mut(&a).unwrap().links.insert(b);
+        self.channels.get_mut(&b).unwrap().links.insert(a);
+    }
+
+    fn unlink(&mut self, a: u32, b: u32) {
+        if let Some(channel) = self.channels.get_mut(&a) {
+            channel.links.remove(&b);
+        }
+        if let Some(channel) = self.channels.get_mut(&b) {
+            channel.links.remove(&a);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(id: u32, parent: Option<u32>, name: &str) -> msgs::ChannelState {
+        msgs::ChannelState {
+            channel_id: Some(id),
+            parent,
+            name: Some(name.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// Root
+    /// ├── A (1)
+    /// │   └── C (3)
+    /// └── B (2)
+    fn tree() -> ChannelTree {
+        let mut tree = ChannelTree::new();
+        tree.apply_state(&state(0, None, "Root")).unwrap();
+        tree.apply_state(&state(1, Some(0), "A")).unwrap();
+        tree.apply_state(&state(2, Some(0), "B")).unwrap();
+        tree.apply_state(&state(3, Some(1), "C")).unwrap();
+        tree
+    }
+
+    #[test]
+    fn builds_hierarchy() {
+        let tree = tree();
+        assert_eq!(4, tree.len());
+        assert_eq!(Some(vec![0, 1, 3]), tree.path(3));
+        assert_eq!(vec![1, 3, 2], tree.descendants(0));
+        assert_eq!(&BTreeSet::from([3]), tree.get(1).unwrap().children());
+        assert_eq!(None, tree.path(9));
+    }
+
+    #[test]
+    fn partial_updates_keep_other_fields() {
+        let mut tree = tree();
+        let update = msgs::ChannelState { channel_id: Some(1), max_users: Some(5), ..Default::default() };
+
+        assert_eq!(Ok(Change::Updated), tree.apply_state(&update));
+        let channel = tree.get(1).unwrap();
+        assert_eq!(("A", Some(0), 5), (channel.name.as_str(), channel.parent, channel.max_users));
+    }
+
+    #[test]
+    fn siblings_are_ordered_by_position_and_name() {
+        let mut tree = tree();
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(1), position: Some(10), ..Default::default() }).unwrap();
+        tree.apply_state(&state(4, Some(0), "AA")).unwrap();
+
+        assert_eq!(vec![4, 2, 1, 3], tree.descendants(0));
+    }
+
+    #[test]
+    fn moves_channels() {
+        let mut tree = tree();
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(1), parent: Some(2), ..Default::default() }).unwrap();
+
+        assert_eq!(Some(vec![0, 2, 1, 3]), tree.path(3));
+        assert!(tree.get(0).unwrap().children().contains(&2));
+        assert!(!tree.get(0).unwrap().children().contains(&1));
+    }
+
+    #[test]
+    fn rejects_invalid_changes() {
+        let mut tree = tree();
+        let moving = |id, parent| msgs::ChannelState { channel_id: Some(id), parent: Some(parent), ..Default::default() };
+
+        assert_eq!(Err(TreeError::Cycle { channel_id: 1, parent: 3 }), tree.apply_state(&moving(1, 3)));
+        assert_eq!(Err(TreeError::Cycle { channel_id: 1, parent: 1 }), tree.apply_state(&moving(1, 1)));
+        assert_eq!(Err(TreeError::Root), tree.apply_state(&moving(0, 1)));
+        assert_eq!(Err(TreeError::UnknownParent(9)), tree.apply_state(&moving(1, 9)));
+        assert_eq!(Err(TreeError::MissingParent(5)), tree.apply_state(&state(5, None, "E")));
+        assert_eq!(Err(TreeError::MissingChannelId), tree.apply_state(&Default::default()));
+
+        let linking = msgs::ChannelState { channel_id: Some(1), name: Some("X".into()), links_add: vec![9], ..Default::default() };
+        assert_eq!(Err(TreeError::UnknownChannel(9)), tree.apply_state(&linking));
+        // nothing was applied
+        assert_eq!("A", tree.get(1).unwrap().name);
+        assert_eq!(Some(vec![0, 1, 3]), tree.path(3));
+    }
+
+    #[test]
+    fn maintains_symmetric_links() {
+        let mut tree = tree();
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(1), links_add: vec![2], ..Default::default() }).unwrap();
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(2), links_add: vec![3], ..Default::default() }).unwrap();
+
+        assert_eq!(BTreeSet::from([1, 3]), tree.get(2).unwrap().links);
+        assert_eq!(BTreeSet::from([1, 2, 3]), tree.linked(1));
+        assert_eq!(BTreeSet::from([0]), tree.linked(0));
+
+        // full replacement
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(2), links: vec![0], ..Default::default() }).unwrap();
+        assert_eq!(BTreeSet::from([0]), tree.get(2).unwrap().links);
+        assert!(tree.get(1).unwrap().links.is_empty());
+
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(0), links_remove: vec![2], ..Default::default() }).unwrap();
+        assert!(tree.get(2).unwrap().links.is_empty());
+    }
+
+    #[test]
+    fn removes_subtrees() {
+        let mut tree = tree();
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(2), links_add: vec![3], ..Default::default() }).unwrap();
+
+        assert_eq!(Ok(vec![3, 1]), tree.apply_channel_remove(&msgs::ChannelRemove { channel_id: 1 }));
+        assert_eq!(vec![2], tree.descendants(0));
+        assert!(tree.get(2).unwrap().links.is_empty());
+
+        assert_eq!(Err(TreeError::UnknownChannel(1)), tree.apply_remove(1));
+        assert_eq!(Err(TreeError::Root), tree.apply_remove(0));
+    }
+
+    #[test]
+    fn full_sync_sends_links_last() {
+        let mut tree = tree();
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(3), links_add: vec![2], ..Default::default() }).unwrap();
+
+        let messages = tree.full_sync();
+        let ids: Vec<_> = messages.iter().map(|state| state.channel_id.unwrap()).collect();
+        assert_eq!(vec![0, 1, 3, 2, 3, 2], ids);
+        assert!(messages[..4].iter().all(|state| state.links.is_empty()));
+        assert_eq!(vec![2], messages[4].links);
+
+        let mut copy = ChannelTree::new();
+        for state in &messages {
+            copy.apply_state(state).unwrap();
+        }
+        assert_eq!(tree.iter().collect::<Vec<_>>(), copy.iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,566 @@
+//! Client side of a Mumble connection, independent of the transport
+//!
+//! The session is driven by the caller: control messages read from the server are passed to
+//! `handle`, and `poll` is called regularly to send pings. Messages to write to the server are
+//! taken out with `drain_outgoing`, what happened on the connection with `drain_events`.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/mumble/ServerHandler.cpp
+
+use bytes::BytesMut;
+
+use crate::control::ControlMessage;
+use crate::crypt_state::{CryptState, DecryptError, BLOCK_SIZE, KEY_SIZE};
+use crate::mumble_proto as msgs;
+use crate::mumble_proto::reject::RejectType;
+use crate::ping_tracker::PingTracker;
+
+/// Version announced by default, 1.5.0 in the `version_v2` format.
+pub const DEFAULT_VERSION: u64 = 1 << 48 | 5 << 32;
+/// Interval between pings sent by the official client.
+pub const DEFAULT_PING_INTERVAL_MS: u64 = 5000;
+/// Time without any message from the server after which the connection is considered dead.
+pub const DEFAULT_PING_TIMEOUT_MS: u64 = 30000;
+
+/// What the client announces in `Version` and `Authenticate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub username: String,
+    pub password: Option<String>,
+    /// Access tokens, used by the server to evaluate `#token` groups.
+    pub tokens: Vec<String>,
+    /// Version in the `version_v2` format, `major << 48 | minor << 32 | patch << 16`.
+    pub version: u64,
+    pub release: String,
+    pub os: String,
+    pub os_version: String,
+    pub opus: bool,
+    /// Announces the client as a bot, which servers don't count as a user.
+    pub bot: bool,
+    pub ping_interval_ms: u64,
+    pub ping_timeout_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            username: String::new(),
+            password: None,
+            tokens: Vec::new(),
+            version: DEFAULT_VERSION,
+            release: concat!("rb_mumble_protocol ", env!("CARGO_PKG_VERSION")).to_string(),
+            os: std::env::consts::OS.to_string(),
+            os_version: String::new(),
+            opus: true,
+            bot: false,
+            ping_interval_ms: DEFAULT_PING_INTERVAL_MS,
+            ping_timeout_ms: DEFAULT_PING_TIMEOUT_MS,
+        }
+    }
+}
+
+impl Config {
+    /// The version in the legacy `version_v1` format, `major << 16 | minor << 8 | patch`.
+    pub fn version_v1(&self) -> u32 {
+        version_to_v1(self.version)
+    }
+}
+
+/// Converts a `version_v2` version to the legacy `major << 16 | minor << 8 | patch` format,
+/// parts over 255 saturate.
+pub fn version_to_v1(version: u64) -> u32 {
+    let part = |shift: u32| ((version >> shift) & 0xffff).min(0xff) as u32;
+    part(48) << 16 | part(32) << 8 | part(16)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// `start` hasn't been called yet.
+    Idle,
+    /// `Version` and `Authenticate` have been sent, waiting for `ServerSync`.
+    Authenticating,
+    /// The server state has been received, the client is connected.
+    Synced,
+    /// The server refused the connection.
+    Rejected,
+    /// The client was kicked or banned, or the server stopped answering.
+    Disconnected,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Idle => "idle",
+            State::Authenticating => "authenticating",
+            State::Synced => "synced",
+            State::Rejected => "rejected",
+            State::Disconnected => "disconnected",
+        }
+    }
+
+    /// Whether the connection is over and should be closed.
+    pub fn is_closed(self) -> bool {
+        matches!(self, State::Rejected | State::Disconnected)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The voice channel keys were received, see `ClientSession::crypt_state`.
+    CryptSetup,
+    /// The server finished sending its state.
+    Synced {
+        session: u32,
+        max_bandwidth: Option<u32>,
+        welcome_text: Option<String>,
+        permissions: Option<u64>,
+    },
+    Rejected {
+        reject_type: RejectType,
+        reason: Option<String>,
+    },
+    /// Our own user was removed by the server. `ban` is set if it was a ban rather than a kick.
+    Kicked {
+        actor: Option<u32>,
+        reason: Option<String>,
+        ban: bool,
+    },
+    /// Nothing was received from the server for `Config::ping_timeout_ms`.
+    TimedOut,
+    /// A message the session doesn't handle itself, e.g. `ChannelState` or `TextMessage`.
+    Message(Box<ControlMessage>),
+}
+
+pub struct ClientSession {
+    config: Config,
+    state: State,
+    session: Option<u32>,
+    server_version: Option<msgs::Version>,
+    crypt_state: Option<CryptState>,
+    ping_tracker: PingTracker,
+    next_ping_ms: u64,
+    last_received_ms: u64,
+    outgoing: Vec<ControlMessage>,
+    events: Vec<Event>,
+}
+
+impl ClientSession {
+    pub fn new(config: Config) -> Self {
+        ClientSession {
+            config,
+            state: State::Idle,
+            session: None,
+            server_version: None,
+            crypt_state: None,
+            ping_tracker: PingTracker::new(),
+            next_ping_ms: 0,
+            last_received_ms: 0,
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Our own session id, known once synced.
+    pub fn session(&self) -> Option<u32> {
+        self.session
+    }
+
+    /// The `Version` announced by the server.
+    pub fn server_version(&self) -> Option<&msgs::Version> {
+        self.server_version.as_ref()
+    }
+
+    /// The voice channel encryption, available once the server sent `CryptSetup`.
+    pub fn crypt_state(&self) -> Option<&CryptState> {
+        self.crypt_state.as_ref()
+    }
+
+    pub fn crypt_state_mut(&mut self) -> Option<&mut CryptState> {
+        self.crypt_state.as_mut()
+    }
+
+    /// Round trip times and the statistics reported by the server.
+    pub fn ping_tracker(&self) -> &PingTracker {
+        &self.ping_tracker
+    }
+
+    /// Mutable access, to track pings sent over the voice channel.
+    pub fn ping_tracker_mut(&mut self) -> &mut PingTracker {
+        &mut self.ping_tracker
+    }
+
+    /// Queues `Version` and `Authenticate`, to be called once the transport is connected.
+    pub fn start(&mut self, now_ms: u64) {
+        if self.state != State::Idle {
+            return;
+        }
+
+        let version = msgs::Version {
+            version_v1: Some(self.config.version_v1()),
+            version_v2: Some(self.config.version),
+            release: Some(self.config.release.clone()),
+            os: Some(self.config.os.clone()),
+            os_version: Some(self.config.os_version.clone()),
+        };
+        let authenticate = msgs::Authenticate {
+            username: Some(self.config.username.clone()),
+            password: self.config.password.clone(),
+            tokens: self.config.tokens.clone(),
+            celt_versions: Vec::new(),
+            opus: Some(self.config.opus),
+            client_type: Some(self.config.bot as i32),
+        };
+        self.outgoing.push(version.into());
+        self.outgoing.push(authenticate.into());
+
+        self.state = State::Authenticating;
+        self.last_received_ms = now_ms;
+        self.next_ping_ms = now_ms + self.config.ping_interval_ms;
+    }
+
+    /// Updates the access tokens, also sending them to the server once started.
+    pub fn set_tokens(&mut self, tokens: Vec<String>) {
+        self.config.tokens = tokens;
+        if matches!(self.state, State::Authenticating | State::Synced) {
+            let authenticate = msgs::Authenticate { tokens: self.config.tokens.clone(), ..Default::default() };
+            self.outgoing.push(authenticate.into());
+        }
+    }
+
+    /// Handles a message received from the server.
+    ///
+    /// Malformed `CryptSetup` messages are ignored, like the official client does. Messages
+    /// received once the connection is closed are dropped.
+    pub fn handle(&mut self, message: ControlMessage, now_ms: u64) {
+        if self.state.is_closed() {
+            return;
+        }
+        self.last_received_ms = now_ms;
+
+        match message {
+            ControlMessage::Version(version) => self.server_version = Some(version),
+            ControlMessage::Ping(ping) => {
+                self.ping_tracker.receive(&ping, now_ms);
+            }
+            ControlMessage::CryptSetup(setup) => self.handle_crypt_setup(setup),
+            ControlMessage::Reject(reject) => {
+                let reject_type = reject.r#type.and_then(|ty| RejectType::try_from(ty).ok());
+                self.state = State::Rejected;
+                self.events.push(Event::Rejected {
+                    reject_type: reject_type.unwrap_or(RejectType::None),
+                    reason: reject.reason,
+                });
+            }
+            ControlMessage::ServerSync(sync) if self.state == State::Authenticating => {
+                self.state = State::Synced;
+                self.session = sync.session;
+                self.events.push(Event::Synced {
+                    session: sync.session.unwrap_or_default(),
+                    max_bandwidth: sync.max_bandwidth,
+                    welcome_text: sync.welcome_text,
+                    permissions: sync.permissions,
+                });
+            }
+            ControlMessage::UserRemove(remove) if self.session.is_some_and(|session| session == remove.session) => {
+                self.state = State::Disconnected;
+                self.events.push(Event::Kicked {
+                    actor: remove.actor,
+                    reason: remove.reason,
+                    ban: remove.ban.unwrap_or(false),
+                });
+            }
+            message => self.events.push(Event::Message(Box::new(message))),
+        }
+    }
+
+    fn handle_crypt_setup(&mut self, setup: msgs::CryptSetup) {
+        let key = setup.key.as_deref().and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok());
+        let client_nonce = setup.client_nonce.as_deref().and_then(|nonce| <[u8; BLOCK_SIZE]>::try_from(nonce).ok());
+        let server_nonce = setup.server_nonce.as_deref().and_then(|nonce| <[u8; BLOCK_SIZE]>::try_from(nonce).ok());
+
+        match (key, client_nonce, server_nonce) {
+            (Some(key), Some(client_nonce), Some(server_nonce)) => {
+                self.crypt_state = Some(CryptState::new_from(key, client_nonce, server_nonce));
+                self.events.push(Event::CryptSetup);
+            }
+            // The server resynchronises our decrypt nonce
+            (None, None, Some(server_nonce)) => {
+                if let Some(crypt_state) = self.crypt_state.as_mut() {
+                    crypt_state.set_decrypt_nonce(&server_nonce);
+                    self.ping_tracker.record_resync();
+                }
+            }
+            // The server asks for our encrypt nonce
+            _ if setup == msgs::CryptSetup::default() => {
+                if let Some(crypt_state) = self.crypt_state.as_ref() {
+                    let reply = msgs::CryptSetup {
+                        client_nonce: Some(crypt_state.get_encrypt_nonce().to_vec()),
+                        ..Default::default()
+                    };
+                    self.outgoing.push(reply.into());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Asks the server for its encrypt nonce, e.g. after many packets failed to decrypt.
+    pub fn request_crypt_resync(&mut self) {
+        if self.crypt_state.is_some() && !self.state.is_closed() {
+            self.outgoing.push(msgs::CryptSetup::default().into());
+        }
+    }
+
+    /// Encrypts a voice packet, returns `None` until the server sent `CryptSetup`.
+    pub fn encrypt(&mut self, packet: &[u8]) -> Option<BytesMut> {
+        let crypt_state = self.crypt_state.as_mut()?;
+        let mut buffer = BytesMut::new();
+        crypt_state.encrypt(packet, &mut buffer);
+
+        Some(buffer)
+    }
+
+    /// Decrypts a voice datagram in place, failing with `Eof` until the server sent `CryptSetup`.
+    pub fn decrypt(&mut self, buffer: &mut BytesMut) -> Result<(), DecryptError> {
+        match self.crypt_state.as_mut() {
+            Some(crypt_state) => crypt_state.decrypt(buffer),
+            None => Err(DecryptError::Eof),
+        }
+    }
+
+    /// Sends a ping when due and detects timeouts.
+    pub fn poll(&mut self, now_ms: u64) {
+        if !matches!(self.state, State::Authenticating | State::Synced) {
+            return;
+        }
+
+        if now_ms.saturating_sub(self.last_received_ms) >= self.config.ping_timeout_ms {
+            self.state = State::Disconnected;
+            self.events.push(Event::TimedOut);
+            return;
+        }
+
+        if now_ms >= self.next_ping_ms {
+            let ping = self.ping_tracker.ping(now_ms, self.crypt_state.as_ref());
+            self.outgoing.push(ping.into());
+            self.next_ping_ms = now_ms + self.config.ping_interval_ms;
+        }
+    }
+
+    /// When `poll` next needs to be called, `None` while the connection isn't active.
+    pub fn next_poll_ms(&self) -> Option<u64> {
+        match self.state {
+            State::Authenticating | State::Synced => {
+                Some(self.next_ping_ms.min(self.last_received_ms + self.config.ping_timeout_ms))
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes the messages to send to the server, in order.
+    pub fn drain_outgoing(&mut self) -> Vec<ControlMessage> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Takes the events which happened since the last call, in order.
+    pub fn drain_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn started() -> ClientSession {
+        let config = Config { username: "bot".into(), tokens: vec!["secret".into()], bot: true, ..Default::default() };
+        let mut session = ClientSession::new(config);
+        session.start(1000);
+        session
+    }
+
+    fn crypt_setup(key: u8, client_nonce: u8, server_nonce: u8) -> ControlMessage {
+        msgs::CryptSetup {
+            key: Some(vec![key; KEY_SIZE]),
+            client_nonce: Some(vec![client_nonce; BLOCK_SIZE]),
+            server_nonce: Some(vec![server_nonce; BLOCK_SIZE]),
+        }
+        .into()
+    }
+
+    fn server_sync(session: u32) -> ControlMessage {
+        msgs::ServerSync {
+            session: Some(session),
+            max_bandwidth: Some(72000),
+            welcome_text: Some("hi".into()),
+            permissions: Some(0xf07),
+        }
+        .into()
+    }
+
+    #[test]
+    fn handshake() {
+        let mut session = started();
+        assert_eq!(State::Authenticating, session.state());
+
+        let outgoing = session.drain_outgoing();
+        let ControlMessage::Version(version) = &outgoing[0] else { panic!("{outgoing:?}") };
+        assert_eq!(Some(0x10500), version.version_v1);
+        assert_eq!(Some(DEFAULT_VERSION), version.version_v2);
+        let ControlMessage::Authenticate(authenticate) = &outgoing[1] else { panic!("{outgoing:?}") };
+        assert_eq!(Some("bot".into()), authenticate.username);
+        assert_eq!(vec!["secret".to_string()], authenticate.tokens);
+        assert_eq!(Some(1), authenticate.client_type);
+        assert_eq!(2, outgoing.len());
+
+        session.handle(msgs::Version { version_v2: Some(DEFAULT_VERSION), ..Default::default() }.into(), 1010);
+        session.handle(crypt_setup(1, 2, 3), 1020);
+        session.handle(msgs::ChannelState { channel_id: Some(0), ..Default::default() }.into(), 1030);
+        session.handle(server_sync(7), 1040);
+
+        assert_eq!(State::Synced, session.state());
+        assert_eq!(Some(7), session.session());
+        assert_eq!(Some(DEFAULT_VERSION), session.server_version().and_then(|version| version.version_v2));
+        assert_eq!([3; BLOCK_SIZE], session.crypt_state().unwrap().get_decrypt_nonce());
+
+        let events = session.drain_events();
+        assert_eq!(Event::CryptSetup, events[0]);
+        assert!(matches!(&events[1], Event::Message(message) if matches!(**message, ControlMessage::ChannelState(_))));
+        assert_eq!(
+            Event::Synced { session: 7, max_bandwidth: Some(72000), welcome_text: Some("hi".into()), permissions: Some(0xf07) },
+            events[2]
+        );
+        assert_eq!(3, events.len());
+        assert!(session.drain_outgoing().is_empty());
+    }
+
+    #[test]
+    fn version_v1_saturates() {
+        let config = Config { version: 1 << 48 | 300 << 32 | 2 << 16, ..Default::default() };
+        assert_eq!(0x1ff02, config.version_v1());
+    }
+
+    #[test]
+    fn rejected() {
+        let mut session = started();
+        let reject = msgs::Reject { r#type: Some(RejectType::WrongUserPw as i32), reason: Some("nope".into()) };
+        session.handle(reject.into(), 1100);
+
+        assert_eq!(State::Rejected, session.state());
+        assert_eq!(
+            vec![Event::Rejected { reject_type: RejectType::WrongUserPw, reason: Some("nope".into()) }],
+            session.drain_events()
+        );
+
+        session.handle(server_sync(1), 1200);
+        session.poll(100_000);
+        assert!(session.drain_events().is_empty());
+        assert_eq!(None, session.next_poll_ms());
+    }
+
+    #[test]
+    fn kicked() {
+        let mut session = started();
+        session.handle(server_sync(7), 1100);
+        session.drain_events();
+
+        let other = msgs::UserRemove { session: 8, actor: Some(7), reason: None, ban: None };
+        session.handle(other.into(), 1200);
+        assert_eq!(State::Synced, session.state());
+        assert!(matches!(&session.drain_events()[..], [Event::Message(message)] if matches!(**message, ControlMessage::UserRemove(_))));
+
+        let own = msgs::UserRemove { session: 7, actor: Some(1), reason: Some("bye".into()), ban: Some(true) };
+        session.handle(own.into(), 1300);
+        assert_eq!(State::Disconnected, session.state());
+        assert_eq!(
+            vec![Event::Kicked { actor: Some(1), reason: Some("bye".into()), ban: true }],
+            session.drain_events()
+        );
+    }
+
+    #[test]
+    fn crypt_resync() {
+        let mut session = started();
+        session.handle(crypt_setup(1, 2, 3), 1100);
+        session.drain_outgoing();
+
+        session.handle(msgs::CryptSetup { server_nonce: Some(vec![9; BLOCK_SIZE]), ..Default::default() }.into(), 1200);
+        assert_eq!([9; BLOCK_SIZE], session.crypt_state().unwrap().get_decrypt_nonce());
+        assert_eq!(1, session.ping_tracker().resync());
+
+        session.handle(msgs::CryptSetup::default().into(), 1300);
+        let encrypt_nonce = session.crypt_state().unwrap().get_encrypt_nonce().to_vec();
+        assert_eq!(
+            vec![ControlMessage::from(msgs::CryptSetup { client_nonce: Some(encrypt_nonce), ..Default::default() })],
+            session.drain_outgoing()
+        );
+
+        // Wrong sizes are ignored
+        session.handle(msgs::CryptSetup { server_nonce: Some(vec![1; 4]), ..Default::default() }.into(), 1400);
+        assert_eq!([9; BLOCK_SIZE], session.crypt_state().unwrap().get_decrypt_nonce());
+
+        session.request_crypt_resync();
+        assert_eq!(vec![ControlMessage::from(msgs::CryptSetup::default())], session.drain_outgoing());
+        assert_eq!(vec![Event::CryptSetup], session.drain_events());
+    }
+
+    #[test]
+    fn voice_round_trip() {
+        let mut session = started();
+        assert_eq!(None, session.encrypt(b"voice"));
+        assert_eq!(Err(DecryptError::Eof), session.decrypt(&mut BytesMut::from(&b"voice"[..])));
+
+        session.handle(crypt_setup(1, 2, 2), 1100);
+        let mut encrypted = session.encrypt(b"voice").unwrap();
+        assert_eq!(Ok(()), session.decrypt(&mut encrypted));
+        assert_eq!(&b"voice"[..], &encrypted[..]);
+    }
+
+    #[test]
+    fn pings_and_timeout() {
+        let mut session = started();
+        session.drain_outgoing();
+        assert_eq!(Some(1000 + DEFAULT_PING_INTERVAL_MS), session.next_poll_ms());
+
+        session.poll(2000);
+        assert!(session.drain_outgoing().is_empty());
+
+        session.handle(crypt_setup(1, 2, 3), 5000);
+        session.poll(6000);
+        let outgoing = session.drain_outgoing();
+        let [ControlMessage::Ping(ping)] = &outgoing[..] else { panic!("{outgoing:?}") };
+        assert_eq!(Some(6000), ping.timestamp);
+        assert_eq!(Some(0), ping.good);
+        assert_eq!(Some(11000), session.next_poll_ms());
+
+        session.handle(msgs::Ping { timestamp: Some(6000), good: Some(4), ..Default::default() }.into(), 6030);
+        assert_eq!(30.0, session.ping_tracker().tcp().mean());
+        assert_eq!(4, session.ping_tracker().peer().good);
+
+        session.poll(6030 + DEFAULT_PING_TIMEOUT_MS);
+        assert_eq!(State::Disconnected, session.state());
+        assert_eq!(vec![Event::CryptSetup, Event::TimedOut], session.drain_events());
+    }
+
+    #[test]
+    fn tokens_update() {
+        let mut session = ClientSession::new(Config::default());
+        session.set_tokens(vec!["a".into()]);
+        assert!(session.drain_outgoing().is_empty());
+
+        session.start(0);
+        session.drain_outgoing();
+        session.set_tokens(vec!["b".into()]);
+        let outgoing = session.drain_outgoing();
+        let [ControlMessage::Authenticate(authenticate)] = &outgoing[..] else { panic!("{outgoing:?}") };
+        assert_eq!(vec!["b".to_string()], authenticate.tokens);
+        assert_eq!(None, authenticate.username);
+    }
+}
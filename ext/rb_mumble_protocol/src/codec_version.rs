@@ -0,0 +1,221 @@
+//! Selection of the voice codecs announced in `CodecVersion`
+//!
+//! Clients list the CELT bitstream versions they support and whether they support Opus in
+//! `Authenticate`. The server picks the CELT version most of its users support and announces it
+//! as either the alpha or the beta codec, so that clients switching over can still decode the
+//! other one, and enables Opus once enough users support it.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Server.cpp
+//! (`Server::recheckCodecVersions`)
+
+use std::collections::BTreeMap;
+
+use crate::mumble_proto as msgs;
+use crate::voice::VoicePayload;
+
+/// Bitstream version of CELT 0.7.0, assumed for clients which don't list any.
+pub const CELT_0_7_0: i32 = 0x8000000bu32 as i32;
+/// Percentage of users which must support Opus for it to be enabled, as Murmur's `opusthreshold`.
+pub const DEFAULT_OPUS_THRESHOLD: u32 = 100;
+
+/// The codecs a client advertised in `Authenticate`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Codecs {
+    pub celt_versions: Vec<i32>,
+    pub opus: bool,
+}
+
+impl Codecs {
+    /// The CELT versions the client counts for when selecting one, CELT 0.7.0 if it listed none.
+    fn voted_celt_versions(&self) -> &[i32] {
+        if self.celt_versions.is_empty() {
+            &[CELT_0_7_0]
+        } else {
+            &self.celt_versions
+        }
+    }
+}
+
+impl From<&msgs::Authenticate> for Codecs {
+    fn from(authenticate: &msgs::Authenticate) -> Self {
+        Codecs {
+            celt_versions: authenticate.celt_versions.clone(),
+            opus: authenticate.opus.unwrap_or(false),
+        }
+    }
+}
+
+/// The codecs announced by a server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CodecSelection {
+    /// CELT bitstream version sent as `CeltAlpha`, 0 if none.
+    pub alpha: i32,
+    /// CELT bitstream version sent as `CeltBeta`, 0 if none.
+    pub beta: i32,
+    /// Whether clients should encode with the alpha rather than the beta version.
+    pub prefer_alpha: bool,
+    pub opus: bool,
+}
+
+impl Default for CodecSelection {
+    /// What Murmur announces before anyone connected.
+    fn default() -> Self {
+        CodecSelection { alpha: 0, beta: 0, prefer_alpha: false, opus: true }
+    }
+}
+
+impl From<&msgs::CodecVersion> for CodecSelection {
+    fn from(version: &msgs::CodecVersion) -> Self {
+        CodecSelection {
+            alpha: version.alpha,
+            beta: version.beta,
+            prefer_alpha: version.prefer_alpha,
+            opus: version.opus.unwrap_or(false),
+        }
+    }
+}
+
+impl CodecSelection {
+    pub fn to_message(&self) -> msgs::CodecVersion {
+        msgs::CodecVersion {
+            alpha: self.alpha,
+            beta: self.beta,
+            prefer_alpha: self.prefer_alpha,
+            opus: Some(self.opus),
+        }
+    }
+
+    /// The CELT version clients should encode with.
+    pub fn preferred_celt(&self) -> i32 {
+        if self.prefer_alpha { self.alpha } else { self.beta }
+    }
+
+    /// Selects the codecs for the connected clients, to be called whenever one connects or
+    /// leaves. Returns whether the selection changed and has to be announced to everyone.
+    ///
+    /// Opus is enabled if at least `opus_threshold` percent of the clients support it. A newly
+    /// preferred CELT version replaces the one that isn't preferred, CELT 0.7.0 always being
+    /// the alpha codec.
+    pub fn recheck<'a, I>(&mut self, clients: I, opus_threshold: u32) -> bool
+    where
+        I: IntoIterator<Item = &'a Codecs>,
+    {
+        let mut users = 0;
+        let mut opus = 0;
+        let mut celt_users: BTreeMap<i32, u32> = BTreeMap::new();
+
+        for codecs in clients {
+            users += 1;
+            if codecs.opus {
+                opus += 1;
+            }
+            for &version in codecs.voted_celt_versions() {
+                *celt_users.entry(version).or_default() += 1;
+            }
+        }
+        if users == 0 {
+            return false;
+        }
+
+        let enable_opus = opus * 100 / users >= opus_threshold;
+
+        // The version most users support, the highest one on ties
+        let mut version = 0;
+        let mut maximum_users = 0;
+        for (&candidate, &count) in celt_users.iter().rev() {
+            if count > maximum_users {
+                version = candidate;
+                maximum_users = count;
+            }
+        }
+
+        if self.preferred_celt() != version {
+            self.prefer_alpha = version == CELT_0_7_0 || !self.prefer_alpha;
+            if self.prefer_alpha {
+                self.alpha = version;
+            } else {
+                self.beta = version;
+            }
+        } else if self.opus == enable_opus {
+            return false;
+        }
+
+        self.opus = enable_opus;
+        true
+    }
+
+    /// Whether a client may be sent voice in this codec: CELT only in a version it advertised
+    /// (0.7.0 if it listed none), Opus only if it supports it. Speex isn't negotiated and always passes.
+    pub fn accepts(&self, codecs: &Codecs, payload: &VoicePayload) -> bool {
+        match payload {
+            VoicePayload::CeltAlpha(_) => self.alpha != 0 && codecs.voted_celt_versions().contains(&self.alpha),
+            VoicePayload::CeltBeta(_) => self.beta != 0 && codecs.voted_celt_versions().contains(&self.beta),
+            VoicePayload::Opus(..) => codecs.opus,
+            VoicePayload::Speex(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+
+    const CELT_0_11_0: i32 = 0x80000010u32 as i32;
+
+    fn client(celt_versions: &[i32], opus: bool) -> Codecs {
+        Codecs { celt_versions: celt_versions.to_vec(), opus }
+    }
+
+    #[test]
+    fn selects_the_most_supported_celt_version() {
+        let mut selection = CodecSelection::default();
+        assert!(!selection.recheck(&[], DEFAULT_OPUS_THRESHOLD));
+
+        let mut clients = vec![client(&[], false)];
+        assert!(selection.recheck(&clients, DEFAULT_OPUS_THRESHOLD));
+        assert_eq!(CodecSelection { alpha: CELT_0_7_0, beta: 0, prefer_alpha: true, opus: false }, selection);
+        assert!(!selection.recheck(&clients, DEFAULT_OPUS_THRESHOLD));
+
+        // Ties go to the newer version, which becomes the beta codec
+        clients.push(client(&[CELT_0_11_0], true));
+        assert!(selection.recheck(&clients, DEFAULT_OPUS_THRESHOLD));
+        assert_eq!(CodecSelection { alpha: CELT_0_7_0, beta: CELT_0_11_0, prefer_alpha: false, opus: false }, selection);
+
+        clients.push(client(&[CELT_0_7_0], true));
+        assert!(selection.recheck(&clients, DEFAULT_OPUS_THRESHOLD));
+        assert_eq!(CELT_0_7_0, selection.preferred_celt());
+        assert!(selection.recheck(&clients, 60));
+        assert!(selection.to_message().opus.unwrap());
+    }
+
+    #[test]
+    fn enables_opus_over_the_threshold() {
+        let mut selection = CodecSelection::default();
+        let clients = [client(&[CELT_0_7_0], true), client(&[CELT_0_7_0], true), client(&[CELT_0_7_0], false)];
+
+        assert!(selection.recheck(&clients, 67));
+        assert!(!selection.opus);
+        assert!(selection.recheck(&clients, 66));
+        assert!(selection.opus);
+        assert_eq!(selection, CodecSelection::from(&selection.to_message()));
+    }
+
+    #[test]
+    fn only_sends_advertised_codecs() {
+        let selection = CodecSelection { alpha: CELT_0_7_0, beta: CELT_0_11_0, prefer_alpha: true, opus: true };
+        let legacy = client(&[CELT_0_7_0], false);
+        let modern = client(&[], true);
+        let celt = VoicePayload::CeltAlpha(vec![Bytes::from_static(b"frame")]);
+        let opus = VoicePayload::Opus(Bytes::from_static(b"frame"), false);
+
+        assert!(selection.accepts(&legacy, &celt));
+        assert!(!selection.accepts(&legacy, &opus));
+        assert!(!selection.accepts(&legacy, &VoicePayload::CeltBeta(Vec::new())));
+        // Clients listing no CELT version are assumed to support 0.7.0
+        assert!(selection.accepts(&modern, &celt));
+        assert!(!selection.accepts(&modern, &VoicePayload::CeltBeta(Vec::new())));
+        assert!(selection.accepts(&modern, &opus));
+    }
+}
@@ -0,0 +1,430 @@
+//! Framing and decoding of messages sent over Mumble's TCP control channel
+//!
+//! Every message is prefixed by a 6 byte header: the message type as a big endian `u16`,
+//! followed by the payload length as a big endian `u32`. Payloads are protobuf encoded, except
+//! for `UDPTunnel` which carries a raw (unencrypted) voice packet.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Serializer};
+
+use crate::mumble_proto as msgs;
+
+/// Size in bytes of the header preceding every control message.
+pub const HEADER_SIZE: usize = 6;
+/// Largest payload accepted by default, matching the limit enforced by Murmur.
+pub const MAX_MESSAGE_SIZE: usize = 0x7f_ffff;
+
+/// The reason a control message could not be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlError {
+    /// The header announces a payload larger than the configured maximum.
+    TooLarge(usize),
+    /// The header carries a message type this implementation doesn't know.
+    /// The offending frame has been skipped.
+    UnknownType(u16),
+    /// The payload isn't a valid protobuf message of the announced type.
+    /// The offending frame has been skipped.
+    Protobuf(MessageType, prost::DecodeError),
+    /// The frame wasn't admitted by the filter given to `decode_next_filtered`.
+    /// It has been skipped without decoding its payload.
+    RateLimited(MessageType),
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::TooLarge(len) => write!(f, "control message of {len} bytes is too large"),
+            ControlError::UnknownType(id) => write!(f, "unknown control message type {id}"),
+            ControlError::Protobuf(ty, e) => write!(f, "invalid {} message: {e}", ty.name()),
+            ControlError::RateLimited(ty) => write!(f, "{} message dropped by rate limiting", ty.name()),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// Payload of a control message.
+trait Payload: Sized {
+    fn decode_payload(buf: Bytes) -> Result<Self, prost::DecodeError>;
+    fn encoded_len(&self) -> usize;
+    fn encode_payload(&self, dst: &mut BytesMut);
+}
+
+/// `UDPTunnel` payloads are sent as-is, without protobuf wrapping.
+impl Payload for Bytes {
+    fn decode_payload(buf: Bytes) -> Result<Self, prost::DecodeError> {
+        Ok(buf)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.len()
+    }
+
+    fn encode_payload(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(self);
+    }
+}
+
+macro_rules! protobuf_payloads {
+    ($($ty:ty),*) => {$(
+        impl Payload for $ty {
+            fn decode_payload(buf: Bytes) -> Result<Self, prost::DecodeError> {
+                <$ty as Message>::decode(buf)
+            }
+
+            fn encoded_len(&self) -> usize {
+                Message::encoded_len(self)
+            }
+
+            fn encode_payload(&self, dst: &mut BytesMut) {
+                Message::encode(self, dst).expect("BytesMut grows as needed");
+            }
+        }
+    )*};
+}
+
+/// Builds a message out of some external representation (e.g. a Ruby `Hash`).
+pub trait PayloadSource {
+    type Error;
+
+    fn build<T: DeserializeOwned>(self) -> Result<T, Self::Error>;
+}
+
+macro_rules! control_messages {
+    ($($id:literal => $variant:ident($ty:ty), $name:literal;)*) => {
+        /// Type of a control message, as found in the message header.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        #[repr(u16)]
+        pub enum MessageType {
+            $($variant = $id,)*
+        }
+
+        impl MessageType {
+            /// All known message types, ordered by id.
+            pub const ALL: &'static [MessageType] = &[$(MessageType::$variant,)*];
+
+            pub fn from_id(id: u16) -> Option<Self> {
+                match id {
+                    $($id => Some(MessageType::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// Looks a message type up by its snake_case name.
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $($name => Some(MessageType::$variant),)*
+                    _ => None,
+                }
+            }
+
+            pub fn id(self) -> u16 {
+                self as u16
+            }
+
+            /// Returns the snake_case name of the message type, e.g. `channel_state`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(MessageType::$variant => $name,)*
+                }
+            }
+        }
+
+        /// A decoded control message.
+        #[derive(Clone, Debug, PartialEq)]
+        pub enum ControlMessage {
+            $($variant($ty),)*
+        }
+
+        impl ControlMessage {
+            pub fn message_type(&self) -> MessageType {
+                match self {
+                    $(ControlMessage::$variant(_) => MessageType::$variant,)*
+                }
+            }
+
+            /// Decodes the payload of a message of the given type.
+            pub fn decode(ty: MessageType, payload: Bytes) -> Result<Self, ControlError> {
+                match ty {
+                    $(MessageType::$variant => <$ty as Payload>::decode_payload(payload)
+                        .map(ControlMessage::$variant)
+                        .map_err(|e| ControlError::Protobuf(ty, e)),)*
+                }
+            }
+
+            /// Builds a message of the given type out of an external representation.
+            pub fn build<S: PayloadSource>(ty: MessageType, source: S) -> Result<Self, S::Error> {
+                match ty {
+                    $(MessageType::$variant => source.build::<$ty>().map(ControlMessage::$variant),)*
+                }
+            }
+
+            /// Returns the length of the payload once encoded, excluding the header.
+            pub fn encoded_len(&self) -> usize {
+                match self {
+                    $(ControlMessage::$variant(msg) => Payload::encoded_len(msg),)*
+                }
+            }
+
+            /// Appends the framed message (header and payload) to `dst`.
+            pub fn encode(&self, dst: &mut BytesMut) {
+                let len = self.encoded_len();
+                dst.reserve(HEADER_SIZE + len);
+                dst.put_u16(self.message_type().id());
+                dst.put_u32(len as u32);
+
+                match self {
+                    $(ControlMessage::$variant(msg) => msg.encode_payload(dst),)*
+                }
+            }
+        }
+
+        /// Serializes only the payload, the type is available from `message_type`.
+        impl Serialize for ControlMessage {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match self {
+                    $(ControlMessage::$variant(msg) => msg.serialize(serializer),)*
+                }
+            }
+        }
+
+        $(impl From<$ty> for ControlMessage {
+            fn from(msg: $ty) -> Self {
+                ControlMessage::$variant(msg)
+            }
+        })*
+    };
+}
+
+protobuf_payloads!(
+    msgs::Version, msgs::Authenticate, msgs::Ping, msgs::Reject, msgs::ServerSync,
+    msgs::ChannelRemove, msgs::ChannelState, msgs::UserRemove, msgs::UserState, msgs::BanList,
+    msgs::TextMessage, msgs::PermissionDenied, msgs::Acl, msgs::QueryUsers, msgs::CryptSetup,
+    msgs::ContextActionModify, msgs::ContextAction, msgs::UserList, msgs::VoiceTarget,
+    msgs::PermissionQuery, msgs::CodecVersion, msgs::UserStats, msgs::RequestBlob,
+    msgs::ServerConfig, msgs::SuggestConfig, msgs::PluginDataTransmission
+);
+
+control_messages! {
+    0 => Version(msgs::Version), "version";
+    1 => UdpTunnel(Bytes), "udp_tunnel";
+    2 => Authenticate(msgs::Authenticate), "authenticate";
+    3 => Ping(msgs::Ping), "ping";
+    4 => Reject(msgs::Reject), "reject";
+    5 => ServerSync(msgs::ServerSync), "server_sync";
+    6 => ChannelRemove(msgs::ChannelRemove), "channel_remove";
+    7 => ChannelState(msgs::ChannelState), "channel_state";
+    8 => UserRemove(msgs::UserRemove), "user_remove";
+    9 => UserState(msgs::UserState), "user_state";
+    10 => BanList(msgs::BanList), "ban_list";
+    11 => TextMessage(msgs::TextMessage), "text_message";
+    12 => PermissionDenied(msgs::PermissionDenied), "permission_denied";
+    13 => Acl(msgs::Acl), "acl";
+    14 => QueryUsers(msgs::QueryUsers), "query_users";
+    15 => CryptSetup(msgs::CryptSetup), "crypt_setup";
+    16 => ContextActionModify(msgs::ContextActionModify), "context_action_modify";
+    17 => ContextAction(msgs::ContextAction), "context_action";
+    18 => UserList(msgs::UserList), "user_list";
+    19 => VoiceTarget(msgs::VoiceTarget), "voice_target";
+    20 => PermissionQuery(msgs::PermissionQuery), "permission_query";
+    21 => CodecVersion(msgs::CodecVersion), "codec_version";
+    22 => UserStats(msgs::UserStats), "user_stats";
+    23 => RequestBlob(msgs::RequestBlob), "request_blob";
+    24 => ServerConfig(msgs::ServerConfig), "server_config";
+    25 => SuggestConfig(msgs::SuggestConfig), "suggest_config";
+    26 => PluginDataTransmission(msgs::PluginDataTransmission), "plugin_data_transmission";
+}
+
+/// Splits a byte stream into control messages.
+///
+/// Bytes are appended with `extend` as they arrive from the transport, complete messages are
+/// taken out with `decode_next`. Partial messages stay buffered until the rest arrives.
+pub struct ControlCodec {
+    buffer: BytesMut,
+    max_message_size: usize,
+}
+
+impl Default for ControlCodec {
+    fn default() -> Self {
+        ControlCodec::new(MAX_MESSAGE_SIZE)
+    }
+}
+
+impl ControlCodec {
+    pub fn new(max_message_size: usize) -> Self {
+        ControlCodec {
+            buffer: BytesMut::new(),
+            max_message_size,
+        }
+    }
+
+    /// Appends bytes received from the transport.
+    pub fn extend(&mut self, src: &[u8]) {
+        self.buffer.extend_from_slice(src);
+    }
+
+    /// Returns the amount of bytes buffered but not decoded yet.
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns the header of the next message, if it has been fully received.
+    pub fn peek_header(&self) -> Option<(u16, usize)> {
+        if self.buffer.len() < HEADER_SIZE {
+            return None;
+        }
+        let mut header = &self.buffer[..HEADER_SIZE];
+        Some((header.get_u16(), header.get_u32() as usize))
+    }
+
+    /// Takes the next complete frame out of the buffer without decoding its payload.
+    pub fn next_frame(&mut self) -> Result<Option<(u16, Bytes)>, ControlError> {
+        let (id, len) = match self.peek_header() {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if len > self.max_message_size {
+            return Err(ControlError::TooLarge(len));
+        }
+        if self.buffer.len() < HEADER_SIZE + len {
+            self.buffer.reserve(HEADER_SIZE + len - self.buffer.len());
+            return Ok(None);
+        }
+
+        self.buffer.advance(HEADER_SIZE);
+        Ok(Some((id, self.buffer.split_to(len).freeze())))
+    }
+
+    /// Takes the next complete message out of the buffer and decodes it.
+    ///
+    /// Returns `Ok(None)` if more bytes are needed. Unknown and malformed messages are skipped
+    /// and reported as an error, so decoding may continue afterwards. `TooLarge` however leaves
+    /// the stream in an unrecoverable state, the connection should be dropped.
+    pub fn decode_next(&mut self) -> Result<Option<ControlMessage>, ControlError> {
+        self.decode_next_filtered(|_| true)
+    }
+
+    /// Like `decode_next`, but frames for which `admit` returns false are skipped before their
+    /// payload is decoded and reported as `RateLimited`, e.g. with `MessageLimiter::allow`.
+    pub fn decode_next_filtered<F>(&mut self, mut admit: F) -> Result<Option<ControlMessage>, ControlError>
+    where
+        F: FnMut(MessageType) -> bool,
+    {
+        let (id, payload) = match self.next_frame()? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let ty = MessageType::from_id(id).ok_or(ControlError::UnknownType(id))?;
+        if !admit(ty) {
+            return Err(ControlError::RateLimited(ty));
+        }
+
+        ControlMessage::decode(ty, payload).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn message_types_round_trip() {
+        for (index, ty) in MessageType::ALL.iter().enumerate() {
+            assert_eq!(index as u16, ty.id());
+            assert_eq!(Some(*ty), MessageType::from_id(ty.id()));
+            assert_eq!(Some(*ty), MessageType::from_name(ty.name()));
+        }
+        assert_eq!(None, MessageType::from_id(27));
+    }
+
+    #[test]
+    fn encode_and_decode_are_inverse() {
+        let msg = ControlMessage::from(msgs::ChannelState {
+            channel_id: Some(3),
+            name: Some("Lobby".to_owned()),
+            links: vec![1, 2],
+            ..Default::default()
+        });
+
+        let mut buf = BytesMut::new();
+        msg.encode(&mut buf);
+        assert_eq!(HEADER_SIZE + msg.encoded_len(), buf.len());
+
+        let mut codec = ControlCodec::default();
+        codec.extend(&buf);
+        assert_eq!(Some(msg), codec.decode_next().unwrap());
+        assert_eq!(0, codec.buffered_len());
+    }
+
+    #[test]
+    fn waits_for_partial_messages() {
+        // Version { release: "1.5" }
+        let bytes = frame(0, b"\x12\x031.5");
+        let mut codec = ControlCodec::default();
+
+        for byte in &bytes[..bytes.len() - 1] {
+            codec.extend(&[*byte]);
+            assert_eq!(None, codec.decode_next().unwrap());
+        }
+        codec.extend(&bytes[bytes.len() - 1..]);
+
+        let expected = msgs::Version { release: Some("1.5".to_owned()), ..Default::default() };
+        assert_eq!(Some(ControlMessage::Version(expected)), codec.decode_next().unwrap());
+    }
+
+    #[test]
+    fn udp_tunnel_is_not_protobuf() {
+        let mut codec = ControlCodec::default();
+        codec.extend(&frame(1, &[0x20, 0x00, 0x01]));
+
+        let expected = ControlMessage::UdpTunnel(Bytes::from_static(&[0x20, 0x00, 0x01]));
+        assert_eq!(Some(expected), codec.decode_next().unwrap());
+    }
+
+    #[test]
+    fn skips_unknown_and_malformed_messages() {
+        let mut codec = ControlCodec::default();
+        codec.extend(&frame(99, b"junk"));
+        codec.extend(&frame(3, &[0xff]));
+        codec.extend(&frame(3, &[]));
+
+        assert_eq!(Err(ControlError::UnknownType(99)), codec.decode_next());
+        assert!(matches!(codec.decode_next(), Err(ControlError::Protobuf(MessageType::Ping, _))));
+        assert_eq!(Some(ControlMessage::Ping(Default::default())), codec.decode_next().unwrap());
+    }
+
+    #[test]
+    fn skips_frames_not_admitted() {
+        let mut codec = ControlCodec::default();
+        codec.extend(&frame(11, &[0xff]));
+        codec.extend(&frame(3, &[]));
+
+        let mut seen = Vec::new();
+        let mut admit = |ty| {
+            seen.push(ty);
+            ty != MessageType::TextMessage
+        };
+        assert_eq!(Err(ControlError::RateLimited(MessageType::TextMessage)), codec.decode_next_filtered(&mut admit));
+        assert_eq!(Some(ControlMessage::Ping(Default::default())), codec.decode_next_filtered(&mut admit).unwrap());
+        assert_eq!(None, codec.decode_next_filtered(&mut admit).unwrap());
+        assert_eq!(vec![MessageType::TextMessage, MessageType::Ping], seen);
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let mut codec = ControlCodec::new(16);
+        codec.extend(&frame(11, &[0; 17]));
+
+        assert_eq!(Err(ControlError::TooLarge(17)), codec.decode_next());
+    }
+}
@@ -57,6 +57,13 @@ pub enum DecryptError {
     Mac,
 }
 
+/// Nonce of a packet being decrypted and its effect on the statistics.
+struct Incoming {
+    nonce: u128,
+    late: bool,
+    lost: i32,
+}
+
 impl CryptState {
     /// Creates a new CryptState with randomly generated key and initial encrypt- and decrypt-nonce.
     pub fn generate_new() -> Self {
@@ -165,61 +172,82 @@ impl CryptState {
     }
 
     /// Decrypts a voice packet and (if successful) returns the resulting bytes.
+    ///
+    /// The decrypt nonce is only updated by packets which decrypt successfully. Like Murmur's
+    /// `CryptStateOCB2::decrypt`, a packet rejected as late by more than 30 packets leaves it
+    /// untouched, so the packets that follow in order aren't counted as lost.
     pub fn decrypt(&mut self, buf: &mut BytesMut) -> Result<(), DecryptError> {
         if buf.len() < 4 {
             return Err(DecryptError::Eof);
         }
+        let incoming = self.incoming(buf[0])?;
         let header = buf.split_to(4);
-        let nonce_0 = header[0];
-
-        // If we update our decrypt_nonce and the tag check fails or we've been processing late
-        // packets, we need to revert it
-        let saved_nonce = self.decrypt_nonce;
-        let mut late = false; // will always restore nonce if this is the case
-        let mut lost = 0; // for stats only
-
-        if self.decrypt_nonce.wrapping_add(1) as u8 == nonce_0 {
-            // in order
-            self.decrypt_nonce = self.decrypt_nonce.wrapping_add(1);
-        } else {
-            // packet is late or repeated, or we lost a few packets in between
-            let diff = nonce_0.wrapping_sub(self.decrypt_nonce as u8) as i8;
-
-            self.decrypt_nonce = self.decrypt_nonce.wrapping_add(diff as u128);
-            if diff > 0 {
-                lost = i32::from(diff - 1); // lost a few packets in between this and the last one
-            } else if diff > -30 {
-                if self.decrypt_history.0[nonce_0 as usize] == (self.decrypt_nonce >> 8) as u8 {
-                    self.decrypt_nonce = saved_nonce;
-                    return Err(DecryptError::Repeat);
-                }
-                // just late
-                late = true;
-                lost = -1;
-            } else {
-                return Err(DecryptError::Late); // late by more than 30 packets
-            }
-        }
 
-        let tag = self.ocb_decrypt(buf.as_mut());
+        let tag = self.ocb_decrypt(incoming.nonce, buf.as_mut());
 
         if !memcmp::eq(&tag.to_be_bytes()[0..3], &header[1..4]) {
-            self.decrypt_nonce = saved_nonce;
             return Err(DecryptError::Mac);
         }
 
-        self.decrypt_history.0[nonce_0 as usize] = (self.decrypt_nonce >> 8) as u8;
+        self.decrypt_history.0[header[0] as usize] = (incoming.nonce >> 8) as u8;
 
         self.good += 1;
-        if late {
+        if incoming.late {
             self.late += 1;
-            self.decrypt_nonce = saved_nonce;
+        } else {
+            // late packets don't move the nonce forward
+            self.decrypt_nonce = incoming.nonce;
         }
-        self.lost = (self.lost as i32 + lost as i32) as u32;
+        self.lost = (self.lost as i32 + incoming.lost) as u32;
 
         Ok(())
     }
 
+    /// Decrypts a voice packet without updating the nonce, history or statistics, e.g. to find
+    /// out which of several states a packet belongs to. Returns the decrypted bytes.
+    ///
+    /// A successful packet still needs to go through `decrypt` to be accounted for.
+    pub fn try_decrypt(&self, src: &[u8]) -> Result<BytesMut, DecryptError> {
+        if src.len() < 4 {
+            return Err(DecryptError::Eof);
+        }
+        let incoming = self.incoming(src[0])?;
+        let mut buf = BytesMut::from(&src[4..]);
+
+        let tag = self.ocb_decrypt(incoming.nonce, buf.as_mut());
+
+        if !memcmp::eq(&tag.to_be_bytes()[0..3], &src[1..4]) {
+            return Err(DecryptError::Mac);
+        }
+
+        Ok(buf)
+    }
+
+    /// Works out the nonce of a packet from its first byte, relative to the decrypt nonce.
+    fn incoming(&self, nonce_0: u8) -> Result<Incoming, DecryptError> {
+        if self.decrypt_nonce.wrapping_add(1) as u8 == nonce_0 {
+            // in order
+            return Ok(Incoming { nonce: self.decrypt_nonce.wrapping_add(1), late: false, lost: 0 });
+        }
+
+        // packet is late or repeated, or we lost a few packets in between
+        let diff = nonce_0.wrapping_sub(self.decrypt_nonce as u8) as i8;
+        let nonce = self.decrypt_nonce.wrapping_add(diff as u128);
+
+        if diff > 0 {
+            // lost a few packets in between this and the last one
+            Ok(Incoming { nonce, late: false, lost: i32::from(diff - 1) })
+        } else if diff > -30 {
+            if self.decrypt_history.0[nonce_0 as usize] == (nonce >> 8) as u8 {
+                return Err(DecryptError::Repeat);
+            }
+            // just late
+            Ok(Incoming { nonce, late: true, lost: -1 })
+        } else {
+            Err(DecryptError::Late) // late by more than 30 packets
+        }
+    }
+
     /// Encrypt the provided buffer using AES-OCB, returning the tag.
     fn ocb_encrypt(&self, mut buf: &mut [u8]) -> u128 {
         let mut offset = self.aes_encrypt(self.encrypt_nonce.to_be());
@@ -257,8 +285,8 @@ impl CryptState {
 
     /// Decrypt the provided buffer using AES-OCB, returning the tag.
     /// **Make sure to verify that the tag matches!**
-    fn ocb_decrypt(&self, mut buf: &mut [u8]) -> u128 {
-        let mut offset = self.aes_encrypt(self.decrypt_nonce.to_be());
+    fn ocb_decrypt(&self, nonce: u128, mut buf: &mut [u8]) -> u128 {
+        let mut offset = self.aes_encrypt(nonce.to_be());
         let mut checksum = 0u128;
 
         while buf.len() > BLOCK_SIZE {
@@ -380,6 +408,64 @@ mod test {
         assert_eq!(src, buffer2.to_vec());
     }
 
+    #[test]
+    fn try_decrypt_leaves_state_untouched() {
+        let mut server_state = CryptState::generate_new();
+        let mut client_state =
+            CryptState::new_from(
+                *server_state.get_key(),
+                server_state.get_decrypt_nonce(),
+                server_state.get_encrypt_nonce(),
+            );
+        let other_state = CryptState::generate_new();
+
+        let mut packet = BytesMut::new();
+        server_state.encrypt(b"test", &mut packet);
+
+        assert_eq!(Err(DecryptError::Mac), other_state.try_decrypt(&packet));
+        assert_eq!(Err(DecryptError::Eof), client_state.try_decrypt(&packet[..3]));
+        assert_eq!(&b"test"[..], &client_state.try_decrypt(&packet).unwrap()[..]);
+        assert_eq!(&b"test"[..], &client_state.try_decrypt(&packet).unwrap()[..]);
+        assert_eq!(0, client_state.get_good());
+
+        client_state.decrypt(&mut packet.clone()).unwrap();
+        assert_eq!(1, client_state.get_good());
+        assert_eq!(Err(DecryptError::Repeat), client_state.try_decrypt(&packet));
+    }
+
+    // Packets late by more than 30 don't move the nonce back, see `decrypt`
+    #[test]
+    fn failed_decrypt_keeps_nonce() {
+        let mut server_state = CryptState::generate_new();
+        let mut client_state =
+            CryptState::new_from(
+                *server_state.get_key(),
+                server_state.get_decrypt_nonce(),
+                server_state.get_encrypt_nonce(),
+            );
+
+        let mut first = BytesMut::new();
+        server_state.encrypt(b"first", &mut first);
+        for _ in 0..40 {
+            let mut packet = BytesMut::new();
+            server_state.encrypt(b"skipped", &mut packet);
+        }
+        let mut last = BytesMut::new();
+        server_state.encrypt(b"last", &mut last);
+
+        client_state.decrypt(&mut last.clone()).unwrap();
+        let nonce = client_state.get_decrypt_nonce();
+        assert_eq!(Err(DecryptError::Late), client_state.decrypt(&mut first.clone()));
+        assert_eq!(nonce, client_state.get_decrypt_nonce());
+        assert_eq!(41, client_state.get_lost());
+
+        // the next packet is still in order
+        let mut next = BytesMut::new();
+        server_state.encrypt(b"next", &mut next);
+        client_state.decrypt(&mut next).unwrap();
+        assert_eq!(41, client_state.get_lost());
+    }
+
     #[test]
     fn aes_test_vectors() {
         let key = u128hex("E8E9EAEBEDEEEFF0F2F3F4F5F7F8F9FA");
@@ -422,7 +508,7 @@ mod test {
                 assert_eq!(u128hex($tag), tag, concat!("ENCRYPT-TAG-", $name));
 
                 hex_to_bytes($cipher.as_ref(), &mut result);
-                let tag = state.ocb_decrypt(&mut result);
+                let tag = state.ocb_decrypt(state.decrypt_nonce, &mut result);
                 assert_eq!(bytes_from_hex($plain), result, concat!("DECRYPT-RESULT-", $name));
                 assert_eq!(u128hex($tag), tag, concat!("DECRYPT-TAG-", $name));
             )*};
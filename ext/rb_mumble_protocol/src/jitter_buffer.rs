@@ -0,0 +1,466 @@
+//! Reordering of incoming voice packets per speaker
+//!
+//! Packets are buffered for a short delay and played out ordered by sequence number. Missing
+//! packets are reported as gaps, so the caller can apply packet loss concealment. The delay is
+//! picked at the start of each talk spurt from the interarrival jitter (RFC 3550, 6.4.1).
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::opus;
+use crate::voice::{AudioPacket, VoicePayload};
+
+/// Duration in milliseconds of the unit Mumble's sequence numbers count in.
+pub const FRAME_MS: u64 = 10;
+/// Longest wait for the packet after a gap, in frames (one second), however far the sequence
+/// number jumped.
+pub const MAX_GAP_FRAMES: u64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Smallest playout delay, used when there is no jitter at all.
+    pub min_delay_ms: u64,
+    /// Largest playout delay, regardless of the measured jitter.
+    pub max_delay_ms: u64,
+    /// Packets buffered per speaker before the oldest ones are dropped.
+    pub max_packets: usize,
+    /// Silence after which a talk spurt without terminator is considered over.
+    pub idle_timeout_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            min_delay_ms: 20,
+            max_delay_ms: 200,
+            max_packets: 64,
+            idle_timeout_ms: 1000,
+        }
+    }
+}
+
+/// What happened to a pushed packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Push {
+    Queued,
+    /// The packet arrived after its place in the stream was already played out.
+    Late,
+    /// The packet is already buffered.
+    Duplicate,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Playout {
+    /// The next packet of the stream.
+    Audio(AudioPacket),
+    /// This many frames are missing before the next packet, which is played at most
+    /// `MAX_GAP_FRAMES` later.
+    Gap(u64),
+    /// The talk spurt ended, either by a terminator or by timing out.
+    End,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub late: u32,
+    pub duplicate: u32,
+    /// Frames reported as gaps.
+    pub lost: u64,
+    /// Packets dropped because the buffer was full.
+    pub overflow: u32,
+}
+
+struct Buffered {
+    packet: AudioPacket,
+    frames: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    Buffering { first_arrival_ms: u64 },
+    Playing { next_seq: u64, due_ms: u64 },
+}
+
+/// Jitter buffer of a single speaker.
+pub struct SpeakerBuffer {
+    config: Config,
+    state: State,
+    packets: BTreeMap<u64, Buffered>,
+    /// Everything before this sequence number has been played out.
+    played_until: Option<u64>,
+    end_pending: bool,
+    last_arrival_ms: u64,
+    last_transit: Option<i128>,
+    jitter_ms: f64,
+    delay_ms: u64,
+    stats: Stats,
+}
+
+impl SpeakerBuffer {
+    pub fn new(config: Config) -> Self {
+        SpeakerBuffer {
+            config,
+            state: State::Idle,
+            packets: BTreeMap::new(),
+            played_until: None,
+            end_pending: false,
+            last_arrival_ms: 0,
+            last_transit: None,
+            jitter_ms: 0.0,
+            delay_ms: config.min_delay_ms,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Returns the measured interarrival jitter.
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter_ms
+    }
+
+    /// Returns the playout delay of the current (or last) talk spurt.
+    pub fn delay_ms(&self) -> u64 {
+        self.delay_ms
+    }
+
+    /// Returns the amount of buffered packets.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn push(&mut self, packet: AudioPacket, now_ms: u64) -> Push {
+        let seq = packet.seq_num;
+        if self.played_until.is_some_and(|until| seq < until) {
+            self.stats.late += 1;
+            return Push::Late;
+        }
+        if self.packets.contains_key(&seq) {
+            self.stats.duplicate += 1;
+            return Push::Duplicate;
+        }
+
+        if self.state == State::Idle {
+            // the sequence number doesn't advance during silence, start measuring anew
+            self.last_transit = None;
+            self.delay_ms = self.target_delay_ms();
+            self.state = State::Buffering { first_arrival_ms: now_ms };
+        }
+        self.update_jitter(seq, now_ms);
+        self.last_arrival_ms = now_ms;
+
+        let frames = packet_frames(&packet);
+        self.packets.insert(seq, Buffered { packet, frames });
+        while self.packets.len() > self.config.max_packets {
+            self.packets.pop_first();
+            self.stats.overflow += 1;
+        }
+
+        Push::Queued
+    }
+
+    /// Returns the next due playout event, if any.
+    pub fn poll(&mut self, now_ms: u64) -> Option<Playout> {
+        if self.end_pending {
+            self.end_pending = false;
+            return Some(Playout::End);
+        }
+
+        let (next_seq, due_ms) = match self.state {
+            State::Idle => return None,
+            State::Buffering { first_arrival_ms } => {
+                let due_ms = first_arrival_ms.saturating_add(self.delay_ms);
+                match self.packets.keys().next() {
+                    Some(&seq) if now_ms >= due_ms => (seq, due_ms),
+                    _ => return None,
+                }
+            }
+            State::Playing { next_seq, due_ms } => (next_seq, due_ms),
+        };
+        if now_ms < due_ms {
+            self.state = State::Playing { next_seq, due_ms };
+            return None;
+        }
+
+        if let Some(Buffered { packet, frames }) = self.packets.remove(&next_seq) {
+            let played_until = next_seq.saturating_add(frames.max(1));
+            self.played_until = Some(played_until);
+            // packets overlapping the frames just played came too late
+            let kept = self.packets.split_off(&played_until);
+            self.stats.late += std::mem::replace(&mut self.packets, kept).len() as u32;
+            self.state = State::Playing {
+                next_seq: next_seq.saturating_add(frames),
+                due_ms: due_ms.saturating_add(frames * FRAME_MS),
+            };
+
+            if packet.payload.is_terminator() {
+                self.state = State::Idle;
+                if frames == 0 {
+                    return Some(Playout::End);
+                }
+                self.end_pending = true;
+            }
+            return Some(Playout::Audio(packet));
+        }
+
+        match self.packets.keys().next() {
+            Some(&later) => {
+                let missing = later - next_seq;
+                self.stats.lost = self.stats.lost.saturating_add(missing);
+                self.played_until = Some(later);
+                self.state = State::Playing {
+                    next_seq: later,
+                    due_ms: due_ms.saturating_add(missing.min(MAX_GAP_FRAMES) * FRAME_MS),
+                };
+                Some(Playout::Gap(missing))
+            }
+            None if now_ms >= self.last_arrival_ms.saturating_add(self.config.idle_timeout_ms) => {
+                self.state = State::Idle;
+                Some(Playout::End)
+            }
+            None => {
+                self.state = State::Playing { next_seq, due_ms };
+                None
+            }
+        }
+    }
+
+    fn update_jitter(&mut self, seq: u64, now_ms: u64) {
+        let transit = i128::from(now_ms) - i128::from(seq) * i128::from(FRAME_MS);
+        if let Some(last_transit) = self.last_transit {
+            let deviation = (transit - last_transit).unsigned_abs() as f64;
+            self.jitter_ms += (deviation - self.jitter_ms) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
+
+    fn target_delay_ms(&self) -> u64 {
+        let delay = self.config.min_delay_ms + (3.0 * self.jitter_ms).ceil() as u64;
+        delay.clamp(self.config.min_delay_ms, self.config.max_delay_ms)
+    }
+}
+
+/// Jitter buffers of all speakers, keyed by session.
+pub struct JitterBuffer {
+    config: Config,
+    speakers: HashMap<u32, SpeakerBuffer>,
+}
+
+impl Default for JitterBuffer {
+    fn default() -> Self {
+        JitterBuffer::new(Config::default())
+    }
+}
+
+impl JitterBuffer {
+    pub fn new(config: Config) -> Self {
+        JitterBuffer {
+            config,
+            speakers: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, session: u32, packet: AudioPacket, now_ms: u64) -> Push {
+        let config = self.config;
+        self.speakers
+            .entry(session)
+            .or_insert_with(|| SpeakerBuffer::new(config))
+            .push(packet, now_ms)
+    }
+
+    /// Returns all due playout events, ordered by session.
+    pub fn poll(&mut self, now_ms: u64) -> Vec<(u32, Playout)> {
+        let mut sessions: Vec<u32> = self.speakers.keys().copied().collect();
+        sessions.sort_unstable();
+
+        let mut events = Vec::new();
+        for session in sessions {
+            let speaker = self.speakers.get_mut(&session).expect("key was just collected");
+            while let Some(event) = speaker.poll(now_ms) {
+                events.push((session, event));
+            }
+        }
+        events
+    }
+
+    pub fn speaker(&self, session: u32) -> Option<&SpeakerBuffer> {
+        self.speakers.get(&session)
+    }
+
+    /// Forgets a speaker, e.g. once it disconnected.
+    pub fn remove(&mut self, session: u32) -> Option<SpeakerBuffer> {
+        self.speakers.remove(&session)
+    }
+}
+
+/// Returns the duration of a packet in 10 ms frames, i.e. how far it advances the sequence number.
+pub fn packet_frames(packet: &AudioPacket) -> u64 {
+    match &packet.payload {
+        VoicePayload::Opus(..) => match packet.opus_info() {
+            Some(Ok(info)) => u64::from(info.total_samples()).div_ceil(opus::SAMPLE_RATE as u64 * FRAME_MS / 1000),
+            Some(Err(_)) => 1,
+            None => 0,
+        },
+        VoicePayload::CeltAlpha(frames) | VoicePayload::Speex(frames) | VoicePayload::CeltBeta(frames) => {
+            frames.iter().filter(|frame| !frame.is_empty()).count() as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+
+    // CELT fullband, 20 ms
+    const OPUS_20MS: &[u8] = &[31 << 3, 0xaa];
+
+    fn packet(seq: u64, terminator: bool) -> AudioPacket {
+        AudioPacket {
+            target: 0,
+            session_id: Some(1),
+            seq_num: seq,
+            payload: VoicePayload::Opus(Bytes::from_static(OPUS_20MS), terminator),
+            position_info: None,
+        }
+    }
+
+    fn drain(buffer: &mut SpeakerBuffer, now_ms: u64) -> Vec<Playout> {
+        std::iter::from_fn(|| buffer.poll(now_ms)).collect()
+    }
+
+    fn seqs(events: &[Playout]) -> Vec<Option<u64>> {
+        events
+            .iter()
+            .map(|event| match event {
+                Playout::Audio(packet) => Some(packet.seq_num),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let mut buffer = SpeakerBuffer::new(Config::default());
+        assert_eq!(Push::Queued, buffer.push(packet(2, false), 0));
+        assert_eq!(Push::Queued, buffer.push(packet(0, false), 5));
+        assert_eq!(Push::Queued, buffer.push(packet(4, false), 10));
+
+        assert_eq!(None, buffer.poll(19));
+        assert_eq!(vec![Some(0), Some(2), Some(4)], seqs(&drain(&mut buffer, 60)));
+    }
+
+    #[test]
+    fn rejects_late_and_duplicate_packets() {
+        let mut buffer = SpeakerBuffer::new(Config::default());
+        buffer.push(packet(10, false), 0);
+        assert_eq!(Push::Duplicate, buffer.push(packet(10, false), 0));
+
+        assert_eq!(1, drain(&mut buffer, 20).len());
+        assert_eq!(Push::Late, buffer.push(packet(8, false), 25));
+        assert_eq!(Stats { late: 1, duplicate: 1, ..Default::default() }, buffer.stats());
+    }
+
+    #[test]
+    fn reports_gaps() {
+        let mut buffer = SpeakerBuffer::new(Config::default());
+        buffer.push(packet(0, false), 0);
+        buffer.push(packet(6, false), 10);
+
+        let events = drain(&mut buffer, 100);
+        assert_eq!(Playout::Gap(4), events[1]);
+        assert_eq!(vec![Some(0), None, Some(6)], seqs(&events));
+        assert_eq!(4, buffer.stats().lost);
+    }
+
+    #[test]
+    fn bounds_sequence_number_jumps() {
+        let mut buffer = SpeakerBuffer::new(Config::default());
+        buffer.push(packet(0, false), 0);
+        buffer.push(packet(u64::MAX - 1, false), 10);
+        assert!(buffer.jitter_ms() > 0.0);
+
+        let events = drain(&mut buffer, 100);
+        assert_eq!(vec![Some(0), None], seqs(&events));
+        assert_eq!(Playout::Gap(u64::MAX - 3), events[1]);
+        // played a second after the gap rather than never
+        assert!(drain(&mut buffer, 1039).is_empty());
+        assert_eq!(vec![Some(u64::MAX - 1)], seqs(&drain(&mut buffer, 1040)));
+        assert_eq!(Push::Late, buffer.push(packet(u64::MAX - 2, false), 1050));
+    }
+
+    #[test]
+    fn drops_packets_overlapping_played_frames() {
+        let mut buffer = SpeakerBuffer::new(Config::default());
+        buffer.push(packet(0, false), 0);
+        buffer.push(packet(1, false), 0);
+        buffer.push(packet(2, false), 0);
+
+        assert_eq!(vec![Some(0), Some(2)], seqs(&drain(&mut buffer, 100)));
+        assert_eq!(Stats { late: 1, ..Default::default() }, buffer.stats());
+    }
+
+    #[test]
+    fn waits_for_missing_packets_until_due() {
+        let mut buffer = SpeakerBuffer::new(Config::default());
+        buffer.push(packet(0, false), 0);
+        assert_eq!(vec![Some(0)], seqs(&drain(&mut buffer, 20)));
+
+        // seq 2 is due at 40 ms
+        buffer.push(packet(4, false), 30);
+        assert!(drain(&mut buffer, 39).is_empty());
+        buffer.push(packet(2, false), 39);
+        assert_eq!(vec![Some(2), Some(4)], seqs(&drain(&mut buffer, 60)));
+    }
+
+    #[test]
+    fn ends_talk_spurts() {
+        let mut buffer = SpeakerBuffer::new(Config::default());
+        buffer.push(packet(0, false), 0);
+        buffer.push(packet(2, true), 20);
+
+        let events = drain(&mut buffer, 40);
+        assert_eq!(vec![Some(0), Some(2), None], seqs(&events));
+        assert_eq!(Some(&Playout::End), events.last());
+
+        // next talk spurt
+        buffer.push(packet(50, false), 1000);
+        assert_eq!(vec![Some(50)], seqs(&drain(&mut buffer, 1020)));
+
+        // no terminator
+        assert!(drain(&mut buffer, 1500).is_empty());
+        assert_eq!(vec![Playout::End], drain(&mut buffer, 2000));
+    }
+
+    #[test]
+    fn adapts_delay_to_jitter() {
+        let mut buffer = SpeakerBuffer::new(Config::default());
+        for (index, arrival) in [0, 45, 40, 90, 80, 140, 120].iter().enumerate() {
+            buffer.push(packet(index as u64 * 2, false), *arrival);
+        }
+        buffer.push(packet(14, true), 150);
+        drain(&mut buffer, 1000);
+        assert!(buffer.jitter_ms() > 5.0);
+
+        buffer.push(packet(100, false), 2000);
+        assert!(buffer.delay_ms() > 20);
+        assert!(buffer.poll(2020).is_none());
+    }
+
+    #[test]
+    fn keeps_speakers_apart() {
+        let mut buffer = JitterBuffer::default();
+        buffer.push(2, packet(0, false), 0);
+        buffer.push(1, packet(7, false), 0);
+
+        let sessions: Vec<u32> = buffer.poll(20).into_iter().map(|(session, _)| session).collect();
+        assert_eq!(vec![1, 2], sessions);
+    }
+}
@@ -0,0 +1,257 @@
+//! Rate limiting of control messages
+//!
+//! Murmur throttles each connection with leaky buckets: every message adds a token, tokens leak
+//! at a constant rate, and messages which would overflow the bucket are dropped. Normal messages
+//! share one bucket (`messagelimit` per second, `messageburst` at once), plugin data has its own
+//! (`pluginmessagelimit`, `pluginmessageburst`).
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Server.cpp
+//! (`LeakyBucket::ratelimit`)
+
+use std::cell::Cell;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::control::MessageType;
+
+/// Messages throttled by `messagelimit`: those changing the server state or reaching other users.
+pub const LIMITED_MESSAGES: &[MessageType] = &[MessageType::ChannelState, MessageType::UserState, MessageType::TextMessage];
+
+/// Messages throttled by `pluginmessagelimit`.
+pub const LIMITED_PLUGIN_MESSAGES: &[MessageType] = &[MessageType::PluginDataTransmission];
+
+/// Source of monotonic time in milliseconds, replaceable to test time-dependent code.
+pub trait Clock {
+    fn now_ms(&self) -> u64;
+}
+
+/// Milliseconds elapsed since the clock was created.
+#[derive(Clone, Copy, Debug)]
+pub struct MonotonicClock {
+    epoch: Instant,
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        MonotonicClock { epoch: Instant::now() }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+}
+
+/// A clock which only moves when told to.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    now_ms: Cell<u64>,
+}
+
+impl ManualClock {
+    pub fn new(now_ms: u64) -> Self {
+        ManualClock { now_ms: Cell::new(now_ms) }
+    }
+
+    pub fn set(&self, now_ms: u64) {
+        self.now_ms.set(now_ms);
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now_ms.set(self.now_ms.get() + ms);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.get()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeakyBucket {
+    tokens_per_sec: u32,
+    max_tokens: u32,
+    tokens: u64,
+    /// When tokens last leaked.
+    leaked_ms: u64,
+}
+
+impl LeakyBucket {
+    pub fn new(tokens_per_sec: u32, max_tokens: u32) -> Self {
+        LeakyBucket { tokens_per_sec, max_tokens, tokens: 0, leaked_ms: 0 }
+    }
+
+    /// Tokens currently in the bucket, as of the last call to `allow`.
+    pub fn tokens(&self) -> u64 {
+        self.tokens
+    }
+
+    /// Adds the tokens if they fit into the bucket once the tokens leaked since the last call are
+    /// removed. Returns false if they don't, i.e. the message should be dropped.
+    pub fn allow(&mut self, tokens: u32, now_ms: u64) -> bool {
+        let leaked = now_ms.saturating_sub(self.leaked_ms) * self.tokens_per_sec as u64 / 1000;
+        // Like Murmur, the time is only reset once a whole token leaked
+        if leaked > 0 {
+            self.leaked_ms = now_ms;
+        }
+        self.tokens = self.tokens.saturating_sub(leaked);
+
+        let allowed = self.tokens + tokens as u64 <= self.max_tokens as u64;
+        if allowed {
+            self.tokens += tokens as u64;
+        }
+        allowed
+    }
+}
+
+/// Murmur's rate limiting settings, see `MessageLimiter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Messages per second.
+    pub message_limit: u32,
+    /// Messages accepted at once.
+    pub message_burst: u32,
+    pub plugin_message_limit: u32,
+    pub plugin_message_burst: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            message_limit: 1,
+            message_burst: 5,
+            plugin_message_limit: 4,
+            plugin_message_burst: 15,
+        }
+    }
+}
+
+/// Throttles the control messages received on one connection, each group of message types
+/// sharing a bucket. Other message types aren't limited.
+#[derive(Clone, Debug)]
+pub struct MessageLimiter<C: Clock = MonotonicClock> {
+    clock: C,
+    buckets: Vec<(Vec<MessageType>, LeakyBucket)>,
+    dropped: u64,
+}
+
+impl Default for MessageLimiter {
+    fn default() -> Self {
+        MessageLimiter::new(&Limits::default())
+    }
+}
+
+impl MessageLimiter {
+    pub fn new(limits: &Limits) -> Self {
+        MessageLimiter::with_clock(limits, MonotonicClock::default())
+    }
+}
+
+impl<C: Clock> MessageLimiter<C> {
+    /// Throttles `LIMITED_MESSAGES` and `LIMITED_PLUGIN_MESSAGES` like Murmur.
+    pub fn with_clock(limits: &Limits, clock: C) -> Self {
+        let mut limiter = MessageLimiter { clock, buckets: Vec::new(), dropped: 0 };
+        limiter.limit(LIMITED_MESSAGES, limits.message_limit, limits.message_burst);
+        limiter.limit(LIMITED_PLUGIN_MESSAGES, limits.plugin_message_limit, limits.plugin_message_burst);
+        limiter
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Throttles the message types with a new bucket shared between them, replacing their
+    /// previous limits.
+    pub fn limit(&mut self, types: &[MessageType], per_sec: u32, burst: u32) {
+        self.unlimit(types);
+        self.buckets.push((types.to_vec(), LeakyBucket::new(per_sec, burst)));
+    }
+
+    pub fn unlimit(&mut self, types: &[MessageType]) {
+        for (limited, _) in &mut self.buckets {
+            limited.retain(|ty| !types.contains(ty));
+        }
+        self.buckets.retain(|(limited, _)| !limited.is_empty());
+    }
+
+    /// Returns whether a message of the type may be handled now, counting it otherwise.
+    pub fn allow(&mut self, ty: MessageType) -> bool {
+        let now_ms = self.clock.now_ms();
+        let allowed = match self.buckets.iter_mut().find(|(limited, _)| limited.contains(&ty)) {
+            Some((_, bucket)) => bucket.allow(1, now_ms),
+            None => true,
+        };
+
+        if !allowed {
+            self.dropped += 1;
+        }
+        allowed
+    }
+
+    /// Amount of messages which weren't allowed.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn leaks_over_time() {
+        let mut bucket = LeakyBucket::new(2, 3);
+        assert!((0..3).all(|_| bucket.allow(1, 0)));
+        assert!(!bucket.allow(1, 0));
+        assert!(!bucket.allow(1, 499));
+
+        assert!(bucket.allow(1, 500));
+        assert!(!bucket.allow(1, 500));
+        assert_eq!(3, bucket.tokens());
+
+        assert!(bucket.allow(3, 10_000));
+        assert!(!bucket.allow(4, 20_000));
+        assert_eq!(0, bucket.tokens());
+    }
+
+    #[test]
+    fn keeps_partial_leaks() {
+        let mut bucket = LeakyBucket::new(1, 1);
+        assert!(bucket.allow(1, 0));
+
+        // 600 ms leak no whole token, the next 400 ms complete it
+        assert!(!bucket.allow(1, 600));
+        assert!(bucket.allow(1, 1000));
+    }
+
+    #[test]
+    fn limits_message_types() {
+        let limits = Limits { message_limit: 1, message_burst: 2, ..Default::default() };
+        let mut limiter = MessageLimiter::with_clock(&limits, ManualClock::new(0));
+
+        assert!(limiter.allow(MessageType::TextMessage));
+        assert!(limiter.allow(MessageType::UserState));
+        assert!(!limiter.allow(MessageType::ChannelState));
+        assert!(limiter.allow(MessageType::Ping));
+        assert!(limiter.allow(MessageType::PluginDataTransmission));
+        assert_eq!(1, limiter.dropped());
+
+        limiter.clock().advance(1000);
+        assert!(limiter.allow(MessageType::TextMessage));
+        assert!(!limiter.allow(MessageType::TextMessage));
+
+        limiter.limit(&[MessageType::TextMessage], 0, 1);
+        assert!(limiter.allow(MessageType::TextMessage));
+        assert!(!limiter.allow(MessageType::TextMessage));
+        assert!(!limiter.allow(MessageType::UserState));
+
+        limiter.unlimit(&[MessageType::TextMessage, MessageType::UserState, MessageType::ChannelState]);
+        assert!(limiter.allow(MessageType::TextMessage));
+        assert_eq!(1, limiter.buckets.len());
+        assert_eq!(4, limiter.dropped());
+    }
+}
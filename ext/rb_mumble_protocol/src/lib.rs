@@ -1,18 +1,25 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use magnus::{
-    method, prelude::*,
-    Error, RHash, RString,
+    function, method, prelude::*,
+    Error, RArray, RHash, RString,
     RClass, RModule, Ruby, Value,
     Symbol,
-    value::{Lazy},
+    value::{Lazy, Opaque},
     exception::ExceptionClass,
-    gc::register_mark_object,
+    gc::{self, register_mark_object},
+    kwargs,
+    r_hash::ForEach,
     scan_args::{get_kwargs, scan_args},
-    typed_data
+    typed_data, DataTypeFunctions, TypedData,
 };
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
 
 static BASE_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| {
     let ex = ruby
@@ -28,9 +35,61 @@ static BASE_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| {
     ex
 });
 
+pub mod acl;
+pub mod bandwidth;
+pub mod ban_list;
+pub mod blob_store;
+pub mod certificate;
+pub mod channel_tree;
+pub mod client_session;
+pub mod codec_version;
+pub mod control;
 pub mod crypt_state;
+pub mod jitter_buffer;
+pub mod leaky_bucket;
+pub mod mumble_proto;
+pub mod ogg_opus;
+pub mod opus;
+pub mod pcap;
+pub mod permissions;
+pub mod ping_tracker;
+pub mod plugin_data;
+pub mod positional;
+pub mod replay;
+pub mod server_connection;
+pub mod text_message;
+pub mod udp_demux;
+pub mod user_registry;
+pub mod voice;
+pub mod voice_target;
+pub mod voice_transport;
 
+use acl::{AclStore, Identity};
+use bandwidth::BandwidthMeter;
+use ban_list::{Ban, BanList};
+use blob_store::BlobStore;
+use certificate::{Bundle, Certificate, KeyType};
+use channel_tree::{Change, ChannelTree};
+use client_session::ClientSession;
+use codec_version::{CodecSelection, Codecs};
+use control::{ControlCodec, ControlError, ControlMessage, MessageType, PayloadSource};
 use crypt_state::{DecryptError};
+use jitter_buffer::{JitterBuffer, Playout, Push};
+use leaky_bucket::MessageLimiter;
+use mumble_proto::reject::RejectType;
+use ogg_opus::{OggOpusWriter, Written};
+use pcap::{Packet, PcapReader, PcapWriter, Transport};
+use permissions::Permissions;
+use ping_tracker::{PingTracker, RttStats};
+use plugin_data::{PluginData, PluginDataRouter};
+use positional::{Attenuation, Listener, Vector3};
+use replay::{EventKind, Replay};
+use server_connection::ServerConnection;
+use udp_demux::{Source, UdpDemux};
+use user_registry::{User, UserRegistry};
+use voice::{AudioPacket, Direction, VoicePacket, VoicePayload};
+use voice_target::{Credentials, VoiceTargets};
+use voice_transport::{VoicePath, VoiceTransport};
 
 #[magnus::wrap(class = "RbMumbleProtocol::CryptState", name = "Rust CryptState wrapper", free_immediately, size)]
 #[derive(Default)]
@@ -168,12 +227,7 @@ impl CryptStateRef {
                 let reason =
                     match result {
                         Ok(()) => Ruby::to_symbol(ruby, "ok"),
-                        Err(e) => match e {
-                            DecryptError::Repeat => Ruby::to_symbol(ruby, "repeat"),
-                            DecryptError::Late   => Ruby::to_symbol(ruby, "late"),
-                            DecryptError::Mac    => Ruby::to_symbol(ruby, "bad_mac"),
-                            DecryptError::Eof    => Ruby::to_symbol(ruby, "eof"),
-                        }
+                        Err(e) => decrypt_error_symbol(ruby, e),
                     };
 
                 Ok((ruby_string, reason))
@@ -181,6 +235,3032 @@ impl CryptStateRef {
             Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
         }
     }
+
+    /// Decrypts without updating the nonce, history or statistics, e.g. to find out which
+    /// session sent a datagram. Returns `[nil, reason]` on failure.
+    pub fn try_decrypt(ruby: &Ruby, rb_self: &Self, encrypted: RString) -> Result<(Option<RString>, Symbol), Error> {
+        match rb_self.state.try_borrow() {
+            Ok(state) => {
+                match state.try_decrypt(unsafe { encrypted.as_slice() }) {
+                    Ok(buffer) => Ok((Some(ruby.str_from_slice(&buffer)), Ruby::to_symbol(ruby, "ok"))),
+                    Err(e) => Ok((None, decrypt_error_symbol(ruby, e))),
+                }
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+fn decrypt_error_symbol(ruby: &Ruby, error: DecryptError) -> Symbol {
+    match error {
+        DecryptError::Repeat => Ruby::to_symbol(ruby, "repeat"),
+        DecryptError::Late   => Ruby::to_symbol(ruby, "late"),
+        DecryptError::Mac    => Ruby::to_symbol(ruby, "bad_mac"),
+        DecryptError::Eof    => Ruby::to_symbol(ruby, "eof"),
+    }
+}
+
+/// Amount of bytes requested from the IO per `read_nonblock` call.
+const READ_SIZE: usize = 16 * 1024;
+
+#[derive(TypedData, Default)]
+#[magnus(class = "RbMumbleProtocol::ControlStream", name = "Rust ControlStream wrapper", free_immediately, size, mark)]
+struct ControlStreamRef {
+    io: RefCell<Option<Opaque<Value>>>,
+    codec: RefCell<ControlCodec>,
+    limiter: RefCell<Option<MessageLimiter>>,
+    /// Frames of an unknown type or with an invalid payload, which were skipped.
+    skipped: Cell<u64>,
+}
+
+impl DataTypeFunctions for ControlStreamRef {
+    fn mark(&self, marker: &gc::Marker) {
+        if let Ok(io) = self.io.try_borrow() {
+            if let Some(io) = *io {
+                marker.mark(io);
+            }
+        }
+    }
+}
+
+impl ControlStreamRef {
+    fn initialize(
+      ruby: &Ruby,
+      rb_self: typed_data::Obj<Self>,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(Value,), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (), (Option<usize>, Option<Value>), ()>(
+          args.keywords,
+          &[],
+          &["max_message_size", "rate_limit"],
+      )?;
+      let (io,) = args.required;
+      let (max_message_size, rate_limit) = kwargs.optional;
+      let max_message_size = max_message_size.unwrap_or(control::MAX_MESSAGE_SIZE);
+      let limiter = match rate_limit {
+          Some(limits) => {
+              let limits: leaky_bucket::Limits = serde_magnus::deserialize(ruby, limits)?;
+              Some(MessageLimiter::new(&limits))
+          },
+          None => None,
+      };
+
+      *rb_self.io.borrow_mut() = Some(io.into());
+      *rb_self.codec.borrow_mut() = ControlCodec::new(max_message_size);
+      *rb_self.limiter.borrow_mut() = limiter;
+
+      Ok(())
+    }
+
+    pub fn io(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        match rb_self.io.try_borrow() {
+            Ok(io) => match *io {
+                Some(io) => Ok(ruby.get_inner(io)),
+                None => Err(Error::new(ruby.get_inner(&BASE_ERROR), "stream is not initialized")),
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the next buffered message as `[type, message]`, if there is a complete one.
+    /// Messages dropped by rate limiting are skipped silently, like Murmur does. So are frames
+    /// of unknown types or with invalid payloads, which don't desynchronize the stream.
+    /// Only frames over the size limit raise.
+    fn decode_next(ruby: &Ruby, rb_self: &Self) -> Result<Option<Value>, Error> {
+        let message = match (rb_self.codec.try_borrow_mut(), rb_self.limiter.try_borrow_mut()) {
+            (Ok(mut codec), Ok(mut limiter)) => loop {
+                let result = match limiter.as_mut() {
+                    Some(limiter) => codec.decode_next_filtered(|ty| limiter.allow(ty)),
+                    None => codec.decode_next(),
+                };
+
+                match result {
+                    Err(ControlError::RateLimited(_)) => continue,
+                    Err(ControlError::UnknownType(_) | ControlError::Protobuf(..)) => {
+                        rb_self.skipped.set(rb_self.skipped.get() + 1);
+                        continue;
+                    }
+                    result => break result.map_err(|e| control_error(ruby, e))?,
+                }
+            },
+            _ => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        match message {
+            Some(message) => Ok(Some(message_to_value(ruby, &message)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn read_nonblock(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Value, Error> {
+        let args = scan_args::<(), (), (), (), _, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<bool>,), ()>(
+            args.keywords,
+            &[],
+            &["exception"],
+        )?;
+        let exception = kwargs.optional.0.unwrap_or(true);
+        let io = Self::io(ruby, rb_self)?;
+
+        loop {
+            if let Some(message) = Self::decode_next(ruby, rb_self)? {
+                return Ok(message);
+            }
+
+            // Raises IO::WaitReadable & co. or returns :wait_readable & co., depending on `exception`
+            let chunk: Value = if exception {
+                io.funcall("read_nonblock", (READ_SIZE,))?
+            } else {
+                io.funcall("read_nonblock", (READ_SIZE, kwargs!("exception" => false)))?
+            };
+
+            if let Some(chunk) = RString::from_value(chunk) {
+                match rb_self.codec.try_borrow_mut() {
+                    Ok(mut codec) => codec.extend(unsafe { chunk.as_slice() }),
+                    Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+                }
+            } else if chunk.is_nil() {
+                let buffered = rb_self.codec.try_borrow().map(|codec| codec.buffered_len()).unwrap_or(0);
+                if buffered > 0 {
+                    return Err(Error::new(ruby.exception_eof_error(), "end of file reached inside a control message"));
+                }
+
+                return Ok(chunk);
+            } else {
+                return Ok(chunk);
+            }
+        }
+    }
+
+    /// Amount of received messages dropped by rate limiting.
+    pub fn dropped_messages(ruby: &Ruby, rb_self: &Self) -> Result<u64, Error> {
+        match rb_self.limiter.try_borrow() {
+            Ok(limiter) => Ok(limiter.as_ref().map_or(0, MessageLimiter::dropped)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Amount of received frames skipped because of an unknown type or an invalid payload.
+    pub fn skipped_messages(rb_self: &Self) -> u64 {
+        rb_self.skipped.get()
+    }
+
+    pub fn write_message(ruby: &Ruby, rb_self: &Self, message_type: Symbol, message: Value) -> Result<Value, Error> {
+        let ty = message_type_from_symbol(ruby, message_type)?;
+        let message = ControlMessage::build(ty, RubyPayload { ruby, value: message })?;
+
+        let mut buffer = BytesMut::new();
+        message.encode(&mut buffer);
+
+        Self::io(ruby, rb_self)?.funcall("write", (ruby.str_from_slice(&buffer),))
+    }
+}
+
+/// Builds control messages out of Ruby hashes (or strings, for `udp_tunnel`).
+struct RubyPayload<'a> {
+    ruby: &'a Ruby,
+    value: Value,
+}
+
+impl PayloadSource for RubyPayload<'_> {
+    type Error = Error;
+
+    fn build<T: DeserializeOwned>(self) -> Result<T, Error> {
+        serde_magnus::deserialize(self.ruby, self.value)
+    }
+}
+
+/// Converts a message to `[type, message]`, omitting fields which are not set.
+fn message_to_value(ruby: &Ruby, message: &ControlMessage) -> Result<Value, Error> {
+    let payload: Value = serde_magnus::serialize(ruby, message)?;
+    let type_symbol = ruby.to_symbol(message.message_type().name());
+
+    Ok(ruby.ary_new_from_values(&[type_symbol.as_value(), compact(ruby, payload)?]).as_value())
+}
+
+/// Recursively removes unset fields from hashes: `nil` optional and empty repeated fields.
+fn compact(ruby: &Ruby, value: Value) -> Result<Value, Error> {
+    if let Some(hash) = RHash::from_value(value) {
+        let compacted = ruby.hash_new();
+        hash.foreach(|key: Value, value: Value| {
+            let empty = RArray::from_value(value).is_some_and(|array| array.is_empty());
+            if !value.is_nil() && !empty {
+                compacted.aset(key, compact(ruby, value)?)?;
+            }
+            Ok(ForEach::Continue)
+        })?;
+
+        Ok(compacted.as_value())
+    } else if let Some(array) = RArray::from_value(value) {
+        let compacted = ruby.ary_new_capa(array.len());
+        for item in array.to_vec::<Value>()? {
+            compacted.push(compact(ruby, item)?)?;
+        }
+
+        Ok(compacted.as_value())
+    } else {
+        Ok(value)
+    }
+}
+
+fn message_type_from_symbol(ruby: &Ruby, symbol: Symbol) -> Result<MessageType, Error> {
+    let name = symbol.name()?;
+    MessageType::from_name(&name)
+        .ok_or_else(|| Error::new(ruby.exception_arg_error(), format!("Unknown message type: {name}")))
+}
+
+fn control_error(ruby: &Ruby, error: ControlError) -> Error {
+    Error::new(ruby.get_inner(&BASE_ERROR), error.to_string())
+}
+
+fn direction_from_symbol(ruby: &Ruby, symbol: Symbol) -> Result<Direction, Error> {
+    match symbol.name()?.as_ref() {
+        "serverbound" => Ok(Direction::Serverbound),
+        "clientbound" => Ok(Direction::Clientbound),
+        name => Err(Error::new(
+            ruby.exception_arg_error(),
+            format!("Expected :serverbound or :clientbound, got :{name}"),
+        )),
+    }
+}
+
+fn decode_voice_packet(ruby: &Ruby, data: RString, direction: Symbol) -> Result<RHash, Error> {
+    let direction = direction_from_symbol(ruby, direction)?;
+    let buffer = Bytes::copy_from_slice(unsafe { data.as_slice() });
+    let packet = VoicePacket::decode(buffer, direction)
+        .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+    voice_packet_to_hash(ruby, &packet)
+}
+
+fn encode_voice_packet(ruby: &Ruby, packet: RHash, direction: Symbol) -> Result<RString, Error> {
+    let direction = direction_from_symbol(ruby, direction)?;
+    let packet = voice_packet_from_hash(ruby, packet)?;
+
+    let mut buffer = BytesMut::new();
+    packet
+        .encode(direction, &mut buffer)
+        .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+    Ok(ruby.str_from_slice(&buffer))
+}
+
+fn voice_packet_to_hash(ruby: &Ruby, packet: &VoicePacket) -> Result<RHash, Error> {
+    match packet {
+        VoicePacket::Ping { timestamp } => {
+            let hash = ruby.hash_new();
+            hash.aset(ruby.to_symbol("type"), ruby.to_symbol("ping"))?;
+            hash.aset(ruby.to_symbol("timestamp"), *timestamp)?;
+
+            Ok(hash)
+        },
+        VoicePacket::Audio(audio) => audio_packet_to_hash(ruby, audio),
+    }
+}
+
+fn audio_packet_to_hash(ruby: &Ruby, audio: &AudioPacket) -> Result<RHash, Error> {
+    let hash = ruby.hash_new();
+
+    hash.aset(ruby.to_symbol("type"), ruby.to_symbol(audio.payload.codec_name()))?;
+    hash.aset(ruby.to_symbol("target"), audio.target)?;
+    if let Some(session_id) = audio.session_id {
+        hash.aset(ruby.to_symbol("session_id"), session_id)?;
+    }
+    hash.aset(ruby.to_symbol("sequence"), audio.seq_num)?;
+    hash.aset(ruby.to_symbol("terminator"), audio.payload.is_terminator())?;
+
+    match &audio.payload {
+        VoicePayload::Opus(data, _) => {
+            hash.aset(ruby.to_symbol("payload"), ruby.str_from_slice(data))?;
+        },
+        VoicePayload::CeltAlpha(frames)
+        | VoicePayload::Speex(frames)
+        | VoicePayload::CeltBeta(frames) => {
+            let array = ruby.ary_new_capa(frames.len());
+            for frame in frames {
+                array.push(ruby.str_from_slice(frame))?;
+            }
+            hash.aset(ruby.to_symbol("frames"), array)?;
+        },
+    }
+
+    if let Some(Ok(info)) = audio.opus_info() {
+        hash.aset(ruby.to_symbol("opus"), opus_info_to_hash(ruby, &info)?)?;
+    }
+    if let Some(position_info) = &audio.position_info {
+        hash.aset(ruby.to_symbol("position_info"), ruby.str_from_slice(position_info))?;
+    }
+    if let Some(position) = audio.position() {
+        hash.aset(ruby.to_symbol("position"), position.to_array().to_vec())?;
+    }
+
+    Ok(hash)
+}
+
+fn voice_packet_from_hash(ruby: &Ruby, packet: RHash) -> Result<VoicePacket, Error> {
+    let codec: Symbol = packet.fetch(ruby.to_symbol("type"))?;
+
+    if codec.name()? == "ping" {
+        return Ok(VoicePacket::Ping { timestamp: packet.fetch(ruby.to_symbol("timestamp"))? });
+    }
+
+    audio_packet_from_hash(ruby, packet).map(VoicePacket::Audio)
+}
+
+fn audio_packet_from_hash(ruby: &Ruby, packet: RHash) -> Result<AudioPacket, Error> {
+    let codec: Symbol = packet.fetch(ruby.to_symbol("type"))?;
+
+    let payload = match codec.name()?.as_ref() {
+        "opus" => {
+            let data: RString = packet.fetch(ruby.to_symbol("payload"))?;
+            let terminator: Option<bool> = packet.lookup(ruby.to_symbol("terminator"))?;
+            VoicePayload::Opus(Bytes::copy_from_slice(unsafe { data.as_slice() }), terminator.unwrap_or(false))
+        },
+        name @ ("celt_alpha" | "speex" | "celt_beta") => {
+            let array: RArray = packet.fetch(ruby.to_symbol("frames"))?;
+            let mut frames = Vec::with_capacity(array.len());
+            for index in 0..array.len() {
+                let frame: RString = array.entry(index as isize)?;
+                frames.push(Bytes::copy_from_slice(unsafe { frame.as_slice() }));
+            }
+            match name {
+                "celt_alpha" => VoicePayload::CeltAlpha(frames),
+                "speex" => VoicePayload::Speex(frames),
+                _ => VoicePayload::CeltBeta(frames),
+            }
+        },
+        name => {
+            return Err(Error::new(ruby.exception_arg_error(), format!("Unknown voice packet type: {name}")))
+        },
+    };
+    let target: Option<u8> = packet.lookup(ruby.to_symbol("target"))?;
+    let position_info: Option<RString> = packet.lookup(ruby.to_symbol("position_info"))?;
+    let position_info = match position_info {
+        Some(info) => Some(Bytes::copy_from_slice(unsafe { info.as_slice() })),
+        None => packet.lookup::<_, Option<Value>>(ruby.to_symbol("position"))?
+            .map(|position| vector_from_value(ruby, position).map(|position| position.encode()))
+            .transpose()?,
+    };
+
+    Ok(AudioPacket {
+        target: target.unwrap_or(voice::TARGET_NORMAL),
+        session_id: packet.lookup(ruby.to_symbol("session_id"))?,
+        seq_num: packet.fetch(ruby.to_symbol("sequence"))?,
+        payload,
+        position_info,
+    })
+}
+
+fn parse_opus_packet(ruby: &Ruby, data: RString) -> Result<RHash, Error> {
+    let info = opus::parse_packet(unsafe { data.as_slice() })
+        .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), format!("Malformed Opus packet: {e}")))?;
+
+    opus_info_to_hash(ruby, &info)
+}
+
+fn opus_info_to_hash(ruby: &Ruby, info: &opus::PacketInfo) -> Result<RHash, Error> {
+    let hash = ruby.hash_new();
+
+    hash.aset(ruby.to_symbol("mode"), ruby.to_symbol(info.mode().name()))?;
+    hash.aset(ruby.to_symbol("bandwidth"), ruby.to_symbol(info.bandwidth().name()))?;
+    hash.aset(ruby.to_symbol("stereo"), info.stereo())?;
+    hash.aset(ruby.to_symbol("frame_sizes"), info.frame_sizes.clone())?;
+    hash.aset(ruby.to_symbol("frame_duration"), info.frame_duration_ms())?;
+    hash.aset(ruby.to_symbol("duration"), info.duration_ms())?;
+    hash.aset(ruby.to_symbol("samples"), info.total_samples())?;
+
+    Ok(hash)
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::JitterBuffer", name = "Rust JitterBuffer wrapper", free_immediately, size)]
+struct JitterBufferRef {
+    buffer: RefCell<JitterBuffer>,
+    /// Origin of the clock used when no explicit time is passed.
+    epoch: Instant,
+}
+
+impl Default for JitterBufferRef {
+    fn default() -> Self {
+        JitterBufferRef {
+            buffer: RefCell::new(JitterBuffer::default()),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl JitterBufferRef {
+    fn initialize(
+      rb_self: typed_data::Obj<Self>,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (), (Option<u64>, Option<u64>, Option<usize>, Option<u64>), ()>(
+          args.keywords,
+          &[],
+          &["min_delay", "max_delay", "max_packets", "idle_timeout"],
+      )?;
+      let (min_delay, max_delay, max_packets, idle_timeout) = kwargs.optional;
+      let defaults = jitter_buffer::Config::default();
+
+      let config = jitter_buffer::Config {
+          min_delay_ms: min_delay.unwrap_or(defaults.min_delay_ms),
+          max_delay_ms: max_delay.unwrap_or(defaults.max_delay_ms),
+          max_packets: max_packets.unwrap_or(defaults.max_packets),
+          idle_timeout_ms: idle_timeout.unwrap_or(defaults.idle_timeout_ms),
+      };
+      *rb_self.buffer.borrow_mut() = JitterBuffer::new(config);
+
+      Ok(())
+    }
+
+    fn now_ms(&self, now: Option<u64>) -> u64 {
+        now.unwrap_or_else(|| self.epoch.elapsed().as_millis() as u64)
+    }
+
+    pub fn push(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Symbol, Error> {
+        let args = scan_args::<(u32, RHash), (Option<u64>,), (), (), (), ()>(args)?;
+        let (session, packet) = args.required;
+        let now = rb_self.now_ms(args.optional.0);
+        let packet = audio_packet_from_hash(ruby, packet)?;
+
+        match rb_self.buffer.try_borrow_mut() {
+            Ok(mut buffer) => {
+                let result = match buffer.push(session, packet, now) {
+                    Push::Queued => "queued",
+                    Push::Late => "late",
+                    Push::Duplicate => "duplicate",
+                };
+
+                Ok(ruby.to_symbol(result))
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn poll(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<RArray, Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        let events = match rb_self.buffer.try_borrow_mut() {
+            Ok(mut buffer) => buffer.poll(now),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(events.len());
+        for (session, event) in events {
+            let (kind, value) = match event {
+                Playout::Audio(packet) => ("audio", audio_packet_to_hash(ruby, &packet)?.as_value()),
+                Playout::Gap(frames) => ("gap", ruby.into_value(frames)),
+                Playout::End => ("end", ruby.qnil().as_value()),
+            };
+            array.push((session, ruby.to_symbol(kind), value))?;
+        }
+
+        Ok(array)
+    }
+
+    pub fn remove(ruby: &Ruby, rb_self: &Self, session: u32) -> Result<bool, Error> {
+        match rb_self.buffer.try_borrow_mut() {
+            Ok(mut buffer) => Ok(buffer.remove(session).is_some()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn stats(ruby: &Ruby, rb_self: &Self, session: u32) -> Result<Option<RHash>, Error> {
+        match rb_self.buffer.try_borrow() {
+            Ok(buffer) => {
+                let speaker = match buffer.speaker(session) {
+                    Some(speaker) => speaker,
+                    None => return Ok(None),
+                };
+                let stats = speaker.stats();
+                let hash = Ruby::hash_new(ruby);
+
+                let _ = hash.aset(Ruby::to_symbol(ruby, "late"), stats.late);
+                let _ = hash.aset(Ruby::to_symbol(ruby, "duplicate"), stats.duplicate);
+                let _ = hash.aset(Ruby::to_symbol(ruby, "lost"), stats.lost);
+                let _ = hash.aset(Ruby::to_symbol(ruby, "overflow"), stats.overflow);
+                let _ = hash.aset(Ruby::to_symbol(ruby, "buffered"), speaker.len());
+                let _ = hash.aset(Ruby::to_symbol(ruby, "jitter"), speaker.jitter_ms());
+                let _ = hash.aset(Ruby::to_symbol(ruby, "delay"), speaker.delay_ms());
+
+                Ok(Some(hash))
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+#[derive(TypedData, Default)]
+#[magnus(class = "RbMumbleProtocol::OggOpusWriter", name = "Rust OggOpusWriter wrapper", free_immediately, size, mark)]
+struct OggOpusWriterRef {
+    io: RefCell<Option<Opaque<Value>>>,
+    /// Pages are assembled in memory and handed over to the IO after every call.
+    writer: RefCell<Option<OggOpusWriter<Vec<u8>>>>,
+}
+
+impl DataTypeFunctions for OggOpusWriterRef {
+    fn mark(&self, marker: &gc::Marker) {
+        if let Ok(io) = self.io.try_borrow() {
+            if let Some(io) = *io {
+                marker.mark(io);
+            }
+        }
+    }
+}
+
+impl OggOpusWriterRef {
+    fn initialize(
+      ruby: &Ruby,
+      rb_self: typed_data::Obj<Self>,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(Value,), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (), (Option<u8>, Option<u16>, Option<Vec<String>>), ()>(
+          args.keywords,
+          &[],
+          &["channels", "pre_skip", "comments"],
+      )?;
+      let (io,) = args.required;
+      let (channels, pre_skip, comments) = kwargs.optional;
+      let defaults = ogg_opus::Config::default();
+
+      let channels = channels.unwrap_or(defaults.channels);
+      if !(1..=2).contains(&channels) {
+          return Err(Error::new(ruby.exception_arg_error(), "channels must be 1 or 2"));
+      }
+
+      let mut serial = [0u8; 4];
+      openssl::rand::rand_bytes(&mut serial)
+          .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+      let config = ogg_opus::Config {
+          channels,
+          pre_skip: pre_skip.unwrap_or(defaults.pre_skip),
+          serial: u32::from_le_bytes(serial),
+          comments: comments.unwrap_or_default(),
+          ..defaults
+      };
+
+      *rb_self.io.borrow_mut() = Some(io.into());
+      *rb_self.writer.borrow_mut() = Some(OggOpusWriter::new(Vec::new(), config));
+
+      Ok(())
+    }
+
+    pub fn io(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        match rb_self.io.try_borrow() {
+            Ok(io) => match *io {
+                Some(io) => Ok(ruby.get_inner(io)),
+                None => Err(Error::new(ruby.get_inner(&BASE_ERROR), "writer is not initialized")),
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Runs `f` on the writer and writes out whatever pages it completed.
+    fn with_writer<T>(
+        ruby: &Ruby,
+        rb_self: &Self,
+        f: impl FnOnce(&mut OggOpusWriter<Vec<u8>>) -> std::io::Result<T>,
+    ) -> Result<T, Error> {
+        let (result, pages) = match rb_self.writer.try_borrow_mut() {
+            Ok(mut writer) => match writer.as_mut() {
+                Some(writer) => {
+                    let result = f(writer);
+                    (result, std::mem::take(writer.get_mut()))
+                },
+                None => return Err(Error::new(ruby.exception_io_error(), "closed stream")),
+            },
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        if !pages.is_empty() {
+            let _: Value = Self::io(ruby, rb_self)?.funcall("write", (ruby.str_from_slice(&pages),))?;
+        }
+        result.map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))
+    }
+
+    /// Appends the Opus payload of a decoded voice packet, returns `:appended` or `:skipped`.
+    pub fn write_packet(ruby: &Ruby, rb_self: &Self, packet: RHash) -> Result<Symbol, Error> {
+        let packet = audio_packet_from_hash(ruby, packet)?;
+        let payload = match &packet.payload {
+            VoicePayload::Opus(payload, _) => payload,
+            other => {
+                return Err(Error::new(ruby.exception_arg_error(), format!("Expected an Opus packet, got {}", other.codec_name())))
+            },
+        };
+
+        // Terminators may come without any audio
+        if payload.is_empty() {
+            return Ok(ruby.to_symbol("skipped"));
+        }
+
+        let written = Self::with_writer(ruby, rb_self, |writer| writer.write_packet(packet.seq_num, payload))?;
+        match written {
+            Written::Appended { .. } => Ok(ruby.to_symbol("appended")),
+            Written::Skipped => Ok(ruby.to_symbol("skipped")),
+        }
+    }
+
+    /// Appends this many 10 ms frames of silence, at most a minute.
+    pub fn write_silence(ruby: &Ruby, rb_self: &Self, frames: u64) -> Result<(), Error> {
+        Self::with_writer(ruby, rb_self, |writer| writer.write_silence(frames))
+    }
+
+    /// Duration of the recording so far, in samples at 48 kHz.
+    pub fn samples(ruby: &Ruby, rb_self: &Self) -> Result<u64, Error> {
+        match rb_self.writer.try_borrow() {
+            Ok(writer) => Ok(writer.as_ref().map(|writer| writer.samples()).unwrap_or(0)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Writes the final page. The IO itself is left open.
+    pub fn close(ruby: &Ruby, rb_self: &Self) -> Result<(), Error> {
+        let writer = match rb_self.writer.try_borrow_mut() {
+            Ok(mut writer) => writer.take(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        if let Some(writer) = writer {
+            let pages = writer.finish().map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+            let _: Value = Self::io(ruby, rb_self)?.funcall("write", (ruby.str_from_slice(&pages),))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(ruby: &Ruby, rb_self: &Self) -> Result<bool, Error> {
+        match rb_self.writer.try_borrow() {
+            Ok(writer) => Ok(writer.is_none()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::PcapReader", name = "Rust PcapReader wrapper", free_immediately, size)]
+#[derive(Default)]
+struct PcapReaderRef {
+    reader: RefCell<Option<PcapReader<Cursor<Vec<u8>>>>>,
+}
+
+impl PcapReaderRef {
+    fn initialize(ruby: &Ruby, rb_self: &Self, data: RString) -> Result<(), Error> {
+        let reader = PcapReader::new(Cursor::new(unsafe { data.as_slice() }.to_vec()))
+            .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+        *rb_self.reader.borrow_mut() = Some(reader);
+
+        Ok(())
+    }
+
+    /// Returns the next UDP datagram or TCP segment, `nil` at the end of the capture.
+    pub fn next_packet(ruby: &Ruby, rb_self: &Self) -> Result<Option<RHash>, Error> {
+        let packet = match rb_self.reader.try_borrow_mut() {
+            Ok(mut reader) => match reader.as_mut() {
+                Some(reader) => reader.next_packet()
+                    .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?,
+                None => return Err(Error::new(ruby.get_inner(&BASE_ERROR), "reader is not initialized")),
+            },
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        match packet {
+            Some(packet) => Ok(Some(pcap_packet_to_hash(ruby, &packet)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(TypedData, Default)]
+#[magnus(class = "RbMumbleProtocol::PcapWriter", name = "Rust PcapWriter wrapper", free_immediately, size, mark)]
+struct PcapWriterRef {
+    io: RefCell<Option<Opaque<Value>>>,
+    /// Records are assembled in memory and handed over to the IO after every call.
+    writer: RefCell<Option<PcapWriter<Vec<u8>>>>,
+}
+
+impl DataTypeFunctions for PcapWriterRef {
+    fn mark(&self, marker: &gc::Marker) {
+        if let Ok(io) = self.io.try_borrow() {
+            if let Some(io) = *io {
+                marker.mark(io);
+            }
+        }
+    }
+}
+
+impl PcapWriterRef {
+    fn initialize(ruby: &Ruby, rb_self: typed_data::Obj<Self>, io: Value) -> Result<(), Error> {
+        let writer = PcapWriter::new(Vec::new())
+            .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+        *rb_self.io.borrow_mut() = Some(io.into());
+        *rb_self.writer.borrow_mut() = Some(writer);
+
+        Self::write_pending(ruby, &rb_self)
+    }
+
+    pub fn io(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        match rb_self.io.try_borrow() {
+            Ok(io) => match *io {
+                Some(io) => Ok(ruby.get_inner(io)),
+                None => Err(Error::new(ruby.get_inner(&BASE_ERROR), "writer is not initialized")),
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Hands the records written so far over to the IO.
+    fn write_pending(ruby: &Ruby, rb_self: &Self) -> Result<(), Error> {
+        let pending = match rb_self.writer.try_borrow_mut() {
+            Ok(mut writer) => writer.as_mut().map(|writer| std::mem::take(writer.get_mut())).unwrap_or_default(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        if !pending.is_empty() {
+            let _: Value = Self::io(ruby, rb_self)?.funcall("write", (ruby.str_from_slice(&pending),))?;
+        }
+        Ok(())
+    }
+
+    fn write(ruby: &Ruby, rb_self: &Self, protocol: Transport, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(String, String, RString), (Option<u64>,), (), (), (), ()>(args)?;
+        let (source, destination, payload) = args.required;
+        let source = socket_addr_from_string(ruby, &source)?;
+        let destination = socket_addr_from_string(ruby, &destination)?;
+        let timestamp = args.optional.0.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_micros() as u64).unwrap_or(0)
+        });
+
+        let result = match rb_self.writer.try_borrow_mut() {
+            Ok(mut writer) => match writer.as_mut() {
+                Some(writer) => {
+                    let payload = unsafe { payload.as_slice() };
+                    match protocol {
+                        Transport::Udp => writer.write_udp(timestamp, source, destination, payload),
+                        Transport::Tcp { .. } => writer.write_tcp(timestamp, source, destination, payload),
+                    }
+                },
+                None => return Err(Error::new(ruby.get_inner(&BASE_ERROR), "writer is not initialized")),
+            },
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+        result.map_err(|e| Error::new(ruby.exception_arg_error(), e.to_string()))?;
+
+        Self::write_pending(ruby, rb_self)
+    }
+
+    pub fn write_udp(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        Self::write(ruby, rb_self, Transport::Udp, args)
+    }
+
+    pub fn write_tcp(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        Self::write(ruby, rb_self, Transport::Tcp { seq: 0, flags: 0 }, args)
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::PcapReplay", name = "Rust PcapReplay wrapper", free_immediately, size)]
+#[derive(Default)]
+struct PcapReplayRef {
+    replay: RefCell<Option<Replay>>,
+}
+
+impl PcapReplayRef {
+    fn initialize(
+      ruby: &Ruby,
+      rb_self: &Self,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (u16, RString, RString, RString), (), ()>(
+          args.keywords,
+          &["server_port", "key", "client_nonce", "server_nonce"],
+          &[],
+      )?;
+      let (server_port, key, client_nonce, server_nonce) = kwargs.required;
+
+      let replay = Replay::new(
+          server_port,
+          rstring_to_array(ruby, &key)?,
+          rstring_to_array(ruby, &client_nonce)?,
+          rstring_to_array(ruby, &server_nonce)?,
+      );
+      *rb_self.replay.borrow_mut() = Some(replay);
+
+      Ok(())
+    }
+
+    /// Decodes a packet returned by `PcapReader#next_packet`, returns the events found in it.
+    pub fn feed(ruby: &Ruby, rb_self: &Self, packet: RHash) -> Result<RArray, Error> {
+        let packet = pcap_packet_from_hash(ruby, packet)?;
+        let events = match rb_self.replay.try_borrow_mut() {
+            Ok(mut replay) => match replay.as_mut() {
+                Some(replay) => replay.feed(&packet),
+                None => return Err(Error::new(ruby.get_inner(&BASE_ERROR), "replay is not initialized")),
+            },
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(events.len());
+        for event in events {
+            let hash = ruby.hash_new();
+            hash.aset(ruby.to_symbol("timestamp"), event.timestamp_us)?;
+            hash.aset(ruby.to_symbol("direction"), ruby.to_symbol(direction_name(event.direction)))?;
+            hash.aset(ruby.to_symbol("protocol"), ruby.to_symbol(event.transport.name()))?;
+
+            let kind = match event.kind {
+                EventKind::Voice(packet) => {
+                    hash.aset(ruby.to_symbol("packet"), voice_packet_to_hash(ruby, &packet)?)?;
+                    "voice"
+                },
+                EventKind::Control(message) => {
+                    let payload: Value = serde_magnus::serialize(ruby, &*message)?;
+                    hash.aset(ruby.to_symbol("type"), ruby.to_symbol(message.message_type().name()))?;
+                    hash.aset(ruby.to_symbol("message"), compact(ruby, payload)?)?;
+                    "control"
+                },
+                EventKind::Decrypt(e) => {
+                    hash.aset(ruby.to_symbol("reason"), decrypt_error_symbol(ruby, e))?;
+                    "decrypt_failed"
+                },
+                EventKind::VoiceError(e) => {
+                    hash.aset(ruby.to_symbol("error"), e.to_string())?;
+                    "error"
+                },
+                EventKind::ControlError(e) => {
+                    hash.aset(ruby.to_symbol("error"), e.to_string())?;
+                    "error"
+                },
+                EventKind::MissingData(bytes) => {
+                    hash.aset(ruby.to_symbol("bytes"), bytes)?;
+                    "missing_data"
+                },
+                EventKind::Tls => "tls",
+            };
+            hash.aset(ruby.to_symbol("kind"), ruby.to_symbol(kind))?;
+            array.push(hash)?;
+        }
+
+        Ok(array)
+    }
+
+    /// Decryption statistics of the given direction.
+    pub fn stats(ruby: &Ruby, rb_self: &Self, direction: Symbol) -> Result<RHash, Error> {
+        let direction = direction_from_symbol(ruby, direction)?;
+        match rb_self.replay.try_borrow() {
+            Ok(replay) => {
+                let replay = replay.as_ref()
+                    .ok_or_else(|| Error::new(ruby.get_inner(&BASE_ERROR), "replay is not initialized"))?;
+                let state = replay.crypt_state(direction);
+                let hash = Ruby::hash_new(ruby);
+
+                let _ = hash.aset(Ruby::to_symbol(ruby, "good"), state.get_good());
+                let _ = hash.aset(Ruby::to_symbol(ruby, "late"), state.get_late());
+                let _ = hash.aset(Ruby::to_symbol(ruby, "lost"), state.get_lost());
+
+                Ok(hash)
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Serverbound => "serverbound",
+        Direction::Clientbound => "clientbound",
+    }
+}
+
+fn socket_addr_from_string(ruby: &Ruby, address: &str) -> Result<SocketAddr, Error> {
+    address.parse()
+        .map_err(|_| Error::new(ruby.exception_arg_error(), format!("Expected an address like \"10.0.0.1:64738\", got {address:?}")))
+}
+
+fn pcap_packet_to_hash(ruby: &Ruby, packet: &Packet) -> Result<RHash, Error> {
+    let hash = ruby.hash_new();
+
+    hash.aset(ruby.to_symbol("timestamp"), packet.timestamp_us)?;
+    hash.aset(ruby.to_symbol("protocol"), ruby.to_symbol(packet.transport.name()))?;
+    hash.aset(ruby.to_symbol("source"), packet.source.to_string())?;
+    hash.aset(ruby.to_symbol("destination"), packet.destination.to_string())?;
+    if let Transport::Tcp { seq, flags } = packet.transport {
+        hash.aset(ruby.to_symbol("seq"), seq)?;
+        hash.aset(ruby.to_symbol("flags"), flags)?;
+    }
+    hash.aset(ruby.to_symbol("payload"), ruby.str_from_slice(&packet.payload))?;
+
+    Ok(hash)
+}
+
+fn pcap_packet_from_hash(ruby: &Ruby, packet: RHash) -> Result<Packet, Error> {
+    let protocol: Symbol = packet.fetch(ruby.to_symbol("protocol"))?;
+    let transport = match protocol.name()?.as_ref() {
+        "udp" => Transport::Udp,
+        "tcp" => Transport::Tcp {
+            seq: packet.fetch(ruby.to_symbol("seq"))?,
+            flags: packet.lookup::<_, Option<u8>>(ruby.to_symbol("flags"))?.unwrap_or(0),
+        },
+        name => return Err(Error::new(ruby.exception_arg_error(), format!("Expected :udp or :tcp, got :{name}"))),
+    };
+    let source: String = packet.fetch(ruby.to_symbol("source"))?;
+    let destination: String = packet.fetch(ruby.to_symbol("destination"))?;
+    let payload: RString = packet.fetch(ruby.to_symbol("payload"))?;
+    let timestamp: Option<u64> = packet.lookup(ruby.to_symbol("timestamp"))?;
+
+    Ok(Packet {
+        timestamp_us: timestamp.unwrap_or(0),
+        transport,
+        source: socket_addr_from_string(ruby, &source)?,
+        destination: socket_addr_from_string(ruby, &destination)?,
+        payload: unsafe { payload.as_slice() }.to_vec(),
+    })
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::ChannelTree", name = "Rust ChannelTree wrapper", free_immediately, size)]
+#[derive(Default)]
+struct ChannelTreeRef {
+    tree: RefCell<ChannelTree>,
+}
+
+impl ChannelTreeRef {
+    pub fn apply_state(ruby: &Ruby, rb_self: &Self, state: Value) -> Result<Symbol, Error> {
+        let state: mumble_proto::ChannelState = serde_magnus::deserialize(ruby, state)?;
+
+        match rb_self.tree.try_borrow_mut() {
+            Ok(mut tree) => {
+                let change = tree.apply_state(&state)
+                    .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+                Ok(ruby.to_symbol(match change {
+                    Change::Created => "created",
+                    Change::Updated => "updated",
+                }))
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Removes the channel and its descendants, returns the removed ids.
+    pub fn apply_remove(ruby: &Ruby, rb_self: &Self, channel_id: u32) -> Result<Vec<u32>, Error> {
+        match rb_self.tree.try_borrow_mut() {
+            Ok(mut tree) => tree.apply_remove(channel_id)
+                .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the channel as a `ChannelState` hash with its `children`, or `nil`.
+    pub fn get(ruby: &Ruby, rb_self: &Self, channel_id: u32) -> Result<Option<Value>, Error> {
+        match rb_self.tree.try_borrow() {
+            Ok(tree) => match tree.get(channel_id) {
+                Some(channel) => {
+                    let state: Value = serde_magnus::serialize(ruby, &channel.to_state(true))?;
+                    let hash = RHash::from_value(compact(ruby, state)?).unwrap();
+                    hash.aset(ruby.to_symbol("children"), channel.children().iter().copied().collect::<Vec<u32>>())?;
+
+                    Ok(Some(hash.as_value()))
+                },
+                None => Ok(None),
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn ids(ruby: &Ruby, rb_self: &Self) -> Result<Vec<u32>, Error> {
+        match rb_self.tree.try_borrow() {
+            Ok(tree) => Ok(tree.iter().map(|channel| channel.id).collect()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn size(ruby: &Ruby, rb_self: &Self) -> Result<usize, Error> {
+        match rb_self.tree.try_borrow() {
+            Ok(tree) => Ok(tree.len()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn path(ruby: &Ruby, rb_self: &Self, channel_id: u32) -> Result<Option<Vec<u32>>, Error> {
+        match rb_self.tree.try_borrow() {
+            Ok(tree) => Ok(tree.path(channel_id)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn descendants(ruby: &Ruby, rb_self: &Self, channel_id: u32) -> Result<Vec<u32>, Error> {
+        match rb_self.tree.try_borrow() {
+            Ok(tree) => Ok(tree.descendants(channel_id)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn linked(ruby: &Ruby, rb_self: &Self, channel_id: u32) -> Result<Vec<u32>, Error> {
+        match rb_self.tree.try_borrow() {
+            Ok(tree) => Ok(tree.linked(channel_id).into_iter().collect()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns `[:channel_state, message]` pairs describing the whole tree, ready for
+    /// `ControlStream#write_message`.
+    pub fn full_sync(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        let messages = match rb_self.tree.try_borrow() {
+            Ok(tree) => tree.full_sync(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(messages.len());
+        for message in messages {
+            array.push(message_to_value(ruby, &ControlMessage::from(message))?)?;
+        }
+
+        Ok(array)
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::UserRegistry", name = "Rust UserRegistry wrapper", free_immediately, size)]
+#[derive(Default)]
+struct UserRegistryRef {
+    registry: RefCell<UserRegistry>,
+}
+
+impl UserRegistryRef {
+    /// Applies a `UserState` hash, returns the resulting change event.
+    pub fn apply_state(ruby: &Ruby, rb_self: &Self, state: Value) -> Result<RHash, Error> {
+        let state: mumble_proto::UserState = serde_magnus::deserialize(ruby, state)?;
+        let change = match rb_self.registry.try_borrow_mut() {
+            Ok(mut registry) => registry.apply_state(&state)
+                .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?,
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let hash = ruby.hash_new();
+        let event = if change.created { "connected" } else { "updated" };
+        hash.aset(ruby.to_symbol("event"), ruby.to_symbol(event))?;
+        hash.aset(ruby.to_symbol("session"), change.session)?;
+        let fields = ruby.ary_new_capa(change.fields.len());
+        for field in &change.fields {
+            fields.push(ruby.to_symbol(field.name()))?;
+        }
+        hash.aset(ruby.to_symbol("changed"), fields)?;
+        if let Some(previous_channel_id) = change.previous_channel_id {
+            hash.aset(ruby.to_symbol("previous_channel_id"), previous_channel_id)?;
+        }
+        if let Some(actor) = change.actor {
+            hash.aset(ruby.to_symbol("actor"), actor)?;
+        }
+
+        Ok(hash)
+    }
+
+    /// Removes a user, returns its last state.
+    pub fn apply_remove(ruby: &Ruby, rb_self: &Self, session: u32) -> Result<RHash, Error> {
+        let user = match rb_self.registry.try_borrow_mut() {
+            Ok(mut registry) => registry.apply_remove(session)
+                .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?,
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        user_to_hash(ruby, &user)
+    }
+
+    pub fn get(ruby: &Ruby, rb_self: &Self, session: u32) -> Result<Option<RHash>, Error> {
+        match rb_self.registry.try_borrow() {
+            Ok(registry) => registry.get(session).map(|user| user_to_hash(ruby, user)).transpose(),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn find_by_name(ruby: &Ruby, rb_self: &Self, name: String) -> Result<Option<RHash>, Error> {
+        match rb_self.registry.try_borrow() {
+            Ok(registry) => registry.find_by_name(&name).map(|user| user_to_hash(ruby, user)).transpose(),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn sessions(ruby: &Ruby, rb_self: &Self) -> Result<Vec<u32>, Error> {
+        match rb_self.registry.try_borrow() {
+            Ok(registry) => Ok(registry.iter().map(|user| user.session).collect()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn size(ruby: &Ruby, rb_self: &Self) -> Result<usize, Error> {
+        match rb_self.registry.try_borrow() {
+            Ok(registry) => Ok(registry.len()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn in_channel(ruby: &Ruby, rb_self: &Self, channel_id: u32) -> Result<Vec<u32>, Error> {
+        match rb_self.registry.try_borrow() {
+            Ok(registry) => Ok(registry.in_channel(channel_id)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Records the codecs a user's client advertised in `Authenticate`.
+    pub fn set_codecs(ruby: &Ruby, rb_self: &Self, session: u32, celt_versions: Vec<i32>, opus: bool) -> Result<(), Error> {
+        match rb_self.registry.try_borrow_mut() {
+            Ok(mut registry) => registry.set_codecs(session, Codecs { celt_versions, opus })
+                .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn listeners(ruby: &Ruby, rb_self: &Self, channel_id: u32) -> Result<Vec<u32>, Error> {
+        match rb_self.registry.try_borrow() {
+            Ok(registry) => Ok(registry.listeners(channel_id)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns `[:user_state, message]` pairs describing every user, ready for
+    /// `ControlStream#write_message`.
+    pub fn full_sync(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        let messages = match rb_self.registry.try_borrow() {
+            Ok(registry) => registry.full_sync(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(messages.len());
+        for message in messages {
+            array.push(message_to_value(ruby, &ControlMessage::from(message))?)?;
+        }
+
+        Ok(array)
+    }
+}
+
+fn user_to_hash(ruby: &Ruby, user: &User) -> Result<RHash, Error> {
+    let hash = ruby.hash_new();
+
+    hash.aset(ruby.to_symbol("session"), user.session)?;
+    hash.aset(ruby.to_symbol("name"), user.name.as_str())?;
+    hash.aset(ruby.to_symbol("user_id"), user.user_id)?;
+    hash.aset(ruby.to_symbol("channel_id"), user.channel_id)?;
+    hash.aset(ruby.to_symbol("mute"), user.mute)?;
+    hash.aset(ruby.to_symbol("deaf"), user.deaf)?;
+    hash.aset(ruby.to_symbol("suppress"), user.suppress)?;
+    hash.aset(ruby.to_symbol("self_mute"), user.self_mute)?;
+    hash.aset(ruby.to_symbol("self_deaf"), user.self_deaf)?;
+    hash.aset(ruby.to_symbol("priority_speaker"), user.priority_speaker)?;
+    hash.aset(ruby.to_symbol("recording"), user.recording)?;
+    hash.aset(ruby.to_symbol("texture"), user.texture.as_deref().map(|texture| ruby.str_from_slice(texture)))?;
+    hash.aset(ruby.to_symbol("texture_hash"), user.texture_hash.as_deref().map(|hash| ruby.str_from_slice(hash)))?;
+    hash.aset(ruby.to_symbol("comment"), user.comment.as_deref())?;
+    hash.aset(ruby.to_symbol("comment_hash"), user.comment_hash.as_deref().map(|hash| ruby.str_from_slice(hash)))?;
+    hash.aset(ruby.to_symbol("hash"), user.hash.as_deref())?;
+    hash.aset(ruby.to_symbol("plugin_context"), user.plugin_context.as_deref().map(|context| ruby.str_from_slice(context)))?;
+    hash.aset(ruby.to_symbol("plugin_identity"), user.plugin_identity.as_deref())?;
+    hash.aset(ruby.to_symbol("listening_channels"), user.listening_channels.iter().copied().collect::<Vec<u32>>())?;
+
+    let volumes = ruby.hash_new();
+    for (&channel, &volume) in &user.listening_volumes {
+        volumes.aset(channel, volume)?;
+    }
+    hash.aset(ruby.to_symbol("listening_volumes"), volumes)?;
+    hash.aset(ruby.to_symbol("celt_versions"), user.codecs.celt_versions.clone())?;
+    hash.aset(ruby.to_symbol("opus"), user.codecs.opus)?;
+
+    Ok(hash)
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::AclEvaluator", name = "Rust AclEvaluator wrapper", free_immediately, size)]
+#[derive(Default)]
+struct AclEvaluatorRef {
+    store: RefCell<AclStore>,
+}
+
+impl AclEvaluatorRef {
+    /// Replaces the ACL of a channel with the one of an `ACL` message hash.
+    pub fn apply_acl(ruby: &Ruby, rb_self: &Self, acl: Value) -> Result<(), Error> {
+        let acl: mumble_proto::Acl = serde_magnus::deserialize(ruby, acl)?;
+
+        match rb_self.store.try_borrow_mut() {
+            Ok(mut store) => { store.apply(&acl); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn remove(ruby: &Ruby, rb_self: &Self, channel_id: u32) -> Result<bool, Error> {
+        match rb_self.store.try_borrow_mut() {
+            Ok(mut store) => Ok(store.remove(channel_id).is_some()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the permission bitmask of the user in the channel.
+    pub fn permissions(ruby: &Ruby, rb_self: &Self, tree: &ChannelTreeRef, user: RHash, channel_id: u32) -> Result<u32, Error> {
+        let identity = identity_from_hash(ruby, user)?;
+
+        match (rb_self.store.try_borrow(), tree.tree.try_borrow()) {
+            (Ok(store), Ok(tree)) => Ok(store.effective_permissions(&tree, &identity, channel_id).bits()),
+            _ => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Whether the user belongs to the group, evaluated in the given channel.
+    pub fn is_member(ruby: &Ruby, rb_self: &Self, tree: &ChannelTreeRef, user: RHash, channel_id: u32, group: String) -> Result<bool, Error> {
+        let identity = identity_from_hash(ruby, user)?;
+
+        match (rb_self.store.try_borrow(), tree.tree.try_borrow()) {
+            (Ok(store), Ok(tree)) => Ok(store.is_member(&tree, channel_id, channel_id, &group, &identity)),
+            _ => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+/// Reads a user hash, as returned by `UserRegistry#[]`, optionally with `strong` and
+/// `access_tokens`.
+fn identity_from_hash(ruby: &Ruby, user: RHash) -> Result<Identity, Error> {
+    Ok(Identity {
+        user_id: user.lookup(ruby.to_symbol("user_id"))?,
+        channel_id: user.lookup::<_, Option<u32>>(ruby.to_symbol("channel_id"))?.unwrap_or(channel_tree::ROOT_ID),
+        cert_hash: user.lookup(ruby.to_symbol("hash"))?,
+        strong: user.lookup::<_, Option<bool>>(ruby.to_symbol("strong"))?.unwrap_or(false),
+        access_tokens: user.lookup::<_, Option<Vec<String>>>(ruby.to_symbol("access_tokens"))?.unwrap_or_default(),
+    })
+}
+
+fn permission_names(ruby: &Ruby, bits: u32) -> Vec<Symbol> {
+    Permissions::from_bits(bits).names().into_iter().map(|name| ruby.to_symbol(name)).collect()
+}
+
+fn permissions_from_names(ruby: &Ruby, names: Vec<Symbol>) -> Result<u32, Error> {
+    let names = names.iter().map(|name| name.name()).collect::<Result<Vec<_>, Error>>()?;
+
+    Permissions::from_names(names.iter().map(|name| name.as_ref()))
+        .map(Permissions::bits)
+        .map_err(|name| Error::new(ruby.exception_arg_error(), format!("Unknown permission :{name}")))
+}
+
+fn validate_permissions(ruby: &Ruby, bits: u32, root: bool) -> Result<(), Error> {
+    Permissions::from_bits(bits)
+        .validate(root)
+        .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))
+}
+
+fn applicable_permissions(root: bool) -> u32 {
+    Permissions::applicable(root).bits()
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::VoiceTargets", name = "Rust VoiceTargets wrapper", free_immediately, size)]
+#[derive(Default)]
+struct VoiceTargetsRef {
+    targets: RefCell<VoiceTargets>,
+}
+
+impl VoiceTargetsRef {
+    /// Registers a `VoiceTarget` message hash sent by the session.
+    pub fn apply_target(ruby: &Ruby, rb_self: &Self, session: u32, message: Value) -> Result<(), Error> {
+        let message: mumble_proto::VoiceTarget = serde_magnus::deserialize(ruby, message)?;
+
+        match rb_self.targets.try_borrow_mut() {
+            Ok(mut targets) => targets.apply(session, &message)
+                .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn set_credentials(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(u32,), (), (), (), _, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<bool>, Option<Vec<String>>), ()>(
+            args.keywords,
+            &[],
+            &["strong", "access_tokens"],
+        )?;
+        let (session,) = args.required;
+        let credentials = Credentials {
+            strong: kwargs.optional.0.unwrap_or(false),
+            access_tokens: kwargs.optional.1.unwrap_or_default(),
+        };
+
+        match rb_self.targets.try_borrow_mut() {
+            Ok(mut targets) => { targets.set_credentials(session, credentials); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn remove(ruby: &Ruby, rb_self: &Self, session: u32) -> Result<(), Error> {
+        match rb_self.targets.try_borrow_mut() {
+            Ok(mut targets) => { targets.remove(session); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the entries of a registered voice target as `VoiceTarget::Target` hashes, or `nil`.
+    pub fn target(ruby: &Ruby, rb_self: &Self, session: u32, id: u32) -> Result<Option<RArray>, Error> {
+        let targets = match rb_self.targets.try_borrow() {
+            Ok(targets) => targets,
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+        let entries = match targets.get(session, id) {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+
+        let array = ruby.ary_new_capa(entries.len());
+        for entry in entries {
+            array.push(compact(ruby, serde_magnus::serialize(ruby, entry)?)?)?;
+        }
+
+        Ok(Some(array))
+    }
+
+    /// Returns `[session, delivery]` pairs of the recipients of a voice packet, `delivery` being
+    /// one of `:normal`, `:shout`, `:whisper` and `:loopback`.
+    pub fn resolve(
+        ruby: &Ruby,
+        rb_self: &Self,
+        tree: &ChannelTreeRef,
+        registry: &UserRegistryRef,
+        acls: &AclEvaluatorRef,
+        sender: u32,
+        target: u8,
+    ) -> Result<RArray, Error> {
+        let recipients = match (
+            rb_self.targets.try_borrow(),
+            tree.tree.try_borrow(),
+            registry.registry.try_borrow(),
+            acls.store.try_borrow(),
+        ) {
+            (Ok(targets), Ok(tree), Ok(users), Ok(acls)) => {
+                let server = voice_target::Server { tree: &tree, users: &users, acls: &acls };
+                targets.resolve(server, sender, target)
+            },
+            _ => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(recipients.len());
+        for (session, delivery) in recipients {
+            array.push((session, ruby.to_symbol(delivery.name())))?;
+        }
+
+        Ok(array)
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::BanList", name = "Rust BanList wrapper", free_immediately, size)]
+#[derive(Default)]
+struct BanListRef {
+    list: RefCell<BanList>,
+}
+
+impl BanListRef {
+    /// Replaces all bans with those of a `BanList` message hash.
+    pub fn apply_list(ruby: &Ruby, rb_self: &Self, message: Value) -> Result<(), Error> {
+        let message: mumble_proto::BanList = serde_magnus::deserialize(ruby, message)?;
+
+        match rb_self.list.try_borrow_mut() {
+            Ok(mut list) => list.apply(&message)
+                .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn add(ruby: &Ruby, rb_self: &Self, ban: RHash) -> Result<(), Error> {
+        let ban = ban_from_hash(ruby, ban)?;
+
+        match rb_self.list.try_borrow_mut() {
+            Ok(mut list) => { list.insert(ban); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn remove(ruby: &Ruby, rb_self: &Self, index: usize) -> Result<Option<RHash>, Error> {
+        let ban = match rb_self.list.try_borrow_mut() {
+            Ok(mut list) => list.remove(index),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        ban.map(|ban| ban_to_hash(ruby, &ban)).transpose()
+    }
+
+    /// Removes the bans expired at `now` (Unix time, defaults to the current time), returns them.
+    pub fn remove_expired(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<RArray, Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = args.optional.0.unwrap_or_else(unix_time);
+
+        let expired = match rb_self.list.try_borrow_mut() {
+            Ok(mut list) => list.remove_expired(now),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(expired.len());
+        for ban in &expired {
+            array.push(ban_to_hash(ruby, ban)?)?;
+        }
+
+        Ok(array)
+    }
+
+    /// Returns the active ban covering the address (`"10.0.0.1"`) or certificate hash, or `nil`.
+    pub fn find(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Option<RHash>, Error> {
+        let args = scan_args::<(Option<String>,), (Option<String>, Option<u64>), (), (), (), ()>(args)?;
+        let (address,) = args.required;
+        let (hash, now) = args.optional;
+        let address = address
+            .map(|address| address.parse::<IpAddr>()
+                .map_err(|_| Error::new(ruby.exception_arg_error(), format!("Expected an IP address, got {address:?}"))))
+            .transpose()?;
+        let now = now.unwrap_or_else(unix_time);
+
+        match rb_self.list.try_borrow() {
+            Ok(list) => list.find(address, hash.as_deref(), now).map(|ban| ban_to_hash(ruby, ban)).transpose(),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn bans(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        let list = match rb_self.list.try_borrow() {
+            Ok(list) => list,
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(list.len());
+        for ban in list.iter() {
+            array.push(ban_to_hash(ruby, ban)?)?;
+        }
+
+        Ok(array)
+    }
+
+    pub fn size(ruby: &Ruby, rb_self: &Self) -> Result<usize, Error> {
+        match rb_self.list.try_borrow() {
+            Ok(list) => Ok(list.len()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the `BanList` message hash listing every ban.
+    pub fn to_message(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        let message = match rb_self.list.try_borrow() {
+            Ok(list) => list.to_message(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        compact(ruby, serde_magnus::serialize(ruby, &message)?)
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+fn ban_to_hash(ruby: &Ruby, ban: &Ban) -> Result<RHash, Error> {
+    let hash = ruby.hash_new();
+    let (address, mask) = ban.subnet();
+
+    hash.aset(ruby.to_symbol("address"), format!("{address}/{mask}"))?;
+    hash.aset(ruby.to_symbol("name"), ban.name.as_deref())?;
+    hash.aset(ruby.to_symbol("hash"), ban.hash.as_deref())?;
+    hash.aset(ruby.to_symbol("reason"), ban.reason.as_deref())?;
+    hash.aset(ruby.to_symbol("start"), ban.start)?;
+    hash.aset(ruby.to_symbol("duration"), ban.duration)?;
+    hash.aset(ruby.to_symbol("expires_at"), ban.expires_at())?;
+
+    Ok(hash)
+}
+
+/// Reads a ban hash, `address` being a subnet like `"10.0.0.0/8"` or a single address.
+fn ban_from_hash(ruby: &Ruby, hash: RHash) -> Result<Ban, Error> {
+    let subnet: String = hash.fetch(ruby.to_symbol("address"))?;
+    let invalid = || Error::new(ruby.exception_arg_error(), format!("Expected a subnet like \"10.0.0.0/8\", got {subnet:?}"));
+    let (address, mask) = match subnet.split_once('/') {
+        Some((address, mask)) => (address.parse::<IpAddr>().map_err(|_| invalid())?, Some(mask.parse::<u8>().map_err(|_| invalid())?)),
+        None => (subnet.parse::<IpAddr>().map_err(|_| invalid())?, None),
+    };
+    let mask = mask.unwrap_or(if address.is_ipv4() { 32 } else { 128 });
+    let start: Option<u64> = hash.lookup(ruby.to_symbol("start"))?;
+
+    let ban = Ban::new(address, mask, start.unwrap_or_else(unix_time))
+        .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+    Ok(Ban {
+        name: hash.lookup(ruby.to_symbol("name"))?,
+        hash: hash.lookup(ruby.to_symbol("hash"))?,
+        reason: hash.lookup(ruby.to_symbol("reason"))?,
+        duration: hash.lookup::<_, Option<u32>>(ruby.to_symbol("duration"))?.unwrap_or(0),
+        ..ban
+    })
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::Certificate", name = "Rust Certificate wrapper", free_immediately, size)]
+#[derive(Default)]
+struct CertificateRef {
+    certificate: RefCell<Option<Certificate>>,
+}
+
+impl CertificateRef {
+    fn initialize(ruby: &Ruby, rb_self: &Self, data: RString) -> Result<(), Error> {
+        let certificate = Certificate::parse(unsafe { data.as_slice() })
+            .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+        *rb_self.certificate.borrow_mut() = Some(certificate);
+        Ok(())
+    }
+
+    fn with_certificate<T>(
+        ruby: &Ruby,
+        rb_self: &Self,
+        f: impl FnOnce(&Certificate) -> Result<T, certificate::CertificateError>,
+    ) -> Result<T, Error> {
+        match rb_self.certificate.try_borrow() {
+            Ok(certificate) => match certificate.as_ref() {
+                Some(certificate) => f(certificate).map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string())),
+                None => Err(Error::new(ruby.get_inner(&BASE_ERROR), "certificate not initialized")),
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the Mumble hash of the certificate, as in `UserState#hash`.
+    pub fn hash(ruby: &Ruby, rb_self: &Self) -> Result<String, Error> {
+        Self::with_certificate(ruby, rb_self, Certificate::hash)
+    }
+
+    pub fn to_der(ruby: &Ruby, rb_self: &Self) -> Result<RString, Error> {
+        let der = Self::with_certificate(ruby, rb_self, Certificate::to_der)?;
+        Ok(ruby.str_from_slice(&der))
+    }
+
+    pub fn common_name(ruby: &Ruby, rb_self: &Self) -> Result<Option<String>, Error> {
+        Self::with_certificate(ruby, rb_self, |certificate| Ok(certificate.common_name()))
+    }
+
+    pub fn emails(ruby: &Ruby, rb_self: &Self) -> Result<Vec<String>, Error> {
+        Self::with_certificate(ruby, rb_self, |certificate| Ok(certificate.emails()))
+    }
+
+    pub fn is_self_signed(ruby: &Ruby, rb_self: &Self) -> Result<bool, Error> {
+        Self::with_certificate(ruby, rb_self, |certificate| Ok(certificate.is_self_signed()))
+    }
+
+    pub fn not_before(ruby: &Ruby, rb_self: &Self) -> Result<i64, Error> {
+        Self::with_certificate(ruby, rb_self, Certificate::not_before)
+    }
+
+    pub fn not_after(ruby: &Ruby, rb_self: &Self) -> Result<i64, Error> {
+        Self::with_certificate(ruby, rb_self, Certificate::not_after)
+    }
+
+    /// Whether the certificate is valid at `now` (Unix time, defaults to the current time).
+    pub fn is_valid(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<bool, Error> {
+        let args = scan_args::<(), (Option<i64>,), (), (), (), ()>(args)?;
+        let now = args.optional.0.unwrap_or_else(|| unix_time() as i64);
+
+        Self::with_certificate(ruby, rb_self, |certificate| certificate.is_valid_at(now))
+    }
+}
+
+fn certificate_hash(ruby: &Ruby, data: RString) -> Result<String, Error> {
+    certificate::hash(unsafe { data.as_slice() })
+        .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::CertificateBundle", name = "Rust CertificateBundle wrapper", free_immediately, size)]
+struct CertificateBundleRef {
+    bundle: Bundle,
+}
+
+impl CertificateBundleRef {
+    /// Generates a self-signed certificate, `key_type` being `:rsa` (the default) or `:ed25519`.
+    pub fn generate(ruby: &Ruby, args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(String,), (), (), (), _, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<String>, Option<Symbol>), ()>(
+            args.keywords,
+            &[],
+            &["email", "key_type"],
+        )?;
+        let (common_name,) = args.required;
+        let (email, key_type) = kwargs.optional;
+        let key_type = match key_type {
+            None => KeyType::Rsa2048,
+            Some(key_type) => match key_type.name()?.as_ref() {
+                "rsa" => KeyType::Rsa2048,
+                "ed25519" => KeyType::Ed25519,
+                name => return Err(Error::new(ruby.exception_arg_error(), format!("Expected :rsa or :ed25519, got :{name}"))),
+            },
+        };
+
+        let bundle = Bundle::generate(&common_name, email.as_deref(), key_type, unix_time() as i64)
+            .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+        Ok(CertificateBundleRef { bundle })
+    }
+
+    pub fn from_pkcs12(ruby: &Ruby, args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(RString,), (Option<String>,), (), (), (), ()>(args)?;
+        let (data,) = args.required;
+        let password = args.optional.0.unwrap_or_default();
+
+        let bundle = Bundle::from_pkcs12(unsafe { data.as_slice() }, &password)
+            .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+        Ok(CertificateBundleRef { bundle })
+    }
+
+    pub fn certificate(rb_self: &Self) -> CertificateRef {
+        CertificateRef { certificate: RefCell::new(Some(rb_self.bundle.certificate.clone())) }
+    }
+
+    pub fn certificate_pem(ruby: &Ruby, rb_self: &Self) -> Result<RString, Error> {
+        let pem = rb_self.bundle.certificate.to_pem()
+            .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+        Ok(ruby.str_from_slice(&pem))
+    }
+
+    pub fn private_key_pem(ruby: &Ruby, rb_self: &Self) -> Result<RString, Error> {
+        let pem = rb_self.bundle.private_key_pem()
+            .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+        Ok(ruby.str_from_slice(&pem))
+    }
+
+    /// Returns a PKCS#12 archive, protected by `password` (empty by default).
+    pub fn to_pkcs12(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<RString, Error> {
+        let args = scan_args::<(), (Option<String>,), (), (), (), ()>(args)?;
+        let password = args.optional.0.unwrap_or_default();
+
+        let der = rb_self.bundle.to_pkcs12(&password)
+            .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+        Ok(ruby.str_from_slice(&der))
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::ClientSession", name = "Rust ClientSession wrapper", free_immediately, size)]
+struct ClientSessionRef {
+    session: RefCell<ClientSession>,
+    /// Origin of the clock used when no explicit time is passed.
+    epoch: Instant,
+}
+
+impl Default for ClientSessionRef {
+    fn default() -> Self {
+        ClientSessionRef {
+            session: RefCell::new(ClientSession::new(client_session::Config::default())),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl ClientSessionRef {
+    fn initialize(
+      rb_self: typed_data::Obj<Self>,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<
+          _,
+          (String,),
+          (Option<String>, Option<Vec<String>>, Option<bool>, Option<String>, Option<String>, Option<String>, Option<u64>, Option<u64>, Option<u64>),
+          (),
+      >(
+          args.keywords,
+          &["username"],
+          &["password", "tokens", "bot", "release", "os", "os_version", "version", "ping_interval", "ping_timeout"],
+      )?;
+      let (username,) = kwargs.required;
+      let (password, tokens, bot, release, os, os_version, version, ping_interval, ping_timeout) = kwargs.optional;
+      let defaults = client_session::Config::default();
+
+      let config = client_session::Config {
+          username,
+          password,
+          tokens: tokens.unwrap_or_default(),
+          version: version.unwrap_or(defaults.version),
+          release: release.unwrap_or(defaults.release),
+          os: os.unwrap_or(defaults.os),
+          os_version: os_version.unwrap_or(defaults.os_version),
+          opus: defaults.opus,
+          bot: bot.unwrap_or(defaults.bot),
+          ping_interval_ms: ping_interval.unwrap_or(defaults.ping_interval_ms),
+          ping_timeout_ms: ping_timeout.unwrap_or(defaults.ping_timeout_ms),
+      };
+      *rb_self.session.borrow_mut() = ClientSession::new(config);
+
+      Ok(())
+    }
+
+    fn now_ms(&self, now: Option<u64>) -> u64 {
+        now.unwrap_or_else(|| self.epoch.elapsed().as_millis() as u64)
+    }
+
+    pub fn start(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => { session.start(now); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Handles a message received from the server, as returned by `ControlStream#read_message`.
+    pub fn handle(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(Symbol, Value), (Option<u64>,), (), (), (), ()>(args)?;
+        let (message_type, message) = args.required;
+        let now = rb_self.now_ms(args.optional.0);
+        let ty = message_type_from_symbol(ruby, message_type)?;
+        let message = ControlMessage::build(ty, RubyPayload { ruby, value: message })?;
+
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => { session.handle(message, now); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn poll(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => { session.poll(now); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn next_poll(ruby: &Ruby, rb_self: &Self) -> Result<Option<u64>, Error> {
+        match rb_self.session.try_borrow() {
+            Ok(session) => Ok(session.next_poll_ms()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the `[type, message]` pairs to send to the server.
+    pub fn drain_outgoing(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        let outgoing = match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => session.drain_outgoing(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(outgoing.len());
+        for message in &outgoing {
+            array.push(message_to_value(ruby, message)?)?;
+        }
+
+        Ok(array)
+    }
+
+    /// Returns `[kind, details]` pairs, see `ClientSession` in the Ruby sources.
+    pub fn drain_events(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        let events = match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => session.drain_events(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(events.len());
+        for event in events {
+            let (kind, details) = match event {
+                client_session::Event::CryptSetup => ("crypt_setup", ruby.qnil().as_value()),
+                client_session::Event::Synced { session, max_bandwidth, welcome_text, permissions } => {
+                    let hash = ruby.hash_new();
+                    hash.aset(ruby.to_symbol("session"), session)?;
+                    hash.aset(ruby.to_symbol("max_bandwidth"), max_bandwidth)?;
+                    hash.aset(ruby.to_symbol("welcome_text"), welcome_text)?;
+                    hash.aset(ruby.to_symbol("permissions"), permissions)?;
+                    ("synced", hash.as_value())
+                },
+                client_session::Event::Rejected { reject_type, reason } => {
+                    let hash = ruby.hash_new();
+                    hash.aset(ruby.to_symbol("type"), reject_type_symbol(ruby, reject_type))?;
+                    hash.aset(ruby.to_symbol("reason"), reason)?;
+                    ("rejected", hash.as_value())
+                },
+                client_session::Event::Kicked { actor, reason, ban } => {
+                    let hash = ruby.hash_new();
+                    hash.aset(ruby.to_symbol("actor"), actor)?;
+                    hash.aset(ruby.to_symbol("reason"), reason)?;
+                    hash.aset(ruby.to_symbol("ban"), ban)?;
+                    ("kicked", hash.as_value())
+                },
+                client_session::Event::TimedOut => ("timed_out", ruby.qnil().as_value()),
+                client_session::Event::Message(message) => ("message", message_to_value(ruby, &message)?),
+            };
+            array.push((ruby.to_symbol(kind), details))?;
+        }
+
+        Ok(array)
+    }
+
+    pub fn state(ruby: &Ruby, rb_self: &Self) -> Result<Symbol, Error> {
+        match rb_self.session.try_borrow() {
+            Ok(session) => Ok(ruby.to_symbol(session.state().name())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn session(ruby: &Ruby, rb_self: &Self) -> Result<Option<u32>, Error> {
+        match rb_self.session.try_borrow() {
+            Ok(session) => Ok(session.session()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn server_version(ruby: &Ruby, rb_self: &Self) -> Result<Option<Value>, Error> {
+        match rb_self.session.try_borrow() {
+            Ok(session) => session.server_version()
+                .map(|version| compact(ruby, serde_magnus::serialize(ruby, version)?))
+                .transpose(),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn set_tokens(ruby: &Ruby, rb_self: &Self, tokens: Vec<String>) -> Result<(), Error> {
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => { session.set_tokens(tokens); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn ping_stats(ruby: &Ruby, rb_self: &Self) -> Result<RHash, Error> {
+        match rb_self.session.try_borrow() {
+            Ok(session) => ping_stats_to_hash(ruby, session.ping_tracker()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Remembers a ping sent over the voice channel, returns the timestamp to send with it.
+    pub fn udp_ping(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<u64, Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => Ok(session.ping_tracker_mut().udp_ping(now)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Handles the answer to a voice channel ping, returns the round trip time if it was ours.
+    pub fn receive_udp_ping(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Option<u64>, Error> {
+        let args = scan_args::<(u64,), (Option<u64>,), (), (), (), ()>(args)?;
+        let (timestamp,) = args.required;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => Ok(session.ping_tracker_mut().receive_udp(timestamp, now)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn is_crypt_ready(ruby: &Ruby, rb_self: &Self) -> Result<bool, Error> {
+        match rb_self.session.try_borrow() {
+            Ok(session) => Ok(session.crypt_state().is_some()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn request_crypt_resync(ruby: &Ruby, rb_self: &Self) -> Result<(), Error> {
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => { session.request_crypt_resync(); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Encrypts a voice packet for UDP, returns `nil` until the server sent `CryptSetup`.
+    pub fn encrypt(ruby: &Ruby, rb_self: &Self, src: RString) -> Result<Option<RString>, Error> {
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => {
+                let encrypted = session.encrypt(unsafe { src.as_slice() });
+                Ok(encrypted.map(|buffer| ruby.str_from_slice(&buffer)))
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn decrypt(ruby: &Ruby, rb_self: &Self, encrypted: RString) -> Result<(RString, Symbol), Error> {
+        match rb_self.session.try_borrow_mut() {
+            Ok(mut session) => {
+                let mut buffer = BytesMut::new();
+                buffer.extend_from_slice(unsafe { encrypted.as_slice() });
+                let result = session.decrypt(&mut buffer);
+
+                let ruby_string = ruby.str_from_slice(&buffer);
+                let reason =
+                    match result {
+                        Ok(()) => Ruby::to_symbol(ruby, "ok"),
+                        Err(e) => decrypt_error_symbol(ruby, e),
+                    };
+
+                Ok((ruby_string, reason))
+            },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+#[derive(TypedData)]
+#[magnus(class = "RbMumbleProtocol::ServerConnection", name = "Rust ServerConnection wrapper", free_immediately, size, mark)]
+struct ServerConnectionRef {
+    connection: RefCell<ServerConnection>,
+    /// The `CryptState` passed to `accept`.
+    crypt_state: RefCell<Option<Opaque<typed_data::Obj<CryptStateRef>>>>,
+    /// Origin of the clock used when no explicit time is passed.
+    epoch: Instant,
+}
+
+impl Default for ServerConnectionRef {
+    fn default() -> Self {
+        ServerConnectionRef {
+            connection: RefCell::new(ServerConnection::new(server_connection::Config::default())),
+            crypt_state: RefCell::new(None),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl DataTypeFunctions for ServerConnectionRef {
+    fn mark(&self, marker: &gc::Marker) {
+        if let Ok(crypt_state) = self.crypt_state.try_borrow() {
+            if let Some(crypt_state) = *crypt_state {
+                marker.mark(crypt_state);
+            }
+        }
+    }
+}
+
+impl ServerConnectionRef {
+    fn initialize(
+      rb_self: typed_data::Obj<Self>,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<
+          _,
+          (),
+          (Option<String>, Option<u64>, Option<bool>, Option<usize>, Option<u32>, Option<String>, Option<u64>, Option<String>, Option<String>),
+          (),
+      >(
+          args.keywords,
+          &[],
+          &["password", "min_version", "require_opus", "max_username_length", "max_bandwidth", "welcome_text", "timeout", "release", "os"],
+      )?;
+      let (password, min_version, require_opus, max_username_length, max_bandwidth, welcome_text, timeout, release, os) =
+          kwargs.optional;
+      let defaults = server_connection::Config::default();
+
+      let config = server_connection::Config {
+          version: defaults.version,
+          release: release.unwrap_or(defaults.release),
+          os: os.unwrap_or(defaults.os),
+          os_version: defaults.os_version,
+          password,
+          min_version: min_version.unwrap_or(defaults.min_version),
+          require_opus: require_opus.unwrap_or(defaults.require_opus),
+          max_username_length: max_username_length.unwrap_or(defaults.max_username_length),
+          max_bandwidth: max_bandwidth.unwrap_or(defaults.max_bandwidth),
+          welcome_text,
+          timeout_ms: timeout.unwrap_or(defaults.timeout_ms),
+      };
+      *rb_self.connection.borrow_mut() = ServerConnection::new(config);
+
+      Ok(())
+    }
+
+    fn now_ms(&self, now: Option<u64>) -> u64 {
+        now.unwrap_or_else(|| self.epoch.elapsed().as_millis() as u64)
+    }
+
+    /// The `CryptState` of the voice channel, `nil` until the client was accepted.
+    pub fn crypt_state(ruby: &Ruby, rb_self: &Self) -> Result<Option<typed_data::Obj<CryptStateRef>>, Error> {
+        match rb_self.crypt_state.try_borrow() {
+            Ok(crypt_state) => Ok((*crypt_state).map(|crypt_state| ruby.get_inner(crypt_state))),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn start(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.connection.try_borrow_mut() {
+            Ok(mut connection) => { connection.start(now); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Handles a message received from the client, as returned by `ControlStream#read_message`.
+    pub fn handle(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(Symbol, Value), (Option<u64>,), (), (), (), ()>(args)?;
+        let (message_type, message) = args.required;
+        let now = rb_self.now_ms(args.optional.0);
+        let ty = message_type_from_symbol(ruby, message_type)?;
+        let message = ControlMessage::build(ty, RubyPayload { ruby, value: message })?;
+        let crypt_state = Self::crypt_state(ruby, rb_self)?;
+
+        match (rb_self.connection.try_borrow_mut(), crypt_state.as_ref().map(|crypt_state| crypt_state.state.try_borrow_mut()).transpose()) {
+            (Ok(mut connection), Ok(mut crypt_state)) => {
+                connection.handle(message, now, crypt_state.as_deref_mut());
+                Ok(())
+            },
+            _ => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn poll(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.connection.try_borrow_mut() {
+            Ok(mut connection) => { connection.poll(now); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn next_poll(ruby: &Ruby, rb_self: &Self) -> Result<Option<u64>, Error> {
+        match rb_self.connection.try_borrow() {
+            Ok(connection) => Ok(connection.next_poll_ms()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Lets the client in, `server_state` being `[type, message]` pairs sent before `ServerSync`.
+    /// The keys of the `crypt_state:` keyword, a new `CryptState` by default, are sent to the client.
+    pub fn accept(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(u32, u32), (Option<RArray>,), (), (), _, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<typed_data::Obj<CryptStateRef>>,), ()>(
+            args.keywords,
+            &[],
+            &["crypt_state"],
+        )?;
+        let (session, permissions) = args.required;
+        let crypt_state = kwargs.optional.0.unwrap_or_else(|| {
+            ruby.obj_wrap(CryptStateRef { state: RefCell::new(crypt_state::CryptState::generate_new()) })
+        });
+
+        let mut server_state = Vec::new();
+        if let Some(messages) = args.optional.0 {
+            for pair in messages.to_vec::<(Symbol, Value)>()? {
+                let ty = message_type_from_symbol(ruby, pair.0)?;
+                server_state.push(ControlMessage::build(ty, RubyPayload { ruby, value: pair.1 })?);
+            }
+        }
+
+        match (rb_self.connection.try_borrow_mut(), crypt_state.state.try_borrow(), rb_self.crypt_state.try_borrow_mut()) {
+            (Ok(mut connection), Ok(state), Ok(mut current)) => {
+                if connection.state() == server_connection::State::Authenticating {
+                    connection.accept(session, Permissions::from_bits(permissions), &state, server_state);
+                    *current = Some(crypt_state.into());
+                }
+                Ok(())
+            },
+            _ => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn reject(ruby: &Ruby, rb_self: &Self, reject_type: Symbol, reason: String) -> Result<(), Error> {
+        let reject_type = reject_type_from_symbol(ruby, reject_type)?;
+
+        match rb_self.connection.try_borrow_mut() {
+            Ok(mut connection) => { connection.reject(reject_type, &reason); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the `[type, message]` pairs to send to the client.
+    pub fn drain_outgoing(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        let outgoing = match rb_self.connection.try_borrow_mut() {
+            Ok(mut connection) => connection.drain_outgoing(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(outgoing.len());
+        for message in &outgoing {
+            array.push(message_to_value(ruby, message)?)?;
+        }
+
+        Ok(array)
+    }
+
+    /// Returns `[kind, details]` pairs, see `ServerConnection` in the Ruby sources.
+    pub fn drain_events(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        let events = match rb_self.connection.try_borrow_mut() {
+            Ok(mut connection) => connection.drain_events(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(events.len());
+        for event in events {
+            let (kind, details) = match event {
+                server_connection::Event::Authenticate(credentials) => {
+                    ("authenticate", credentials_to_hash(ruby, &credentials)?.as_value())
+                },
+                server_connection::Event::Rejected { reject_type, reason } => {
+                    let hash = ruby.hash_new();
+                    hash.aset(ruby.to_symbol("type"), reject_type_symbol(ruby, reject_type))?;
+                    hash.aset(ruby.to_symbol("reason"), reason)?;
+                    ("rejected", hash.as_value())
+                },
+                server_connection::Event::Tokens(tokens) => ("tokens", ruby.into_value(tokens)),
+                server_connection::Event::TimedOut => ("timed_out", ruby.qnil().as_value()),
+                server_connection::Event::Message(message) => ("message", message_to_value(ruby, &message)?),
+            };
+            array.push((ruby.to_symbol(kind), details))?;
+        }
+
+        Ok(array)
+    }
+
+    pub fn state(ruby: &Ruby, rb_self: &Self) -> Result<Symbol, Error> {
+        match rb_self.connection.try_borrow() {
+            Ok(connection) => Ok(ruby.to_symbol(connection.state().name())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn session(ruby: &Ruby, rb_self: &Self) -> Result<Option<u32>, Error> {
+        match rb_self.connection.try_borrow() {
+            Ok(connection) => Ok(connection.session()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn credentials(ruby: &Ruby, rb_self: &Self) -> Result<Option<RHash>, Error> {
+        match rb_self.connection.try_borrow() {
+            Ok(connection) => connection.credentials()
+                .map(|credentials| credentials_to_hash(ruby, credentials))
+                .transpose(),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn ping_stats(ruby: &Ruby, rb_self: &Self) -> Result<RHash, Error> {
+        match rb_self.connection.try_borrow() {
+            Ok(connection) => ping_stats_to_hash(ruby, connection.ping_tracker()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the connection statistics of a `UserStats` message about this client.
+    pub fn user_stats(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        let mut stats = mumble_proto::UserStats::default();
+        let crypt_state = Self::crypt_state(ruby, rb_self)?;
+        match (rb_self.connection.try_borrow(), crypt_state.as_ref().map(|crypt_state| crypt_state.state.try_borrow()).transpose()) {
+            (Ok(connection), Ok(crypt_state)) => {
+                stats.session = connection.session();
+                connection.fill_user_stats(&mut stats, crypt_state.as_deref());
+            },
+            _ => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+
+        compact(ruby, serde_magnus::serialize(ruby, &stats)?)
+    }
+
+    pub fn request_crypt_resync(ruby: &Ruby, rb_self: &Self) -> Result<(), Error> {
+        match rb_self.connection.try_borrow_mut() {
+            Ok(mut connection) => { connection.request_crypt_resync(); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+fn credentials_to_hash(ruby: &Ruby, credentials: &server_connection::Credentials) -> Result<RHash, Error> {
+    let hash = ruby.hash_new();
+
+    hash.aset(ruby.to_symbol("username"), credentials.username.as_str())?;
+    hash.aset(ruby.to_symbol("password"), credentials.password.as_deref())?;
+    hash.aset(ruby.to_symbol("tokens"), credentials.tokens.clone())?;
+    hash.aset(ruby.to_symbol("celt_versions"), credentials.celt_versions.clone())?;
+    hash.aset(ruby.to_symbol("opus"), credentials.opus)?;
+    hash.aset(ruby.to_symbol("bot"), credentials.bot)?;
+    hash.aset(ruby.to_symbol("version"), credentials.version)?;
+    hash.aset(ruby.to_symbol("release"), credentials.release.as_deref())?;
+    hash.aset(ruby.to_symbol("os"), credentials.os.as_deref())?;
+
+    Ok(hash)
+}
+
+const REJECT_TYPES: [(RejectType, &str); 10] = [
+    (RejectType::None, "none"),
+    (RejectType::WrongVersion, "wrong_version"),
+    (RejectType::InvalidUsername, "invalid_username"),
+    (RejectType::WrongUserPw, "wrong_user_pw"),
+    (RejectType::WrongServerPw, "wrong_server_pw"),
+    (RejectType::UsernameInUse, "username_in_use"),
+    (RejectType::ServerFull, "server_full"),
+    (RejectType::NoCertificate, "no_certificate"),
+    (RejectType::AuthenticatorFail, "authenticator_fail"),
+    (RejectType::NoNewConnections, "no_new_connections"),
+];
+
+fn reject_type_symbol(ruby: &Ruby, reject_type: RejectType) -> Symbol {
+    let (_, name) = REJECT_TYPES.iter().find(|(ty, _)| *ty == reject_type).unwrap();
+
+    ruby.to_symbol(name)
+}
+
+fn reject_type_from_symbol(ruby: &Ruby, symbol: Symbol) -> Result<RejectType, Error> {
+    let name = symbol.name()?;
+    REJECT_TYPES.iter()
+        .find(|(_, n)| *n == name)
+        .map(|(ty, _)| *ty)
+        .ok_or_else(|| Error::new(ruby.exception_arg_error(), format!("Unknown reject type: {name}")))
+}
+
+fn ping_stats_to_hash(ruby: &Ruby, tracker: &PingTracker) -> Result<RHash, Error> {
+    let rtt_to_hash = |stats: &RttStats| -> Result<RHash, Error> {
+        let hash = ruby.hash_new();
+        hash.aset(ruby.to_symbol("count"), stats.count())?;
+        hash.aset(ruby.to_symbol("mean"), stats.mean())?;
+        hash.aset(ruby.to_symbol("variance"), stats.variance())?;
+        Ok(hash)
+    };
+    let peer = tracker.peer();
+    let peer_hash = ruby.hash_new();
+    peer_hash.aset(ruby.to_symbol("good"), peer.good)?;
+    peer_hash.aset(ruby.to_symbol("late"), peer.late)?;
+    peer_hash.aset(ruby.to_symbol("lost"), peer.lost)?;
+    peer_hash.aset(ruby.to_symbol("resync"), peer.resync)?;
+    peer_hash.aset(ruby.to_symbol("udp_packets"), peer.udp_packets)?;
+    peer_hash.aset(ruby.to_symbol("tcp_packets"), peer.tcp_packets)?;
+    peer_hash.aset(ruby.to_symbol("udp_ping_avg"), peer.udp_ping_avg)?;
+    peer_hash.aset(ruby.to_symbol("udp_ping_var"), peer.udp_ping_var)?;
+    peer_hash.aset(ruby.to_symbol("tcp_ping_avg"), peer.tcp_ping_avg)?;
+    peer_hash.aset(ruby.to_symbol("tcp_ping_var"), peer.tcp_ping_var)?;
+
+    let hash = ruby.hash_new();
+    hash.aset(ruby.to_symbol("tcp"), rtt_to_hash(tracker.tcp())?)?;
+    hash.aset(ruby.to_symbol("udp"), rtt_to_hash(tracker.udp())?)?;
+    hash.aset(ruby.to_symbol("resync"), tracker.resync())?;
+    hash.aset(ruby.to_symbol("peer"), peer_hash)?;
+
+    Ok(hash)
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::VoiceTransport", name = "Rust VoiceTransport wrapper", free_immediately, size)]
+struct VoiceTransportRef {
+    transport: RefCell<VoiceTransport>,
+    /// Origin of the clock used when no explicit time is passed.
+    epoch: Instant,
+}
+
+impl Default for VoiceTransportRef {
+    fn default() -> Self {
+        VoiceTransportRef {
+            transport: RefCell::new(VoiceTransport::default()),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl VoiceTransportRef {
+    fn initialize(
+      rb_self: typed_data::Obj<Self>,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (), (Option<u64>, Option<bool>), ()>(
+          args.keywords,
+          &[],
+          &["silence_timeout", "force_tunnel"],
+      )?;
+      let (silence_timeout, force_tunnel) = kwargs.optional;
+      let defaults = voice_transport::Config::default();
+
+      let config = voice_transport::Config {
+          silence_timeout_ms: silence_timeout.unwrap_or(defaults.silence_timeout_ms),
+          force_tunnel: force_tunnel.unwrap_or(defaults.force_tunnel),
+      };
+      *rb_self.transport.borrow_mut() = VoiceTransport::new(config);
+
+      Ok(())
+    }
+
+    fn now_ms(&self, now: Option<u64>) -> u64 {
+        now.unwrap_or_else(|| self.epoch.elapsed().as_millis() as u64)
+    }
+
+    pub fn udp_ping_received(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.transport.try_borrow_mut() {
+            Ok(mut transport) => { transport.udp_ping_received(now); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Records the reason returned by `CryptState#decrypt`, `:ok` on success.
+    pub fn decrypted(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(Symbol,), (Option<u64>,), (), (), (), ()>(args)?;
+        let (reason,) = args.required;
+        let now = rb_self.now_ms(args.optional.0);
+        let result = match reason.name()?.as_ref() {
+            "ok" => Ok(()),
+            "repeat" => Err(DecryptError::Repeat),
+            "late" => Err(DecryptError::Late),
+            "bad_mac" => Err(DecryptError::Mac),
+            "eof" => Err(DecryptError::Eof),
+            name => return Err(Error::new(ruby.exception_arg_error(), format!("Unknown decrypt result :{name}"))),
+        };
+
+        match rb_self.transport.try_borrow_mut() {
+            Ok(mut transport) => { transport.decrypted(result, now); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn tunnel_received(ruby: &Ruby, rb_self: &Self) -> Result<(), Error> {
+        match rb_self.transport.try_borrow_mut() {
+            Ok(mut transport) => { transport.tunnel_received(); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns `:udp` or `:tunnel`.
+    pub fn path(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Symbol, Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.transport.try_borrow() {
+            Ok(transport) => Ok(ruby.to_symbol(transport.path(now).name())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns the path if it changed since the last call, `nil` otherwise.
+    pub fn poll(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Option<Symbol>, Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.transport.try_borrow_mut() {
+            Ok(mut transport) => Ok(transport.poll(now).map(|path: VoicePath| ruby.to_symbol(path.name()))),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn stats(ruby: &Ruby, rb_self: &Self) -> Result<RHash, Error> {
+        let stats = match rb_self.transport.try_borrow() {
+            Ok(transport) => *transport.stats(),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+        let hash = ruby.hash_new();
+
+        hash.aset(ruby.to_symbol("udp_pings"), stats.udp_pings)?;
+        hash.aset(ruby.to_symbol("decrypted"), stats.decrypted)?;
+        hash.aset(ruby.to_symbol("decrypt_failures"), stats.decrypt_failures)?;
+        hash.aset(ruby.to_symbol("tunneled"), stats.tunneled)?;
+
+        Ok(hash)
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::UdpDemux", name = "Rust UdpDemux wrapper", free_immediately, size)]
+#[derive(Default)]
+struct UdpDemuxRef {
+    demux: RefCell<UdpDemux>,
+}
+
+impl UdpDemuxRef {
+    /// Registers a session with the IP (`"10.0.0.1"`) of its control connection.
+    pub fn add(ruby: &Ruby, rb_self: &Self, session: u32, ip: String) -> Result<(), Error> {
+        let ip = ip.parse::<IpAddr>()
+            .map_err(|_| Error::new(ruby.exception_arg_error(), format!("Expected an IP address, got {ip:?}")))?;
+
+        match rb_self.demux.try_borrow_mut() {
+            Ok(mut demux) => { demux.add(session, ip); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn remove(ruby: &Ruby, rb_self: &Self, session: u32) -> Result<bool, Error> {
+        match rb_self.demux.try_borrow_mut() {
+            Ok(mut demux) => Ok(demux.remove(session)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Binds a UDP address (`"10.0.0.1:64738"`) to a session, returns false for unknown sessions.
+    pub fn bind(ruby: &Ruby, rb_self: &Self, session: u32, address: String) -> Result<bool, Error> {
+        let address = socket_addr_from_string(ruby, &address)?;
+
+        match rb_self.demux.try_borrow_mut() {
+            Ok(mut demux) => Ok(demux.bind(session, address)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn session_for(ruby: &Ruby, rb_self: &Self, address: String) -> Result<Option<u32>, Error> {
+        let address = socket_addr_from_string(ruby, &address)?;
+
+        match rb_self.demux.try_borrow() {
+            Ok(demux) => Ok(demux.session(address)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn address(ruby: &Ruby, rb_self: &Self, session: u32) -> Result<Option<String>, Error> {
+        match rb_self.demux.try_borrow() {
+            Ok(demux) => Ok(demux.address(session).map(|address| address.to_string())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Finds the session which sent a datagram. `states` maps sessions to their `CryptState`, or
+    /// `nil` for clients not accepted yet, which are tried without being modified when the
+    /// address is unknown. Returns `[session, :known]`, `[session, :matched]` or `nil`.
+    pub fn route(ruby: &Ruby, rb_self: &Self, address: String, datagram: RString, states: RHash) -> Result<Option<(u32, Symbol)>, Error> {
+        let address = socket_addr_from_string(ruby, &address)?;
+
+        let mut crypt_states = Vec::new();
+        states.foreach(|session: u32, value: Value| {
+            if !value.is_nil() {
+                let crypt_state = typed_data::Obj::<CryptStateRef>::try_convert(value)
+                    .map_err(|_| Error::new(ruby.exception_arg_error(), "Expected CryptState or nil values"))?;
+                crypt_states.push((session, crypt_state));
+            }
+            Ok(ForEach::Continue)
+        })?;
+        let guards = crypt_states.iter()
+            .map(|(session, crypt_state)| match crypt_state.state.try_borrow() {
+                Ok(state) => Ok((*session, state)),
+                Err(_e) => Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")),
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        let source = match rb_self.demux.try_borrow_mut() {
+            Ok(mut demux) => demux.route(address, unsafe { datagram.as_slice() }, |session| {
+                guards.get(&session).map(|state| &**state)
+            }),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        Ok(match source {
+            Source::Known(session) => Some((session, ruby.to_symbol("known"))),
+            Source::Matched(session) => Some((session, ruby.to_symbol("matched"))),
+            Source::Unknown => None,
+        })
+    }
+
+    pub fn size(ruby: &Ruby, rb_self: &Self) -> Result<usize, Error> {
+        match rb_self.demux.try_borrow() {
+            Ok(demux) => Ok(demux.len()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+/// Returns the body to deliver under the limits of a `ServerConfig` message hash, or `nil` if it
+/// is too long.
+fn text_message_filter(ruby: &Ruby, args: &[Value]) -> Result<Option<String>, Error> {
+    let args = scan_args::<(String,), (Option<Value>,), (), (), (), ()>(args)?;
+    let (text,) = args.required;
+    let limits = match args.optional.0 {
+        Some(config) => {
+            let config: mumble_proto::ServerConfig = serde_magnus::deserialize(ruby, config)?;
+            text_message::Limits::from(&config)
+        },
+        None => text_message::Limits::default(),
+    };
+
+    Ok(text_message::filter(&text, &limits).ok())
+}
+
+fn text_message_sanitize(html: String) -> String {
+    text_message::sanitize(&html)
+}
+
+fn text_message_plain_text(html: String) -> String {
+    text_message::to_plain_text(&html)
+}
+
+/// Length counted against `message_length`, without the data of inline images.
+fn text_message_length(html: String) -> usize {
+    text_message::text_length(&html)
+}
+
+/// Returns `{ mime:, data: }` hashes of the inline images, raises on invalid ones.
+fn text_message_images(ruby: &Ruby, html: String) -> Result<RArray, Error> {
+    let images = text_message::images(&html)
+        .map_err(|e| Error::new(ruby.get_inner(&BASE_ERROR), e.to_string()))?;
+
+    let array = ruby.ary_new_capa(images.len());
+    for image in images {
+        let hash = ruby.hash_new();
+        hash.aset(ruby.to_symbol("mime"), image.mime)?;
+        hash.aset(ruby.to_symbol("data"), ruby.str_from_slice(&image.data))?;
+        array.push(hash)?;
+    }
+
+    Ok(array)
+}
+
+fn text_message_channels(ruby: &Ruby, message: Value, tree: &ChannelTreeRef) -> Result<Vec<u32>, Error> {
+    let message: mumble_proto::TextMessage = serde_magnus::deserialize(ruby, message)?;
+
+    match tree.tree.try_borrow() {
+        Ok(tree) => Ok(text_message::channels(&tree, &message).into_iter().collect()),
+        Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+    }
+}
+
+fn text_message_recipients(
+    ruby: &Ruby,
+    message: Value,
+    tree: &ChannelTreeRef,
+    registry: &UserRegistryRef,
+    sender: u32,
+) -> Result<Vec<u32>, Error> {
+    let message: mumble_proto::TextMessage = serde_magnus::deserialize(ruby, message)?;
+
+    match (tree.tree.try_borrow(), registry.registry.try_borrow()) {
+        (Ok(tree), Ok(users)) => Ok(text_message::recipients(&tree, &users, sender, &message)),
+        _ => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::BandwidthMeter", name = "Rust BandwidthMeter wrapper", free_immediately, size)]
+struct BandwidthMeterRef {
+    meter: RefCell<BandwidthMeter>,
+    /// Origin of the clock used when no explicit time is passed.
+    epoch: Instant,
+}
+
+impl Default for BandwidthMeterRef {
+    fn default() -> Self {
+        BandwidthMeterRef {
+            meter: RefCell::new(BandwidthMeter::new(0, 0)),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl BandwidthMeterRef {
+    fn initialize(
+      rb_self: typed_data::Obj<Self>,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (), (Option<u32>, Option<u64>), ()>(
+          args.keywords,
+          &[],
+          &["max_bandwidth", "now"],
+      )?;
+      let (max_bandwidth, now) = kwargs.optional;
+      let now = rb_self.now_ms(now);
+
+      *rb_self.meter.borrow_mut() = BandwidthMeter::new(max_bandwidth.unwrap_or(0), now);
+
+      Ok(())
+    }
+
+    fn now_ms(&self, now: Option<u64>) -> u64 {
+        now.unwrap_or_else(|| self.epoch.elapsed().as_millis() as u64)
+    }
+
+    /// Accounts a voice packet by its size before encryption, returns false if it should be dropped.
+    pub fn add(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<bool, Error> {
+        let args = scan_args::<(usize,), (Option<u64>,), (), (), (), ()>(args)?;
+        let (size,) = args.required;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.meter.try_borrow_mut() {
+            Ok(mut meter) => Ok(meter.add(size, now)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn add_encrypted(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<bool, Error> {
+        let args = scan_args::<(usize,), (Option<u64>,), (), (), (), ()>(args)?;
+        let (size,) = args.required;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.meter.try_borrow_mut() {
+            Ok(mut meter) => Ok(meter.add_encrypted(size, now)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Bytes per second used over the last second.
+    pub fn bandwidth(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<u32, Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+
+        match rb_self.meter.try_borrow() {
+            Ok(meter) => Ok(meter.bandwidth(now)),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Returns `bandwidth`, `onlinesecs` and `idlesecs` of a `UserStats` message.
+    pub fn user_stats(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Value, Error> {
+        let args = scan_args::<(), (Option<u64>,), (), (), (), ()>(args)?;
+        let now = rb_self.now_ms(args.optional.0);
+        let mut stats = mumble_proto::UserStats::default();
+
+        match rb_self.meter.try_borrow() {
+            Ok(meter) => meter.fill_user_stats(&mut stats, now),
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+
+        compact(ruby, serde_magnus::serialize(ruby, &stats)?)
+    }
+
+    /// Bits per second a client sending `frames` 10 ms frames per packet at `bitrate` expects to use.
+    pub fn network_bandwidth(args: &[Value]) -> Result<u32, Error> {
+        let args = scan_args::<(u32, u32), (), (), (), _, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<bool>, Option<bool>), ()>(
+            args.keywords,
+            &[],
+            &["positional", "tunnel"],
+        )?;
+        let (bitrate, frames) = args.required;
+        let (positional, tunnel) = kwargs.optional;
+
+        Ok(bandwidth::network_bandwidth(bitrate, frames, positional.unwrap_or(false), tunnel.unwrap_or(false)))
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::CodecVersion", name = "Rust CodecVersion wrapper", free_immediately, size)]
+struct CodecVersionRef {
+    selection: RefCell<CodecSelection>,
+    opus_threshold: RefCell<u32>,
+}
+
+impl Default for CodecVersionRef {
+    fn default() -> Self {
+        CodecVersionRef {
+            selection: RefCell::new(CodecSelection::default()),
+            opus_threshold: RefCell::new(codec_version::DEFAULT_OPUS_THRESHOLD),
+        }
+    }
+}
+
+impl CodecVersionRef {
+    fn initialize(
+      ruby: &Ruby,
+      rb_self: &Self,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (), (Option<u32>, Option<Value>), ()>(
+          args.keywords,
+          &[],
+          &["opus_threshold", "message"],
+      )?;
+      let (opus_threshold, message) = kwargs.optional;
+
+      if let Some(opus_threshold) = opus_threshold {
+          *rb_self.opus_threshold.borrow_mut() = opus_threshold;
+      }
+      if let Some(message) = message {
+          Self::apply(ruby, rb_self, message)?;
+      }
+
+      Ok(())
+    }
+
+    /// Selects the codecs for the users of the registry, returns the `CodecVersion` message to
+    /// send to everyone if the selection changed.
+    pub fn recheck(ruby: &Ruby, rb_self: &Self, registry: &UserRegistryRef) -> Result<Option<Value>, Error> {
+        let threshold = *rb_self.opus_threshold.borrow();
+        let changed = match (rb_self.selection.try_borrow_mut(), registry.registry.try_borrow()) {
+            (Ok(mut selection), Ok(registry)) => selection.recheck(registry.codecs(), threshold),
+            _ => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        if changed { Self::message(ruby, rb_self).map(Some) } else { Ok(None) }
+    }
+
+    /// Returns the current selection as a `[:codec_version, message]` pair.
+    pub fn message(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        match rb_self.selection.try_borrow() {
+            Ok(selection) => message_to_value(ruby, &ControlMessage::from(selection.to_message())),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Takes over the selection announced in a `CodecVersion` message hash, e.g. on the client.
+    pub fn apply(ruby: &Ruby, rb_self: &Self, message: Value) -> Result<(), Error> {
+        let message: mumble_proto::CodecVersion = serde_magnus::deserialize(ruby, message)?;
+
+        match rb_self.selection.try_borrow_mut() {
+            Ok(mut selection) => { *selection = CodecSelection::from(&message); Ok(()) },
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Whether voice of the given type (e.g. `:celt_alpha`) may be sent to a user.
+    pub fn accepts(ruby: &Ruby, rb_self: &Self, registry: &UserRegistryRef, session: u32, codec: Symbol) -> Result<bool, Error> {
+        let payload = match codec.name()?.as_ref() {
+            "celt_alpha" => VoicePayload::CeltAlpha(Vec::new()),
+            "speex" => VoicePayload::Speex(Vec::new()),
+            "celt_beta" => VoicePayload::CeltBeta(Vec::new()),
+            "opus" => VoicePayload::Opus(Bytes::new(), false),
+            name => {
+                return Err(Error::new(ruby.exception_arg_error(), format!("Unknown voice packet type: {name}")))
+            },
+        };
+
+        match (rb_self.selection.try_borrow(), registry.registry.try_borrow()) {
+            (Ok(selection), Ok(registry)) => {
+                let user = registry.get(session)
+                    .ok_or_else(|| Error::new(ruby.get_inner(&BASE_ERROR), format!("unknown session {session}")))?;
+                Ok(selection.accepts(&user.codecs, &payload))
+            },
+            _ => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::BlobStore", name = "Rust BlobStore wrapper", free_immediately, size)]
+#[derive(Default)]
+struct BlobStoreRef {
+    store: RefCell<BlobStore>,
+}
+
+impl BlobStoreRef {
+    fn initialize(
+      rb_self: &Self,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (), (Option<usize>,), ()>(
+          args.keywords,
+          &[],
+          &["inline_limit"],
+      )?;
+      let (inline_limit,) = kwargs.optional;
+
+      *rb_self.store.borrow_mut() = BlobStore::new(inline_limit.unwrap_or(blob_store::DEFAULT_INLINE_LIMIT));
+
+      Ok(())
+    }
+
+    /// The SHA-1 of the data, as sent in `texture_hash`, `comment_hash` and `description_hash`.
+    pub fn digest(ruby: &Ruby, data: RString) -> RString {
+        ruby.str_from_slice(&blob_store::hash(unsafe { data.as_slice() }))
+    }
+
+    /// Stores the data if it is too large to be sent along, returns its hash or `nil`.
+    pub fn store(ruby: &Ruby, rb_self: &Self, data: RString) -> Result<Option<RString>, Error> {
+        match rb_self.store.try_borrow_mut() {
+            Ok(mut store) => Ok(store.store(unsafe { data.as_slice() }).map(|hash| ruby.str_from_slice(&hash))),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Stores the data whatever its size, returns its hash.
+    pub fn insert(ruby: &Ruby, rb_self: &Self, data: RString) -> Result<RString, Error> {
+        match rb_self.store.try_borrow_mut() {
+            Ok(mut store) => Ok(ruby.str_from_slice(&store.insert(unsafe { data.as_slice() }))),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn get(ruby: &Ruby, rb_self: &Self, hash: RString) -> Result<Option<RString>, Error> {
+        match rb_self.store.try_borrow() {
+            Ok(store) => Ok(store.get(unsafe { hash.as_slice() }).map(|data| ruby.str_from_slice(data))),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn contains(ruby: &Ruby, rb_self: &Self, hash: RString) -> Result<bool, Error> {
+        match rb_self.store.try_borrow() {
+            Ok(store) => Ok(store.contains(unsafe { hash.as_slice() })),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn size(ruby: &Ruby, rb_self: &Self) -> Result<usize, Error> {
+        match rb_self.store.try_borrow() {
+            Ok(store) => Ok(store.len()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    /// Replaces large data of a `:user_state` or `:channel_state` message hash by its hash,
+    /// returns the message to broadcast. Other messages are returned unchanged.
+    pub fn compact(ruby: &Ruby, rb_self: &Self, message_type: Symbol, message: Value) -> Result<Value, Error> {
+        let mut store = match rb_self.store.try_borrow_mut() {
+            Ok(store) => store,
+            Err(_e) => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let compacted = match message_type.name()?.as_ref() {
+            "user_state" => {
+                let mut state: mumble_proto::UserState = serde_magnus::deserialize(ruby, message)?;
+                store.compact_user_state(&mut state);
+                serde_magnus::serialize(ruby, &state)?
+            },
+            "channel_state" => {
+                let mut state: mumble_proto::ChannelState = serde_magnus::deserialize(ruby, message)?;
+                store.compact_channel_state(&mut state);
+                serde_magnus::serialize(ruby, &state)?
+            },
+            _ => return Ok(message),
+        };
+
+        compact(ruby, compacted)
+    }
+
+    /// Answers a `RequestBlob` message hash, returns `[type, message]` pairs ready for
+    /// `ControlStream#write_message`.
+    pub fn answer(
+        ruby: &Ruby,
+        rb_self: &Self,
+        request: Value,
+        registry: &UserRegistryRef,
+        tree: &ChannelTreeRef,
+    ) -> Result<RArray, Error> {
+        let request: mumble_proto::RequestBlob = serde_magnus::deserialize(ruby, request)?;
+        let messages = match (rb_self.store.try_borrow(), registry.registry.try_borrow(), tree.tree.try_borrow()) {
+            (Ok(store), Ok(users), Ok(channels)) => store.answer(&request, &users, &channels),
+            _ => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        let array = ruby.ary_new_capa(messages.len());
+        for message in messages {
+            array.push(message_to_value(ruby, &message)?)?;
+        }
+
+        Ok(array)
+    }
+
+    /// Returns the `[:request_blob, message]` pair fetching the blobs not stored yet, or `nil`.
+    pub fn request_missing(
+        ruby: &Ruby,
+        rb_self: &Self,
+        registry: &UserRegistryRef,
+        tree: &ChannelTreeRef,
+    ) -> Result<Option<Value>, Error> {
+        let request = match (rb_self.store.try_borrow(), registry.registry.try_borrow(), tree.tree.try_borrow()) {
+            (Ok(store), Ok(users), Ok(channels)) => store.request_missing(&users, &channels),
+            _ => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        request.map(|request| message_to_value(ruby, &ControlMessage::from(request))).transpose()
+    }
+
+    /// Drops the blobs no user or channel refers to anymore, returns how many.
+    pub fn prune(ruby: &Ruby, rb_self: &Self, registry: &UserRegistryRef, tree: &ChannelTreeRef) -> Result<usize, Error> {
+        match (rb_self.store.try_borrow_mut(), registry.registry.try_borrow(), tree.tree.try_borrow()) {
+            (Ok(mut store), Ok(users), Ok(channels)) => Ok(store.prune(&users, &channels)),
+            _ => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+}
+
+/// Converts an `[x, y, z]` array.
+fn vector_from_value(ruby: &Ruby, value: Value) -> Result<Vector3, Error> {
+    let coordinates: Vec<f32> = Vec::try_convert(value)?;
+
+    Vector3::from_slice(&coordinates)
+        .ok_or_else(|| Error::new(ruby.exception_arg_error(), "position must have 3 coordinates"))
+}
+
+/// Converts a `{ position:, front:, top: }` hash, missing vectors taking their default.
+fn listener_from_hash(ruby: &Ruby, hash: RHash) -> Result<Listener, Error> {
+    let defaults = Listener::default();
+    let vector = |name: &str, default: Vector3| -> Result<Vector3, Error> {
+        match hash.lookup::<_, Option<Value>>(ruby.to_symbol(name))? {
+            Some(value) => vector_from_value(ruby, value),
+            None => Ok(default),
+        }
+    };
+
+    Ok(Listener {
+        position: vector("position", defaults.position)?,
+        front: vector("front", defaults.front)?,
+        top: vector("top", defaults.top)?,
+    })
+}
+
+fn attenuation_from_kwargs(keywords: RHash) -> Result<Attenuation, Error> {
+    let kwargs = get_kwargs::<_, (), (Option<f32>, Option<f32>, Option<f32>, Option<f32>), ()>(
+        keywords,
+        &[],
+        &["min_distance", "max_distance", "max_distance_volume", "bloom"],
+    )?;
+    let (min_distance, max_distance, max_distance_volume, bloom) = kwargs.optional;
+    let defaults = Attenuation::default();
+
+    Ok(Attenuation {
+        min_distance: min_distance.unwrap_or(defaults.min_distance),
+        max_distance: max_distance.unwrap_or(defaults.max_distance),
+        max_distance_volume: max_distance_volume.unwrap_or(defaults.max_distance_volume),
+        bloom: bloom.unwrap_or(defaults.bloom),
+    })
+}
+
+fn positional_distance(ruby: &Ruby, from: Value, to: Value) -> Result<f32, Error> {
+    Ok(vector_from_value(ruby, from)?.distance(vector_from_value(ruby, to)?))
+}
+
+/// The unit vector pointing from the listener to the speaker, as `[right, up, front]`.
+fn positional_direction(ruby: &Ruby, listener: RHash, speaker: Value) -> Result<Vec<f32>, Error> {
+    let listener = listener_from_hash(ruby, listener)?;
+
+    Ok(listener.direction(vector_from_value(ruby, speaker)?).to_array().to_vec())
+}
+
+/// The gain of a speaker at a distance, for an ear facing it (1) or turned away (-1).
+fn positional_gain(args: &[Value]) -> Result<f32, Error> {
+    let args = scan_args::<(f32, f32), (), (), (), RHash, ()>(args)?;
+    let (dot, distance) = args.required;
+
+    Ok(attenuation_from_kwargs(args.keywords)?.gain(dot, distance))
+}
+
+/// Gains of the listener's left and right ear for a speaker.
+fn positional_stereo_gains(ruby: &Ruby, args: &[Value]) -> Result<(f32, f32), Error> {
+    let args = scan_args::<(RHash, Value), (), (), (), RHash, ()>(args)?;
+    let (listener, speaker) = args.required;
+    let listener = listener_from_hash(ruby, listener)?;
+
+    Ok(listener.stereo_gains(vector_from_value(ruby, speaker)?, &attenuation_from_kwargs(args.keywords)?))
+}
+
+#[magnus::wrap(class = "RbMumbleProtocol::PluginDataRouter", name = "Rust PluginDataRouter wrapper", free_immediately, size)]
+#[derive(Default)]
+struct PluginDataRouterRef {
+    router: RefCell<PluginDataRouter>,
+}
+
+impl PluginDataRouterRef {
+    fn initialize(
+      rb_self: &Self,
+      args: &[Value],
+    ) -> Result<(), Error> {
+      let args = scan_args::<(), (), (), (), _, ()>(args)?;
+      let kwargs = get_kwargs::<_, (), (Option<Vec<String>>,), ()>(
+          args.keywords,
+          &[],
+          &["namespaces"],
+      )?;
+      let (namespaces,) = kwargs.optional;
+
+      let mut router = PluginDataRouter::new();
+      for namespace in namespaces.unwrap_or_default() {
+          router.allow_namespace(&namespace);
+      }
+      *rb_self.router.borrow_mut() = router;
+
+      Ok(())
+    }
+
+    /// Builds a `[:plugin_data_transmission, message]` pair to send to the server, raises
+    /// `ArgumentError` if the data or data ID exceed Mumble's limits.
+    pub fn message(ruby: &Ruby, args: &[Value]) -> Result<Value, Error> {
+        let args = scan_args::<(String, RString), (Option<Vec<u32>>,), (), (), (), ()>(args)?;
+        let (data_id, data) = args.required;
+        let receivers = args.optional.0.unwrap_or_default();
+
+        let plugin_data = PluginData::new(&data_id, unsafe { data.as_slice() }, &receivers)
+            .map_err(|e| Error::new(ruby.exception_arg_error(), e.to_string()))?;
+
+        message_to_value(ruby, &ControlMessage::from(plugin_data.to_message()))
+    }
+
+    /// Returns why a `PluginDataTransmission` message hash would be dropped, `nil` if it is valid.
+    pub fn validate(ruby: &Ruby, message: Value) -> Result<Option<String>, Error> {
+        let message: mumble_proto::PluginDataTransmission = serde_magnus::deserialize(ruby, message)?;
+
+        Ok(PluginData::try_from(&message).err().map(|e| e.to_string()))
+    }
+
+    /// Returns the connected receivers of a message sent by `sender` and the
+    /// `[:plugin_data_transmission, message]` pair to send them, or `nil` if it is dropped.
+    pub fn route(
+        ruby: &Ruby,
+        rb_self: &Self,
+        sender: u32,
+        message: Value,
+        registry: &UserRegistryRef,
+    ) -> Result<Option<(Vec<u32>, Value)>, Error> {
+        let message: mumble_proto::PluginDataTransmission = serde_magnus::deserialize(ruby, message)?;
+        let delivery = match (rb_self.router.try_borrow_mut(), registry.registry.try_borrow()) {
+            (Ok(mut router), Ok(users)) => router.route(sender, &message, &users),
+            _ => { return Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        };
+
+        match delivery {
+            Ok(delivery) => {
+                let message = message_to_value(ruby, &ControlMessage::from(delivery.message))?;
+                Ok(Some((delivery.receivers, message)))
+            },
+            Err(_e) => Ok(None),
+        }
+    }
+
+    pub fn namespaces(ruby: &Ruby, rb_self: &Self) -> Result<Vec<String>, Error> {
+        match rb_self.router.try_borrow() {
+            Ok(router) => Ok(router.namespaces().iter().cloned().collect()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
+
+    pub fn dropped(ruby: &Ruby, rb_self: &Self) -> Result<u64, Error> {
+        match rb_self.router.try_borrow() {
+            Ok(router) => Ok(router.dropped()),
+            Err(_e) => { Err(Error::new(ruby.get_inner(&BASE_ERROR), "borrow error")) }
+        }
+    }
 }
 
 fn rstring_to_array<const N: usize>(ruby: &Ruby, rstring: &RString) -> Result<[u8; N], Error> {
@@ -204,6 +3284,298 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
 
     class1.define_method("encrypt", method!(CryptStateRef::encrypt, 1))?;
     class1.define_method("decrypt", method!(CryptStateRef::decrypt, 1))?;
+    class1.define_method("try_decrypt", method!(CryptStateRef::try_decrypt, 1))?;
+
+    let control_stream = module.const_get::<_, RClass>("ControlStream").unwrap();
+
+    control_stream.define_alloc_func::<ControlStreamRef>();
+    control_stream.define_method("initialize", method!(ControlStreamRef::initialize, -1))?;
+
+    control_stream.define_method("io", method!(ControlStreamRef::io, 0))?;
+    control_stream.define_method("read_nonblock", method!(ControlStreamRef::read_nonblock, -1))?;
+    control_stream.define_method("write_message", method!(ControlStreamRef::write_message, 2))?;
+    control_stream.define_method("dropped_messages", method!(ControlStreamRef::dropped_messages, 0))?;
+    control_stream.define_method("skipped_messages", method!(ControlStreamRef::skipped_messages, 0))?;
+
+    let voice_packet = module.const_get::<_, RModule>("VoicePacket").unwrap();
+
+    voice_packet.define_module_function("decode", function!(decode_voice_packet, 2))?;
+    voice_packet.define_module_function("encode", function!(encode_voice_packet, 2))?;
+    voice_packet.define_module_function("opus_info", function!(parse_opus_packet, 1))?;
+
+    let jitter_buffer = module.const_get::<_, RClass>("JitterBuffer").unwrap();
+
+    jitter_buffer.define_alloc_func::<JitterBufferRef>();
+    jitter_buffer.define_method("initialize", method!(JitterBufferRef::initialize, -1))?;
+
+    jitter_buffer.define_method("push", method!(JitterBufferRef::push, -1))?;
+    jitter_buffer.define_method("poll", method!(JitterBufferRef::poll, -1))?;
+    jitter_buffer.define_method("remove", method!(JitterBufferRef::remove, 1))?;
+    jitter_buffer.define_method("stats", method!(JitterBufferRef::stats, 1))?;
+
+    let ogg_opus_writer = module.const_get::<_, RClass>("OggOpusWriter").unwrap();
+
+    ogg_opus_writer.define_alloc_func::<OggOpusWriterRef>();
+    ogg_opus_writer.define_method("initialize", method!(OggOpusWriterRef::initialize, -1))?;
+
+    ogg_opus_writer.define_method("io", method!(OggOpusWriterRef::io, 0))?;
+    ogg_opus_writer.define_method("write_packet", method!(OggOpusWriterRef::write_packet, 1))?;
+    ogg_opus_writer.define_method("write_silence", method!(OggOpusWriterRef::write_silence, 1))?;
+    ogg_opus_writer.define_method("samples", method!(OggOpusWriterRef::samples, 0))?;
+    ogg_opus_writer.define_method("close", method!(OggOpusWriterRef::close, 0))?;
+    ogg_opus_writer.define_method("closed?", method!(OggOpusWriterRef::is_closed, 0))?;
+
+    let pcap_reader = module.const_get::<_, RClass>("PcapReader").unwrap();
+
+    pcap_reader.define_alloc_func::<PcapReaderRef>();
+    pcap_reader.define_method("initialize", method!(PcapReaderRef::initialize, 1))?;
+    pcap_reader.define_method("next_packet", method!(PcapReaderRef::next_packet, 0))?;
+
+    let pcap_writer = module.const_get::<_, RClass>("PcapWriter").unwrap();
+
+    pcap_writer.define_alloc_func::<PcapWriterRef>();
+    pcap_writer.define_method("initialize", method!(PcapWriterRef::initialize, 1))?;
+
+    pcap_writer.define_method("io", method!(PcapWriterRef::io, 0))?;
+    pcap_writer.define_method("write_udp", method!(PcapWriterRef::write_udp, -1))?;
+    pcap_writer.define_method("write_tcp", method!(PcapWriterRef::write_tcp, -1))?;
+
+    let pcap_replay = module.const_get::<_, RClass>("PcapReplay").unwrap();
+
+    pcap_replay.define_alloc_func::<PcapReplayRef>();
+    pcap_replay.define_method("initialize", method!(PcapReplayRef::initialize, -1))?;
+
+    pcap_replay.define_method("feed", method!(PcapReplayRef::feed, 1))?;
+    pcap_replay.define_method("stats", method!(PcapReplayRef::stats, 1))?;
+
+    let channel_tree = module.const_get::<_, RClass>("ChannelTree").unwrap();
+
+    channel_tree.define_alloc_func::<ChannelTreeRef>();
+    channel_tree.define_method("apply_state", method!(ChannelTreeRef::apply_state, 1))?;
+    channel_tree.define_method("apply_remove", method!(ChannelTreeRef::apply_remove, 1))?;
+
+    channel_tree.define_method("[]", method!(ChannelTreeRef::get, 1))?;
+    channel_tree.define_method("ids", method!(ChannelTreeRef::ids, 0))?;
+    channel_tree.define_method("size", method!(ChannelTreeRef::size, 0))?;
+    channel_tree.define_method("path", method!(ChannelTreeRef::path, 1))?;
+    channel_tree.define_method("descendants", method!(ChannelTreeRef::descendants, 1))?;
+    channel_tree.define_method("linked", method!(ChannelTreeRef::linked, 1))?;
+    channel_tree.define_method("full_sync", method!(ChannelTreeRef::full_sync, 0))?;
+
+    let user_registry = module.const_get::<_, RClass>("UserRegistry").unwrap();
+
+    user_registry.define_alloc_func::<UserRegistryRef>();
+    user_registry.define_method("apply_state", method!(UserRegistryRef::apply_state, 1))?;
+    user_registry.define_method("apply_remove", method!(UserRegistryRef::apply_remove, 1))?;
+
+    user_registry.define_method("[]", method!(UserRegistryRef::get, 1))?;
+    user_registry.define_method("find_by_name", method!(UserRegistryRef::find_by_name, 1))?;
+    user_registry.define_method("sessions", method!(UserRegistryRef::sessions, 0))?;
+    user_registry.define_method("size", method!(UserRegistryRef::size, 0))?;
+    user_registry.define_method("in_channel", method!(UserRegistryRef::in_channel, 1))?;
+    user_registry.define_method("listeners", method!(UserRegistryRef::listeners, 1))?;
+    user_registry.define_method("full_sync", method!(UserRegistryRef::full_sync, 0))?;
+    user_registry.define_method("set_codecs", method!(UserRegistryRef::set_codecs, 3))?;
+
+    let acl_evaluator = module.const_get::<_, RClass>("AclEvaluator").unwrap();
+
+    acl_evaluator.define_alloc_func::<AclEvaluatorRef>();
+    acl_evaluator.define_method("apply_acl", method!(AclEvaluatorRef::apply_acl, 1))?;
+    acl_evaluator.define_method("remove", method!(AclEvaluatorRef::remove, 1))?;
+    acl_evaluator.define_method("permissions", method!(AclEvaluatorRef::permissions, 3))?;
+    acl_evaluator.define_method("member?", method!(AclEvaluatorRef::is_member, 4))?;
+
+    let permissions = module.const_get::<_, RModule>("Permissions").unwrap();
+
+    permissions.const_set("NONE", Permissions::NONE.bits())?;
+    for (permission, name) in Permissions::NAMES {
+        permissions.const_set(name.to_uppercase(), permission.bits())?;
+    }
+    permissions.const_set("ALL", Permissions::ALL.bits())?;
+    permissions.const_set("ROOT_ONLY", Permissions::ROOT_ONLY.bits())?;
+
+    permissions.define_module_function("names", function!(permission_names, 1))?;
+    permissions.define_module_function("from_names", function!(permissions_from_names, 1))?;
+    permissions.define_module_function("validate", function!(validate_permissions, 2))?;
+    permissions.define_module_function("applicable", function!(applicable_permissions, 1))?;
+
+    let voice_targets = module.const_get::<_, RClass>("VoiceTargets").unwrap();
+
+    voice_targets.define_alloc_func::<VoiceTargetsRef>();
+    voice_targets.define_method("apply_target", method!(VoiceTargetsRef::apply_target, 2))?;
+    voice_targets.define_method("set_credentials", method!(VoiceTargetsRef::set_credentials, -1))?;
+    voice_targets.define_method("remove", method!(VoiceTargetsRef::remove, 1))?;
+    voice_targets.define_method("target", method!(VoiceTargetsRef::target, 2))?;
+    voice_targets.define_method("resolve", method!(VoiceTargetsRef::resolve, 5))?;
+
+    let ban_list = module.const_get::<_, RClass>("BanList").unwrap();
+
+    ban_list.define_alloc_func::<BanListRef>();
+    ban_list.define_method("apply_list", method!(BanListRef::apply_list, 1))?;
+    ban_list.define_method("add", method!(BanListRef::add, 1))?;
+    ban_list.define_method("remove", method!(BanListRef::remove, 1))?;
+    ban_list.define_method("remove_expired", method!(BanListRef::remove_expired, -1))?;
+    ban_list.define_method("find", method!(BanListRef::find, -1))?;
+    ban_list.define_method("bans", method!(BanListRef::bans, 0))?;
+    ban_list.define_method("size", method!(BanListRef::size, 0))?;
+    ban_list.define_method("to_message", method!(BanListRef::to_message, 0))?;
+
+    let certificate = module.const_get::<_, RClass>("Certificate").unwrap();
+
+    certificate.define_alloc_func::<CertificateRef>();
+    certificate.define_method("initialize", method!(CertificateRef::initialize, 1))?;
+    certificate.define_singleton_method("hash_of", function!(certificate_hash, 1))?;
+
+    certificate.define_method("mumble_hash", method!(CertificateRef::hash, 0))?;
+    certificate.define_method("to_der", method!(CertificateRef::to_der, 0))?;
+    certificate.define_method("common_name", method!(CertificateRef::common_name, 0))?;
+    certificate.define_method("emails", method!(CertificateRef::emails, 0))?;
+    certificate.define_method("self_signed?", method!(CertificateRef::is_self_signed, 0))?;
+    certificate.define_method("not_before", method!(CertificateRef::not_before, 0))?;
+    certificate.define_method("not_after", method!(CertificateRef::not_after, 0))?;
+    certificate.define_method("valid?", method!(CertificateRef::is_valid, -1))?;
+
+    let certificate_bundle = module.const_get::<_, RClass>("CertificateBundle").unwrap();
+
+    certificate_bundle.undef_default_alloc_func();
+    certificate_bundle.define_singleton_method("generate", function!(CertificateBundleRef::generate, -1))?;
+    certificate_bundle.define_singleton_method("from_pkcs12", function!(CertificateBundleRef::from_pkcs12, -1))?;
+
+    certificate_bundle.define_method("certificate", method!(CertificateBundleRef::certificate, 0))?;
+    certificate_bundle.define_method("certificate_pem", method!(CertificateBundleRef::certificate_pem, 0))?;
+    certificate_bundle.define_method("private_key_pem", method!(CertificateBundleRef::private_key_pem, 0))?;
+    certificate_bundle.define_method("to_pkcs12", method!(CertificateBundleRef::to_pkcs12, -1))?;
+
+    let client_session = module.const_get::<_, RClass>("ClientSession").unwrap();
+
+    client_session.define_alloc_func::<ClientSessionRef>();
+    client_session.define_method("initialize", method!(ClientSessionRef::initialize, -1))?;
+
+    client_session.define_method("start", method!(ClientSessionRef::start, -1))?;
+    client_session.define_method("handle", method!(ClientSessionRef::handle, -1))?;
+    client_session.define_method("poll", method!(ClientSessionRef::poll, -1))?;
+    client_session.define_method("next_poll", method!(ClientSessionRef::next_poll, 0))?;
+    client_session.define_method("drain_outgoing", method!(ClientSessionRef::drain_outgoing, 0))?;
+    client_session.define_method("drain_events", method!(ClientSessionRef::drain_events, 0))?;
+    client_session.define_method("state", method!(ClientSessionRef::state, 0))?;
+    client_session.define_method("session", method!(ClientSessionRef::session, 0))?;
+    client_session.define_method("server_version", method!(ClientSessionRef::server_version, 0))?;
+    client_session.define_method("tokens=", method!(ClientSessionRef::set_tokens, 1))?;
+    client_session.define_method("ping_stats", method!(ClientSessionRef::ping_stats, 0))?;
+    client_session.define_method("udp_ping", method!(ClientSessionRef::udp_ping, -1))?;
+    client_session.define_method("receive_udp_ping", method!(ClientSessionRef::receive_udp_ping, -1))?;
+    client_session.define_method("crypt_ready?", method!(ClientSessionRef::is_crypt_ready, 0))?;
+    client_session.define_method("request_crypt_resync", method!(ClientSessionRef::request_crypt_resync, 0))?;
+    client_session.define_method("encrypt", method!(ClientSessionRef::encrypt, 1))?;
+    client_session.define_method("decrypt", method!(ClientSessionRef::decrypt, 1))?;
+
+    let server_connection = module.const_get::<_, RClass>("ServerConnection").unwrap();
+
+    server_connection.define_alloc_func::<ServerConnectionRef>();
+    server_connection.define_method("initialize", method!(ServerConnectionRef::initialize, -1))?;
+
+    server_connection.define_method("start", method!(ServerConnectionRef::start, -1))?;
+    server_connection.define_method("handle", method!(ServerConnectionRef::handle, -1))?;
+    server_connection.define_method("poll", method!(ServerConnectionRef::poll, -1))?;
+    server_connection.define_method("next_poll", method!(ServerConnectionRef::next_poll, 0))?;
+    server_connection.define_method("accept", method!(ServerConnectionRef::accept, -1))?;
+    server_connection.define_method("reject", method!(ServerConnectionRef::reject, 2))?;
+    server_connection.define_method("drain_outgoing", method!(ServerConnectionRef::drain_outgoing, 0))?;
+    server_connection.define_method("drain_events", method!(ServerConnectionRef::drain_events, 0))?;
+    server_connection.define_method("state", method!(ServerConnectionRef::state, 0))?;
+    server_connection.define_method("session", method!(ServerConnectionRef::session, 0))?;
+    server_connection.define_method("credentials", method!(ServerConnectionRef::credentials, 0))?;
+    server_connection.define_method("ping_stats", method!(ServerConnectionRef::ping_stats, 0))?;
+    server_connection.define_method("user_stats", method!(ServerConnectionRef::user_stats, 0))?;
+    server_connection.define_method("crypt_state", method!(ServerConnectionRef::crypt_state, 0))?;
+    server_connection.define_method("request_crypt_resync", method!(ServerConnectionRef::request_crypt_resync, 0))?;
+
+    let voice_transport = module.const_get::<_, RClass>("VoiceTransport").unwrap();
+
+    voice_transport.define_alloc_func::<VoiceTransportRef>();
+    voice_transport.define_method("initialize", method!(VoiceTransportRef::initialize, -1))?;
+
+    voice_transport.define_method("udp_ping_received", method!(VoiceTransportRef::udp_ping_received, -1))?;
+    voice_transport.define_method("decrypted", method!(VoiceTransportRef::decrypted, -1))?;
+    voice_transport.define_method("tunnel_received", method!(VoiceTransportRef::tunnel_received, 0))?;
+    voice_transport.define_method("path", method!(VoiceTransportRef::path, -1))?;
+    voice_transport.define_method("poll", method!(VoiceTransportRef::poll, -1))?;
+    voice_transport.define_method("stats", method!(VoiceTransportRef::stats, 0))?;
+
+    let udp_demux = module.const_get::<_, RClass>("UdpDemux").unwrap();
+
+    udp_demux.define_alloc_func::<UdpDemuxRef>();
+    udp_demux.define_method("add", method!(UdpDemuxRef::add, 2))?;
+    udp_demux.define_method("remove", method!(UdpDemuxRef::remove, 1))?;
+    udp_demux.define_method("bind", method!(UdpDemuxRef::bind, 2))?;
+    udp_demux.define_method("session_for", method!(UdpDemuxRef::session_for, 1))?;
+    udp_demux.define_method("address", method!(UdpDemuxRef::address, 1))?;
+    udp_demux.define_method("route", method!(UdpDemuxRef::route, 3))?;
+    udp_demux.define_method("size", method!(UdpDemuxRef::size, 0))?;
+
+    let text_message = module.const_get::<_, RModule>("TextMessage").unwrap();
+
+    text_message.define_module_function("filter", function!(text_message_filter, -1))?;
+    text_message.define_module_function("sanitize", function!(text_message_sanitize, 1))?;
+    text_message.define_module_function("plain_text", function!(text_message_plain_text, 1))?;
+    text_message.define_module_function("length", function!(text_message_length, 1))?;
+    text_message.define_module_function("images", function!(text_message_images, 1))?;
+    text_message.define_module_function("channels", function!(text_message_channels, 2))?;
+    text_message.define_module_function("recipients", function!(text_message_recipients, 4))?;
+
+    let bandwidth_meter = module.const_get::<_, RClass>("BandwidthMeter").unwrap();
+
+    bandwidth_meter.define_alloc_func::<BandwidthMeterRef>();
+    bandwidth_meter.define_method("initialize", method!(BandwidthMeterRef::initialize, -1))?;
+    bandwidth_meter.define_singleton_method("network_bandwidth", function!(BandwidthMeterRef::network_bandwidth, -1))?;
+
+    bandwidth_meter.define_method("add", method!(BandwidthMeterRef::add, -1))?;
+    bandwidth_meter.define_method("add_encrypted", method!(BandwidthMeterRef::add_encrypted, -1))?;
+    bandwidth_meter.define_method("bandwidth", method!(BandwidthMeterRef::bandwidth, -1))?;
+    bandwidth_meter.define_method("user_stats", method!(BandwidthMeterRef::user_stats, -1))?;
+
+    let codec_version = module.const_get::<_, RClass>("CodecVersion").unwrap();
+
+    codec_version.define_alloc_func::<CodecVersionRef>();
+    codec_version.define_method("initialize", method!(CodecVersionRef::initialize, -1))?;
+    codec_version.define_method("recheck", method!(CodecVersionRef::recheck, 1))?;
+    codec_version.define_method("message", method!(CodecVersionRef::message, 0))?;
+    codec_version.define_method("apply", method!(CodecVersionRef::apply, 1))?;
+    codec_version.define_method("accepts?", method!(CodecVersionRef::accepts, 3))?;
+
+    let blob_store = module.const_get::<_, RClass>("BlobStore").unwrap();
+
+    blob_store.define_alloc_func::<BlobStoreRef>();
+    blob_store.define_method("initialize", method!(BlobStoreRef::initialize, -1))?;
+    blob_store.define_singleton_method("digest", function!(BlobStoreRef::digest, 1))?;
+
+    blob_store.define_method("store", method!(BlobStoreRef::store, 1))?;
+    blob_store.define_method("insert", method!(BlobStoreRef::insert, 1))?;
+    blob_store.define_method("[]", method!(BlobStoreRef::get, 1))?;
+    blob_store.define_method("include?", method!(BlobStoreRef::contains, 1))?;
+    blob_store.define_method("size", method!(BlobStoreRef::size, 0))?;
+    blob_store.define_method("compact", method!(BlobStoreRef::compact, 2))?;
+    blob_store.define_method("answer", method!(BlobStoreRef::answer, 3))?;
+    blob_store.define_method("request_missing", method!(BlobStoreRef::request_missing, 2))?;
+    blob_store.define_method("prune", method!(BlobStoreRef::prune, 2))?;
+
+    let positional = module.const_get::<_, RModule>("Positional").unwrap();
+
+    positional.define_module_function("distance", function!(positional_distance, 2))?;
+    positional.define_module_function("direction", function!(positional_direction, 2))?;
+    positional.define_module_function("gain", function!(positional_gain, -1))?;
+    positional.define_module_function("stereo_gains", function!(positional_stereo_gains, -1))?;
+
+    let plugin_data_router = module.const_get::<_, RClass>("PluginDataRouter").unwrap();
+
+    plugin_data_router.define_alloc_func::<PluginDataRouterRef>();
+    plugin_data_router.define_method("initialize", method!(PluginDataRouterRef::initialize, -1))?;
+    plugin_data_router.define_singleton_method("message", function!(PluginDataRouterRef::message, -1))?;
+    plugin_data_router.define_singleton_method("validate", function!(PluginDataRouterRef::validate, 1))?;
+
+    plugin_data_router.define_method("route", method!(PluginDataRouterRef::route, 3))?;
+    plugin_data_router.define_method("namespaces", method!(PluginDataRouterRef::namespaces, 0))?;
+    plugin_data_router.define_method("dropped", method!(PluginDataRouterRef::dropped, 0))?;
 
     Ok(())
 }
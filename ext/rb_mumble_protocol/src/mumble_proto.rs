@@ -0,0 +1,666 @@
+//! Protobuf messages of Mumble's TCP control channel
+//!
+//! Mirrors `Mumble.proto` of Mumble 1.5. The structs are written out by hand instead of being
+//! generated by `prost-build`, so building the extension doesn't require `protoc`.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/Mumble.proto
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Version {
+    /// Legacy version number, `major << 16 | minor << 8 | patch`.
+    #[prost(uint32, optional, tag = "1")]
+    pub version_v1: Option<u32>,
+    /// Version number, `major << 48 | minor << 32 | patch << 16`.
+    #[prost(uint64, optional, tag = "5")]
+    pub version_v2: Option<u64>,
+    #[prost(string, optional, tag = "2")]
+    pub release: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub os: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub os_version: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Authenticate {
+    #[prost(string, optional, tag = "1")]
+    pub username: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub password: Option<String>,
+    #[prost(string, repeated, tag = "3")]
+    pub tokens: Vec<String>,
+    #[prost(int32, repeated, packed = "false", tag = "4")]
+    pub celt_versions: Vec<i32>,
+    #[prost(bool, optional, tag = "5", default = "false")]
+    pub opus: Option<bool>,
+    /// 0 = regular client, 1 = bot.
+    #[prost(int32, optional, tag = "6", default = "0")]
+    pub client_type: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ping {
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "2")]
+    pub good: Option<u32>,
+    #[prost(uint32, optional, tag = "3")]
+    pub late: Option<u32>,
+    #[prost(uint32, optional, tag = "4")]
+    pub lost: Option<u32>,
+    #[prost(uint32, optional, tag = "5")]
+    pub resync: Option<u32>,
+    #[prost(uint32, optional, tag = "6")]
+    pub udp_packets: Option<u32>,
+    #[prost(uint32, optional, tag = "7")]
+    pub tcp_packets: Option<u32>,
+    #[prost(float, optional, tag = "8")]
+    pub udp_ping_avg: Option<f32>,
+    #[prost(float, optional, tag = "9")]
+    pub udp_ping_var: Option<f32>,
+    #[prost(float, optional, tag = "10")]
+    pub tcp_ping_avg: Option<f32>,
+    #[prost(float, optional, tag = "11")]
+    pub tcp_ping_var: Option<f32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Reject {
+    #[prost(enumeration = "reject::RejectType", optional, tag = "1")]
+    pub r#type: Option<i32>,
+    #[prost(string, optional, tag = "2")]
+    pub reason: Option<String>,
+}
+
+pub mod reject {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum RejectType {
+        None = 0,
+        WrongVersion = 1,
+        InvalidUsername = 2,
+        WrongUserPw = 3,
+        WrongServerPw = 4,
+        UsernameInUse = 5,
+        ServerFull = 6,
+        NoCertificate = 7,
+        AuthenticatorFail = 8,
+        NoNewConnections = 9,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerSync {
+    #[prost(uint32, optional, tag = "1")]
+    pub session: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub max_bandwidth: Option<u32>,
+    #[prost(string, optional, tag = "3")]
+    pub welcome_text: Option<String>,
+    #[prost(uint64, optional, tag = "4")]
+    pub permissions: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelRemove {
+    #[prost(uint32, required, tag = "1")]
+    pub channel_id: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelState {
+    #[prost(uint32, optional, tag = "1")]
+    pub channel_id: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub parent: Option<u32>,
+    #[prost(string, optional, tag = "3")]
+    pub name: Option<String>,
+    #[prost(uint32, repeated, packed = "false", tag = "4")]
+    pub links: Vec<u32>,
+    #[prost(string, optional, tag = "5")]
+    pub description: Option<String>,
+    #[prost(uint32, repeated, packed = "false", tag = "6")]
+    pub links_add: Vec<u32>,
+    #[prost(uint32, repeated, packed = "false", tag = "7")]
+    pub links_remove: Vec<u32>,
+    #[prost(bool, optional, tag = "8", default = "false")]
+    pub temporary: Option<bool>,
+    #[prost(int32, optional, tag = "9", default = "0")]
+    pub position: Option<i32>,
+    #[prost(bytes = "vec", optional, tag = "10")]
+    #[serde(with = "serde_bytes")]
+    pub description_hash: Option<Vec<u8>>,
+    #[prost(uint32, optional, tag = "11")]
+    pub max_users: Option<u32>,
+    #[prost(bool, optional, tag = "12")]
+    pub is_enter_restricted: Option<bool>,
+    #[prost(bool, optional, tag = "13")]
+    pub can_enter: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserRemove {
+    #[prost(uint32, required, tag = "1")]
+    pub session: u32,
+    #[prost(uint32, optional, tag = "2")]
+    pub actor: Option<u32>,
+    #[prost(string, optional, tag = "3")]
+    pub reason: Option<String>,
+    #[prost(bool, optional, tag = "4")]
+    pub ban: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserState {
+    #[prost(uint32, optional, tag = "1")]
+    pub session: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub actor: Option<u32>,
+    #[prost(string, optional, tag = "3")]
+    pub name: Option<String>,
+    #[prost(uint32, optional, tag = "4")]
+    pub user_id: Option<u32>,
+    #[prost(uint32, optional, tag = "5")]
+    pub channel_id: Option<u32>,
+    #[prost(bool, optional, tag = "6")]
+    pub mute: Option<bool>,
+    #[prost(bool, optional, tag = "7")]
+    pub deaf: Option<bool>,
+    #[prost(bool, optional, tag = "8")]
+    pub suppress: Option<bool>,
+    #[prost(bool, optional, tag = "9")]
+    pub self_mute: Option<bool>,
+    #[prost(bool, optional, tag = "10")]
+    pub self_deaf: Option<bool>,
+    #[prost(bytes = "vec", optional, tag = "11")]
+    #[serde(with = "serde_bytes")]
+    pub texture: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "12")]
+    #[serde(with = "serde_bytes")]
+    pub plugin_context: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "13")]
+    pub plugin_identity: Option<String>,
+    #[prost(string, optional, tag = "14")]
+    pub comment: Option<String>,
+    /// Hex encoded SHA-1 hash of the user's certificate.
+    #[prost(string, optional, tag = "15")]
+    pub hash: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "16")]
+    #[serde(with = "serde_bytes")]
+    pub comment_hash: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "17")]
+    #[serde(with = "serde_bytes")]
+    pub texture_hash: Option<Vec<u8>>,
+    #[prost(bool, optional, tag = "18")]
+    pub priority_speaker: Option<bool>,
+    #[prost(bool, optional, tag = "19")]
+    pub recording: Option<bool>,
+    #[prost(string, repeated, tag = "20")]
+    pub temporary_access_tokens: Vec<String>,
+    #[prost(uint32, repeated, packed = "false", tag = "21")]
+    pub listening_channel_add: Vec<u32>,
+    #[prost(uint32, repeated, packed = "false", tag = "22")]
+    pub listening_channel_remove: Vec<u32>,
+    #[prost(message, repeated, tag = "23")]
+    pub listening_volume_adjustment: Vec<user_state::VolumeAdjustment>,
+}
+
+pub mod user_state {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct VolumeAdjustment {
+        #[prost(uint32, optional, tag = "1")]
+        pub listening_channel: Option<u32>,
+        #[prost(float, optional, tag = "2")]
+        pub volume_adjustment: Option<f32>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BanList {
+    #[prost(message, repeated, tag = "1")]
+    pub bans: Vec<ban_list::BanEntry>,
+    #[prost(bool, optional, tag = "2", default = "false")]
+    pub query: Option<bool>,
+}
+
+pub mod ban_list {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct BanEntry {
+        /// IPv6 address, IPv4 addresses are IPv4-mapped.
+        #[prost(bytes = "vec", required, tag = "1")]
+        #[serde(with = "serde_bytes")]
+        pub address: Vec<u8>,
+        #[prost(uint32, required, tag = "2")]
+        pub mask: u32,
+        #[prost(string, optional, tag = "3")]
+        pub name: Option<String>,
+        #[prost(string, optional, tag = "4")]
+        pub hash: Option<String>,
+        #[prost(string, optional, tag = "5")]
+        pub reason: Option<String>,
+        /// ISO 8601 timestamp of the ban's start.
+        #[prost(string, optional, tag = "6")]
+        pub start: Option<String>,
+        /// Duration in seconds, 0 means permanent.
+        #[prost(uint32, optional, tag = "7")]
+        pub duration: Option<u32>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TextMessage {
+    #[prost(uint32, optional, tag = "1")]
+    pub actor: Option<u32>,
+    #[prost(uint32, repeated, packed = "false", tag = "2")]
+    pub session: Vec<u32>,
+    #[prost(uint32, repeated, packed = "false", tag = "3")]
+    pub channel_id: Vec<u32>,
+    #[prost(uint32, repeated, packed = "false", tag = "4")]
+    pub tree_id: Vec<u32>,
+    #[prost(string, required, tag = "5")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PermissionDenied {
+    #[prost(uint32, optional, tag = "1")]
+    pub permission: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub channel_id: Option<u32>,
+    #[prost(uint32, optional, tag = "3")]
+    pub session: Option<u32>,
+    #[prost(string, optional, tag = "4")]
+    pub reason: Option<String>,
+    #[prost(enumeration = "permission_denied::DenyType", optional, tag = "5")]
+    pub r#type: Option<i32>,
+    #[prost(string, optional, tag = "6")]
+    pub name: Option<String>,
+}
+
+pub mod permission_denied {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum DenyType {
+        Text = 0,
+        Permission = 1,
+        SuperUser = 2,
+        ChannelName = 3,
+        TextTooLong = 4,
+        H9k = 5,
+        TemporaryChannel = 6,
+        MissingCertificate = 7,
+        UserName = 8,
+        ChannelFull = 9,
+        NestingLimit = 10,
+        ChannelCountLimit = 11,
+        ChannelListenerLimit = 12,
+        UserListenerLimit = 13,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Acl {
+    #[prost(uint32, required, tag = "1")]
+    pub channel_id: u32,
+    #[prost(bool, optional, tag = "2", default = "true")]
+    pub inherit_acls: Option<bool>,
+    #[prost(message, repeated, tag = "3")]
+    pub groups: Vec<acl::ChanGroup>,
+    #[prost(message, repeated, tag = "4")]
+    pub acls: Vec<acl::ChanAcl>,
+    #[prost(bool, optional, tag = "5", default = "false")]
+    pub query: Option<bool>,
+}
+
+pub mod acl {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct ChanGroup {
+        #[prost(string, required, tag = "1")]
+        pub name: String,
+        #[prost(bool, optional, tag = "2", default = "true")]
+        pub inherited: Option<bool>,
+        #[prost(bool, optional, tag = "3", default = "true")]
+        pub inherit: Option<bool>,
+        #[prost(bool, optional, tag = "4", default = "true")]
+        pub inheritable: Option<bool>,
+        #[prost(uint32, repeated, packed = "false", tag = "5")]
+        pub add: Vec<u32>,
+        #[prost(uint32, repeated, packed = "false", tag = "6")]
+        pub remove: Vec<u32>,
+        #[prost(uint32, repeated, packed = "false", tag = "7")]
+        pub inherited_members: Vec<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct ChanAcl {
+        #[prost(bool, optional, tag = "1", default = "true")]
+        pub apply_here: Option<bool>,
+        #[prost(bool, optional, tag = "2", default = "true")]
+        pub apply_subs: Option<bool>,
+        #[prost(bool, optional, tag = "3", default = "true")]
+        pub inherited: Option<bool>,
+        #[prost(uint32, optional, tag = "4")]
+        pub user_id: Option<u32>,
+        #[prost(string, optional, tag = "5")]
+        pub group: Option<String>,
+        #[prost(uint32, optional, tag = "6")]
+        pub grant: Option<u32>,
+        #[prost(uint32, optional, tag = "7")]
+        pub deny: Option<u32>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueryUsers {
+    #[prost(uint32, repeated, packed = "false", tag = "1")]
+    pub ids: Vec<u32>,
+    #[prost(string, repeated, tag = "2")]
+    pub names: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CryptSetup {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    #[serde(with = "serde_bytes")]
+    pub key: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    #[serde(with = "serde_bytes")]
+    pub client_nonce: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    #[serde(with = "serde_bytes")]
+    pub server_nonce: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextActionModify {
+    #[prost(string, required, tag = "1")]
+    pub action: String,
+    #[prost(string, optional, tag = "2")]
+    pub text: Option<String>,
+    /// Bitmask of `context_action_modify::Context`.
+    #[prost(uint32, optional, tag = "3")]
+    pub context: Option<u32>,
+    #[prost(enumeration = "context_action_modify::Operation", optional, tag = "4")]
+    pub operation: Option<i32>,
+}
+
+pub mod context_action_modify {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Context {
+        Server = 1,
+        Channel = 2,
+        User = 4,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Operation {
+        Add = 0,
+        Remove = 1,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextAction {
+    #[prost(uint32, optional, tag = "1")]
+    pub session: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub channel_id: Option<u32>,
+    #[prost(string, required, tag = "3")]
+    pub action: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserList {
+    #[prost(message, repeated, tag = "1")]
+    pub users: Vec<user_list::User>,
+}
+
+pub mod user_list {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct User {
+        #[prost(uint32, required, tag = "1")]
+        pub user_id: u32,
+        #[prost(string, optional, tag = "2")]
+        pub name: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        pub last_seen: Option<String>,
+        #[prost(uint32, optional, tag = "4")]
+        pub last_channel: Option<u32>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VoiceTarget {
+    /// 1 to 30, the target id used in the voice packet header.
+    #[prost(uint32, optional, tag = "1")]
+    pub id: Option<u32>,
+    #[prost(message, repeated, tag = "2")]
+    pub targets: Vec<voice_target::Target>,
+}
+
+pub mod voice_target {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct Target {
+        #[prost(uint32, repeated, packed = "false", tag = "1")]
+        pub session: Vec<u32>,
+        #[prost(uint32, optional, tag = "2")]
+        pub channel_id: Option<u32>,
+        #[prost(string, optional, tag = "3")]
+        pub group: Option<String>,
+        #[prost(bool, optional, tag = "4", default = "false")]
+        pub links: Option<bool>,
+        #[prost(bool, optional, tag = "5", default = "false")]
+        pub children: Option<bool>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PermissionQuery {
+    #[prost(uint32, optional, tag = "1")]
+    pub channel_id: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub permissions: Option<u32>,
+    #[prost(bool, optional, tag = "3", default = "false")]
+    pub flush: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CodecVersion {
+    #[prost(int32, required, tag = "1")]
+    pub alpha: i32,
+    #[prost(int32, required, tag = "2")]
+    pub beta: i32,
+    #[prost(bool, required, tag = "3", default = "true")]
+    pub prefer_alpha: bool,
+    #[prost(bool, optional, tag = "4", default = "false")]
+    pub opus: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserStats {
+    #[prost(uint32, optional, tag = "1")]
+    pub session: Option<u32>,
+    #[prost(bool, optional, tag = "2", default = "false")]
+    pub stats_only: Option<bool>,
+    /// DER encoded certificate chain of the user.
+    #[prost(bytes = "vec", repeated, tag = "3")]
+    #[serde(with = "bytes_list")]
+    pub certificates: Vec<Vec<u8>>,
+    #[prost(message, optional, tag = "4")]
+    pub from_client: Option<user_stats::Stats>,
+    #[prost(message, optional, tag = "5")]
+    pub from_server: Option<user_stats::Stats>,
+    #[prost(uint32, optional, tag = "6")]
+    pub udp_packets: Option<u32>,
+    #[prost(uint32, optional, tag = "7")]
+    pub tcp_packets: Option<u32>,
+    #[prost(float, optional, tag = "8")]
+    pub udp_ping_avg: Option<f32>,
+    #[prost(float, optional, tag = "9")]
+    pub udp_ping_var: Option<f32>,
+    #[prost(float, optional, tag = "10")]
+    pub tcp_ping_avg: Option<f32>,
+    #[prost(float, optional, tag = "11")]
+    pub tcp_ping_var: Option<f32>,
+    #[prost(message, optional, tag = "12")]
+    pub version: Option<Version>,
+    #[prost(int32, repeated, packed = "false", tag = "13")]
+    pub celt_versions: Vec<i32>,
+    #[prost(bytes = "vec", optional, tag = "14")]
+    #[serde(with = "serde_bytes")]
+    pub address: Option<Vec<u8>>,
+    #[prost(uint32, optional, tag = "15")]
+    pub bandwidth: Option<u32>,
+    #[prost(uint32, optional, tag = "16")]
+    pub onlinesecs: Option<u32>,
+    #[prost(uint32, optional, tag = "17")]
+    pub idlesecs: Option<u32>,
+    #[prost(bool, optional, tag = "18", default = "false")]
+    pub strong_certificate: Option<bool>,
+    #[prost(bool, optional, tag = "19", default = "false")]
+    pub opus: Option<bool>,
+    #[prost(message, optional, tag = "20")]
+    pub rolling_stats: Option<user_stats::RollingStats>,
+}
+
+pub mod user_stats {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct Stats {
+        #[prost(uint32, optional, tag = "1")]
+        pub good: Option<u32>,
+        #[prost(uint32, optional, tag = "2")]
+        pub late: Option<u32>,
+        #[prost(uint32, optional, tag = "3")]
+        pub lost: Option<u32>,
+        #[prost(uint32, optional, tag = "4")]
+        pub resync: Option<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct RollingStats {
+        #[prost(uint32, optional, tag = "1")]
+        pub time_window: Option<u32>,
+        #[prost(message, optional, tag = "2")]
+        pub from_client: Option<Stats>,
+        #[prost(message, optional, tag = "3")]
+        pub from_server: Option<Stats>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestBlob {
+    #[prost(uint32, repeated, packed = "false", tag = "1")]
+    pub session_texture: Vec<u32>,
+    #[prost(uint32, repeated, packed = "false", tag = "2")]
+    pub session_comment: Vec<u32>,
+    #[prost(uint32, repeated, packed = "false", tag = "3")]
+    pub channel_description: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    #[prost(uint32, optional, tag = "1")]
+    pub max_bandwidth: Option<u32>,
+    #[prost(string, optional, tag = "2")]
+    pub welcome_text: Option<String>,
+    #[prost(bool, optional, tag = "3")]
+    pub allow_html: Option<bool>,
+    #[prost(uint32, optional, tag = "4")]
+    pub message_length: Option<u32>,
+    #[prost(uint32, optional, tag = "5")]
+    pub image_message_length: Option<u32>,
+    #[prost(uint32, optional, tag = "6")]
+    pub max_users: Option<u32>,
+    #[prost(bool, optional, tag = "7")]
+    pub recording_allowed: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SuggestConfig {
+    #[prost(uint32, optional, tag = "1")]
+    pub version_v1: Option<u32>,
+    #[prost(uint64, optional, tag = "4")]
+    pub version_v2: Option<u64>,
+    #[prost(bool, optional, tag = "2")]
+    pub positional: Option<bool>,
+    #[prost(bool, optional, tag = "3")]
+    pub push_to_talk: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PluginDataTransmission {
+    #[prost(uint32, optional, tag = "1")]
+    pub sender_session: Option<u32>,
+    #[prost(uint32, repeated, packed = "true", tag = "2")]
+    pub receiver_sessions: Vec<u32>,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    #[serde(with = "serde_bytes")]
+    pub data: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "4")]
+    pub data_id: Option<String>,
+}
+
+/// (De)serializes `repeated bytes` fields as a list of byte strings rather than lists of integers.
+mod bytes_list {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub fn serialize<S: Serializer>(list: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter().map(|bytes| Bytes::new(bytes)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        let list = Vec::<ByteBuf>::deserialize(deserializer)?;
+        Ok(list.into_iter().map(ByteBuf::into_vec).collect())
+    }
+}
@@ -0,0 +1,371 @@
+//! Recording of Opus voice packets into an Ogg Opus file
+//!
+//! The packets are muxed as they are, without decoding. Gaps in the sequence numbers are filled
+//! with silent frames, so the timing of the recording matches the transmission. Gaps longer than
+//! `MAX_SILENCE`, e.g. after a client restarted its sequence numbers, are shortened to it.
+//!
+//! See RFC 3533 (Ogg) and RFC 7845 (Ogg encapsulation for Opus).
+
+use std::io::{self, Write};
+
+use crate::opus;
+
+/// Duration in samples of one unit of Mumble's sequence numbers (10 ms).
+const SAMPLES_PER_SEQ: u64 = 480;
+/// Longest silence written at once, in 10 ms frames (one minute).
+pub const MAX_SILENCE: u64 = 6000;
+/// Silent CELT fullband frames of 20 and 10 ms.
+const SILENCE_20MS: &[u8] = &[0xf8, 0xff, 0xfe];
+const SILENCE_10MS: &[u8] = &[0xf0, 0xff, 0xfe];
+/// Audio pages are flushed once they hold about a second of audio.
+const PAGE_SAMPLES: u64 = 48_000;
+const MAX_PAGE_SEGMENTS: usize = 255;
+
+const HEADER_BOS: u8 = 0x02;
+const HEADER_EOS: u8 = 0x04;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// 1 for mono or 2 for stereo.
+    pub channels: u8,
+    /// Samples to discard at the start of playback (the encoder's lookahead).
+    pub pre_skip: u16,
+    /// Bitstream serial number, should be random.
+    pub serial: u32,
+    pub vendor: String,
+    /// User comments, e.g. `TITLE=General`.
+    pub comments: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            channels: 1,
+            pre_skip: 0,
+            serial: 0,
+            vendor: concat!("rb_mumble_protocol ", env!("CARGO_PKG_VERSION")).to_owned(),
+            comments: Vec::new(),
+        }
+    }
+}
+
+/// What happened to a written packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Written {
+    /// The packet was appended, after this many frames of silence, at most `MAX_SILENCE`.
+    Appended { silence: u64 },
+    /// The packet's sequence number was already written.
+    Skipped,
+}
+
+/// Writes an Ogg Opus stream.
+pub struct OggOpusWriter<W: Write> {
+    inner: W,
+    config: Config,
+    page_seq: u32,
+    /// Granule position after the last written packet.
+    granule: u64,
+    next_seq: Option<u64>,
+    /// Packets of the page being assembled.
+    packets: Vec<Vec<u8>>,
+    page_start_granule: u64,
+    started: bool,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    pub fn new(inner: W, config: Config) -> Self {
+        let granule = u64::from(config.pre_skip);
+        OggOpusWriter {
+            inner,
+            config,
+            page_seq: 0,
+            granule,
+            next_seq: None,
+            packets: Vec::new(),
+            page_start_granule: granule,
+            started: false,
+        }
+    }
+
+    /// Returns the writer the stream is written to.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the amount of samples written so far, excluding the pre-skip.
+    pub fn samples(&self) -> u64 {
+        self.granule - u64::from(self.config.pre_skip)
+    }
+
+    /// Appends an Opus packet with the given sequence number, preceded by silence for any gap.
+    ///
+    /// Packets have to be written in order, ones with an already written sequence number are
+    /// skipped.
+    pub fn write_packet(&mut self, seq: u64, packet: &[u8]) -> io::Result<Written> {
+        let info = opus::parse_packet(packet)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let silence = match self.next_seq {
+            Some(next_seq) if seq < next_seq => return Ok(Written::Skipped),
+            Some(next_seq) => (seq - next_seq).min(MAX_SILENCE),
+            None => 0,
+        };
+        self.write_silence(silence)?;
+
+        let samples = u64::from(info.total_samples());
+        self.next_seq = Some(seq.saturating_add(samples.div_ceil(SAMPLES_PER_SEQ)));
+        self.append(packet.to_vec(), samples)?;
+
+        Ok(Written::Appended { silence })
+    }
+
+    /// Appends this many 10 ms frames of silence, e.g. for a pause between talk spurts, at most
+    /// `MAX_SILENCE`.
+    pub fn write_silence(&mut self, frames: u64) -> io::Result<()> {
+        let frames = frames.min(MAX_SILENCE);
+        for _ in 0..frames / 2 {
+            self.append(SILENCE_20MS.to_vec(), 2 * SAMPLES_PER_SEQ)?;
+        }
+        if frames % 2 == 1 {
+            self.append(SILENCE_10MS.to_vec(), SAMPLES_PER_SEQ)?;
+        }
+        if let Some(next_seq) = &mut self.next_seq {
+            *next_seq = next_seq.saturating_add(frames);
+        }
+        Ok(())
+    }
+
+    /// Writes the last page and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_headers()?;
+        self.flush_page(HEADER_EOS)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn append(&mut self, packet: Vec<u8>, samples: u64) -> io::Result<()> {
+        self.write_headers()?;
+
+        let segments: usize = self.packets.iter().map(|packet| lacing_len(packet)).sum();
+        if segments + lacing_len(&packet) > MAX_PAGE_SEGMENTS
+            || self.granule - self.page_start_granule >= PAGE_SAMPLES
+        {
+            self.flush_page(0)?;
+        }
+
+        self.packets.push(packet);
+        self.granule += samples;
+        Ok(())
+    }
+
+    /// Writes the identification and comment headers, each on its own page.
+    fn write_headers(&mut self) -> io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1); // version
+        head.push(self.config.channels);
+        head.extend_from_slice(&self.config.pre_skip.to_le_bytes());
+        head.extend_from_slice(&opus::SAMPLE_RATE.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family
+        self.write_page(HEADER_BOS, 0, &[head])?;
+
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(self.config.vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(self.config.vendor.as_bytes());
+        tags.extend_from_slice(&(self.config.comments.len() as u32).to_le_bytes());
+        for comment in &self.config.comments {
+            tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            tags.extend_from_slice(comment.as_bytes());
+        }
+        self.write_page(0, 0, &[tags])
+    }
+
+    fn flush_page(&mut self, flags: u8) -> io::Result<()> {
+        if self.packets.is_empty() && flags & HEADER_EOS == 0 {
+            return Ok(());
+        }
+        let packets = std::mem::take(&mut self.packets);
+        self.write_page(flags, self.granule, &packets)?;
+        self.page_start_granule = self.granule;
+        Ok(())
+    }
+
+    fn write_page(&mut self, flags: u8, granule: u64, packets: &[Vec<u8>]) -> io::Result<()> {
+        let mut page = Vec::with_capacity(27 + MAX_PAGE_SEGMENTS);
+        page.extend_from_slice(b"OggS");
+        page.push(0); // version
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&self.config.serial.to_le_bytes());
+        page.extend_from_slice(&self.page_seq.to_le_bytes());
+        page.extend_from_slice(&[0; 4]); // checksum, filled in below
+
+        let segments: usize = packets.iter().map(|packet| lacing_len(packet)).sum();
+        page.push(segments as u8);
+        for packet in packets {
+            page.extend(std::iter::repeat_n(255, packet.len() / 255));
+            page.push((packet.len() % 255) as u8);
+        }
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+
+        let checksum = crc32(&page);
+        page[22..26].copy_from_slice(&checksum.to_le_bytes());
+
+        self.page_seq += 1;
+        self.inner.write_all(&page)
+    }
+}
+
+/// Returns the amount of lacing values (segments) needed for a packet.
+fn lacing_len(packet: &[u8]) -> usize {
+    packet.len() / 255 + 1
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = (index as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+/// CRC-32 as used by Ogg: polynomial 0x04c11db7, no reflection, no final XOR.
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // CELT fullband, 20 ms
+    const PACKET: &[u8] = &[0xf8, 0x01, 0x02];
+
+    struct Page {
+        flags: u8,
+        granule: u64,
+        seq: u32,
+        packets: Vec<Vec<u8>>,
+    }
+
+    fn parse_pages(mut data: &[u8]) -> Vec<Page> {
+        let mut pages = Vec::new();
+        while !data.is_empty() {
+            assert_eq!(b"OggS", &data[..4]);
+            let segments = data[26] as usize;
+            let lacing = &data[27..27 + segments];
+            let len = 27 + segments + lacing.iter().map(|&l| l as usize).sum::<usize>();
+
+            let mut page = data[..len].to_vec();
+            page[22..26].fill(0);
+            assert_eq!(u32::from_le_bytes(data[22..26].try_into().unwrap()), crc32(&page));
+
+            let mut packets = Vec::new();
+            let mut body = &data[27 + segments..len];
+            let mut current = Vec::new();
+            for &l in lacing {
+                current.extend_from_slice(&body[..l as usize]);
+                body = &body[l as usize..];
+                if l < 255 {
+                    packets.push(std::mem::take(&mut current));
+                }
+            }
+
+            pages.push(Page {
+                flags: data[5],
+                granule: u64::from_le_bytes(data[6..14].try_into().unwrap()),
+                seq: u32::from_le_bytes(data[18..22].try_into().unwrap()),
+                packets,
+            });
+            data = &data[len..];
+        }
+        pages
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        // CRC-32/POSIX without the final XOR
+        assert_eq!(0x89a1_897f, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn writes_headers_and_audio() {
+        let mut writer = OggOpusWriter::new(Vec::new(), Config { serial: 42, ..Default::default() });
+        writer.write_packet(10, PACKET).unwrap();
+        writer.write_packet(12, PACKET).unwrap();
+        let pages = parse_pages(&writer.finish().unwrap());
+
+        assert_eq!(3, pages.len());
+        assert_eq!(HEADER_BOS, pages[0].flags);
+        assert_eq!(b"OpusHead", &pages[0].packets[0][..8]);
+        assert_eq!(b"OpusTags", &pages[1].packets[0][..8]);
+        assert_eq!(HEADER_EOS, pages[2].flags);
+        assert_eq!(1920, pages[2].granule);
+        assert_eq!(vec![0, 1, 2], pages.iter().map(|page| page.seq).collect::<Vec<_>>());
+        assert_eq!(vec![PACKET.to_vec(); 2], pages[2].packets);
+    }
+
+    #[test]
+    fn fills_gaps_with_silence() {
+        let mut writer = OggOpusWriter::new(Vec::new(), Config::default());
+        writer.write_packet(0, PACKET).unwrap();
+        assert_eq!(Written::Appended { silence: 3 }, writer.write_packet(5, PACKET).unwrap());
+        assert_eq!(Written::Skipped, writer.write_packet(4, PACKET).unwrap());
+        assert_eq!(3 * 480 + 2 * 960, writer.samples());
+
+        let pages = parse_pages(&writer.finish().unwrap());
+        let packets = &pages[2].packets;
+        assert_eq!(vec![PACKET, SILENCE_20MS, SILENCE_10MS, PACKET], packets.iter().map(Vec::as_slice).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shortens_long_gaps() {
+        let mut writer = OggOpusWriter::new(Vec::new(), Config::default());
+        writer.write_packet(0, PACKET).unwrap();
+        assert_eq!(Written::Appended { silence: MAX_SILENCE }, writer.write_packet(u64::MAX - 3, PACKET).unwrap());
+        assert_eq!(Written::Appended { silence: 0 }, writer.write_packet(u64::MAX - 1, PACKET).unwrap());
+        assert_eq!(Written::Skipped, writer.write_packet(u64::MAX - 2, PACKET).unwrap());
+        assert_eq!(MAX_SILENCE * 480 + 3 * 960, writer.samples());
+
+        writer.write_silence(u64::MAX).unwrap();
+        assert_eq!(2 * MAX_SILENCE * 480 + 3 * 960, writer.samples());
+    }
+
+    #[test]
+    fn splits_pages() {
+        let mut writer = OggOpusWriter::new(Vec::new(), Config { pre_skip: 312, ..Default::default() });
+        for seq in 0..60 {
+            writer.write_packet(seq * 2, PACKET).unwrap();
+        }
+        let pages = parse_pages(&writer.finish().unwrap());
+
+        assert_eq!(4, pages.len());
+        assert_eq!(312 + 48_000, pages[2].granule);
+        assert_eq!(312 + 60 * 960, pages[3].granule);
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let mut writer = OggOpusWriter::new(Vec::new(), Config::default());
+        assert!(writer.write_packet(0, &[]).is_err());
+    }
+}
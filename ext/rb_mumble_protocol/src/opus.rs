@@ -0,0 +1,346 @@
+//! Inspection of Opus packets without decoding them
+//!
+//! Parses the TOC byte and frame lengths as specified in RFC 6716, section 3.
+
+/// Sample rate all Opus durations are expressed in.
+pub const SAMPLE_RATE: u32 = 48_000;
+/// Longest duration of a single Opus packet, in samples (120 ms).
+pub const MAX_PACKET_SAMPLES: u32 = 5_760;
+/// Longest encoded frame allowed by RFC 6716.
+pub const MAX_FRAME_SIZE: usize = 1_275;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Silk,
+    Hybrid,
+    Celt,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Silk => "silk",
+            Mode::Hybrid => "hybrid",
+            Mode::Celt => "celt",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bandwidth {
+    /// 4 kHz
+    Narrowband,
+    /// 6 kHz
+    Mediumband,
+    /// 8 kHz
+    Wideband,
+    /// 12 kHz
+    SuperWideband,
+    /// 20 kHz
+    Fullband,
+}
+
+impl Bandwidth {
+    pub fn name(self) -> &'static str {
+        match self {
+            Bandwidth::Narrowband => "narrowband",
+            Bandwidth::Mediumband => "mediumband",
+            Bandwidth::Wideband => "wideband",
+            Bandwidth::SuperWideband => "super_wideband",
+            Bandwidth::Fullband => "fullband",
+        }
+    }
+}
+
+/// The reason an Opus packet is malformed (see RFC 6716, section 3.4).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpusError {
+    /// The packet is empty, i.e. it doesn't even have a TOC byte.
+    Empty,
+    /// The packet ends before the frame lengths or frames announced in it.
+    Truncated,
+    /// A frame is larger than 1275 bytes.
+    FrameTooLarge,
+    /// The frames of a packet with equal sized frames can't be evenly split.
+    UnevenFrames,
+    /// A code 3 packet announces no frames or more than 120 ms of audio.
+    InvalidFrameCount,
+}
+
+impl std::fmt::Display for OpusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            OpusError::Empty => "empty packet",
+            OpusError::Truncated => "truncated packet",
+            OpusError::FrameTooLarge => "frame larger than 1275 bytes",
+            OpusError::UnevenFrames => "frames of equal size don't add up",
+            OpusError::InvalidFrameCount => "invalid frame count",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for OpusError {}
+
+/// The table of contents byte starting every Opus packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Toc(pub u8);
+
+impl Toc {
+    /// Configuration number 0 to 31, selecting mode, bandwidth and frame duration.
+    pub fn config(self) -> u8 {
+        self.0 >> 3
+    }
+
+    pub fn stereo(self) -> bool {
+        self.0 & 0x04 != 0
+    }
+
+    /// Frame count code 0 to 3.
+    pub fn frame_count_code(self) -> u8 {
+        self.0 & 0x03
+    }
+
+    pub fn mode(self) -> Mode {
+        match self.config() {
+            0..=11 => Mode::Silk,
+            12..=15 => Mode::Hybrid,
+            _ => Mode::Celt,
+        }
+    }
+
+    pub fn bandwidth(self) -> Bandwidth {
+        match self.config() {
+            0..=3 | 16..=19 => Bandwidth::Narrowband,
+            4..=7 => Bandwidth::Mediumband,
+            8..=11 | 20..=23 => Bandwidth::Wideband,
+            12..=13 | 24..=27 => Bandwidth::SuperWideband,
+            _ => Bandwidth::Fullband,
+        }
+    }
+
+    /// Duration of every frame in the packet, in samples at 48 kHz.
+    pub fn frame_samples(self) -> u32 {
+        let config = self.config();
+        match self.mode() {
+            // 10, 20, 40, 60 ms
+            Mode::Silk => [480, 960, 1920, 2880][(config % 4) as usize],
+            // 10, 20 ms
+            Mode::Hybrid => [480, 960][(config % 2) as usize],
+            // 2.5, 5, 10, 20 ms
+            Mode::Celt => [120, 240, 480, 960][(config % 4) as usize],
+        }
+    }
+}
+
+/// Everything that can be learned about an Opus packet without decoding it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub toc: Toc,
+    /// Length in bytes of each frame. A length of 0 means the frame was not transmitted (DTX).
+    pub frame_sizes: Vec<usize>,
+    /// Amount of padding bytes at the end of a code 3 packet.
+    pub padding: usize,
+}
+
+impl PacketInfo {
+    pub fn mode(&self) -> Mode {
+        self.toc.mode()
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.toc.bandwidth()
+    }
+
+    pub fn stereo(&self) -> bool {
+        self.toc.stereo()
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frame_sizes.len()
+    }
+
+    /// Duration of a single frame, in samples at 48 kHz.
+    pub fn frame_samples(&self) -> u32 {
+        self.toc.frame_samples()
+    }
+
+    /// Duration of a single frame, in milliseconds.
+    pub fn frame_duration_ms(&self) -> f32 {
+        self.frame_samples() as f32 * 1000.0 / SAMPLE_RATE as f32
+    }
+
+    /// Duration of the whole packet, in samples at 48 kHz.
+    pub fn total_samples(&self) -> u32 {
+        self.frame_samples() * self.frame_count() as u32
+    }
+
+    /// Duration of the whole packet, in milliseconds.
+    pub fn duration_ms(&self) -> f32 {
+        self.total_samples() as f32 * 1000.0 / SAMPLE_RATE as f32
+    }
+}
+
+/// Parses the TOC byte and the frame lengths of an Opus packet.
+pub fn parse_packet(packet: &[u8]) -> Result<PacketInfo, OpusError> {
+    let (&toc, mut data) = packet.split_first().ok_or(OpusError::Empty)?;
+    let toc = Toc(toc);
+    let mut padding = 0;
+
+    let frame_sizes = match toc.frame_count_code() {
+        // one frame
+        0 => vec![data.len()],
+        // two frames of equal size
+        1 => {
+            if data.len() % 2 != 0 {
+                return Err(OpusError::UnevenFrames);
+            }
+            vec![data.len() / 2; 2]
+        }
+        // two frames of different size
+        2 => {
+            let first = read_frame_size(&mut data)?;
+            if first > data.len() {
+                return Err(OpusError::Truncated);
+            }
+            vec![first, data.len() - first]
+        }
+        // arbitrary amount of frames
+        _ => {
+            let (&count, rest) = data.split_first().ok_or(OpusError::Truncated)?;
+            data = rest;
+
+            let vbr = count & 0x80 != 0;
+            let has_padding = count & 0x40 != 0;
+            let frames = (count & 0x3f) as usize;
+            if frames == 0 || frames as u32 * toc.frame_samples() > MAX_PACKET_SAMPLES {
+                return Err(OpusError::InvalidFrameCount);
+            }
+
+            if has_padding {
+                loop {
+                    let (&byte, rest) = data.split_first().ok_or(OpusError::Truncated)?;
+                    data = rest;
+                    if byte == 255 {
+                        padding += 254;
+                    } else {
+                        padding += byte as usize;
+                        break;
+                    }
+                }
+            }
+
+            if vbr {
+                let mut sizes = Vec::with_capacity(frames);
+                for _ in 1..frames {
+                    sizes.push(read_frame_size(&mut data)?);
+                }
+                let used = sizes.iter().sum::<usize>() + padding;
+                if used > data.len() {
+                    return Err(OpusError::Truncated);
+                }
+                sizes.push(data.len() - used);
+                sizes
+            } else {
+                let available = data.len().checked_sub(padding).ok_or(OpusError::Truncated)?;
+                if available % frames != 0 {
+                    return Err(OpusError::UnevenFrames);
+                }
+                vec![available / frames; frames]
+            }
+        }
+    };
+
+    if frame_sizes.iter().any(|&size| size > MAX_FRAME_SIZE) {
+        return Err(OpusError::FrameTooLarge);
+    }
+
+    Ok(PacketInfo { toc, frame_sizes, padding })
+}
+
+/// Reads a frame length coded in one or two bytes.
+fn read_frame_size(data: &mut &[u8]) -> Result<usize, OpusError> {
+    match *data {
+        [first @ 0..=251, ref rest @ ..] => {
+            *data = rest;
+            Ok(*first as usize)
+        }
+        [first, second, ref rest @ ..] => {
+            *data = rest;
+            Ok(*second as usize * 4 + *first as usize)
+        }
+        _ => Err(OpusError::Truncated),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn toc_configurations() {
+        // SILK wideband, 20 ms, mono, one frame
+        let toc = Toc(9 << 3);
+        assert_eq!((Mode::Silk, Bandwidth::Wideband, 960, false), (toc.mode(), toc.bandwidth(), toc.frame_samples(), toc.stereo()));
+
+        // Hybrid fullband, 10 ms, stereo
+        let toc = Toc(14 << 3 | 0x04);
+        assert_eq!((Mode::Hybrid, Bandwidth::Fullband, 480, true), (toc.mode(), toc.bandwidth(), toc.frame_samples(), toc.stereo()));
+
+        // CELT super-wideband, 2.5 ms
+        let toc = Toc(24 << 3);
+        assert_eq!((Mode::Celt, Bandwidth::SuperWideband, 120), (toc.mode(), toc.bandwidth(), toc.frame_samples()));
+    }
+
+    #[test]
+    fn single_frame() {
+        let info = parse_packet(&[31 << 3, 1, 2, 3]).unwrap();
+        assert_eq!(vec![3], info.frame_sizes);
+        assert_eq!(960, info.total_samples());
+        assert_eq!(20.0, info.duration_ms());
+    }
+
+    #[test]
+    fn two_equal_frames() {
+        let info = parse_packet(&[31 << 3 | 1, 1, 2, 3, 4]).unwrap();
+        assert_eq!(vec![2, 2], info.frame_sizes);
+        assert_eq!(40.0, info.duration_ms());
+
+        assert_eq!(Err(OpusError::UnevenFrames), parse_packet(&[31 << 3 | 1, 1, 2, 3]));
+    }
+
+    #[test]
+    fn two_different_frames() {
+        let info = parse_packet(&[31 << 3 | 2, 1, 0xaa, 0xbb, 0xcc]).unwrap();
+        assert_eq!(vec![1, 2], info.frame_sizes);
+
+        // two byte length: 252 + 4 * 1 = 256
+        let mut packet = vec![31 << 3 | 2, 252, 1];
+        packet.resize(3 + 256 + 10, 0);
+        assert_eq!(vec![256, 10], parse_packet(&packet).unwrap().frame_sizes);
+
+        assert_eq!(Err(OpusError::Truncated), parse_packet(&[31 << 3 | 2, 5, 0]));
+    }
+
+    #[test]
+    fn arbitrary_frames() {
+        // CBR, 3 frames of 2 bytes, 2 bytes of padding
+        let info = parse_packet(&[28 << 3 | 3, 0x40 | 3, 2, 1, 1, 2, 2, 3, 3, 0, 0]).unwrap();
+        assert_eq!(vec![2, 2, 2], info.frame_sizes);
+        assert_eq!(2, info.padding);
+        assert_eq!(360, info.total_samples());
+
+        // VBR, 3 frames of 1, 0 (DTX) and 2 bytes
+        let info = parse_packet(&[28 << 3 | 3, 0x80 | 3, 1, 0, 1, 3, 3]).unwrap();
+        assert_eq!(vec![1, 0, 2], info.frame_sizes);
+
+        // 3 * 60 ms > 120 ms
+        assert_eq!(Err(OpusError::InvalidFrameCount), parse_packet(&[3 << 3 | 3, 3]));
+        assert_eq!(Err(OpusError::InvalidFrameCount), parse_packet(&[3 << 3 | 3, 0]));
+    }
+
+    #[test]
+    fn empty_packet() {
+        assert_eq!(Err(OpusError::Empty), parse_packet(&[]));
+    }
+}
@@ -0,0 +1,798 @@
+//! Reading and writing of packet captures
+//!
+//! Captures in the classic pcap format and in pcapng (as written by tcpdump, dumpcap and
+//! Wireshark) can be read. The captured frames are parsed down to their UDP datagrams and TCP
+//! segments, everything else is skipped. Captures are written in the classic format, with raw IP
+//! frames, so synthetic sessions can be produced for tests.
+//!
+//! See https://www.tcpdump.org/manpages/pcap-savefile.5.html and RFC 9977 (pcapng).
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+
+/// Link types understood by `parse_frame`.
+pub const LINKTYPE_NULL: u32 = 0;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+pub const LINKTYPE_IPV4: u32 = 228;
+pub const LINKTYPE_IPV6: u32 = 229;
+pub const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+/// Option of an interface description block holding the timestamp resolution.
+const PCAPNG_IF_TSRESOL: u16 = 9;
+/// Blocks larger than this are considered corrupt rather than allocated.
+const MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+const SNAPLEN: u32 = 65_535;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// TCP flags set on the segments of a capture.
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// The reason a capture could not be read.
+#[derive(Debug)]
+pub enum PcapError {
+    Io(io::Error),
+    /// The file starts with neither a pcap nor a pcapng header.
+    UnknownFormat,
+    /// A record or block is inconsistent.
+    Corrupt(&'static str),
+}
+
+impl std::fmt::Display for PcapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PcapError::Io(e) => write!(f, "{e}"),
+            PcapError::UnknownFormat => write!(f, "not a pcap or pcapng file"),
+            PcapError::Corrupt(reason) => write!(f, "corrupt capture: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for PcapError {}
+
+impl From<io::Error> for PcapError {
+    fn from(e: io::Error) -> Self {
+        PcapError::Io(e)
+    }
+}
+
+/// A captured link layer frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    pub link_type: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp {
+        /// Sequence number of the first payload byte.
+        seq: u32,
+        flags: u8,
+    },
+}
+
+impl Transport {
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp { .. } => "tcp",
+        }
+    }
+}
+
+/// A UDP datagram or TCP segment found in a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    /// Microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    pub transport: Transport,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        }
+    }
+}
+
+enum Format {
+    Pcap {
+        endian: Endian,
+        nanos: bool,
+        link_type: u32,
+    },
+    Pcapng {
+        endian: Endian,
+        /// Link type and timestamp units per second of every interface of the current section.
+        interfaces: Vec<(u32, u64)>,
+    },
+}
+
+/// Reads the frames of a pcap or pcapng capture.
+pub struct PcapReader<R: Read> {
+    inner: R,
+    format: Format,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Reads the file header, detecting the format of the capture.
+    pub fn new(mut inner: R) -> Result<Self, PcapError> {
+        let mut magic = [0; 4];
+        inner.read_exact(&mut magic)?;
+
+        let format = if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            let mut len = [0; 4];
+            inner.read_exact(&mut len)?;
+            let endian = read_section_header(&mut inner, len)?;
+            Format::Pcapng { endian, interfaces: Vec::new() }
+        } else {
+            let (endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                (PCAP_MAGIC_MICROS, _) => (Endian::Little, false),
+                (PCAP_MAGIC_NANOS, _) => (Endian::Little, true),
+                (_, PCAP_MAGIC_MICROS) => (Endian::Big, false),
+                (_, PCAP_MAGIC_NANOS) => (Endian::Big, true),
+                _ => return Err(PcapError::UnknownFormat),
+            };
+            let mut header = [0; 20];
+            inner.read_exact(&mut header)?;
+            // FCS information lives in the upper bits of the link type field
+            let link_type = endian.u32(&header[16..]) & 0x0fff_ffff;
+            Format::Pcap { endian, nanos, link_type }
+        };
+
+        Ok(PcapReader { inner, format })
+    }
+
+    /// Returns the next captured frame, or `None` at the end of the capture.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, PcapError> {
+        match &mut self.format {
+            Format::Pcap { endian, nanos, link_type } => {
+                let mut header = [0; 16];
+                if !read_or_eof(&mut self.inner, &mut header)? {
+                    return Ok(None);
+                }
+                let seconds = u64::from(endian.u32(&header[0..]));
+                let fraction = u64::from(endian.u32(&header[4..]));
+                let len = endian.u32(&header[8..]) as usize;
+                if len > MAX_BLOCK_SIZE {
+                    return Err(PcapError::Corrupt("record too large"));
+                }
+
+                let mut data = vec![0; len];
+                self.inner.read_exact(&mut data)?;
+                let micros = if *nanos { fraction / 1_000 } else { fraction };
+
+                Ok(Some(Frame { timestamp_us: seconds * 1_000_000 + micros, link_type: *link_type, data }))
+            }
+            Format::Pcapng { endian, interfaces } => loop {
+                let mut header = [0; 8];
+                if !read_or_eof(&mut self.inner, &mut header)? {
+                    return Ok(None);
+                }
+                let block_type = endian.u32(&header[0..]);
+
+                if block_type == PCAPNG_SECTION_HEADER {
+                    // a new section starts, possibly with another byte order
+                    *endian = read_section_header(&mut self.inner, header[4..].try_into().unwrap())?;
+                    interfaces.clear();
+                    continue;
+                }
+
+                let len = endian.u32(&header[4..]) as usize;
+                if !(12..=MAX_BLOCK_SIZE).contains(&len) || !len.is_multiple_of(4) {
+                    return Err(PcapError::Corrupt("invalid block length"));
+                }
+                let mut body = vec![0; len - 8];
+                self.inner.read_exact(&mut body)?;
+                let body = &body[..body.len() - 4];
+
+                match block_type {
+                    PCAPNG_INTERFACE_DESCRIPTION => {
+                        if body.len() < 8 {
+                            return Err(PcapError::Corrupt("truncated interface description"));
+                        }
+                        let link_type = u32::from(endian.u16(body));
+                        let resolution = interface_resolution(*endian, &body[8..]);
+                        interfaces.push((link_type, resolution));
+                    }
+                    PCAPNG_ENHANCED_PACKET => {
+                        if body.len() < 20 {
+                            return Err(PcapError::Corrupt("truncated packet block"));
+                        }
+                        let interface = endian.u32(body) as usize;
+                        let &(link_type, resolution) =
+                            interfaces.get(interface).ok_or(PcapError::Corrupt("unknown interface"))?;
+                        let timestamp = u64::from(endian.u32(&body[4..])) << 32 | u64::from(endian.u32(&body[8..]));
+                        let captured = endian.u32(&body[12..]) as usize;
+                        let data = body.get(20..20 + captured).ok_or(PcapError::Corrupt("truncated packet block"))?;
+
+                        let timestamp_us = (u128::from(timestamp) * 1_000_000 / u128::from(resolution)) as u64;
+                        return Ok(Some(Frame { timestamp_us, link_type, data: data.to_vec() }));
+                    }
+                    PCAPNG_SIMPLE_PACKET => {
+                        let &(link_type, _) = interfaces.first().ok_or(PcapError::Corrupt("unknown interface"))?;
+                        if body.len() < 4 {
+                            return Err(PcapError::Corrupt("truncated packet block"));
+                        }
+                        let original = endian.u32(body) as usize;
+                        let data = &body[4..];
+                        let data = &data[..original.min(data.len())];
+                        // simple packet blocks carry no timestamp
+                        return Ok(Some(Frame { timestamp_us: 0, link_type, data: data.to_vec() }));
+                    }
+                    _ => {}
+                }
+            },
+        }
+    }
+
+    /// Returns the next UDP datagram or TCP segment, skipping any other frames.
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, PcapError> {
+        while let Some(frame) = self.next_frame()? {
+            if let Some(packet) = parse_frame(&frame) {
+                return Ok(Some(packet));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Reads the rest of a section header block whose type and length field have been read.
+///
+/// The byte order of the length is only known once the byte order magic following it is read.
+fn read_section_header<R: Read>(inner: &mut R, len: [u8; 4]) -> Result<Endian, PcapError> {
+    let mut magic = [0; 4];
+    inner.read_exact(&mut magic)?;
+    let endian = match u32::from_le_bytes(magic) {
+        PCAPNG_BYTE_ORDER_MAGIC => Endian::Little,
+        magic if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => Endian::Big,
+        _ => return Err(PcapError::UnknownFormat),
+    };
+    let len = endian.u32(&len) as usize;
+    if !(28..=MAX_BLOCK_SIZE).contains(&len) || !len.is_multiple_of(4) {
+        return Err(PcapError::Corrupt("invalid block length"));
+    }
+    io::copy(&mut inner.take((len - 12) as u64), &mut io::sink())?;
+    Ok(endian)
+}
+
+/// Returns the timestamp units per second announced by the options of an interface.
+fn interface_resolution(endian: Endian, mut options: &[u8]) -> u64 {
+    while options.len() >= 4 {
+        let code = endian.u16(options);
+        let len = endian.u16(&options[2..]) as usize;
+        let value = match options.get(4..4 + len) {
+            Some(value) => value,
+            None => break,
+        };
+        if code == PCAPNG_IF_TSRESOL && len == 1 {
+            let exponent = u32::from(value[0] & 0x7f);
+            let base: u64 = if value[0] & 0x80 == 0 { 10 } else { 2 };
+            if let Some(resolution) = base.checked_pow(exponent) {
+                return resolution;
+            }
+        }
+        if code == 0 {
+            break;
+        }
+        options = options.get(4 + len.div_ceil(4) * 4..).unwrap_or_default();
+    }
+    1_000_000
+}
+
+/// Fills `buf` completely, or returns `false` if the reader is at its end.
+fn read_or_eof<R: Read>(inner: &mut R, buf: &mut [u8]) -> Result<bool, PcapError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match inner.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(PcapError::Corrupt("truncated record")),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// Extracts the UDP datagram or TCP segment of a frame.
+///
+/// Returns `None` for other protocols, unsupported link types, IP fragments and truncated
+/// frames.
+pub fn parse_frame(frame: &Frame) -> Option<Packet> {
+    let data = frame.data.as_slice();
+    let (ethertype, ip) = match frame.link_type {
+        LINKTYPE_NULL => {
+            // address family in the byte order of the capturing host
+            let family = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+            let family = if family > 0xffff { family.swap_bytes() } else { family };
+            match family {
+                2 => (ETHERTYPE_IPV4, &data[4..]),
+                24 | 28 | 30 => (ETHERTYPE_IPV6, &data[4..]),
+                _ => return None,
+            }
+        }
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes(data.get(12..14)?.try_into().ok()?);
+            let mut offset = 14;
+            while ethertype == ETHERTYPE_VLAN {
+                ethertype = u16::from_be_bytes(data.get(offset + 2..offset + 4)?.try_into().ok()?);
+                offset += 4;
+            }
+            (ethertype, &data[offset..])
+        }
+        LINKTYPE_LINUX_SLL => (u16::from_be_bytes(data.get(14..16)?.try_into().ok()?), data.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => (u16::from_be_bytes(data.get(..2)?.try_into().ok()?), data.get(20..)?),
+        LINKTYPE_RAW => match data.first()? >> 4 {
+            4 => (ETHERTYPE_IPV4, data),
+            6 => (ETHERTYPE_IPV6, data),
+            _ => return None,
+        },
+        LINKTYPE_IPV4 => (ETHERTYPE_IPV4, data),
+        LINKTYPE_IPV6 => (ETHERTYPE_IPV6, data),
+        _ => return None,
+    };
+
+    let (protocol, source, destination, segment) = match ethertype {
+        ETHERTYPE_IPV4 => parse_ipv4(ip)?,
+        ETHERTYPE_IPV6 => parse_ipv6(ip)?,
+        _ => return None,
+    };
+
+    let (transport, ports, payload) = match protocol {
+        IPPROTO_UDP => {
+            let len = u16::from_be_bytes(segment.get(4..6)?.try_into().ok()?) as usize;
+            (Transport::Udp, segment.get(..4)?, segment.get(8..len.max(8))?)
+        }
+        IPPROTO_TCP => {
+            let offset = (*segment.get(12)? >> 4) as usize * 4;
+            let seq = u32::from_be_bytes(segment.get(4..8)?.try_into().ok()?);
+            let flags = *segment.get(13)?;
+            (Transport::Tcp { seq, flags }, segment.get(..4)?, segment.get(offset.max(20)..)?)
+        }
+        _ => return None,
+    };
+
+    Some(Packet {
+        timestamp_us: frame.timestamp_us,
+        transport,
+        source: SocketAddr::new(source, u16::from_be_bytes([ports[0], ports[1]])),
+        destination: SocketAddr::new(destination, u16::from_be_bytes([ports[2], ports[3]])),
+        payload: payload.to_vec(),
+    })
+}
+
+fn parse_ipv4(data: &[u8]) -> Option<(u8, IpAddr, IpAddr, &[u8])> {
+    let header_len = (*data.first()? & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes(data.get(2..4)?.try_into().ok()?) as usize;
+    let fragment = u16::from_be_bytes(data.get(6..8)?.try_into().ok()?);
+    // more fragments or a fragment offset
+    if fragment & 0x3fff != 0 {
+        return None;
+    }
+    let source: [u8; 4] = data.get(12..16)?.try_into().ok()?;
+    let destination: [u8; 4] = data.get(16..20)?.try_into().ok()?;
+    // TSO captures may announce a total length of 0
+    let end = if total_len == 0 { data.len() } else { total_len.min(data.len()) };
+
+    Some((data[9], IpAddr::from(source), IpAddr::from(destination), data.get(header_len.max(20)..end)?))
+}
+
+fn parse_ipv6(data: &[u8]) -> Option<(u8, IpAddr, IpAddr, &[u8])> {
+    let payload_len = u16::from_be_bytes(data.get(4..6)?.try_into().ok()?) as usize;
+    let source: [u8; 16] = data.get(8..24)?.try_into().ok()?;
+    let destination: [u8; 16] = data.get(24..40)?.try_into().ok()?;
+    let end = if payload_len == 0 { data.len() } else { (40 + payload_len).min(data.len()) };
+
+    let mut next_header = *data.get(6)?;
+    let mut payload = data.get(40..end)?;
+    // skip hop-by-hop, routing and destination options, give up on fragments and the rest
+    while matches!(next_header, 0 | 43 | 60) {
+        let len = (*payload.get(1)? as usize + 1) * 8;
+        next_header = *payload.first()?;
+        payload = payload.get(len..)?;
+    }
+
+    Some((next_header, IpAddr::from(source), IpAddr::from(destination), payload))
+}
+
+/// Writes a classic pcap capture of raw IP frames.
+pub struct PcapWriter<W: Write> {
+    inner: W,
+    /// Next sequence number of every TCP flow written so far.
+    tcp_seqs: Vec<((SocketAddr, SocketAddr), u32)>,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC_MICROS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // timezone
+        header.extend_from_slice(&0u32.to_le_bytes()); // accuracy
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        inner.write_all(&header)?;
+
+        Ok(PcapWriter { inner, tcp_seqs: Vec::new() })
+    }
+
+    /// Returns the writer the capture is written to.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    pub fn write_udp(&mut self, timestamp_us: u64, source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> io::Result<()> {
+        let length = u16::try_from(8 + payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too large"))?;
+        let mut segment = Vec::with_capacity(8 + payload.len());
+        segment.extend_from_slice(&source.port().to_be_bytes());
+        segment.extend_from_slice(&destination.port().to_be_bytes());
+        segment.extend_from_slice(&length.to_be_bytes());
+        segment.extend_from_slice(&[0, 0]);
+        segment.extend_from_slice(payload);
+
+        self.write_ip(timestamp_us, IPPROTO_UDP, source, destination, segment, 6)
+    }
+
+    /// Writes a TCP segment, continuing the sequence numbers of earlier segments of the flow.
+    ///
+    /// The first segment of a flow is preceded by a SYN, so dissectors see where it starts.
+    pub fn write_tcp(&mut self, timestamp_us: u64, source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> io::Result<()> {
+        let flow = (source, destination);
+        let seq = match self.tcp_seqs.iter().position(|(known, _)| *known == flow) {
+            Some(index) => self.tcp_seqs[index].1,
+            None => {
+                self.write_tcp_segment(timestamp_us, source, destination, 0, TCP_SYN, &[])?;
+                self.tcp_seqs.push((flow, 1));
+                1
+            }
+        };
+
+        self.write_tcp_segment(timestamp_us, source, destination, seq, TCP_PSH | TCP_ACK, payload)?;
+        if let Some((_, next)) = self.tcp_seqs.iter_mut().find(|(known, _)| *known == flow) {
+            *next = seq.wrapping_add(payload.len() as u32);
+        }
+        Ok(())
+    }
+
+    fn write_tcp_segment(
+        &mut self,
+        timestamp_us: u64,
+        source: SocketAddr,
+        destination: SocketAddr,
+        seq: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut segment = Vec::with_capacity(20 + payload.len());
+        segment.extend_from_slice(&source.port().to_be_bytes());
+        segment.extend_from_slice(&destination.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&0u32.to_be_bytes()); // acknowledgment number
+        segment.push(5 << 4); // data offset
+        segment.push(flags);
+        segment.extend_from_slice(&u16::MAX.to_be_bytes()); // window
+        segment.extend_from_slice(&[0, 0, 0, 0]); // checksum, urgent pointer
+        segment.extend_from_slice(payload);
+
+        self.write_ip(timestamp_us, IPPROTO_TCP, source, destination, segment, 16)
+    }
+
+    fn write_ip(
+        &mut self,
+        timestamp_us: u64,
+        protocol: u8,
+        source: SocketAddr,
+        destination: SocketAddr,
+        mut segment: Vec<u8>,
+        checksum_offset: usize,
+    ) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(40 + segment.len());
+        let mut pseudo_header = Vec::with_capacity(40);
+
+        match (source.ip(), destination.ip()) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                let total_len = u16::try_from(20 + segment.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too large"))?;
+                frame.push(0x45);
+                frame.push(0);
+                frame.extend_from_slice(&total_len.to_be_bytes());
+                frame.extend_from_slice(&[0, 0, 0x40, 0]); // identification, don't fragment
+                frame.push(64); // TTL
+                frame.push(protocol);
+                frame.extend_from_slice(&[0, 0]);
+                frame.extend_from_slice(&source.octets());
+                frame.extend_from_slice(&destination.octets());
+                let checksum = internet_checksum(&[&frame]);
+                frame[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+                pseudo_header.extend_from_slice(&source.octets());
+                pseudo_header.extend_from_slice(&destination.octets());
+                pseudo_header.extend_from_slice(&[0, protocol]);
+                pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                let payload_len = u16::try_from(segment.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too large"))?;
+                frame.extend_from_slice(&[0x60, 0, 0, 0]);
+                frame.extend_from_slice(&payload_len.to_be_bytes());
+                frame.push(protocol);
+                frame.push(64); // hop limit
+                frame.extend_from_slice(&source.octets());
+                frame.extend_from_slice(&destination.octets());
+
+                pseudo_header.extend_from_slice(&source.octets());
+                pseudo_header.extend_from_slice(&destination.octets());
+                pseudo_header.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+                pseudo_header.extend_from_slice(&[0, 0, 0, protocol]);
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "mixed IPv4 and IPv6 addresses")),
+        }
+
+        let checksum = match internet_checksum(&[&pseudo_header, &segment]) {
+            // a zero UDP checksum means "no checksum"
+            0 => 0xffff,
+            checksum => checksum,
+        };
+        segment[checksum_offset..checksum_offset + 2].copy_from_slice(&checksum.to_be_bytes());
+        frame.extend_from_slice(&segment);
+
+        let mut record = Vec::with_capacity(16);
+        record.extend_from_slice(&((timestamp_us / 1_000_000) as u32).to_le_bytes());
+        record.extend_from_slice(&((timestamp_us % 1_000_000) as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        self.inner.write_all(&record)?;
+        self.inner.write_all(&frame)
+    }
+}
+
+/// One's complement sum as used by IP, UDP and TCP (RFC 1071).
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            let word = u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]);
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn read_all(data: &[u8]) -> Vec<Packet> {
+        let mut reader = PcapReader::new(data).unwrap();
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_udp(1_500_000, addr("10.0.0.2:50000"), addr("10.0.0.1:64738"), b"hello").unwrap();
+        writer.write_udp(2_000_001, addr("[::1]:64738"), addr("[::2]:50000"), b"world").unwrap();
+
+        let packets = read_all(&writer.into_inner());
+        assert_eq!(2, packets.len());
+        assert_eq!(
+            Packet {
+                timestamp_us: 1_500_000,
+                transport: Transport::Udp,
+                source: addr("10.0.0.2:50000"),
+                destination: addr("10.0.0.1:64738"),
+                payload: b"hello".to_vec(),
+            },
+            packets[0]
+        );
+        assert_eq!((2_000_001, addr("[::1]:64738")), (packets[1].timestamp_us, packets[1].source));
+        assert_eq!(b"world".to_vec(), packets[1].payload);
+    }
+
+    #[test]
+    fn tcp_flows_continue_sequence_numbers() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let (client, server) = (addr("10.0.0.2:50000"), addr("10.0.0.1:64738"));
+        writer.write_tcp(0, client, server, b"abc").unwrap();
+        writer.write_tcp(0, server, client, b"x").unwrap();
+        writer.write_tcp(0, client, server, b"de").unwrap();
+
+        let packets = read_all(&writer.into_inner());
+        let segments: Vec<_> = packets.iter().map(|p| (p.transport, p.payload.as_slice())).collect();
+        assert_eq!(
+            vec![
+                (Transport::Tcp { seq: 0, flags: TCP_SYN }, &b""[..]),
+                (Transport::Tcp { seq: 1, flags: TCP_PSH | TCP_ACK }, &b"abc"[..]),
+                (Transport::Tcp { seq: 0, flags: TCP_SYN }, &b""[..]),
+                (Transport::Tcp { seq: 1, flags: TCP_PSH | TCP_ACK }, &b"x"[..]),
+                (Transport::Tcp { seq: 4, flags: TCP_PSH | TCP_ACK }, &b"de"[..]),
+            ],
+            segments
+        );
+    }
+
+    #[test]
+    fn written_checksums_are_valid() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_udp(0, addr("10.0.0.2:50000"), addr("10.0.0.1:64738"), b"odd").unwrap();
+        let data = writer.into_inner();
+        let frame = &data[24 + 16..];
+
+        assert_eq!(0, internet_checksum(&[&frame[..20]]));
+        let pseudo_header = [&frame[12..20], &[0, IPPROTO_UDP, 0, 11][..]].concat();
+        assert_eq!(0, internet_checksum(&[&pseudo_header, &frame[20..]]));
+    }
+
+    #[test]
+    fn mixed_address_families_are_rejected() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let result = writer.write_udp(0, addr("10.0.0.2:1"), addr("[::1]:2"), b"");
+        assert_eq!(io::ErrorKind::InvalidInput, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn oversized_datagrams_are_rejected() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let result = writer.write_udp(0, addr("10.0.0.2:1"), addr("10.0.0.1:2"), &[0; 65528]);
+        assert_eq!(io::ErrorKind::InvalidInput, result.unwrap_err().kind());
+        assert!(writer.write_udp(0, addr("10.0.0.2:1"), addr("10.0.0.1:2"), &[0; 1000]).is_ok());
+    }
+
+    #[test]
+    fn big_endian_nanosecond_pcap() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&PCAP_MAGIC_NANOS.to_be_bytes());
+        data.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        data.extend_from_slice(&LINKTYPE_RAW.to_be_bytes());
+
+        let mut frame = PcapWriter::new(Vec::new()).unwrap();
+        frame.write_udp(0, addr("10.0.0.2:1"), addr("10.0.0.1:2"), b"x").unwrap();
+        let frame = &frame.into_inner()[24 + 16..];
+
+        data.extend_from_slice(&3u32.to_be_bytes());
+        data.extend_from_slice(&2_500_000u32.to_be_bytes());
+        data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        data.extend_from_slice(frame);
+
+        let packets = read_all(&data);
+        assert_eq!(3_002_500, packets[0].timestamp_us);
+        assert_eq!(b"x".to_vec(), packets[0].payload);
+    }
+
+    fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = 12 + body.len().div_ceil(4) * 4;
+        let mut block = Vec::new();
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&(len as u32).to_le_bytes());
+        block.extend_from_slice(body);
+        block.resize(len - 4, 0);
+        block.extend_from_slice(&(len as u32).to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn pcapng_with_ethernet_and_vlan() {
+        let mut ip = PcapWriter::new(Vec::new()).unwrap();
+        ip.write_udp(0, addr("10.0.0.2:50000"), addr("10.0.0.1:64738"), b"voice").unwrap();
+        let ip = ip.into_inner()[24 + 16..].to_vec();
+
+        let mut ethernet = vec![0; 12];
+        ethernet.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+        ethernet.extend_from_slice(&[0, 42]);
+        ethernet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        ethernet.extend_from_slice(&ip);
+
+        let mut data = Vec::new();
+        let mut section = Vec::new();
+        section.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&[1, 0, 0, 0]);
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        data.extend(pcapng_block(PCAPNG_SECTION_HEADER, &section));
+
+        // nanosecond resolution
+        let mut interface = Vec::new();
+        interface.extend_from_slice(&(LINKTYPE_ETHERNET as u16).to_le_bytes());
+        interface.extend_from_slice(&[0, 0]);
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        interface.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend(pcapng_block(PCAPNG_INTERFACE_DESCRIPTION, &interface));
+
+        // unknown blocks are skipped
+        data.extend(pcapng_block(0x0bad, &[1, 2, 3]));
+
+        let timestamp: u64 = 1_700_000_000_123_456_789;
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&0u32.to_le_bytes());
+        packet.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        packet.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        packet.extend_from_slice(&(ethernet.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&(ethernet.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&ethernet);
+        data.extend(pcapng_block(PCAPNG_ENHANCED_PACKET, &packet));
+
+        let packets = read_all(&data);
+        assert_eq!(1, packets.len());
+        assert_eq!(1_700_000_000_123_456, packets[0].timestamp_us);
+        assert_eq!(addr("10.0.0.1:64738"), packets[0].destination);
+        assert_eq!(b"voice".to_vec(), packets[0].payload);
+    }
+
+    #[test]
+    fn other_frames_are_skipped() {
+        // ARP over ethernet
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(None, parse_frame(&Frame { timestamp_us: 0, link_type: LINKTYPE_ETHERNET, data: frame }));
+
+        // IPv4 fragment
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_udp(0, addr("10.0.0.2:1"), addr("10.0.0.1:2"), b"x").unwrap();
+        let mut data = writer.into_inner()[24 + 16..].to_vec();
+        data[6] = 0x20;
+        assert_eq!(None, parse_frame(&Frame { timestamp_us: 0, link_type: LINKTYPE_RAW, data }));
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(matches!(PcapReader::new(&b"GIF89a.."[..]), Err(PcapError::UnknownFormat)));
+    }
+}
@@ -0,0 +1,283 @@
+//! Permission bitmasks, as used by `ACL`, `PermissionQuery` and `PermissionDenied`
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/ACL.h
+
+use std::fmt;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not, Sub, SubAssign};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Permissions(u32);
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0x0);
+    pub const WRITE: Permissions = Permissions(0x1);
+    pub const TRAVERSE: Permissions = Permissions(0x2);
+    pub const ENTER: Permissions = Permissions(0x4);
+    pub const SPEAK: Permissions = Permissions(0x8);
+    pub const MUTE_DEAFEN: Permissions = Permissions(0x10);
+    pub const MOVE: Permissions = Permissions(0x20);
+    pub const MAKE_CHANNEL: Permissions = Permissions(0x40);
+    pub const LINK_CHANNEL: Permissions = Permissions(0x80);
+    pub const WHISPER: Permissions = Permissions(0x100);
+    pub const TEXT_MESSAGE: Permissions = Permissions(0x200);
+    pub const MAKE_TEMP_CHANNEL: Permissions = Permissions(0x400);
+    pub const LISTEN: Permissions = Permissions(0x800);
+    pub const KICK: Permissions = Permissions(0x10000);
+    pub const BAN: Permissions = Permissions(0x20000);
+    pub const REGISTER: Permissions = Permissions(0x40000);
+    pub const SELF_REGISTER: Permissions = Permissions(0x80000);
+    pub const RESET_USER_CONTENT: Permissions = Permissions(0x100000);
+    /// Set by the server in `PermissionQuery` when the client may cache the permissions.
+    pub const CACHED: Permissions = Permissions(0x8000000);
+
+    /// Every permission, without the `CACHED` flag.
+    pub const ALL: Permissions = Permissions(0x1f0fff);
+    /// Permissions only meaningful in the root channel, as they apply server wide.
+    pub const ROOT_ONLY: Permissions = Permissions(0x1f0000);
+
+    /// Every permission and flag with its name, lowest bit first.
+    pub const NAMES: [(Permissions, &'static str); 18] = [
+        (Permissions::WRITE, "write"),
+        (Permissions::TRAVERSE, "traverse"),
+        (Permissions::ENTER, "enter"),
+        (Permissions::SPEAK, "speak"),
+        (Permissions::MUTE_DEAFEN, "mute_deafen"),
+        (Permissions::MOVE, "move"),
+        (Permissions::MAKE_CHANNEL, "make_channel"),
+        (Permissions::LINK_CHANNEL, "link_channel"),
+        (Permissions::WHISPER, "whisper"),
+        (Permissions::TEXT_MESSAGE, "text_message"),
+        (Permissions::MAKE_TEMP_CHANNEL, "make_temp_channel"),
+        (Permissions::LISTEN, "listen"),
+        (Permissions::KICK, "kick"),
+        (Permissions::BAN, "ban"),
+        (Permissions::REGISTER, "register"),
+        (Permissions::SELF_REGISTER, "self_register"),
+        (Permissions::RESET_USER_CONTENT, "reset_user_content"),
+        (Permissions::CACHED, "cached"),
+    ];
+
+    /// Keeps all bits, including unknown ones, see `validate`.
+    pub const fn from_bits(bits: u32) -> Self {
+        Permissions(bits)
+    }
+
+    /// Drops the bits not corresponding to a known permission or flag.
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Permissions(bits & (Permissions::ALL.0 | Permissions::CACHED.0))
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether all permissions of `other` are set.
+    pub const fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any permission of `other` is set.
+    pub const fn intersects(self, other: Permissions) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn insert(&mut self, other: Permissions) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Permissions) {
+        self.0 &= !other.0;
+    }
+
+    /// Returns the permission named like in `names`, e.g. `"mute_deafen"`.
+    pub fn from_name(name: &str) -> Option<Permissions> {
+        Permissions::NAMES.iter().find(|(_, n)| *n == name).map(|(permission, _)| *permission)
+    }
+
+    /// Returns the combination of the named permissions, or the first unknown name.
+    pub fn from_names<'a, I: IntoIterator<Item = &'a str>>(names: I) -> Result<Permissions, &'a str> {
+        names.into_iter().try_fold(Permissions::NONE, |permissions, name| {
+            Permissions::from_name(name).map(|permission| permissions | permission).ok_or(name)
+        })
+    }
+
+    /// Returns the names of the set permissions, lowest bit first. Unknown bits are left out.
+    pub fn names(self) -> Vec<&'static str> {
+        Permissions::NAMES
+            .iter()
+            .filter(|(permission, _)| self.contains(*permission))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// The permissions which have a meaning in the root channel or in other channels.
+    pub const fn applicable(root: bool) -> Permissions {
+        if root {
+            Permissions::ALL
+        } else {
+            Permissions(Permissions::ALL.0 & !Permissions::ROOT_ONLY.0)
+        }
+    }
+
+    /// Checks that only known permissions are set, and no server wide ones outside of the root
+    /// channel. The `CACHED` flag is accepted everywhere.
+    pub fn validate(self, root: bool) -> Result<(), PermissionsError> {
+        let unknown = self - Permissions::ALL - Permissions::CACHED;
+        if !unknown.is_empty() {
+            return Err(PermissionsError::Unknown(unknown.0));
+        }
+        let misplaced = self - Permissions::applicable(root) - Permissions::CACHED;
+        if !misplaced.is_empty() {
+            return Err(PermissionsError::RootOnly(misplaced));
+        }
+        Ok(())
+    }
+}
+
+impl From<u32> for Permissions {
+    fn from(bits: u32) -> Self {
+        Permissions(bits)
+    }
+}
+
+impl From<Permissions> for u32 {
+    fn from(permissions: Permissions) -> Self {
+        permissions.0
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Permissions;
+
+    fn bitor(self, other: Permissions) -> Permissions {
+        Permissions(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Permissions {
+    fn bitor_assign(&mut self, other: Permissions) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for Permissions {
+    type Output = Permissions;
+
+    fn bitand(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & other.0)
+    }
+}
+
+impl BitAndAssign for Permissions {
+    fn bitand_assign(&mut self, other: Permissions) {
+        self.0 &= other.0;
+    }
+}
+
+impl Sub for Permissions {
+    type Output = Permissions;
+
+    /// The permissions of `self` not in `other`.
+    fn sub(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & !other.0)
+    }
+}
+
+impl SubAssign for Permissions {
+    fn sub_assign(&mut self, other: Permissions) {
+        self.0 &= !other.0;
+    }
+}
+
+impl Not for Permissions {
+    type Output = Permissions;
+
+    /// The known permissions not in `self`.
+    fn not(self) -> Permissions {
+        Permissions(!self.0 & Permissions::ALL.0)
+    }
+}
+
+impl fmt::Debug for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Permissions({:#x}: {})", self.0, self.names().join(" | "))
+    }
+}
+
+/// The reason a bitmask is not valid for a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PermissionsError {
+    /// Bits not corresponding to any permission.
+    Unknown(u32),
+    /// Server wide permissions set for a channel other than the root.
+    RootOnly(Permissions),
+}
+
+impl fmt::Display for PermissionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PermissionsError::Unknown(bits) => write!(f, "unknown permission bits {bits:#x}"),
+            PermissionsError::RootOnly(permissions) => {
+                write!(f, "{} can only be granted in the root channel", permissions.names().join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PermissionsError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_operations() {
+        let mut permissions = Permissions::SPEAK | Permissions::WHISPER;
+        assert!(permissions.contains(Permissions::SPEAK));
+        assert!(!permissions.contains(Permissions::SPEAK | Permissions::ENTER));
+        assert!(permissions.intersects(Permissions::SPEAK | Permissions::ENTER));
+
+        permissions.insert(Permissions::ENTER);
+        permissions -= Permissions::WHISPER;
+        assert_eq!(Permissions::from_bits(0xc), permissions);
+        assert_eq!(Permissions::ENTER, permissions & Permissions::ENTER);
+        assert_eq!(Permissions::ALL, permissions | !permissions);
+        assert!(!(!Permissions::NONE).contains(Permissions::CACHED));
+    }
+
+    #[test]
+    fn names() {
+        let permissions = Permissions::MUTE_DEAFEN | Permissions::KICK | Permissions::CACHED;
+        assert_eq!(vec!["mute_deafen", "kick", "cached"], permissions.names());
+        assert_eq!(Ok(permissions), Permissions::from_names(["kick", "cached", "mute_deafen"]));
+        assert_eq!(Err("fly"), Permissions::from_names(["kick", "fly"]));
+        assert!(Permissions::from_bits(0x4000).names().is_empty());
+        assert_eq!(
+            "Permissions(0xc: enter | speak)",
+            format!("{:?}", Permissions::ENTER | Permissions::SPEAK)
+        );
+    }
+
+    #[test]
+    fn all_permissions_are_named() {
+        let named = Permissions::from_names(Permissions::ALL.names()).unwrap();
+        assert_eq!(Permissions::ALL, named);
+        assert_eq!(17, Permissions::ALL.names().len());
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(Ok(()), Permissions::ALL.validate(true));
+        assert_eq!(Ok(()), (Permissions::applicable(false) | Permissions::CACHED).validate(false));
+        assert_eq!(
+            Err(PermissionsError::RootOnly(Permissions::BAN | Permissions::SELF_REGISTER)),
+            (Permissions::SPEAK | Permissions::BAN | Permissions::SELF_REGISTER).validate(false)
+        );
+        assert_eq!(Err(PermissionsError::Unknown(0x1000)), Permissions::from_bits(0x1008).validate(true));
+        assert_eq!(Permissions::SPEAK | Permissions::CACHED, Permissions::from_bits_truncate(0x8001008));
+    }
+}
@@ -0,0 +1,307 @@
+//! Round trip times and packet statistics exchanged with `Ping` messages
+//!
+//! Both ends of a connection send pings carrying their voice channel statistics: good, late and
+//! lost packets from their `CryptState`, and the round trip times they measured over UDP and
+//! TCP. The other end echoes the timestamp back, which gives the round trip time.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/mumble/ServerHandler.cpp
+
+use std::collections::VecDeque;
+
+use crate::crypt_state::CryptState;
+use crate::mumble_proto as msgs;
+use crate::mumble_proto::user_stats;
+
+/// Amount of unanswered pings remembered per transport, older ones are considered lost.
+pub const MAX_PENDING: usize = 16;
+
+/// Running mean and variance of round trip times, in milliseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RttStats {
+    count: u32,
+    mean: f64,
+    /// Sum of squared differences from the mean, see Welford's algorithm.
+    m2: f64,
+}
+
+impl RttStats {
+    pub fn push(&mut self, rtt_ms: f64) {
+        self.count += 1;
+        let delta = rtt_ms - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (rtt_ms - self.mean);
+    }
+
+    /// Amount of answered pings.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    /// Population variance, as reported by the official client.
+    pub fn variance(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.m2 / self.count as f64
+        }
+    }
+}
+
+/// Statistics reported by the other end in its last `Ping`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PeerStats {
+    pub good: u32,
+    pub late: u32,
+    pub lost: u32,
+    pub resync: u32,
+    pub udp_packets: u32,
+    pub tcp_packets: u32,
+    pub udp_ping_avg: f32,
+    pub udp_ping_var: f32,
+    pub tcp_ping_avg: f32,
+    pub tcp_ping_var: f32,
+}
+
+impl From<&msgs::Ping> for PeerStats {
+    fn from(ping: &msgs::Ping) -> Self {
+        PeerStats {
+            good: ping.good.unwrap_or_default(),
+            late: ping.late.unwrap_or_default(),
+            lost: ping.lost.unwrap_or_default(),
+            resync: ping.resync.unwrap_or_default(),
+            udp_packets: ping.udp_packets.unwrap_or_default(),
+            tcp_packets: ping.tcp_packets.unwrap_or_default(),
+            udp_ping_avg: ping.udp_ping_avg.unwrap_or_default(),
+            udp_ping_var: ping.udp_ping_var.unwrap_or_default(),
+            tcp_ping_avg: ping.tcp_ping_avg.unwrap_or_default(),
+            tcp_ping_var: ping.tcp_ping_var.unwrap_or_default(),
+        }
+    }
+}
+
+/// Tracks the pings of one end of a connection.
+#[derive(Clone, Debug, Default)]
+pub struct PingTracker {
+    tcp: RttStats,
+    udp: RttStats,
+    pending_tcp: VecDeque<u64>,
+    pending_udp: VecDeque<u64>,
+    resync: u32,
+    peer: PeerStats,
+}
+
+impl PingTracker {
+    pub fn new() -> Self {
+        PingTracker::default()
+    }
+
+    /// Round trip times of pings sent over the control channel.
+    pub fn tcp(&self) -> &RttStats {
+        &self.tcp
+    }
+
+    /// Round trip times of pings sent over the voice channel.
+    pub fn udp(&self) -> &RttStats {
+        &self.udp
+    }
+
+    /// What the other end reported in its last `Ping`.
+    pub fn peer(&self) -> &PeerStats {
+        &self.peer
+    }
+
+    /// Amount of times our decrypt nonce had to be resynchronised.
+    pub fn resync(&self) -> u32 {
+        self.resync
+    }
+
+    pub fn record_resync(&mut self) {
+        self.resync += 1;
+    }
+
+    /// Builds a `Ping` to send over the control channel, remembering it to measure the round trip.
+    pub fn ping(&mut self, now_ms: u64, crypt_state: Option<&CryptState>) -> msgs::Ping {
+        remember(&mut self.pending_tcp, now_ms);
+
+        msgs::Ping {
+            timestamp: Some(now_ms),
+            udp_packets: Some(self.udp.count()),
+            tcp_packets: Some(self.tcp.count()),
+            udp_ping_avg: Some(self.udp.mean() as f32),
+            udp_ping_var: Some(self.udp.variance() as f32),
+            tcp_ping_avg: Some(self.tcp.mean() as f32),
+            tcp_ping_var: Some(self.tcp.variance() as f32),
+            ..self.crypt_stats(crypt_state)
+        }
+    }
+
+    /// Handles a `Ping` received over the control channel. Returns the round trip time if it
+    /// answers one of ours.
+    pub fn receive(&mut self, ping: &msgs::Ping, now_ms: u64) -> Option<u64> {
+        self.peer = PeerStats::from(ping);
+
+        let rtt = answered(&mut self.pending_tcp, ping.timestamp?, now_ms)?;
+        self.tcp.push(rtt as f64);
+        Some(rtt)
+    }
+
+    /// The answer to a `Ping` sent by the other end: its timestamp and our crypt statistics.
+    pub fn reply(&self, ping: &msgs::Ping, crypt_state: Option<&CryptState>) -> msgs::Ping {
+        msgs::Ping { timestamp: ping.timestamp, ..self.crypt_stats(crypt_state) }
+    }
+
+    /// Remembers a ping sent over the voice channel, returns the timestamp to send with it.
+    pub fn udp_ping(&mut self, now_ms: u64) -> u64 {
+        remember(&mut self.pending_udp, now_ms);
+        now_ms
+    }
+
+    /// Handles the answer to a voice channel ping, returns the round trip time if it was ours.
+    pub fn receive_udp(&mut self, timestamp: u64, now_ms: u64) -> Option<u64> {
+        let rtt = answered(&mut self.pending_udp, timestamp, now_ms)?;
+        self.udp.push(rtt as f64);
+        Some(rtt)
+    }
+
+    /// Fills the statistics of a `UserStats` reply like Murmur: `from_client` is what the client
+    /// reported, `from_server` comes from our own `CryptState`.
+    pub fn fill_user_stats(&self, stats: &mut msgs::UserStats, crypt_state: Option<&CryptState>) {
+        let ours = self.crypt_stats(crypt_state);
+
+        stats.from_client = Some(user_stats::Stats {
+            good: Some(self.peer.good),
+            late: Some(self.peer.late),
+            lost: Some(self.peer.lost),
+            resync: Some(self.peer.resync),
+        });
+        stats.from_server = Some(user_stats::Stats {
+            good: ours.good,
+            late: ours.late,
+            lost: ours.lost,
+            resync: ours.resync,
+        });
+        stats.udp_packets = Some(self.peer.udp_packets);
+        stats.tcp_packets = Some(self.peer.tcp_packets);
+        stats.udp_ping_avg = Some(self.peer.udp_ping_avg);
+        stats.udp_ping_var = Some(self.peer.udp_ping_var);
+        stats.tcp_ping_avg = Some(self.peer.tcp_ping_avg);
+        stats.tcp_ping_var = Some(self.peer.tcp_ping_var);
+    }
+
+    fn crypt_stats(&self, crypt_state: Option<&CryptState>) -> msgs::Ping {
+        msgs::Ping {
+            good: Some(crypt_state.map_or(0, CryptState::get_good)),
+            late: Some(crypt_state.map_or(0, CryptState::get_late)),
+            lost: Some(crypt_state.map_or(0, CryptState::get_lost)),
+            resync: Some(self.resync),
+            ..Default::default()
+        }
+    }
+}
+
+fn remember(pending: &mut VecDeque<u64>, timestamp: u64) {
+    if pending.len() == MAX_PENDING {
+        pending.pop_front();
+    }
+    pending.push_back(timestamp);
+}
+
+/// Removes an answered timestamp, and the older ones whose answers won't come anymore.
+fn answered(pending: &mut VecDeque<u64>, timestamp: u64, now_ms: u64) -> Option<u64> {
+    let index = pending.iter().position(|pending| *pending == timestamp)?;
+    pending.drain(..=index);
+
+    Some(now_ms.saturating_sub(timestamp))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rtt_stats() {
+        let mut stats = RttStats::default();
+        assert_eq!(0.0, stats.variance());
+
+        for rtt in [10.0, 20.0, 30.0, 40.0] {
+            stats.push(rtt);
+        }
+        assert_eq!(4, stats.count());
+        assert_eq!(25.0, stats.mean());
+        assert_eq!(125.0, stats.variance());
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        let mut tracker = PingTracker::new();
+        let first = tracker.ping(1000, None);
+        assert_eq!(Some(1000), first.timestamp);
+        assert_eq!(Some(0), first.tcp_packets);
+        let second = tracker.ping(2000, None);
+
+        let reply = msgs::Ping { timestamp: Some(2000), good: Some(50), lost: Some(2), ..Default::default() };
+        assert_eq!(Some(40), tracker.receive(&reply, 2040));
+        assert_eq!(50, tracker.peer().good);
+        assert_eq!(2, tracker.peer().lost);
+
+        // The first ping is given up once a later one was answered
+        let late = msgs::Ping { timestamp: first.timestamp, ..Default::default() };
+        assert_eq!(None, tracker.receive(&late, 2100));
+        assert_eq!(None, tracker.receive(&msgs::Ping { timestamp: second.timestamp, ..Default::default() }, 2200));
+        assert_eq!(0, tracker.peer().good);
+
+        let third = tracker.ping(3000, None);
+        assert_eq!(Some(1), third.tcp_packets);
+        assert_eq!(Some(40.0), third.tcp_ping_avg);
+        assert_eq!(Some(0.0), third.tcp_ping_var);
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        let mut tracker = PingTracker::new();
+        let timestamp = tracker.udp_ping(500);
+        assert_eq!(None, tracker.receive_udp(499, 510));
+        assert_eq!(Some(20), tracker.receive_udp(timestamp, 520));
+        assert_eq!(None, tracker.receive_udp(timestamp, 530));
+
+        for now in 0..(MAX_PENDING as u64 + 1) {
+            tracker.udp_ping(now);
+        }
+        assert_eq!(None, tracker.receive_udp(0, 100));
+        assert_eq!(Some(99), tracker.receive_udp(1, 100));
+
+        let ping = tracker.ping(1000, None);
+        assert_eq!(Some(2), ping.udp_packets);
+        assert_eq!(Some(59.5), ping.udp_ping_avg);
+    }
+
+    #[test]
+    fn replies_with_crypt_stats() {
+        let mut tracker = PingTracker::new();
+        tracker.record_resync();
+        let mut crypt_state = CryptState::new_from([1; 16], [2; 16], [2; 16]);
+        let mut packet = bytes::BytesMut::new();
+        crypt_state.encrypt(b"voice", &mut packet);
+        crypt_state.decrypt(&mut packet).unwrap();
+
+        let request = msgs::Ping { timestamp: Some(77), good: Some(3), udp_packets: Some(4), tcp_ping_avg: Some(12.5), ..Default::default() };
+        assert_eq!(None, tracker.receive(&request, 100));
+        let reply = tracker.reply(&request, Some(&crypt_state));
+        assert_eq!(
+            msgs::Ping { timestamp: Some(77), good: Some(1), late: Some(0), lost: Some(0), resync: Some(1), ..Default::default() },
+            reply
+        );
+
+        let mut stats = msgs::UserStats { session: Some(5), ..Default::default() };
+        tracker.fill_user_stats(&mut stats, Some(&crypt_state));
+        assert_eq!(Some(3), stats.from_client.unwrap().good);
+        assert_eq!(Some(1), stats.from_server.unwrap().resync);
+        assert_eq!(Some(4), stats.udp_packets);
+        assert_eq!(Some(12.5), stats.tcp_ping_avg);
+        assert_eq!(Some(5), stats.session);
+    }
+}
@@ -0,0 +1,264 @@
+//! Plugin data exchanged between clients through the server
+//!
+//! Plugins send `PluginDataTransmission` messages listing the sessions to deliver them to. The
+//! server drops messages without data or data ID and those over Mumble's size limits, sets the
+//! sender's session so it can't be spoofed, and forwards the message to each receiver once,
+//! without the list of receivers.
+//!
+//! Data IDs are free-form. By convention they start with a namespace identifying the plugin,
+//! followed by a colon (e.g. `mygame:position`); a router can be restricted to some namespaces.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Messages.cpp
+//! (`Server::msgPluginDataTransmission`)
+
+use std::collections::BTreeSet;
+
+use crate::mumble_proto as msgs;
+use crate::user_registry::UserRegistry;
+
+/// Largest data accepted, in bytes.
+pub const MAX_DATA_LENGTH: usize = 1000;
+/// Longest data ID accepted, in bytes.
+pub const MAX_DATA_ID_LENGTH: usize = 100;
+
+/// The reason plugin data is dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PluginDataError {
+    MissingData,
+    MissingDataId,
+    DataTooLong(usize),
+    DataIdTooLong(usize),
+    /// The data ID is empty or contains control characters.
+    InvalidDataId,
+    /// The data ID's namespace isn't one the router accepts.
+    NamespaceNotAllowed(String),
+}
+
+impl std::fmt::Display for PluginDataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginDataError::MissingData => write!(f, "plugin data without data"),
+            PluginDataError::MissingDataId => write!(f, "plugin data without data ID"),
+            PluginDataError::DataTooLong(length) => {
+                write!(f, "plugin data of {length} bytes exceeds {MAX_DATA_LENGTH} bytes")
+            }
+            PluginDataError::DataIdTooLong(length) => {
+                write!(f, "plugin data ID of {length} bytes exceeds {MAX_DATA_ID_LENGTH} bytes")
+            }
+            PluginDataError::InvalidDataId => write!(f, "invalid plugin data ID"),
+            PluginDataError::NamespaceNotAllowed(data_id) => write!(f, "plugin data ID {data_id:?} not allowed"),
+        }
+    }
+}
+
+impl std::error::Error for PluginDataError {}
+
+/// A validated `PluginDataTransmission`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PluginData {
+    /// Set by the server when forwarding.
+    pub sender: Option<u32>,
+    /// Only set when sent to the server.
+    pub receivers: Vec<u32>,
+    pub data_id: String,
+    pub data: Vec<u8>,
+}
+
+impl PluginData {
+    /// Plugin data to send to the server, addressed to `receivers`.
+    pub fn new(data_id: &str, data: &[u8], receivers: &[u32]) -> Result<Self, PluginDataError> {
+        let plugin_data = PluginData {
+            sender: None,
+            receivers: receivers.to_vec(),
+            data_id: data_id.to_string(),
+            data: data.to_vec(),
+        };
+        plugin_data.validate()?;
+        Ok(plugin_data)
+    }
+
+    /// The part of the data ID before the first colon, if any.
+    pub fn namespace(&self) -> Option<&str> {
+        self.data_id.split_once(':').map(|(namespace, _)| namespace)
+    }
+
+    /// Checks the data and data ID against Mumble's limits.
+    pub fn validate(&self) -> Result<(), PluginDataError> {
+        if self.data.len() > MAX_DATA_LENGTH {
+            return Err(PluginDataError::DataTooLong(self.data.len()));
+        }
+        if self.data_id.len() > MAX_DATA_ID_LENGTH {
+            return Err(PluginDataError::DataIdTooLong(self.data_id.len()));
+        }
+        if self.data_id.is_empty() || self.data_id.chars().any(char::is_control) {
+            return Err(PluginDataError::InvalidDataId);
+        }
+        Ok(())
+    }
+
+    pub fn to_message(&self) -> msgs::PluginDataTransmission {
+        msgs::PluginDataTransmission {
+            sender_session: self.sender,
+            receiver_sessions: self.receivers.clone(),
+            data: Some(self.data.clone()),
+            data_id: Some(self.data_id.clone()),
+        }
+    }
+}
+
+impl TryFrom<&msgs::PluginDataTransmission> for PluginData {
+    type Error = PluginDataError;
+
+    fn try_from(message: &msgs::PluginDataTransmission) -> Result<Self, Self::Error> {
+        let plugin_data = PluginData {
+            sender: message.sender_session,
+            receivers: message.receiver_sessions.clone(),
+            data_id: message.data_id.clone().ok_or(PluginDataError::MissingDataId)?,
+            data: message.data.clone().ok_or(PluginDataError::MissingData)?,
+        };
+        plugin_data.validate()?;
+        Ok(plugin_data)
+    }
+}
+
+/// Plugin data to forward, as decided by `PluginDataRouter::route`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delivery {
+    /// Connected receivers, each once, in the order they were listed.
+    pub receivers: Vec<u32>,
+    /// The message to send to each of them.
+    pub message: msgs::PluginDataTransmission,
+}
+
+/// Forwards plugin data to the users it is addressed to.
+#[derive(Clone, Debug, Default)]
+pub struct PluginDataRouter {
+    /// Accepted namespaces, any data ID if empty.
+    namespaces: BTreeSet<String>,
+    dropped: u64,
+}
+
+impl PluginDataRouter {
+    pub fn new() -> Self {
+        PluginDataRouter::default()
+    }
+
+    /// Only accepts data IDs in the namespace from now on, in addition to those already allowed.
+    pub fn allow_namespace(&mut self, namespace: &str) {
+        self.namespaces.insert(namespace.to_string());
+    }
+
+    pub fn namespaces(&self) -> &BTreeSet<String> {
+        &self.namespaces
+    }
+
+    /// Amount of messages which weren't forwarded because they were invalid.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Checks a message received from `sender` and returns what to send to whom. Receivers
+    /// which aren't in the registry are skipped.
+    pub fn route(
+        &mut self,
+        sender: u32,
+        message: &msgs::PluginDataTransmission,
+        users: &UserRegistry,
+    ) -> Result<Delivery, PluginDataError> {
+        let plugin_data = PluginData::try_from(message).and_then(|plugin_data| {
+            self.check_namespace(&plugin_data)?;
+            Ok(plugin_data)
+        });
+        let plugin_data = match plugin_data {
+            Ok(plugin_data) => plugin_data,
+            Err(e) => {
+                self.dropped += 1;
+                return Err(e);
+            }
+        };
+
+        let mut seen = BTreeSet::new();
+        let receivers = plugin_data
+            .receivers
+            .iter()
+            .copied()
+            .filter(|session| seen.insert(*session) && users.get(*session).is_some())
+            .collect();
+        let forwarded = PluginData { sender: Some(sender), receivers: Vec::new(), ..plugin_data };
+
+        Ok(Delivery { receivers, message: forwarded.to_message() })
+    }
+
+    fn check_namespace(&self, plugin_data: &PluginData) -> Result<(), PluginDataError> {
+        if self.namespaces.is_empty() {
+            return Ok(());
+        }
+        match plugin_data.namespace() {
+            Some(namespace) if self.namespaces.contains(namespace) => Ok(()),
+            _ => Err(PluginDataError::NamespaceNotAllowed(plugin_data.data_id.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn users(sessions: &[u32]) -> UserRegistry {
+        let mut users = UserRegistry::new();
+        for &session in sessions {
+            users.apply_state(&msgs::UserState { session: Some(session), ..Default::default() }).unwrap();
+        }
+        users
+    }
+
+    #[test]
+    fn validates_plugin_data() {
+        let plugin_data = PluginData::new("game:position", b"data", &[2]).unwrap();
+        assert_eq!(Some("game"), plugin_data.namespace());
+        assert_eq!(Ok(plugin_data.clone()), PluginData::try_from(&plugin_data.to_message()));
+
+        assert_eq!(Err(PluginDataError::DataTooLong(1001)), PluginData::new("id", &[0; 1001], &[]));
+        assert_eq!(Err(PluginDataError::DataIdTooLong(101)), PluginData::new(&"i".repeat(101), b"", &[]));
+        assert_eq!(Err(PluginDataError::InvalidDataId), PluginData::new("", b"", &[]));
+        assert_eq!(Err(PluginDataError::InvalidDataId), PluginData::new("a\nb", b"", &[]));
+        assert_eq!(None, PluginData::new("plain", b"", &[]).unwrap().namespace());
+
+        let no_data = msgs::PluginDataTransmission { data_id: Some("id".into()), ..Default::default() };
+        assert_eq!(Err(PluginDataError::MissingData), PluginData::try_from(&no_data));
+        let no_id = msgs::PluginDataTransmission { data: Some(Vec::new()), ..Default::default() };
+        assert_eq!(Err(PluginDataError::MissingDataId), PluginData::try_from(&no_id));
+    }
+
+    #[test]
+    fn routes_to_connected_receivers() {
+        let mut router = PluginDataRouter::new();
+        let message = msgs::PluginDataTransmission {
+            sender_session: Some(9),
+            receiver_sessions: vec![3, 2, 3, 7],
+            ..PluginData::new("game:state", b"x", &[]).unwrap().to_message()
+        };
+
+        let delivery = router.route(1, &message, &users(&[1, 2, 3])).unwrap();
+        assert_eq!(vec![3, 2], delivery.receivers);
+        assert_eq!(Some(1), delivery.message.sender_session);
+        assert!(delivery.message.receiver_sessions.is_empty());
+        assert_eq!(Some(b"x".to_vec()), delivery.message.data);
+    }
+
+    #[test]
+    fn restricts_namespaces() {
+        let mut router = PluginDataRouter::new();
+        router.allow_namespace("game");
+        let users = users(&[1, 2]);
+
+        let allowed = PluginData::new("game:state", b"", &[2]).unwrap().to_message();
+        assert!(router.route(1, &allowed, &users).is_ok());
+
+        let other = PluginData::new("other:state", b"", &[2]).unwrap().to_message();
+        assert_eq!(Err(PluginDataError::NamespaceNotAllowed("other:state".into())), router.route(1, &other, &users));
+        let no_namespace = PluginData::new("state", b"", &[2]).unwrap().to_message();
+        assert!(router.route(1, &no_namespace, &users).is_err());
+        assert_eq!(2, router.dropped());
+    }
+}
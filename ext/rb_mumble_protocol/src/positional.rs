@@ -0,0 +1,267 @@
+//! Positional audio: speaker positions and the volume they are heard with
+//!
+//! Clients with a game plugin append the speaker's position to their voice packets, three
+//! little endian `f32`s after the audio in legacy packets (`positional_data` in the protobuf
+//! format). Coordinates are in meters in a left handed system: x points right, y up and z
+//! forward. Listeners attenuate voices by distance and direction like the official client.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/mumble/AudioOutput.cpp
+//! (`AudioOutput::calcGain`)
+
+use std::ops::Sub;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Size of a position in a legacy voice packet.
+pub const POSITION_SIZE: usize = 12;
+
+/// Distance under which a speaker is considered to be at the listener's position.
+const INSIDE_DISTANCE: f32 = 0.01;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vector3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vector3 {
+    pub const fn new(x: f32, y: f32, z: f32) -> Self {
+        Vector3 { x, y, z }
+    }
+
+    /// Reads the position at the start of a legacy packet's `position_info`, `None` if it is
+    /// too short.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let coordinates = data.get(..POSITION_SIZE)?;
+        let coordinate = |index: usize| f32::from_le_bytes(coordinates[index * 4..index * 4 + 4].try_into().unwrap());
+        Some(Vector3::new(coordinate(0), coordinate(1), coordinate(2)))
+    }
+
+    /// Reads a protobuf `positional_data` field, `None` unless it has three values.
+    pub fn from_slice(values: &[f32]) -> Option<Self> {
+        match values {
+            [x, y, z] => Some(Vector3::new(*x, *y, *z)),
+            _ => None,
+        }
+    }
+
+    /// Encodes the position as appended to legacy voice packets.
+    pub fn encode(&self) -> Bytes {
+        let mut dst = BytesMut::with_capacity(POSITION_SIZE);
+        dst.put_f32_le(self.x);
+        dst.put_f32_le(self.y);
+        dst.put_f32_le(self.z);
+        dst.freeze()
+    }
+
+    pub fn to_array(self) -> [f32; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    pub fn dot(self, other: Self) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(self, other: Self) -> Self {
+        Vector3::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    /// The vector scaled to a length of 1, the zero vector stays as is.
+    pub fn normalized(self) -> Self {
+        let length = self.length();
+        if length == 0.0 {
+            return self;
+        }
+        Vector3::new(self.x / length, self.y / length, self.z / length)
+    }
+
+    pub fn distance(self, other: Self) -> f32 {
+        (other - self).length()
+    }
+}
+
+impl Sub for Vector3 {
+    type Output = Vector3;
+
+    fn sub(self, other: Self) -> Self {
+        Vector3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+/// Where a listener is and where they look.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Listener {
+    pub position: Vector3,
+    /// Direction the listener faces.
+    pub front: Vector3,
+    /// Direction of the top of the listener's head.
+    pub top: Vector3,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener {
+            position: Vector3::default(),
+            front: Vector3::new(0.0, 0.0, 1.0),
+            top: Vector3::new(0.0, 1.0, 0.0),
+        }
+    }
+}
+
+impl Listener {
+    /// The unit vector pointing to the speaker, relative to the listener: x to their right,
+    /// y above them and z in front of them. The zero vector if they are at the same position.
+    pub fn direction(&self, speaker: Vector3) -> Vector3 {
+        let front = self.front.normalized();
+        let top = self.top.normalized();
+        let right = top.cross(front);
+        let to_speaker = (speaker - self.position).normalized();
+
+        Vector3::new(to_speaker.dot(right), to_speaker.dot(top), to_speaker.dot(front))
+    }
+
+    pub fn distance(&self, speaker: Vector3) -> f32 {
+        self.position.distance(speaker)
+    }
+
+    /// Gains of the left and right ear for a speaker.
+    pub fn stereo_gains(&self, speaker: Vector3, settings: &Attenuation) -> (f32, f32) {
+        let direction = self.direction(speaker);
+        let distance = self.distance(speaker);
+
+        (settings.gain(-direction.x, distance), settings.gain(direction.x, distance))
+    }
+}
+
+/// The official client's positional audio settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Attenuation {
+    /// Distance up to which voices aren't attenuated, in meters.
+    pub min_distance: f32,
+    /// Distance from which voices are at `max_distance_volume`, in meters.
+    pub max_distance: f32,
+    /// Volume of voices beyond `max_distance`, 1 disables attenuation by distance.
+    pub max_distance_volume: f32,
+    /// Volume added to voices closer than `min_distance`, most when at the listener's position.
+    pub bloom: f32,
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Attenuation {
+            min_distance: 1.0,
+            max_distance: 15.0,
+            max_distance_volume: 0.25,
+            bloom: 0.5,
+        }
+    }
+}
+
+impl Attenuation {
+    /// The gain of a speaker at `distance`, heard by an ear whose direction has a dot product
+    /// of `dot` with the direction of the speaker (1 facing it, -1 turned away).
+    pub fn gain(&self, dot: f32, distance: f32) -> f32 {
+        // Ears turned away still hear a twentieth of what they would facing the speaker
+        let facing = (dot.clamp(-1.0, 1.0) + 1.0) / 2.0;
+        let facing = (19.0 * facing + 1.0) / 20.0;
+
+        if distance < INSIDE_DISTANCE {
+            1.0
+        } else if self.max_distance_volume > 0.99 {
+            (facing + self.bloom).min(1.0)
+        } else if distance < self.min_distance {
+            let bloom = self.bloom * (1.0 - distance / self.min_distance);
+            (facing + bloom).min(1.0)
+        } else {
+            facing * self.distance_gain(distance)
+        }
+    }
+
+    /// Attenuation by distance alone, from 1 at `min_distance` down to `max_distance_volume`.
+    pub fn distance_gain(&self, distance: f32) -> f32 {
+        if distance < self.min_distance || self.max_distance_volume > 0.99 {
+            return 1.0;
+        }
+        if distance >= self.max_distance {
+            return self.max_distance_volume;
+        }
+
+        // Falls off exponentially, `max_distance_volume` being at least 0.005
+        let range = self.max_distance - self.min_distance;
+        let relative = if range > 0.0 { (distance - self.min_distance) / range } else { 1.0 };
+        10f32.powf(self.max_distance_volume.max(0.005).log10() * relative)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(expected: f32, actual: f32) {
+        assert!((expected - actual).abs() < 1e-5, "expected {expected}, got {actual}");
+    }
+
+    #[test]
+    fn encodes_positions() {
+        let position = Vector3::new(1.5, -2.0, 100.25);
+        let encoded = position.encode();
+
+        assert_eq!(POSITION_SIZE, encoded.len());
+        assert_eq!(Some(position), Vector3::decode(&[&encoded[..], b"context".as_slice()].concat()));
+        assert_eq!(None, Vector3::decode(&encoded[..8]));
+        assert_eq!(Some(position), Vector3::from_slice(&position.to_array()));
+        assert_eq!(None, Vector3::from_slice(&[1.0, 2.0]));
+    }
+
+    #[test]
+    fn direction_relative_to_listener() {
+        // Looking along x, the speaker is in front, the listener's right is -z
+        let listener = Listener {
+            position: Vector3::new(1.0, 0.0, 1.0),
+            front: Vector3::new(2.0, 0.0, 0.0),
+            top: Vector3::new(0.0, 1.0, 0.0),
+        };
+
+        assert_eq!(Vector3::new(0.0, 0.0, 1.0), listener.direction(Vector3::new(5.0, 0.0, 1.0)));
+        assert_eq!(Vector3::new(1.0, 0.0, 0.0), listener.direction(Vector3::new(1.0, 0.0, -3.0)));
+        assert_eq!(Vector3::default(), listener.direction(listener.position));
+        assert_close(5.0, listener.distance(Vector3::new(4.0, 4.0, 1.0)));
+    }
+
+    #[test]
+    fn attenuates_by_distance_and_direction() {
+        let settings = Attenuation::default();
+
+        assert_eq!(1.0, settings.gain(-1.0, 0.0));
+        assert_eq!(1.0, settings.distance_gain(0.5));
+        assert_close(0.5, settings.distance_gain(8.0));
+        assert_eq!(0.25, settings.distance_gain(20.0));
+
+        assert_close(0.5, settings.gain(1.0, 8.0));
+        assert_close(0.025, settings.gain(-1.0, 8.0));
+        // Half the bloom halfway to `min_distance`
+        assert_close(0.3, settings.gain(-1.0, 0.5));
+
+        let unattenuated = Attenuation { max_distance_volume: 1.0, bloom: 0.0, ..settings };
+        assert_eq!(1.0, unattenuated.gain(1.0, 1000.0));
+        assert_close(0.05, unattenuated.gain(-1.0, 1000.0));
+    }
+
+    #[test]
+    fn stereo() {
+        let listener = Listener::default();
+        let (left, right) = listener.stereo_gains(Vector3::new(4.0, 0.0, 0.0), &Attenuation::default());
+
+        assert!(right > left);
+        assert_close(right / 20.0, left);
+    }
+}
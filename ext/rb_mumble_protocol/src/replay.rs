@@ -0,0 +1,349 @@
+//! Decoding of captured Mumble traffic
+//!
+//! UDP datagrams are decrypted with the key and nonces of the client's `CryptSetup` message,
+//! one `CryptState` per direction, and decoded as voice packets. TCP segments are reassembled
+//! per direction and decoded as control messages. This only works for control channels captured
+//! in plain text, e.g. synthetic sessions or traffic behind a TLS terminating proxy; TLS
+//! encrypted channels are detected and skipped.
+//!
+//! Decryption has to start at the `CryptSetup` the nonces were taken from: `CryptState` only
+//! resynchronizes over small gaps, so a capture starting much later fails to decrypt.
+
+use bytes::{Bytes, BytesMut};
+
+use crate::control::{ControlCodec, ControlError, ControlMessage, MessageType, HEADER_SIZE, MAX_MESSAGE_SIZE};
+use crate::crypt_state::{CryptState, DecryptError, BLOCK_SIZE, KEY_SIZE};
+use crate::pcap::{Packet, Transport};
+use crate::voice::{Direction, VoicePacket, VoiceError};
+
+const TCP_SYN: u8 = 0x02;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    /// A voice packet, sent over UDP or tunneled through the control channel.
+    Voice(VoicePacket),
+    Control(Box<ControlMessage>),
+    /// The datagram could not be decrypted.
+    Decrypt(DecryptError),
+    VoiceError(VoiceError),
+    ControlError(ControlError),
+    /// Bytes of the control channel are missing from the capture. The partially received
+    /// message is dropped, decoding resumes with the first segment starting with a message.
+    MissingData(u32),
+    /// The control channel is TLS encrypted and won't be decoded.
+    Tls,
+}
+
+/// Something found in the traffic of a captured session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// Microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    pub direction: Direction,
+    pub transport: Transport,
+    pub kind: EventKind,
+}
+
+/// Reassembled control channel of one direction.
+#[derive(Default)]
+struct TcpFlow {
+    next_seq: Option<u32>,
+    codec: ControlCodec,
+    /// Set once the flow turned out to be undecodable (TLS or a corrupt frame).
+    skipped: bool,
+    /// Set after missing data until a segment starts with a message header.
+    resyncing: bool,
+}
+
+/// Decodes the traffic between a server and one of its clients.
+pub struct Replay {
+    server_port: u16,
+    /// Decrypts serverbound and clientbound datagrams respectively.
+    crypt: [CryptState; 2],
+    tcp: [TcpFlow; 2],
+}
+
+impl Replay {
+    /// Uses the values of the `CryptSetup` message sent to the client.
+    pub fn new(
+        server_port: u16,
+        key: [u8; KEY_SIZE],
+        client_nonce: [u8; BLOCK_SIZE],
+        server_nonce: [u8; BLOCK_SIZE],
+    ) -> Self {
+        Replay {
+            server_port,
+            crypt: [
+                CryptState::new_from(key, server_nonce, client_nonce),
+                CryptState::new_from(key, client_nonce, server_nonce),
+            ],
+            tcp: Default::default(),
+        }
+    }
+
+    /// Returns the state decrypting packets sent in the given direction, e.g. for its statistics.
+    pub fn crypt_state(&self, direction: Direction) -> &CryptState {
+        &self.crypt[direction_index(direction)]
+    }
+
+    /// Decodes a captured datagram or segment.
+    ///
+    /// Packets neither sent to nor from the server port are ignored.
+    pub fn feed(&mut self, packet: &Packet) -> Vec<Event> {
+        let direction = if packet.destination.port() == self.server_port {
+            Direction::Serverbound
+        } else if packet.source.port() == self.server_port {
+            Direction::Clientbound
+        } else {
+            return Vec::new();
+        };
+
+        let kinds = match packet.transport {
+            Transport::Udp => vec![self.decrypt(direction, &packet.payload)],
+            Transport::Tcp { seq, flags } => self.reassemble(direction, seq, flags, &packet.payload),
+        };
+
+        kinds
+            .into_iter()
+            .map(|kind| Event { timestamp_us: packet.timestamp_us, direction, transport: packet.transport, kind })
+            .collect()
+    }
+
+    fn decrypt(&mut self, direction: Direction, payload: &[u8]) -> EventKind {
+        let mut buffer = BytesMut::from(payload);
+        if let Err(e) = self.crypt[direction_index(direction)].decrypt(&mut buffer) {
+            return EventKind::Decrypt(e);
+        }
+
+        match VoicePacket::decode(buffer.freeze(), direction) {
+            Ok(packet) => EventKind::Voice(packet),
+            Err(e) => EventKind::VoiceError(e),
+        }
+    }
+
+    fn reassemble(&mut self, direction: Direction, seq: u32, flags: u8, payload: &[u8]) -> Vec<EventKind> {
+        let flow = &mut self.tcp[direction_index(direction)];
+        if flow.skipped {
+            return Vec::new();
+        }
+        if flags & TCP_SYN != 0 {
+            flow.next_seq = Some(seq.wrapping_add(1));
+            return Vec::new();
+        }
+        if payload.is_empty() {
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        let expected = *flow.next_seq.get_or_insert(seq);
+        let (start, payload) = match expected.wrapping_sub(seq) as i32 {
+            0 => (seq, payload),
+            // a retransmission, possibly overlapping new data
+            overlap if overlap > 0 => match payload.get(overlap as usize..) {
+                Some(rest) if !rest.is_empty() => (expected, rest),
+                _ => return Vec::new(),
+            },
+            missing => {
+                events.push(EventKind::MissingData(missing.unsigned_abs()));
+                flow.codec = ControlCodec::default();
+                flow.resyncing = true;
+                (seq, payload)
+            }
+        };
+        flow.next_seq = Some(start.wrapping_add(payload.len() as u32));
+
+        if flow.resyncing {
+            if !starts_message(payload) {
+                return events;
+            }
+            flow.resyncing = false;
+        }
+
+        if flow.codec.buffered_len() == 0 && payload.starts_with(&[0x16, 0x03]) {
+            flow.skipped = true;
+            events.push(EventKind::Tls);
+            return events;
+        }
+
+        flow.codec.extend(payload);
+        loop {
+            match flow.codec.decode_next() {
+                Ok(Some(ControlMessage::UdpTunnel(data))) => events.push(decode_tunneled(data, direction)),
+                Ok(Some(message)) => events.push(EventKind::Control(Box::new(message))),
+                Ok(None) => break,
+                Err(e @ ControlError::TooLarge(_)) => {
+                    flow.skipped = true;
+                    events.push(EventKind::ControlError(e));
+                    break;
+                }
+                Err(e) => events.push(EventKind::ControlError(e)),
+            }
+        }
+        events
+    }
+}
+
+/// Whether the data plausibly starts with a control message header: a known type and a length
+/// within Murmur's limit.
+fn starts_message(data: &[u8]) -> bool {
+    match data.get(..HEADER_SIZE) {
+        Some(header) => {
+            let id = u16::from_be_bytes([header[0], header[1]]);
+            let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+            MessageType::from_id(id).is_some() && len <= MAX_MESSAGE_SIZE
+        }
+        None => false,
+    }
+}
+
+fn decode_tunneled(data: Bytes, direction: Direction) -> EventKind {
+    match VoicePacket::decode(data, direction) {
+        Ok(packet) => EventKind::Voice(packet),
+        Err(e) => EventKind::VoiceError(e),
+    }
+}
+
+fn direction_index(direction: Direction) -> usize {
+    match direction {
+        Direction::Serverbound => 0,
+        Direction::Clientbound => 1,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::mumble_proto as msgs;
+    use crate::pcap::{PcapReader, PcapWriter};
+    use crate::voice::{AudioPacket, VoicePayload};
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+    const CLIENT_NONCE: [u8; BLOCK_SIZE] = [1; BLOCK_SIZE];
+    const SERVER_NONCE: [u8; BLOCK_SIZE] = [2; BLOCK_SIZE];
+
+    fn client() -> SocketAddr {
+        "10.0.0.2:50000".parse().unwrap()
+    }
+
+    fn server() -> SocketAddr {
+        "10.0.0.1:64738".parse().unwrap()
+    }
+
+    fn audio(session_id: Option<u32>, seq_num: u64) -> VoicePacket {
+        VoicePacket::Audio(AudioPacket {
+            target: 0,
+            session_id,
+            seq_num,
+            payload: VoicePayload::Opus(Bytes::from_static(&[0xf8, 0xff, 0xfe]), false),
+            position_info: None,
+        })
+    }
+
+    fn replay(capture: Vec<u8>) -> Vec<EventKind> {
+        let mut reader = PcapReader::new(capture.as_slice()).unwrap();
+        let mut replay = Replay::new(64738, KEY, CLIENT_NONCE, SERVER_NONCE);
+        let mut events = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            events.extend(replay.feed(&packet).into_iter().map(|event| event.kind));
+        }
+        events
+    }
+
+    #[test]
+    fn decrypts_both_directions() {
+        let mut states = [
+            CryptState::new_from(KEY, CLIENT_NONCE, SERVER_NONCE),
+            CryptState::new_from(KEY, SERVER_NONCE, CLIENT_NONCE),
+        ];
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+
+        for (direction, packet, source, destination) in [
+            (Direction::Serverbound, audio(None, 0), client(), server()),
+            (Direction::Clientbound, audio(Some(3), 0), server(), client()),
+            (Direction::Serverbound, audio(None, 2), client(), server()),
+        ] {
+            let state = &mut states[direction_index(direction)];
+            let mut plain = BytesMut::new();
+            packet.encode(direction, &mut plain).unwrap();
+            let mut encrypted = BytesMut::new();
+            state.encrypt(&plain, &mut encrypted);
+            writer.write_udp(0, source, destination, &encrypted).unwrap();
+        }
+        // not encrypted with the session's key
+        writer.write_udp(0, client(), server(), &[0; 12]).unwrap();
+        // unrelated traffic
+        writer.write_udp(0, client(), "10.0.0.1:53".parse().unwrap(), b"dns").unwrap();
+
+        assert_eq!(
+            vec![
+                EventKind::Voice(audio(None, 0)),
+                EventKind::Voice(audio(Some(3), 0)),
+                EventKind::Voice(audio(None, 2)),
+                EventKind::Decrypt(DecryptError::Mac),
+            ],
+            replay(writer.into_inner())
+        );
+    }
+
+    #[test]
+    fn reassembles_control_messages() {
+        let mut stream = BytesMut::new();
+        ControlMessage::from(msgs::Ping { timestamp: Some(5), ..Default::default() }).encode(&mut stream);
+        let mut tunnel = BytesMut::new();
+        audio(None, 4).encode(Direction::Serverbound, &mut tunnel).unwrap();
+        ControlMessage::UdpTunnel(tunnel.freeze()).encode(&mut stream);
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_tcp(0, client(), server(), &stream[..4]).unwrap();
+        writer.write_tcp(0, client(), server(), &stream[4..]).unwrap();
+
+        let mut capture = writer.into_inner();
+        // retransmit the last segment
+        let last_record = capture.len() - 16 - 40 - (stream.len() - 4);
+        capture.extend_from_within(last_record..);
+
+        assert_eq!(
+            vec![
+                EventKind::Control(Box::new(msgs::Ping { timestamp: Some(5), ..Default::default() }.into())),
+                EventKind::Voice(audio(None, 4)),
+            ],
+            replay(capture)
+        );
+    }
+
+    #[test]
+    fn resyncs_after_missing_data() {
+        let mut stream = BytesMut::new();
+        for timestamp in [5, 6, 7] {
+            ControlMessage::from(msgs::Ping { timestamp: Some(timestamp), ..Default::default() }).encode(&mut stream);
+        }
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        for segment in [&stream[..4], &stream[4..12], &stream[12..16], &stream[16..]] {
+            writer.write_tcp(0, client(), server(), segment).unwrap();
+        }
+        // lose the second segment, the third one continues the second message
+        let mut capture = writer.into_inner();
+        let lost = 24 + 56 + 56 + 4;
+        capture.drain(lost..lost + 56 + 8);
+
+        assert_eq!(
+            vec![
+                EventKind::MissingData(8),
+                EventKind::Control(Box::new(msgs::Ping { timestamp: Some(7), ..Default::default() }.into())),
+            ],
+            replay(capture)
+        );
+    }
+
+    #[test]
+    fn skips_tls() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_tcp(0, client(), server(), &[0x16, 0x03, 0x01, 0x02, 0x00]).unwrap();
+        writer.write_tcp(0, client(), server(), &[0x17, 0x03, 0x03, 0x00, 0x10]).unwrap();
+
+        assert_eq!(vec![EventKind::Tls], replay(writer.into_inner()));
+    }
+}
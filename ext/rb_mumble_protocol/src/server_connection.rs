@@ -0,0 +1,635 @@
+//! Server side of a single Mumble connection, independent of the transport
+//!
+//! Mirrors `ClientSession`: control messages read from the client are passed to `handle`, `poll`
+//! detects dead connections, and the messages to write back are taken out with `drain_outgoing`.
+//! Deciding whether a user may join (registered passwords, free slots, names in use) is left to
+//! the caller, which answers `Event::Authenticate` with `accept` or `reject`.
+//!
+//! The voice channel's `CryptState` belongs to the caller, which encrypts and decrypts voice with
+//! it. It is passed to `accept`, which sends its keys to the client, and then to `handle`, which
+//! resynchronises its nonces and reports its statistics in ping replies.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Messages.cpp
+
+use crate::client_session::{version_to_v1, DEFAULT_VERSION};
+use crate::codec_version::Codecs;
+use crate::control::ControlMessage;
+use crate::crypt_state::{CryptState, BLOCK_SIZE};
+use crate::mumble_proto as msgs;
+use crate::mumble_proto::reject::RejectType;
+use crate::permissions::Permissions;
+use crate::ping_tracker::PingTracker;
+
+/// Time without any message from the client after which it is disconnected, as in Murmur.
+pub const DEFAULT_TIMEOUT_MS: u64 = 30000;
+pub const DEFAULT_MAX_BANDWIDTH: u32 = 558000;
+pub const DEFAULT_MAX_USERNAME_LENGTH: usize = 128;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Version in the `version_v2` format, `major << 48 | minor << 32 | patch << 16`.
+    pub version: u64,
+    pub release: String,
+    pub os: String,
+    pub os_version: String,
+    /// Server password, required from every client when set.
+    pub password: Option<String>,
+    /// Oldest client version accepted, in the `version_v2` format.
+    pub min_version: u64,
+    /// Rejects clients which don't support Opus.
+    pub require_opus: bool,
+    pub max_username_length: usize,
+    pub max_bandwidth: u32,
+    pub welcome_text: Option<String>,
+    pub timeout_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: DEFAULT_VERSION,
+            release: concat!("rb_mumble_protocol ", env!("CARGO_PKG_VERSION")).to_string(),
+            os: std::env::consts::OS.to_string(),
+            os_version: String::new(),
+            password: None,
+            min_version: 0,
+            require_opus: false,
+            max_username_length: DEFAULT_MAX_USERNAME_LENGTH,
+            max_bandwidth: DEFAULT_MAX_BANDWIDTH,
+            welcome_text: None,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+        }
+    }
+}
+
+/// Converts a legacy `major << 16 | minor << 8 | patch` version to the `version_v2` format.
+pub fn version_from_v1(version: u32) -> u64 {
+    let version = version as u64;
+    (version >> 16) << 48 | ((version >> 8) & 0xff) << 32 | (version & 0xff) << 16
+}
+
+/// Whether a username matches Murmur's default `[ -=\w\[\]\{\}\(\)\@\|\.]+` pattern, where
+/// ` -=` is the range of characters from space to `=`, including digits and most punctuation.
+pub fn is_valid_username(username: &str, max_length: usize) -> bool {
+    !username.trim().is_empty()
+        && username.chars().count() <= max_length
+        && username.chars().all(|c| (' '..='=').contains(&c) || c.is_alphanumeric() || "_[]{}()@|.".contains(c))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// `start` hasn't been called yet.
+    Idle,
+    /// Waiting for the client's `Authenticate`.
+    Connected,
+    /// The credentials were validated, waiting for `accept` or `reject`.
+    Authenticating,
+    /// The client received the server state and is now a user.
+    Synced,
+    Rejected,
+    /// The client stopped answering.
+    Disconnected,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Idle => "idle",
+            State::Connected => "connected",
+            State::Authenticating => "authenticating",
+            State::Synced => "synced",
+            State::Rejected => "rejected",
+            State::Disconnected => "disconnected",
+        }
+    }
+
+    /// Whether the connection is over and should be closed.
+    pub fn is_closed(self) -> bool {
+        matches!(self, State::Rejected | State::Disconnected)
+    }
+}
+
+/// What a client sent in `Version` and `Authenticate`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Option<String>,
+    pub tokens: Vec<String>,
+    /// CELT bitstream versions the client supports.
+    pub celt_versions: Vec<i32>,
+    pub opus: bool,
+    pub bot: bool,
+    /// Version in the `version_v2` format, `None` if the client didn't send `Version`.
+    pub version: Option<u64>,
+    pub release: Option<String>,
+    pub os: Option<String>,
+}
+
+impl Credentials {
+    /// The codecs to record in the `UserRegistry` once the client is accepted.
+    pub fn codecs(&self) -> Codecs {
+        Codecs { celt_versions: self.celt_versions.clone(), opus: self.opus }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The client passed the built-in checks, answer with `accept` or `reject`.
+    Authenticate(Box<Credentials>),
+    /// The connection was refused, the `Reject` message has been queued.
+    Rejected {
+        reject_type: RejectType,
+        reason: String,
+    },
+    /// The client sent new access tokens once authenticated.
+    Tokens(Vec<String>),
+    /// Nothing was received from the client for `Config::timeout_ms`.
+    TimedOut,
+    /// A message sent by a synced client which the connection doesn't handle itself.
+    Message(Box<ControlMessage>),
+}
+
+pub struct ServerConnection {
+    config: Config,
+    state: State,
+    session: Option<u32>,
+    client_version: Option<msgs::Version>,
+    credentials: Option<Credentials>,
+    ping_tracker: PingTracker,
+    last_received_ms: u64,
+    outgoing: Vec<ControlMessage>,
+    events: Vec<Event>,
+}
+
+impl ServerConnection {
+    pub fn new(config: Config) -> Self {
+        ServerConnection {
+            config,
+            state: State::Idle,
+            session: None,
+            client_version: None,
+            credentials: None,
+            ping_tracker: PingTracker::new(),
+            last_received_ms: 0,
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The session id assigned with `accept`.
+    pub fn session(&self) -> Option<u32> {
+        self.session
+    }
+
+    /// The validated credentials, once `Authenticate` was received.
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// What the client reported in its last `Ping`, see `PingTracker::fill_user_stats`.
+    pub fn ping_tracker(&self) -> &PingTracker {
+        &self.ping_tracker
+    }
+
+    /// Fills the connection statistics of a `UserStats` reply about this client.
+    pub fn fill_user_stats(&self, stats: &mut msgs::UserStats, crypt_state: Option<&CryptState>) {
+        self.ping_tracker.fill_user_stats(stats, crypt_state);
+    }
+
+    /// Queues the server `Version`, to be called once the transport is connected.
+    pub fn start(&mut self, now_ms: u64) {
+        if self.state != State::Idle {
+            return;
+        }
+
+        let version = msgs::Version {
+            version_v1: Some(version_to_v1(self.config.version)),
+            version_v2: Some(self.config.version),
+            release: Some(self.config.release.clone()),
+            os: Some(self.config.os.clone()),
+            os_version: Some(self.config.os_version.clone()),
+        };
+        self.outgoing.push(version.into());
+
+        self.state = State::Connected;
+        self.last_received_ms = now_ms;
+    }
+
+    /// Handles a message received from the client, `crypt_state` being the one passed to `accept`.
+    ///
+    /// Messages other than `Version`, `Authenticate` and `Ping` are dropped until the client is
+    /// synced, like Murmur does. Messages received once the connection is closed are dropped.
+    pub fn handle(&mut self, message: ControlMessage, now_ms: u64, crypt_state: Option<&mut CryptState>) {
+        if self.state.is_closed() || self.state == State::Idle {
+            return;
+        }
+        self.last_received_ms = now_ms;
+
+        match message {
+            ControlMessage::Version(version) if self.state == State::Connected => {
+                self.client_version = Some(version);
+            }
+            ControlMessage::Authenticate(authenticate) if self.state == State::Connected => {
+                self.handle_authenticate(authenticate);
+            }
+            ControlMessage::Authenticate(authenticate) if self.state == State::Synced => {
+                if let Some(credentials) = self.credentials.as_mut() {
+                    credentials.tokens = authenticate.tokens.clone();
+                }
+                self.events.push(Event::Tokens(authenticate.tokens));
+            }
+            ControlMessage::Ping(ping) => self.handle_ping(ping, now_ms, crypt_state.as_deref()),
+            ControlMessage::CryptSetup(setup) if self.state == State::Synced => {
+                if let Some(crypt_state) = crypt_state {
+                    self.handle_crypt_setup(setup, crypt_state);
+                }
+            }
+            message if self.state == State::Synced => self.events.push(Event::Message(Box::new(message))),
+            _ => {}
+        }
+    }
+
+    fn handle_authenticate(&mut self, authenticate: msgs::Authenticate) {
+        let version = self.client_version.as_ref().and_then(|version| {
+            version.version_v2.or(version.version_v1.map(version_from_v1))
+        });
+        let credentials = Credentials {
+            username: authenticate.username.unwrap_or_default(),
+            password: authenticate.password,
+            tokens: authenticate.tokens,
+            celt_versions: authenticate.celt_versions,
+            opus: authenticate.opus.unwrap_or(false),
+            bot: authenticate.client_type == Some(1),
+            version,
+            release: self.client_version.as_ref().and_then(|version| version.release.clone()),
+            os: self.client_version.as_ref().and_then(|version| version.os.clone()),
+        };
+
+        let rejection = if version.unwrap_or(0) < self.config.min_version {
+            Some((RejectType::WrongVersion, "Your client is too old to connect to this server".to_string()))
+        } else if self.config.require_opus && !credentials.opus {
+            Some((RejectType::WrongVersion, "This server requires Opus support".to_string()))
+        } else if !matches!(authenticate.client_type, None | Some(0) | Some(1)) {
+            Some((RejectType::None, "Unknown client type".to_string()))
+        } else if !is_valid_username(&credentials.username, self.config.max_username_length) {
+            Some((RejectType::InvalidUsername, "Invalid username".to_string()))
+        } else if self.config.password.is_some() && credentials.password != self.config.password {
+            Some((RejectType::WrongServerPw, "Invalid server password".to_string()))
+        } else {
+            None
+        };
+
+        self.credentials = Some(credentials.clone());
+        match rejection {
+            Some((reject_type, reason)) => self.reject(reject_type, &reason),
+            None => {
+                self.state = State::Authenticating;
+                self.events.push(Event::Authenticate(Box::new(credentials)));
+            }
+        }
+    }
+
+    fn handle_ping(&mut self, ping: msgs::Ping, now_ms: u64, crypt_state: Option<&CryptState>) {
+        self.ping_tracker.receive(&ping, now_ms);
+
+        let reply = self.ping_tracker.reply(&ping, crypt_state);
+        self.outgoing.push(reply.into());
+    }
+
+    fn handle_crypt_setup(&mut self, setup: msgs::CryptSetup, crypt_state: &mut CryptState) {
+        match setup.client_nonce.as_deref().map(<[u8; BLOCK_SIZE]>::try_from) {
+            // The client resynchronises our decrypt nonce
+            Some(Ok(client_nonce)) => {
+                crypt_state.set_decrypt_nonce(&client_nonce);
+                self.ping_tracker.record_resync();
+            }
+            Some(Err(_)) => {}
+            // The client asks for our encrypt nonce
+            None => {
+                let reply = msgs::CryptSetup {
+                    server_nonce: Some(crypt_state.get_encrypt_nonce().to_vec()),
+                    ..Default::default()
+                };
+                self.outgoing.push(reply.into());
+            }
+        }
+    }
+
+    /// Lets an authenticating client in: sends the keys of the voice channel's `crypt_state`
+    /// (usually `CryptState::generate_new()`), the server state (e.g. `ChannelState`, `UserState`
+    /// and `CodecVersion` messages) and `ServerSync` with the permissions of the client in the
+    /// root channel.
+    pub fn accept<I>(&mut self, session: u32, permissions: Permissions, crypt_state: &CryptState, server_state: I)
+    where
+        I: IntoIterator<Item = ControlMessage>,
+    {
+        if self.state != State::Authenticating {
+            return;
+        }
+
+        let setup = msgs::CryptSetup {
+            key: Some(crypt_state.get_key().to_vec()),
+            client_nonce: Some(crypt_state.get_decrypt_nonce().to_vec()),
+            server_nonce: Some(crypt_state.get_encrypt_nonce().to_vec()),
+        };
+        self.outgoing.push(setup.into());
+        self.outgoing.extend(server_state);
+
+        let sync = msgs::ServerSync {
+            session: Some(session),
+            max_bandwidth: Some(self.config.max_bandwidth),
+            welcome_text: self.config.welcome_text.clone(),
+            permissions: Some(permissions.bits() as u64),
+        };
+        self.outgoing.push(sync.into());
+
+        self.session = Some(session);
+        self.state = State::Synced;
+    }
+
+    /// Refuses the connection, e.g. with `UsernameInUse` or `ServerFull`.
+    pub fn reject(&mut self, reject_type: RejectType, reason: &str) {
+        if !matches!(self.state, State::Connected | State::Authenticating) {
+            return;
+        }
+
+        let reject = msgs::Reject { r#type: Some(reject_type as i32), reason: Some(reason.to_string()) };
+        self.outgoing.push(reject.into());
+        self.state = State::Rejected;
+        self.events.push(Event::Rejected { reject_type, reason: reason.to_string() });
+    }
+
+    /// Asks the client for its encrypt nonce, e.g. after many packets failed to decrypt.
+    pub fn request_crypt_resync(&mut self) {
+        if self.state == State::Synced {
+            self.outgoing.push(msgs::CryptSetup::default().into());
+        }
+    }
+
+    /// Disconnects clients which stopped sending anything, including pings.
+    pub fn poll(&mut self, now_ms: u64) {
+        if self.state == State::Idle || self.state.is_closed() {
+            return;
+        }
+
+        if now_ms.saturating_sub(self.last_received_ms) >= self.config.timeout_ms {
+            self.state = State::Disconnected;
+            self.events.push(Event::TimedOut);
+        }
+    }
+
+    /// When `poll` next needs to be called, `None` while the connection isn't active.
+    pub fn next_poll_ms(&self) -> Option<u64> {
+        if self.state == State::Idle || self.state.is_closed() {
+            None
+        } else {
+            Some(self.last_received_ms + self.config.timeout_ms)
+        }
+    }
+
+    /// Takes the messages to send to the client, in order.
+    pub fn drain_outgoing(&mut self) -> Vec<ControlMessage> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Takes the events which happened since the last call, in order.
+    pub fn drain_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client_session::{self, ClientSession};
+    use bytes::BytesMut;
+
+    fn started(config: Config) -> ServerConnection {
+        let mut connection = ServerConnection::new(config);
+        connection.start(0);
+        connection.drain_outgoing();
+        connection
+    }
+
+    fn authenticate(username: &str, password: Option<&str>) -> ControlMessage {
+        msgs::Authenticate {
+            username: Some(username.into()),
+            password: password.map(Into::into),
+            opus: Some(true),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn rejection(connection: &mut ServerConnection) -> Option<(RejectType, String)> {
+        match &connection.drain_outgoing()[..] {
+            [ControlMessage::Reject(reject)] => {
+                Some((RejectType::try_from(reject.r#type?).ok()?, reject.reason.clone()?))
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let config = Config { password: Some("pw".into()), min_version: 1 << 48 | 4 << 32, ..Default::default() };
+
+        let mut connection = started(config.clone());
+        connection.handle(msgs::Version { version_v1: Some(0x10300), ..Default::default() }.into(), 10, None);
+        connection.handle(authenticate("alice", Some("pw")), 20, None);
+        assert_eq!(Some(RejectType::WrongVersion), rejection(&mut connection).map(|(ty, _)| ty));
+        assert_eq!(State::Rejected, connection.state());
+
+        let mut connection = started(config.clone());
+        connection.handle(authenticate("alice", Some("nope")), 20, None);
+        assert_eq!(Some(RejectType::WrongVersion), rejection(&mut connection).map(|(ty, _)| ty));
+
+        let version = msgs::Version { version_v2: Some(DEFAULT_VERSION), ..Default::default() };
+        let mut connection = started(config.clone());
+        connection.handle(version.clone().into(), 10, None);
+        connection.handle(authenticate("alice", Some("nope")), 20, None);
+        assert_eq!(
+            Some((RejectType::WrongServerPw, "Invalid server password".into())),
+            rejection(&mut connection)
+        );
+        assert_eq!(
+            vec![Event::Rejected { reject_type: RejectType::WrongServerPw, reason: "Invalid server password".into() }],
+            connection.drain_events()
+        );
+
+        let mut connection = started(config.clone());
+        connection.handle(version.clone().into(), 10, None);
+        connection.handle(authenticate("  ", Some("pw")), 20, None);
+        assert_eq!(Some(RejectType::InvalidUsername), rejection(&mut connection).map(|(ty, _)| ty));
+
+        let mut connection = started(Config { require_opus: true, ..config.clone() });
+        connection.handle(version.clone().into(), 10, None);
+        let no_opus = msgs::Authenticate { username: Some("alice".into()), password: Some("pw".into()), ..Default::default() };
+        connection.handle(no_opus.into(), 20, None);
+        assert_eq!(Some(RejectType::WrongVersion), rejection(&mut connection).map(|(ty, _)| ty));
+
+        let mut connection = started(config);
+        connection.handle(version.into(), 10, None);
+        let bot = msgs::Authenticate { client_type: Some(1), tokens: vec!["t".into()], ..Default::default() };
+        connection.handle(ControlMessage::from(msgs::Authenticate { username: Some("alice".into()), password: Some("pw".into()), ..bot }), 20, None);
+        assert!(connection.drain_outgoing().is_empty());
+        assert_eq!(State::Authenticating, connection.state());
+        let [Event::Authenticate(credentials)] = &connection.drain_events()[..] else { panic!() };
+        assert_eq!("alice", credentials.username);
+        assert_eq!(vec!["t".to_string()], credentials.tokens);
+        assert!(credentials.bot);
+        assert_eq!(Some(DEFAULT_VERSION), credentials.version);
+    }
+
+    #[test]
+    fn usernames() {
+        assert!(is_valid_username("Älice [AFK] (bot)|x@y.z", 128));
+        assert!(!is_valid_username("", 128));
+        assert!(is_valid_username("bot#1", 128));
+        assert!(is_valid_username("a+b, \"c\" & d's!", 128));
+        assert!(is_valid_username("<3 100%;", 128));
+        assert!(!is_valid_username("a>b", 128));
+        assert!(!is_valid_username("a?b", 128));
+        assert!(!is_valid_username("a~b", 128));
+        assert!(!is_valid_username("abc", 2));
+        assert_eq!(1 << 48 | 2 << 32 | 19 << 16, version_from_v1(0x10213));
+    }
+
+    #[test]
+    fn rejected_by_caller() {
+        let mut connection = started(Config::default());
+        connection.handle(authenticate("alice", None), 10, None);
+        connection.drain_events();
+
+        connection.reject(RejectType::UsernameInUse, "Username already in use");
+        assert_eq!(
+            Some((RejectType::UsernameInUse, "Username already in use".into())),
+            rejection(&mut connection)
+        );
+        connection.accept(1, Permissions::ALL, &CryptState::generate_new(), []);
+        assert!(connection.drain_outgoing().is_empty());
+        assert_eq!(None, connection.session());
+    }
+
+    #[test]
+    fn handshake_with_client() {
+        let mut client = ClientSession::new(client_session::Config { username: "alice".into(), ..Default::default() });
+        let mut server = started(Config { welcome_text: Some("hi".into()), ..Default::default() });
+        let mut crypt_state = CryptState::generate_new();
+
+        client.start(0);
+        for message in client.drain_outgoing() {
+            server.handle(message, 10, None);
+        }
+        assert!(matches!(&server.drain_events()[..], [Event::Authenticate(_)]));
+
+        let root = msgs::ChannelState { channel_id: Some(0), name: Some("Root".into()), ..Default::default() };
+        server.accept(7, Permissions::TRAVERSE | Permissions::ENTER, &crypt_state, [root.into()]);
+        assert_eq!(State::Synced, server.state());
+
+        for message in server.drain_outgoing() {
+            client.handle(message, 20);
+        }
+        let events = client.drain_events();
+        assert_eq!(client_session::Event::CryptSetup, events[0]);
+        assert!(matches!(&events[1], client_session::Event::Message(_)));
+        assert_eq!(
+            client_session::Event::Synced {
+                session: 7,
+                max_bandwidth: Some(DEFAULT_MAX_BANDWIDTH),
+                welcome_text: Some("hi".into()),
+                permissions: Some(0x6),
+            },
+            events[2]
+        );
+
+        // Voice flows both ways with the distributed keys
+        let mut datagram = client.encrypt(b"up").unwrap();
+        assert_eq!(Ok(()), crypt_state.decrypt(&mut datagram));
+        assert_eq!(&b"up"[..], &datagram[..]);
+        let mut datagram = BytesMut::new();
+        crypt_state.encrypt(b"down", &mut datagram);
+        assert_eq!(Ok(()), client.decrypt(&mut datagram));
+        assert_eq!(&b"down"[..], &datagram[..]);
+
+        // Nonce resynchronisation in both directions
+        client.request_crypt_resync();
+        for message in client.drain_outgoing() {
+            server.handle(message, 30, Some(&mut crypt_state));
+        }
+        for message in server.drain_outgoing() {
+            client.handle(message, 40);
+        }
+        assert_eq!(
+            crypt_state.get_encrypt_nonce(),
+            client.crypt_state().unwrap().get_decrypt_nonce()
+        );
+        server.request_crypt_resync();
+        for message in server.drain_outgoing() {
+            client.handle(message, 50);
+        }
+        for message in client.drain_outgoing() {
+            server.handle(message, 60, Some(&mut crypt_state));
+        }
+        assert_eq!(
+            client.crypt_state().unwrap().get_encrypt_nonce(),
+            crypt_state.get_decrypt_nonce()
+        );
+    }
+
+    #[test]
+    fn pings_and_timeout() {
+        let mut connection = started(Config::default());
+        let mut crypt_state = CryptState::generate_new();
+        connection.handle(authenticate("alice", None), 10, None);
+        connection.accept(1, Permissions::NONE, &crypt_state, []);
+        connection.drain_outgoing();
+        connection.drain_events();
+
+        let ping = msgs::Ping { timestamp: Some(1234), good: Some(5), lost: Some(1), ..Default::default() };
+        connection.handle(ping.into(), 5000, Some(&mut crypt_state));
+        let mut stats = msgs::UserStats::default();
+        connection.fill_user_stats(&mut stats, Some(&crypt_state));
+        assert_eq!(Some(5), stats.from_client.as_ref().and_then(|stats| stats.good));
+        assert_eq!(Some(1), stats.from_client.as_ref().and_then(|stats| stats.lost));
+        assert_eq!(Some(0), stats.from_server.as_ref().and_then(|stats| stats.good));
+        let outgoing = connection.drain_outgoing();
+        let [ControlMessage::Ping(reply)] = &outgoing[..] else { panic!("{outgoing:?}") };
+        assert_eq!(Some(1234), reply.timestamp);
+        assert_eq!(Some(0), reply.good);
+
+        assert_eq!(Some(5000 + DEFAULT_TIMEOUT_MS), connection.next_poll_ms());
+        connection.poll(5000 + DEFAULT_TIMEOUT_MS - 1);
+        assert_eq!(State::Synced, connection.state());
+        connection.poll(5000 + DEFAULT_TIMEOUT_MS);
+        assert_eq!(State::Disconnected, connection.state());
+        assert_eq!(vec![Event::TimedOut], connection.drain_events());
+    }
+
+    #[test]
+    fn messages_once_synced() {
+        let mut connection = started(Config::default());
+        let text = msgs::TextMessage { message: "hi".into(), ..Default::default() };
+        connection.handle(text.clone().into(), 10, None);
+        assert!(connection.drain_events().is_empty());
+
+        connection.handle(authenticate("alice", None), 10, None);
+        connection.accept(1, Permissions::NONE, &CryptState::generate_new(), []);
+        connection.drain_events();
+        connection.handle(text.into(), 20, None);
+        connection.handle(msgs::Authenticate { tokens: vec!["a".into()], ..Default::default() }.into(), 30, None);
+
+        let events = connection.drain_events();
+        assert!(matches!(&events[0], Event::Message(message) if matches!(**message, ControlMessage::TextMessage(_))));
+        assert_eq!(Event::Tokens(vec!["a".into()]), events[1]);
+        assert_eq!(vec!["a".to_string()], connection.credentials().unwrap().tokens);
+    }
+}
@@ -0,0 +1,792 @@
+//! Bodies and recipients of `TextMessage`s
+//!
+//! Bodies are HTML, with images inlined as `data:` URIs. Servers advertise two limits in
+//! `ServerConfig`: `message_length` for the text and `image_message_length` for messages with
+//! images. Murmur counts lengths in UTF-16 code units and doesn't count the data of inline images
+//! against `message_length`. When HTML isn't allowed, bodies are reduced to their text.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Server.cpp
+//! (`Server::isTextAllowed`) and https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Messages.cpp
+//! (`Server::msgTextMessage`)
+
+use std::collections::BTreeSet;
+
+use crate::channel_tree::ChannelTree;
+use crate::mumble_proto as msgs;
+use crate::user_registry::UserRegistry;
+
+/// Murmur's default `textmessagelength`.
+pub const DEFAULT_MESSAGE_LENGTH: u32 = 5000;
+/// Murmur's default `imagemessagelength`.
+pub const DEFAULT_IMAGE_MESSAGE_LENGTH: u32 = 131072;
+
+/// Tags kept by `sanitize`, others are removed but their content is kept.
+const ALLOWED_TAGS: &[&str] = &[
+    "a", "b", "blockquote", "br", "code", "div", "em", "font", "h1", "h2", "h3", "h4", "h5", "h6",
+    "hr", "i", "img", "li", "ol", "p", "pre", "s", "span", "strong", "sub", "sup", "table", "tbody",
+    "td", "th", "thead", "tr", "tt", "u", "ul",
+];
+
+/// Tags removed by `sanitize` together with their content.
+const DROPPED_TAGS: &[&str] = &["script", "style", "iframe", "object", "embed", "head", "title"];
+
+/// Tags whose content isn't HTML.
+const RAW_TEXT_TAGS: &[&str] = &["script", "style"];
+
+const ALLOWED_ATTRIBUTES: &[&str] = &[
+    "align", "alt", "border", "cellpadding", "cellspacing", "color", "colspan", "face", "height",
+    "href", "rowspan", "size", "src", "style", "title", "width",
+];
+
+/// Link schemes kept by `sanitize`, including those of the official client's user and channel links.
+const LINK_SCHEMES: &[&str] = &["http", "https", "ftp", "mailto", "mumble", "clientid", "channelid"];
+
+/// Image types allowed inline, with the signature their data starts with.
+const IMAGE_TYPES: &[(&str, &[u8])] = &[
+    ("image/png", b"\x89PNG\r\n\x1a\n"),
+    ("image/jpeg", b"\xff\xd8\xff"),
+    ("image/gif", b"GIF8"),
+    ("image/bmp", b"BM"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextError {
+    /// The message exceeds the applicable limit, in UTF-16 code units.
+    TooLong { length: usize, limit: u32 },
+}
+
+impl std::fmt::Display for TextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextError::TooLong { length, limit } => write!(f, "message of length {length} exceeds {limit}"),
+        }
+    }
+}
+
+impl std::error::Error for TextError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ImageError {
+    /// The source isn't a `data:` URI.
+    NotInline,
+    /// The data isn't base64 encoded.
+    NotBase64,
+    UnsupportedType(String),
+    InvalidBase64,
+    /// The data doesn't start with the signature of its type.
+    InvalidData(String),
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::NotInline => write!(f, "image isn't inline"),
+            ImageError::NotBase64 => write!(f, "image data isn't base64 encoded"),
+            ImageError::UnsupportedType(mime) => write!(f, "unsupported image type {mime}"),
+            ImageError::InvalidBase64 => write!(f, "invalid base64 image data"),
+            ImageError::InvalidData(mime) => write!(f, "image data isn't {mime}"),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// The limits a server advertises in `ServerConfig`, 0 meaning unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub allow_html: bool,
+    pub message_length: u32,
+    pub image_message_length: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            allow_html: true,
+            message_length: DEFAULT_MESSAGE_LENGTH,
+            image_message_length: DEFAULT_IMAGE_MESSAGE_LENGTH,
+        }
+    }
+}
+
+impl From<&msgs::ServerConfig> for Limits {
+    fn from(config: &msgs::ServerConfig) -> Self {
+        let defaults = Limits::default();
+
+        Limits {
+            allow_html: config.allow_html.unwrap_or(defaults.allow_html),
+            message_length: config.message_length.unwrap_or(defaults.message_length),
+            image_message_length: config.image_message_length.unwrap_or(defaults.image_message_length),
+        }
+    }
+}
+
+/// An image inlined in a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub mime: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// Returns the body to deliver, sanitized or reduced to text depending on `allow_html`, or an
+/// error if it exceeds the limits.
+///
+/// With HTML, a message longer than `message_length` is still allowed if it is within
+/// `image_message_length` and its text without image data is within `message_length`. Like
+/// Murmur, messages over `image_message_length` are rejected before being parsed.
+pub fn filter(text: &str, limits: &Limits) -> Result<String, TextError> {
+    if !limits.allow_html {
+        let text = to_plain_text(text);
+        return match utf16_len(&text) {
+            length if limits.message_length != 0 && length > limits.message_length as usize => {
+                Err(TextError::TooLong { length, limit: limits.message_length })
+            }
+            _ => Ok(text),
+        };
+    }
+
+    let length = utf16_len(text);
+    if limits.image_message_length != 0 && length > limits.image_message_length as usize {
+        return Err(TextError::TooLong { length, limit: limits.image_message_length });
+    }
+
+    let text = sanitize(text);
+    let length = utf16_len(&text);
+    if limits.image_message_length != 0 && length > limits.image_message_length as usize {
+        return Err(TextError::TooLong { length, limit: limits.image_message_length });
+    }
+    if limits.message_length == 0 || length <= limits.message_length as usize {
+        return Ok(text);
+    }
+
+    match text_length(&text) {
+        length if length > limits.message_length as usize => Err(TextError::TooLong { length, limit: limits.message_length }),
+        _ => Ok(text),
+    }
+}
+
+/// Length counted against `message_length`: UTF-16 code units, without the `src` of inline images.
+pub fn text_length(html: &str) -> usize {
+    let images: usize = tokenize(html)
+        .iter()
+        .filter_map(|token| match token {
+            Token::Tag(tag) if tag.name == "img" && !tag.closing => Some(tag),
+            _ => None,
+        })
+        .flat_map(|tag| tag.attributes.iter())
+        .filter(|attribute| attribute.name == "src" && attribute.value().trim_start().to_ascii_lowercase().starts_with("data:"))
+        .map(|attribute| utf16_len(attribute.raw))
+        .sum();
+
+    utf16_len(html) - images
+}
+
+/// Removes the tags and attributes clients shouldn't render: scripts, styles and frames with their
+/// content, event handlers, links with unknown schemes, and images which aren't valid inline images.
+/// The content of other unknown tags is kept.
+pub fn sanitize(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut dropped: Option<String> = None;
+
+    for token in tokenize(html) {
+        if let Some(name) = &dropped {
+            if matches!(&token, Token::Tag(tag) if tag.closing && tag.name == *name) {
+                dropped = None;
+            }
+            continue;
+        }
+
+        match token {
+            Token::Text(text) => out.push_str(text),
+            Token::Stray => out.push_str("&lt;"),
+            Token::Raw(_) | Token::Comment => {}
+            Token::Tag(tag) if DROPPED_TAGS.contains(&tag.name.as_str()) => {
+                if !tag.closing && !tag.self_closing {
+                    dropped = Some(tag.name);
+                }
+            }
+            Token::Tag(tag) if ALLOWED_TAGS.contains(&tag.name.as_str()) => write_tag(&mut out, &tag),
+            Token::Tag(_) => {}
+        }
+    }
+    out
+}
+
+/// Reduces a message to its text like Murmur does when HTML isn't allowed: tags are removed,
+/// `<br>` and `<p>` become line breaks. The text stays escaped for clients rendering it as HTML.
+pub fn to_plain_text(html: &str) -> String {
+    if !html.contains('<') {
+        return html.to_owned();
+    }
+
+    let mut out = String::with_capacity(html.len());
+    for token in tokenize(html) {
+        match token {
+            Token::Text(text) => escape(&mut out, &decode_entities(text), false),
+            Token::Stray => out.push_str("&lt;"),
+            Token::Tag(tag) if !tag.closing && (tag.name == "br" || tag.name == "p") => out.push('\n'),
+            Token::Tag(_) | Token::Raw(_) | Token::Comment => {}
+        }
+    }
+    out.trim().to_owned()
+}
+
+/// Returns the images inlined in a message, in order.
+pub fn images(html: &str) -> Result<Vec<Image>, ImageError> {
+    tokenize(html)
+        .iter()
+        .filter_map(|token| match token {
+            Token::Tag(tag) if tag.name == "img" && !tag.closing => tag.attribute("src"),
+            _ => None,
+        })
+        .map(|src| decode_image(&src))
+        .collect()
+}
+
+/// Decodes a `data:image/png;base64,...` URI. The official client percent-encodes the base64 data
+/// and names JPEG images `image/JPG`.
+pub fn decode_image(src: &str) -> Result<Image, ImageError> {
+    let src = src.trim();
+    let uri = match src.get(..5) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("data:") => &src[5..],
+        _ => return Err(ImageError::NotInline),
+    };
+    let (header, data) = uri.split_once(',').ok_or(ImageError::NotBase64)?;
+    let mut parameters = header.split(';');
+
+    let mime = parameters.next().unwrap_or_default().trim().to_ascii_lowercase();
+    if !parameters.any(|parameter| parameter.trim().eq_ignore_ascii_case("base64")) {
+        return Err(ImageError::NotBase64);
+    }
+    let mime = if mime == "image/jpg" { "image/jpeg".to_owned() } else { mime };
+    let &(mime, signature) = IMAGE_TYPES
+        .iter()
+        .find(|(name, _)| *name == mime)
+        .ok_or(ImageError::UnsupportedType(mime))?;
+
+    let data = decode_base64(&percent_decode(data)).ok_or(ImageError::InvalidBase64)?;
+    if !data.starts_with(signature) {
+        return Err(ImageError::InvalidData(mime.to_string()));
+    }
+
+    Ok(Image { mime, data })
+}
+
+/// Returns the channels a message is sent to: its channels, and its trees with every channel
+/// below them. Unknown channels are skipped. Senders need `TEXT_MESSAGE` in all of them.
+pub fn channels(tree: &ChannelTree, message: &msgs::TextMessage) -> BTreeSet<u32> {
+    let known = |id: &u32| tree.get(*id).is_some();
+    let mut channels: BTreeSet<u32> = message.channel_id.iter().copied().filter(known).collect();
+
+    for id in message.tree_id.iter().copied().filter(known) {
+        channels.insert(id);
+        channels.extend(tree.descendants(id));
+    }
+    channels
+}
+
+/// Returns the sessions receiving a message, ordered and without the sender: the sessions it is
+/// sent to, the users in or listening to its channels, and the users in its trees.
+pub fn recipients(tree: &ChannelTree, users: &UserRegistry, sender: u32, message: &msgs::TextMessage) -> Vec<u32> {
+    let mut recipients: BTreeSet<u32> =
+        message.session.iter().copied().filter(|session| users.get(*session).is_some()).collect();
+
+    for &channel in &message.channel_id {
+        recipients.extend(users.in_channel(channel));
+        recipients.extend(users.listeners(channel));
+    }
+    for &id in message.tree_id.iter().filter(|id| tree.get(**id).is_some()) {
+        for channel in std::iter::once(id).chain(tree.descendants(id)) {
+            recipients.extend(users.in_channel(channel));
+        }
+    }
+
+    recipients.remove(&sender);
+    recipients.into_iter().collect()
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Text(&'a str),
+    /// A `<` which doesn't start a tag.
+    Stray,
+    Tag(Tag<'a>),
+    /// The content of a script or style.
+    Raw(&'a str),
+    /// Comments, doctypes and processing instructions.
+    Comment,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Tag<'a> {
+    /// Lowercase name.
+    name: String,
+    closing: bool,
+    self_closing: bool,
+    attributes: Vec<Attribute<'a>>,
+}
+
+impl Tag<'_> {
+    fn attribute(&self, name: &str) -> Option<String> {
+        self.attributes.iter().find(|attribute| attribute.name == name).map(Attribute::value)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Attribute<'a> {
+    /// Lowercase name.
+    name: String,
+    /// The value as written, without quotes and with its entities.
+    raw_value: Option<&'a str>,
+    /// The whole attribute, from the whitespace before it.
+    raw: &'a str,
+}
+
+impl Attribute<'_> {
+    fn value(&self) -> String {
+        self.raw_value.map(decode_entities).unwrap_or_default()
+    }
+}
+
+fn tokenize(html: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    // Every tag ends with a `>`, none can start after the last one
+    let mut tags_end = html.rfind('>').map_or(0, |end| end + 1);
+
+    while position < html.len() {
+        let Some(offset) = html[position..].find('<') else {
+            tokens.push(Token::Text(&html[position..]));
+            break;
+        };
+        if offset > 0 {
+            tokens.push(Token::Text(&html[position..position + offset]));
+        }
+        position += offset;
+        let rest = &html[position..];
+
+        if rest.starts_with("<!--") {
+            position += rest.find("-->").map_or(rest.len(), |end| end + 3);
+            tokens.push(Token::Comment);
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            position += rest.find('>').map_or(rest.len(), |end| end + 1);
+            tokens.push(Token::Comment);
+        } else if let Some((tag, length)) = (position < tags_end && starts_tag(rest)).then(|| parse_tag(rest)).flatten() {
+            position += length;
+            let raw_text = !tag.closing && !tag.self_closing && RAW_TEXT_TAGS.contains(&tag.name.as_str());
+            let end = raw_text.then(|| find_end_tag(&html[position..], &tag.name));
+            tokens.push(Token::Tag(tag));
+
+            if let Some(end) = end {
+                tokens.push(Token::Raw(&html[position..position + end]));
+                position += end;
+            }
+        } else {
+            if position < tags_end && starts_tag(rest) {
+                // The tag runs to the end of the input, so would any starting inside it
+                tags_end = position;
+            }
+            tokens.push(Token::Stray);
+            position += 1;
+        }
+    }
+    tokens
+}
+
+/// Returns the position of the `</name` closing a raw text element, case insensitively, or the
+/// length of `text` if it isn't closed.
+fn find_end_tag(text: &str, name: &str) -> usize {
+    text.match_indices("</")
+        .map(|(start, _)| start)
+        .find(|&start| {
+            text.as_bytes()
+                .get(start + 2..start + 2 + name.len())
+                .is_some_and(|candidate| candidate.eq_ignore_ascii_case(name.as_bytes()))
+        })
+        .unwrap_or(text.len())
+}
+
+/// Whether `text` starts with `<` or `</` followed by a tag name.
+fn starts_tag(text: &str) -> bool {
+    let name = text.strip_prefix("</").or_else(|| text.strip_prefix('<')).unwrap_or_default();
+    name.bytes().next().is_some_and(|byte| byte.is_ascii_alphabetic())
+}
+
+/// Parses the tag at the start of `text`, which `starts_tag`, returns it and its length. `None`
+/// if the input ends before the tag.
+fn parse_tag(text: &str) -> Option<(Tag<'_>, usize)> {
+    let bytes = text.as_bytes();
+    let closing = bytes.get(1) == Some(&b'/');
+    let mut position = if closing { 2 } else { 1 };
+
+    let name_length = bytes[position..].iter().take_while(|byte| byte.is_ascii_alphanumeric()).count();
+    let name = text[position..position + name_length].to_ascii_lowercase();
+    position += name_length;
+
+    let mut tag = Tag { name, closing, self_closing: false, attributes: Vec::new() };
+    loop {
+        let start = position;
+        while bytes.get(position)?.is_ascii_whitespace() {
+            position += 1;
+        }
+        match bytes[position] {
+            b'>' => return Some((tag, position + 1)),
+            b'/' => {
+                position += 1;
+                tag.self_closing = bytes.get(position) == Some(&b'>');
+                continue;
+            }
+            _ => {}
+        }
+
+        let name_length = bytes[position..]
+            .iter()
+            .take_while(|byte| !byte.is_ascii_whitespace() && !matches!(byte, b'=' | b'>' | b'/'))
+            .count()
+            .max(1);
+        let name = text[position..position + name_length].to_ascii_lowercase();
+        position += name_length;
+        tag.self_closing = false;
+
+        let mut after_name = position;
+        while bytes.get(after_name)?.is_ascii_whitespace() {
+            after_name += 1;
+        }
+        let mut raw_value = None;
+        if bytes[after_name] == b'=' {
+            position = after_name + 1;
+            while bytes.get(position)?.is_ascii_whitespace() {
+                position += 1;
+            }
+            let value_start = position;
+            match bytes[position] {
+                quote @ (b'"' | b'\'') => {
+                    let length = text[position + 1..].find(quote as char)?;
+                    raw_value = Some(&text[position + 1..position + 1 + length]);
+                    position += length + 2;
+                }
+                _ => {
+                    position += bytes[position..].iter().take_while(|byte| !byte.is_ascii_whitespace() && **byte != b'>').count();
+                    raw_value = Some(&text[value_start..position]);
+                }
+            }
+        }
+
+        if !closing {
+            tag.attributes.push(Attribute { name, raw_value, raw: &text[start..position] });
+        }
+    }
+}
+
+fn write_tag(out: &mut String, tag: &Tag) {
+    if tag.closing {
+        out.push_str("</");
+        out.push_str(&tag.name);
+        out.push('>');
+        return;
+    }
+
+    let mut attributes = Vec::new();
+    for attribute in &tag.attributes {
+        let value = attribute.value();
+        let allowed = ALLOWED_ATTRIBUTES.contains(&attribute.name.as_str())
+            && match attribute.name.as_str() {
+                "href" => tag.name == "a" && is_allowed_link(&value),
+                "src" => tag.name == "img",
+                "style" => {
+                    let style = value.to_ascii_lowercase();
+                    !style.contains("url(") && !style.contains("expression(")
+                }
+                _ => true,
+            };
+        if allowed {
+            attributes.push((attribute.name.as_str(), value));
+        }
+    }
+
+    if tag.name == "img" {
+        match attributes.iter().find(|(name, _)| *name == "src") {
+            Some((_, src)) if decode_image(src).is_ok() => {}
+            _ => return,
+        }
+    }
+
+    out.push('<');
+    out.push_str(&tag.name);
+    for (name, value) in attributes {
+        out.push(' ');
+        out.push_str(name);
+        out.push_str("=\"");
+        escape(out, &value, true);
+        out.push('"');
+    }
+    out.push_str(if tag.self_closing { " />" } else { ">" });
+}
+
+fn is_allowed_link(href: &str) -> bool {
+    match href.trim().split_once(':') {
+        Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => {
+            LINK_SCHEMES.iter().any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+        }
+        // Relative links
+        _ => true,
+    }
+}
+
+fn escape(out: &mut String, text: &str, attribute: bool) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Decodes the predefined XML entities, `&nbsp;` and character references, others are kept.
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => '\u{a0}',
+                entity => {
+                    let code = match entity.strip_prefix('#')? {
+                        hex if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok()?,
+                        decimal => decimal.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((c, end + 1))
+        });
+
+        match decoded {
+            Some((c, length)) => {
+                out.push(c);
+                rest = &rest[length..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let hex = text.get(position + 1..position + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[position], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                position += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                position += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Decodes standard base64, ignoring whitespace. Padding is optional.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    let mut padding = false;
+
+    for byte in text.bytes().filter(|byte| !byte.is_ascii_whitespace()) {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                padding = true;
+                continue;
+            }
+            _ => return None,
+        };
+        if padding {
+            return None;
+        }
+
+        buffer = buffer << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+
+    // Leftover bits must be the zero padding of the last byte
+    (bits < 6 && buffer & ((1 << bits) - 1) == 0).then_some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PNG: &str = "data:image/png;base64,iVBORw0KGgo=";
+
+    #[test]
+    fn sanitizes_html() {
+        let html = concat!(
+            "<p onclick=\"steal()\" align=center>Hi <B>there</B></p>",
+            "<script>alert('</p>')</script><style>p { color: red }</style>",
+            "<blink>kept</blink><iframe src=\"x\">gone</iframe><!-- note -->",
+            "<a href=\"javascript:steal()\">bad</a> <a href='https://mumble.info'>good</a>",
+            "<img src=\"https://tracker.example/pixel.gif\"><img src=\"", "data:image/png;base64,iVBORw0KGgo=", "\" alt='a \"b\"'/>",
+            "1 < 2 &amp; <span style=\"background: url(x)\">3</span>",
+        );
+
+        assert_eq!(
+            concat!(
+                "<p align=\"center\">Hi <b>there</b></p>",
+                "kept",
+                "<a>bad</a> <a href=\"https://mumble.info\">good</a>",
+                "<img src=\"data:image/png;base64,iVBORw0KGgo=\" alt=\"a &quot;b&quot;\" />",
+                "1 &lt; 2 &amp; <span>3</span>",
+            ),
+            sanitize(html)
+        );
+        assert_eq!("<a href=\"clientid://abc\">x</a>", sanitize("<a href=\"clientid://abc\">x</a>"));
+        assert_eq!("unterminated &lt;b class=\"x", sanitize("unterminated <b class=\"x"));
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!("no & html", to_plain_text("no & html"));
+        assert_eq!("Hi\nthere &amp; &lt;you&gt;", to_plain_text("<p>Hi<br>there &amp; &lt;you&gt;</p><script>x()</script>"));
+        assert_eq!("a\u{a0}b &amp;#zz; \u{e9}", to_plain_text("<b>a&nbsp;b &#zz; &#xe9;</b>"));
+    }
+
+    #[test]
+    fn images_and_lengths() {
+        let html = format!("<b>Look</b> <img src=\"{PNG}\" /> <img src='data:image/JPG;base64,%2F9j%2F'>");
+        let images = images(&html).unwrap();
+        assert_eq!(2, images.len());
+        assert_eq!("image/png", images[0].mime);
+        assert_eq!(b"\x89PNG\r\n\x1a\n", &images[0].data[..]);
+        assert_eq!(vec![0xff, 0xd8, 0xff], images[1].data);
+
+        assert_eq!("<b>Look</b> <img /> <img>".len(), text_length(&html));
+        assert_eq!(4, text_length("🎉🎉"));
+
+        assert_eq!(Err(ImageError::NotInline), decode_image("https://example.com/a.png"));
+        assert_eq!(Err(ImageError::NotBase64), decode_image("data:image/png,abc"));
+        assert_eq!(Err(ImageError::UnsupportedType("image/svg+xml".into())), decode_image("data:image/svg+xml;base64,PHN2Zz4="));
+        assert_eq!(Err(ImageError::InvalidBase64), decode_image("data:image/png;base64,iVBO*"));
+        assert_eq!(Err(ImageError::InvalidData("image/gif".into())), decode_image(PNG.replace("png", "gif").as_str()));
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(Some(b"Man".to_vec()), decode_base64("TWFu"));
+        assert_eq!(Some(b"Ma".to_vec()), decode_base64("TWE="));
+        assert_eq!(Some(b"M".to_vec()), decode_base64("TQ"));
+        assert_eq!(None, decode_base64("TWF"));
+        assert_eq!(None, decode_base64("TR=="));
+        assert_eq!(None, decode_base64("TQ==TQ=="));
+    }
+
+    #[test]
+    fn filters_like_murmur() {
+        let limits = Limits { allow_html: true, message_length: 20, image_message_length: 100 };
+        let image = format!("<img src=\"{PNG}\">");
+
+        assert_eq!(Ok("<b>short</b>".to_owned()), filter("<b>short</b><script>x</script>", &limits));
+        assert_eq!(Err(TextError::TooLong { length: 21, limit: 20 }), filter(&"a".repeat(21), &limits));
+        assert_eq!(Ok(format!("text{image}")), filter(&format!("text{image}"), &limits));
+
+        let long_text = format!("{image}{}", "a".repeat(21));
+        assert_eq!(Err(TextError::TooLong { length: 26, limit: 20 }), filter(&long_text, &limits));
+        let too_big = image.repeat(3);
+        assert_eq!(Err(TextError::TooLong { length: 138, limit: 100 }), filter(&too_big, &limits));
+
+        let plain = Limits { allow_html: false, message_length: 5, image_message_length: 0 };
+        assert_eq!(Ok("a\nb".to_owned()), filter("<p>a</p><p>b</p>", &plain));
+        assert_eq!(Err(TextError::TooLong { length: 6, limit: 5 }), filter("<i>abcdef</i>", &plain));
+
+        let unlimited = Limits { allow_html: true, message_length: 0, image_message_length: 0 };
+        assert!(filter(&"a".repeat(10_000), &unlimited).is_ok());
+    }
+
+    #[test]
+    fn many_tags() {
+        let html = "<script>x</SCRIPT><b>".repeat(50_000);
+        assert_eq!("<b>".repeat(50_000), sanitize(&html));
+        assert_eq!("&lt;a".repeat(50_000), sanitize(&"<a".repeat(50_000)));
+        // Unterminated tags are scanned once, not from every `<`
+        let unterminated = "<a".repeat(200_000) + " x='>'";
+        assert_eq!("&lt;a".repeat(200_000) + " x='>'", sanitize(&unterminated));
+        assert_eq!(400_006, text_length(&unterminated));
+
+        // Rejected before being parsed
+        let limits = Limits { allow_html: true, message_length: 20, image_message_length: 100 };
+        let spam = "<script></script>".repeat(100_000);
+        assert_eq!(Err(TextError::TooLong { length: 1_700_000, limit: 100 }), filter(&spam, &limits));
+    }
+
+    #[test]
+    fn limits_from_server_config() {
+        let config = msgs::ServerConfig { allow_html: Some(false), message_length: Some(128), ..Default::default() };
+        assert_eq!(
+            Limits { allow_html: false, message_length: 128, image_message_length: DEFAULT_IMAGE_MESSAGE_LENGTH },
+            Limits::from(&config)
+        );
+    }
+
+    #[test]
+    fn resolves_targets() {
+        // Root (0) ── Lobby (1) ── Team (2)
+        let mut tree = ChannelTree::new();
+        for (id, parent) in [(0, None), (1, Some(0)), (2, Some(1))] {
+            tree.apply_state(&msgs::ChannelState { channel_id: Some(id), parent, ..Default::default() }).unwrap();
+        }
+        let mut users = UserRegistry::new();
+        for (session, channel_id, listening) in [(1, 0, vec![]), (2, 1, vec![]), (3, 2, vec![]), (4, 0, vec![1])] {
+            let state = msgs::UserState {
+                session: Some(session),
+                channel_id: Some(channel_id),
+                listening_channel_add: listening,
+                ..Default::default()
+            };
+            users.apply_state(&state).unwrap();
+        }
+
+        let message = msgs::TextMessage { session: vec![1, 9], channel_id: vec![1], ..Default::default() };
+        assert_eq!(BTreeSet::from([1]), channels(&tree, &message));
+        assert_eq!(vec![2, 4], recipients(&tree, &users, 1, &message));
+
+        let message = msgs::TextMessage { tree_id: vec![1, 7], ..Default::default() };
+        assert_eq!(BTreeSet::from([1, 2]), channels(&tree, &message));
+        assert_eq!(vec![2, 3], recipients(&tree, &users, 1, &message));
+    }
+}
@@ -0,0 +1,230 @@
+//! Association of UDP datagrams with the sessions which sent them
+//!
+//! Clients don't identify themselves in voice datagrams, the server knows who sent one by its
+//! source address. A datagram from an unknown address is decrypted with the `CryptState` of each
+//! session connected from the same IP until one succeeds, as Murmur does, and the address is then
+//! bound to that session.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Server.cpp
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::crypt_state::CryptState;
+
+/// How the sender of a datagram was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The address was already bound to the session.
+    Known(u32),
+    /// The datagram decrypted with the session's state, the address is now bound to it.
+    Matched(u32),
+    /// No session connected from that IP could decrypt the datagram.
+    Unknown,
+}
+
+impl Source {
+    pub fn session(self) -> Option<u32> {
+        match self {
+            Source::Known(session) | Source::Matched(session) => Some(session),
+            Source::Unknown => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Peer {
+    ip: IpAddr,
+    address: Option<SocketAddr>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UdpDemux {
+    peers: HashMap<u32, Peer>,
+    by_address: HashMap<SocketAddr, u32>,
+    /// Sessions per IP of their control connection, candidates for trial decryption.
+    by_ip: HashMap<IpAddr, BTreeSet<u32>>,
+}
+
+impl UdpDemux {
+    pub fn new() -> Self {
+        UdpDemux::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Registers a session with the IP its control connection comes from.
+    pub fn add(&mut self, session: u32, ip: IpAddr) {
+        self.remove(session);
+
+        let ip = ip.to_canonical();
+        self.peers.insert(session, Peer { ip, address: None });
+        self.by_ip.entry(ip).or_default().insert(session);
+    }
+
+    pub fn remove(&mut self, session: u32) -> bool {
+        let Some(peer) = self.peers.remove(&session) else { return false };
+
+        if let Some(address) = peer.address {
+            self.by_address.remove(&address);
+        }
+        if let Some(sessions) = self.by_ip.get_mut(&peer.ip) {
+            sessions.remove(&session);
+            if sessions.is_empty() {
+                self.by_ip.remove(&peer.ip);
+            }
+        }
+        true
+    }
+
+    /// The UDP address bound to a session.
+    pub fn address(&self, session: u32) -> Option<SocketAddr> {
+        self.peers.get(&session)?.address
+    }
+
+    /// The session bound to a UDP address.
+    pub fn session(&self, address: SocketAddr) -> Option<u32> {
+        self.by_address.get(&canonical(address)).copied()
+    }
+
+    /// Binds a UDP address to a session, e.g. when it is already known from elsewhere.
+    /// Returns false for unknown sessions.
+    pub fn bind(&mut self, session: u32, address: SocketAddr) -> bool {
+        let address = canonical(address);
+        let Some(peer) = self.peers.get_mut(&session) else { return false };
+
+        if let Some(previous) = peer.address.replace(address) {
+            self.by_address.remove(&previous);
+        }
+        if let Some(other) = self.by_address.insert(address, session).filter(|other| *other != session) {
+            if let Some(peer) = self.peers.get_mut(&other) {
+                peer.address = None;
+            }
+        }
+        true
+    }
+
+    /// Finds the session which sent a datagram. Unknown addresses are matched by trying the
+    /// states returned by `crypt_state` for the sessions connected from the same IP, without
+    /// modifying them. The datagram still has to be decrypted with the session's state.
+    ///
+    /// Sessions without a bound address are tried first, a bound session may have moved to a
+    /// new port, e.g. after a NAT rebinding.
+    pub fn route<'a, F>(&mut self, address: SocketAddr, datagram: &[u8], mut crypt_state: F) -> Source
+    where
+        F: FnMut(u32) -> Option<&'a CryptState>,
+    {
+        let address = canonical(address);
+        if let Some(session) = self.by_address.get(&address) {
+            return Source::Known(*session);
+        }
+
+        let Some(sessions) = self.by_ip.get(&address.ip()) else { return Source::Unknown };
+        let (unbound, bound): (Vec<u32>, Vec<u32>) =
+            sessions.iter().partition(|session| self.peers[*session].address.is_none());
+
+        let matched = unbound.into_iter().chain(bound).find(|session| {
+            crypt_state(*session).is_some_and(|state| state.try_decrypt(datagram).is_ok())
+        });
+
+        match matched {
+            Some(session) => {
+                self.bind(session, address);
+                Source::Matched(session)
+            }
+            None => Source::Unknown,
+        }
+    }
+}
+
+/// Unmaps IPv4-mapped IPv6 addresses, as received on dual-stack sockets.
+fn canonical(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use super::*;
+
+    /// Returns the server side state and a datagram encrypted by the client.
+    fn client(payload: &[u8]) -> (CryptState, BytesMut) {
+        let server = CryptState::generate_new();
+        let mut client = CryptState::new_from(*server.get_key(), server.get_decrypt_nonce(), server.get_encrypt_nonce());
+
+        let mut datagram = BytesMut::new();
+        client.encrypt(payload, &mut datagram);
+        (server, datagram)
+    }
+
+    fn address(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_by_trial_decryption() {
+        let (first, _) = client(b"first");
+        let (second, datagram) = client(b"second");
+        let states = HashMap::from([(1, first), (2, second)]);
+
+        let mut demux = UdpDemux::new();
+        demux.add(1, "10.0.0.1".parse().unwrap());
+        demux.add(2, "10.0.0.1".parse().unwrap());
+        demux.add(3, "10.0.0.2".parse().unwrap());
+
+        let source = address("10.0.0.1:5000");
+        assert_eq!(Source::Matched(2), demux.route(source, &datagram, |session| states.get(&session)));
+        assert_eq!(Some(source), demux.address(2));
+        assert_eq!(0, states[&2].get_good());
+
+        let fail = |_| panic!("known addresses aren't decrypted");
+        assert_eq!(Source::Known(2), demux.route(source, &datagram, fail));
+        assert_eq!(Some(2), demux.session(address("[::ffff:10.0.0.1]:5000")));
+
+        assert_eq!(Source::Unknown, demux.route(address("10.0.0.3:5000"), &datagram, |session| states.get(&session)));
+        assert_eq!(Source::Unknown, demux.route(address("10.0.0.2:5000"), &datagram, |_| None));
+        assert_eq!(None, Source::Unknown.session());
+    }
+
+    #[test]
+    fn rebinds_moved_clients() {
+        let (state, datagram) = client(b"voice");
+        let mut demux = UdpDemux::new();
+        demux.add(1, "::ffff:192.168.1.5".parse().unwrap());
+        assert!(demux.bind(1, address("192.168.1.5:1000")));
+
+        let moved = address("192.168.1.5:2000");
+        assert_eq!(Source::Matched(1), demux.route(moved, &datagram, |_| Some(&state)));
+        assert_eq!(Some(moved), demux.address(1));
+        assert_eq!(None, demux.session(address("192.168.1.5:1000")));
+    }
+
+    #[test]
+    fn bind_and_remove() {
+        let mut demux = UdpDemux::new();
+        demux.add(1, "10.0.0.1".parse().unwrap());
+        demux.add(2, "10.0.0.1".parse().unwrap());
+        let shared = address("10.0.0.1:5000");
+
+        assert!(!demux.bind(3, shared));
+        demux.bind(1, shared);
+        demux.bind(2, shared);
+        assert_eq!(Some(2), demux.session(shared));
+        assert_eq!(None, demux.address(1));
+
+        assert!(demux.remove(2));
+        assert!(!demux.remove(2));
+        assert_eq!(None, demux.session(shared));
+        assert_eq!(1, demux.len());
+        demux.remove(1);
+        assert!(demux.is_empty());
+        assert!(demux.by_ip.is_empty());
+    }
+}
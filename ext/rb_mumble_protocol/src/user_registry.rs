@@ -0,0 +1,476 @@
+//! The connected users of a server, built from `UserState` and `UserRemove` messages
+//!
+//! `UserState` messages are deltas: only the fields present in a message change, everything else
+//! keeps its previous value. Applying a message reports which fields actually changed.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::codec_version::Codecs;
+use crate::mumble_proto as msgs;
+
+/// The reason a change could not be applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistryError {
+    /// A `UserState` without `session`.
+    MissingSession,
+    UnknownSession(u32),
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::MissingSession => write!(f, "user state without session"),
+            RegistryError::UnknownSession(session) => write!(f, "unknown session {session}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Field {
+    Name,
+    UserId,
+    ChannelId,
+    Mute,
+    Deaf,
+    Suppress,
+    SelfMute,
+    SelfDeaf,
+    Texture,
+    TextureHash,
+    Comment,
+    CommentHash,
+    Hash,
+    PrioritySpeaker,
+    Recording,
+    PluginContext,
+    PluginIdentity,
+    ListeningChannels,
+    ListeningVolumes,
+}
+
+impl Field {
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Name => "name",
+            Field::UserId => "user_id",
+            Field::ChannelId => "channel_id",
+            Field::Mute => "mute",
+            Field::Deaf => "deaf",
+            Field::Suppress => "suppress",
+            Field::SelfMute => "self_mute",
+            Field::SelfDeaf => "self_deaf",
+            Field::Texture => "texture",
+            Field::TextureHash => "texture_hash",
+            Field::Comment => "comment",
+            Field::CommentHash => "comment_hash",
+            Field::Hash => "hash",
+            Field::PrioritySpeaker => "priority_speaker",
+            Field::Recording => "recording",
+            Field::PluginContext => "plugin_context",
+            Field::PluginIdentity => "plugin_identity",
+            Field::ListeningChannels => "listening_channels",
+            Field::ListeningVolumes => "listening_volumes",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct User {
+    pub session: u32,
+    pub name: String,
+    /// Set for registered users.
+    pub user_id: Option<u32>,
+    pub channel_id: u32,
+    pub mute: bool,
+    pub deaf: bool,
+    pub suppress: bool,
+    pub self_mute: bool,
+    pub self_deaf: bool,
+    pub priority_speaker: bool,
+    pub recording: bool,
+    pub texture: Option<Vec<u8>>,
+    /// SHA-1 of a texture too large to be sent along, see `RequestBlob`.
+    pub texture_hash: Option<Vec<u8>>,
+    pub comment: Option<String>,
+    /// SHA-1 of a comment too large to be sent along, see `RequestBlob`.
+    pub comment_hash: Option<Vec<u8>>,
+    /// Hex encoded SHA-1 of the user's certificate.
+    pub hash: Option<String>,
+    pub plugin_context: Option<Vec<u8>>,
+    pub plugin_identity: Option<String>,
+    /// Channels the user listens to without being in them.
+    pub listening_channels: BTreeSet<u32>,
+    /// Volume adjustment of listened channels, for the user receiving it.
+    pub listening_volumes: BTreeMap<u32, f32>,
+    /// The codecs the user's client advertised in `Authenticate`, only known to the server.
+    pub codecs: Codecs,
+}
+
+impl User {
+    /// Describes the user completely.
+    pub fn to_state(&self) -> msgs::UserState {
+        let flag = |value: bool| if value { Some(true) } else { None };
+
+        msgs::UserState {
+            session: Some(self.session),
+            name: Some(self.name.clone()),
+            user_id: self.user_id,
+            channel_id: Some(self.channel_id),
+            mute: flag(self.mute),
+            deaf: flag(self.deaf),
+            suppress: flag(self.suppress),
+            self_mute: flag(self.self_mute),
+            self_deaf: flag(self.self_deaf),
+            texture: if self.texture_hash.is_some() { None } else { self.texture.clone() },
+            plugin_context: self.plugin_context.clone(),
+            plugin_identity: self.plugin_identity.clone(),
+            comment: if self.comment_hash.is_some() { None } else { self.comment.clone() },
+            hash: self.hash.clone(),
+            comment_hash: self.comment_hash.clone(),
+            texture_hash: self.texture_hash.clone(),
+            priority_speaker: flag(self.priority_speaker),
+            recording: flag(self.recording),
+            listening_channel_add: self.listening_channels.iter().copied().collect(),
+            listening_volume_adjustment: self
+                .listening_volumes
+                .iter()
+                .map(|(&channel, &volume)| msgs::user_state::VolumeAdjustment {
+                    listening_channel: Some(channel),
+                    volume_adjustment: Some(volume),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// What applying a `UserState` did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserChange {
+    pub session: u32,
+    /// Whether the user was unknown so far, i.e. just connected.
+    pub created: bool,
+    /// Fields whose value changed, in declaration order.
+    pub fields: Vec<Field>,
+    /// The channel the user was in before, if it changed.
+    pub previous_channel_id: Option<u32>,
+    /// Session of the user who made the change, if it wasn't the user themself.
+    pub actor: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UserRegistry {
+    users: BTreeMap<u32, User>,
+}
+
+/// Assigns `value` to `field`, recording `name` if that changed anything.
+fn update<T: PartialEq>(fields: &mut Vec<Field>, name: Field, field: &mut T, value: T) {
+    if *field != value {
+        *field = value;
+        fields.push(name);
+    }
+}
+
+impl UserRegistry {
+    pub fn new() -> Self {
+        UserRegistry::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn get(&self, session: u32) -> Option<&User> {
+        self.users.get(&session)
+    }
+
+    /// Iterates over the users ordered by session.
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<&User> {
+        self.users.values().find(|user| user.name == name)
+    }
+
+    /// Sessions of the users in the channel.
+    pub fn in_channel(&self, channel_id: u32) -> Vec<u32> {
+        self.users.values().filter(|user| user.channel_id == channel_id).map(|user| user.session).collect()
+    }
+
+    /// Sessions of the users listening to the channel.
+    pub fn listeners(&self, channel_id: u32) -> Vec<u32> {
+        self.users
+            .values()
+            .filter(|user| user.listening_channels.contains(&channel_id))
+            .map(|user| user.session)
+            .collect()
+    }
+
+    /// The codecs of every user, see `CodecSelection::recheck`.
+    pub fn codecs(&self) -> impl Iterator<Item = &Codecs> {
+        self.users.values().map(|user| &user.codecs)
+    }
+
+    /// Records the codecs a user's client advertised in `Authenticate`.
+    pub fn set_codecs(&mut self, session: u32, codecs: Codecs) -> Result<(), RegistryError> {
+        let user = self.users.get_mut(&session).ok_or(RegistryError::UnknownSession(session))?;
+        user.codecs = codecs;
+        Ok(())
+    }
+
+    /// Applies a `UserState`, adding the user if the session is unknown.
+    pub fn apply_state(&mut self, state: &msgs::UserState) -> Result<UserChange, RegistryError> {
+        let session = state.session.ok_or(RegistryError::MissingSession)?;
+        let created = !self.users.contains_key(&session);
+        let user = self.users.entry(session).or_insert_with(|| User { session, ..Default::default() });
+        let previous_channel_id = user.channel_id;
+        let mut fields = Vec::new();
+
+        if let Some(name) = &state.name {
+            update(&mut fields, Field::Name, &mut user.name, name.clone());
+        }
+        if let Some(user_id) = state.user_id {
+            update(&mut fields, Field::UserId, &mut user.user_id, Some(user_id));
+        }
+        if let Some(channel_id) = state.channel_id {
+            update(&mut fields, Field::ChannelId, &mut user.channel_id, channel_id);
+        }
+        let flags = [
+            (state.mute, Field::Mute, &mut user.mute),
+            (state.deaf, Field::Deaf, &mut user.deaf),
+            (state.suppress, Field::Suppress, &mut user.suppress),
+            (state.self_mute, Field::SelfMute, &mut user.self_mute),
+            (state.self_deaf, Field::SelfDeaf, &mut user.self_deaf),
+        ];
+        for (value, name, field) in flags {
+            if let Some(value) = value {
+                update(&mut fields, name, field, value);
+            }
+        }
+        // an empty texture or comment removes it, a full one replaces the hash and vice versa
+        if let Some(texture) = &state.texture {
+            let texture = if texture.is_empty() { None } else { Some(texture.clone()) };
+            update(&mut fields, Field::Texture, &mut user.texture, texture);
+            update(&mut fields, Field::TextureHash, &mut user.texture_hash, None);
+        }
+        if let Some(hash) = &state.texture_hash {
+            let hash = if hash.is_empty() { None } else { Some(hash.clone()) };
+            if hash != user.texture_hash {
+                update(&mut fields, Field::Texture, &mut user.texture, None);
+            }
+            update(&mut fields, Field::TextureHash, &mut user.texture_hash, hash);
+        }
+        if let Some(comment) = &state.comment {
+            let comment = if comment.is_empty() { None } else { Some(comment.clone()) };
+            update(&mut fields, Field::Comment, &mut user.comment, comment);
+            update(&mut fields, Field::CommentHash, &mut user.comment_hash, None);
+        }
+        if let Some(hash) = &state.comment_hash {
+            let hash = if hash.is_empty() { None } else { Some(hash.clone()) };
+            if hash != user.comment_hash {
+                update(&mut fields, Field::Comment, &mut user.comment, None);
+            }
+            update(&mut fields, Field::CommentHash, &mut user.comment_hash, hash);
+        }
+        if let Some(hash) = &state.hash {
+            update(&mut fields, Field::Hash, &mut user.hash, Some(hash.clone()));
+        }
+        if let Some(priority_speaker) = state.priority_speaker {
+            update(&mut fields, Field::PrioritySpeaker, &mut user.priority_speaker, priority_speaker);
+        }
+        if let Some(recording) = state.recording {
+            update(&mut fields, Field::Recording, &mut user.recording, recording);
+        }
+        if let Some(context) = &state.plugin_context {
+            update(&mut fields, Field::PluginContext, &mut user.plugin_context, Some(context.clone()));
+        }
+        if let Some(identity) = &state.plugin_identity {
+            update(&mut fields, Field::PluginIdentity, &mut user.plugin_identity, Some(identity.clone()));
+        }
+
+        let mut listening = user.listening_channels.clone();
+        listening.extend(&state.listening_channel_add);
+        for channel in &state.listening_channel_remove {
+            listening.remove(channel);
+        }
+        let mut volumes = user.listening_volumes.clone();
+        for adjustment in &state.listening_volume_adjustment {
+            if let (Some(channel), Some(volume)) = (adjustment.listening_channel, adjustment.volume_adjustment) {
+                volumes.insert(channel, volume);
+            }
+        }
+        volumes.retain(|channel, _| listening.contains(channel));
+        update(&mut fields, Field::ListeningChannels, &mut user.listening_channels, listening);
+        update(&mut fields, Field::ListeningVolumes, &mut user.listening_volumes, volumes);
+
+        fields.sort();
+        fields.dedup();
+        let previous_channel_id = fields.contains(&Field::ChannelId).then_some(previous_channel_id).filter(|_| !created);
+        let actor = state.actor.filter(|&actor| actor != session);
+
+        Ok(UserChange { session, created, fields, previous_channel_id, actor })
+    }
+
+    /// Removes a user, returning its last state.
+    pub fn apply_remove(&mut self, session: u32) -> Result<User, RegistryError> {
+        self.users.remove(&session).ok_or(RegistryError::UnknownSession(session))
+    }
+
+    /// Applies a `UserRemove`.
+    pub fn apply_user_remove(&mut self, remove: &msgs::UserRemove) -> Result<User, RegistryError> {
+        self.apply_remove(remove.session)
+    }
+
+    /// Returns the messages describing every user to a new client, ordered by session.
+    pub fn full_sync(&self) -> Vec<msgs::UserState> {
+        self.users.values().map(User::to_state).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec_version::{CodecSelection, DEFAULT_OPUS_THRESHOLD};
+
+    fn joined(session: u32, name: &str, channel_id: u32) -> msgs::UserState {
+        msgs::UserState {
+            session: Some(session),
+            name: Some(name.to_owned()),
+            channel_id: Some(channel_id),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn adds_users() {
+        let mut registry = UserRegistry::new();
+        let change = registry.apply_state(&joined(1, "alice", 0)).unwrap();
+
+        assert!(change.created);
+        assert_eq!(vec![Field::Name], change.fields);
+        assert_eq!(None, change.previous_channel_id);
+        assert_eq!("alice", registry.get(1).unwrap().name);
+        assert_eq!(Some(1), registry.find_by_name("alice").map(|user| user.session));
+    }
+
+    #[test]
+    fn applies_only_present_fields() {
+        let mut registry = UserRegistry::new();
+        registry.apply_state(&msgs::UserState { self_mute: Some(true), ..joined(1, "alice", 0) }).unwrap();
+
+        let change = registry
+            .apply_state(&msgs::UserState { session: Some(1), channel_id: Some(4), actor: Some(2), ..Default::default() })
+            .unwrap();
+        assert!(!change.created);
+        assert_eq!(vec![Field::ChannelId], change.fields);
+        assert_eq!((Some(0), Some(2)), (change.previous_channel_id, change.actor));
+
+        let user = registry.get(1).unwrap();
+        assert_eq!(("alice", 4, true), (user.name.as_str(), user.channel_id, user.self_mute));
+        assert_eq!(vec![1], registry.in_channel(4));
+    }
+
+    #[test]
+    fn unchanged_values_are_not_reported() {
+        let mut registry = UserRegistry::new();
+        registry.apply_state(&joined(1, "alice", 0)).unwrap();
+
+        let change = registry.apply_state(&msgs::UserState { mute: Some(false), ..joined(1, "alice", 0) }).unwrap();
+        assert!(change.fields.is_empty());
+    }
+
+    #[test]
+    fn hashes_replace_full_values() {
+        let mut registry = UserRegistry::new();
+        registry.apply_state(&msgs::UserState { comment: Some("hi".into()), ..joined(1, "alice", 0) }).unwrap();
+
+        let change = registry
+            .apply_state(&msgs::UserState { session: Some(1), comment_hash: Some(vec![1; 20]), ..Default::default() })
+            .unwrap();
+        assert_eq!(vec![Field::Comment, Field::CommentHash], change.fields);
+        assert_eq!(None, registry.get(1).unwrap().comment);
+
+        registry.apply_state(&msgs::UserState { session: Some(1), comment: Some(String::new()), ..Default::default() }).unwrap();
+        assert_eq!((None, None), (registry.get(1).unwrap().comment.clone(), registry.get(1).unwrap().comment_hash.clone()));
+    }
+
+    #[test]
+    fn tracks_listening_channels() {
+        let mut registry = UserRegistry::new();
+        registry
+            .apply_state(&msgs::UserState { listening_channel_add: vec![2, 3], ..joined(1, "alice", 0) })
+            .unwrap();
+        let change = registry
+            .apply_state(&msgs::UserState {
+                session: Some(1),
+                listening_channel_remove: vec![2],
+                listening_volume_adjustment: vec![msgs::user_state::VolumeAdjustment {
+                    listening_channel: Some(3),
+                    volume_adjustment: Some(0.5),
+                }],
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert_eq!(vec![Field::ListeningChannels, Field::ListeningVolumes], change.fields);
+        assert_eq!(vec![1], registry.listeners(3));
+        assert!(registry.listeners(2).is_empty());
+        assert_eq!(Some(&0.5), registry.get(1).unwrap().listening_volumes.get(&3));
+    }
+
+    #[test]
+    fn removes_users() {
+        let mut registry = UserRegistry::new();
+        registry.apply_state(&joined(1, "alice", 0)).unwrap();
+
+        assert_eq!("alice", registry.apply_user_remove(&msgs::UserRemove { session: 1, ..Default::default() }).unwrap().name);
+        assert_eq!(Err(RegistryError::UnknownSession(1)), registry.apply_remove(1).map(|_| ()));
+        assert!(registry.is_empty());
+        assert_eq!(Err(RegistryError::MissingSession), registry.apply_state(&Default::default()).map(|_| ()));
+    }
+
+    #[test]
+    fn selects_codecs_of_users() {
+        let mut registry = UserRegistry::new();
+        registry.apply_state(&joined(1, "alice", 0)).unwrap();
+        registry.apply_state(&joined(2, "bob", 0)).unwrap();
+        let authenticate = msgs::Authenticate { opus: Some(true), ..Default::default() };
+        registry.set_codecs(1, Codecs::from(&authenticate)).unwrap();
+        registry.set_codecs(2, Codecs::from(&authenticate)).unwrap();
+        assert_eq!(Err(RegistryError::UnknownSession(3)), registry.set_codecs(3, Codecs::default()));
+
+        let mut selection = CodecSelection::default();
+        assert!(selection.recheck(registry.codecs(), DEFAULT_OPUS_THRESHOLD));
+        assert!(selection.opus);
+
+        registry.set_codecs(2, Codecs::default()).unwrap();
+        assert!(selection.recheck(registry.codecs(), DEFAULT_OPUS_THRESHOLD));
+        assert!(!selection.opus);
+    }
+
+    #[test]
+    fn full_sync_recreates_registry() {
+        let mut registry = UserRegistry::new();
+        registry.apply_state(&msgs::UserState { user_id: Some(7), deaf: Some(true), ..joined(2, "bob", 3) }).unwrap();
+        registry
+            .apply_state(&msgs::UserState { texture_hash: Some(vec![2; 20]), listening_channel_add: vec![1], ..joined(1, "alice", 0) })
+            .unwrap();
+
+        let messages = registry.full_sync();
+        assert_eq!(vec![Some(1), Some(2)], messages.iter().map(|state| state.session).collect::<Vec<_>>());
+        assert_eq!(None, messages[1].mute);
+
+        let mut copy = UserRegistry::new();
+        for state in &messages {
+            copy.apply_state(state).unwrap();
+        }
+        assert_eq!(registry.iter().collect::<Vec<_>>(), copy.iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,444 @@
+//! Decoding and encoding of Mumble's (legacy) UDP voice packets
+//!
+//! These are the plain packets passed to and returned from `CryptState`, and the payload of
+//! `UDPTunnel` control messages.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/1.4.287/docs/dev/network-protocol/voice_data.rst
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::opus;
+use crate::positional::Vector3;
+
+/// Voice target used for normal talking in the current channel.
+pub const TARGET_NORMAL: u8 = 0;
+/// Voice target asking the server to echo the packet back to its sender.
+pub const TARGET_LOOPBACK: u8 = 31;
+
+/// Opus payload length flag marking the last packet of a transmission.
+const OPUS_TERMINATOR: u64 = 0x2000;
+/// Bits of the Opus payload length, the others being flags.
+const OPUS_LENGTH_MASK: u64 = 0x1fff;
+/// CELT and Speex frame header flag marking that another frame follows.
+const FRAME_CONTINUATION: u8 = 0x80;
+
+/// Which way a packet travels. Only packets sent by the server carry the speaker's session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client to the server.
+    Serverbound,
+    /// Sent by the server to the client.
+    Clientbound,
+}
+
+/// The reason a voice packet could not be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceError {
+    /// The packet ended unexpectedly.
+    Eof,
+    /// The header announces an unknown codec.
+    UnknownType(u8),
+    /// A frame is too long for its length header.
+    FrameTooLong(usize),
+}
+
+impl std::fmt::Display for VoiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoiceError::Eof => write!(f, "voice packet is truncated"),
+            VoiceError::UnknownType(ty) => write!(f, "unknown voice packet type {ty}"),
+            VoiceError::FrameTooLong(len) => write!(f, "voice frame of {len} bytes is too long"),
+        }
+    }
+}
+
+impl std::error::Error for VoiceError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VoicePacket {
+    /// Used to check UDP connectivity and measure latency.
+    Ping { timestamp: u64 },
+    Audio(AudioPacket),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioPacket {
+    /// 0 for normal talking, 1 to 30 for a `VoiceTarget`, 31 for server loopback.
+    /// In clientbound packets 1 means shout (to a channel) and 2 whisper (to the user directly).
+    pub target: u8,
+    /// Session of the speaker, only present in clientbound packets.
+    pub session_id: Option<u32>,
+    pub seq_num: u64,
+    pub payload: VoicePayload,
+    /// Anything following the audio, usually positional data.
+    pub position_info: Option<Bytes>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VoicePayload {
+    CeltAlpha(Vec<Bytes>),
+    Speex(Vec<Bytes>),
+    CeltBeta(Vec<Bytes>),
+    /// Opus packet and whether it ends the transmission.
+    Opus(Bytes, bool),
+}
+
+impl VoicePayload {
+    fn type_id(&self) -> u8 {
+        match self {
+            VoicePayload::CeltAlpha(_) => 0,
+            VoicePayload::Speex(_) => 2,
+            VoicePayload::CeltBeta(_) => 3,
+            VoicePayload::Opus(..) => 4,
+        }
+    }
+
+    /// Returns the name of the codec, e.g. `opus`.
+    pub fn codec_name(&self) -> &'static str {
+        match self {
+            VoicePayload::CeltAlpha(_) => "celt_alpha",
+            VoicePayload::Speex(_) => "speex",
+            VoicePayload::CeltBeta(_) => "celt_beta",
+            VoicePayload::Opus(..) => "opus",
+        }
+    }
+
+    /// Checks that the frames fit their length headers.
+    fn check_lengths(&self) -> Result<(), VoiceError> {
+        let too_long = match self {
+            VoicePayload::CeltAlpha(frames) | VoicePayload::Speex(frames) | VoicePayload::CeltBeta(frames) => {
+                frames.iter().map(Bytes::len).find(|&len| len > 0x7f)
+            }
+            VoicePayload::Opus(data, _) => Some(data.len()).filter(|&len| len > OPUS_LENGTH_MASK as usize),
+        };
+        match too_long {
+            Some(len) => Err(VoiceError::FrameTooLong(len)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether this is the last packet of a transmission.
+    pub fn is_terminator(&self) -> bool {
+        match self {
+            VoicePayload::Opus(_, terminator) => *terminator,
+            VoicePayload::CeltAlpha(frames)
+            | VoicePayload::Speex(frames)
+            | VoicePayload::CeltBeta(frames) => frames.last().is_none_or(|frame| frame.is_empty()),
+        }
+    }
+}
+
+impl AudioPacket {
+    /// Parses the Opus TOC of the payload.
+    ///
+    /// Returns `None` for other codecs and for Opus terminators without audio.
+    pub fn opus_info(&self) -> Option<Result<opus::PacketInfo, opus::OpusError>> {
+        match &self.payload {
+            VoicePayload::Opus(data, _) if !data.is_empty() => Some(opus::parse_packet(data)),
+            _ => None,
+        }
+    }
+
+    /// The speaker's position, the first 12 bytes of `position_info`.
+    pub fn position(&self) -> Option<Vector3> {
+        Vector3::decode(self.position_info.as_deref()?)
+    }
+}
+
+impl VoicePacket {
+    pub fn decode(mut buf: Bytes, direction: Direction) -> Result<Self, VoiceError> {
+        if !buf.has_remaining() {
+            return Err(VoiceError::Eof);
+        }
+        let header = buf.get_u8();
+        let kind = header >> 5;
+        let target = header & 0x1f;
+
+        if kind == 1 {
+            return Ok(VoicePacket::Ping { timestamp: read_varint(&mut buf)? });
+        }
+
+        let session_id = match direction {
+            Direction::Clientbound => Some(read_varint(&mut buf)? as u32),
+            Direction::Serverbound => None,
+        };
+        let seq_num = read_varint(&mut buf)?;
+
+        let payload = match kind {
+            0 => VoicePayload::CeltAlpha(read_frames(&mut buf)?),
+            2 => VoicePayload::Speex(read_frames(&mut buf)?),
+            3 => VoicePayload::CeltBeta(read_frames(&mut buf)?),
+            4 => {
+                let header = read_varint(&mut buf)?;
+                let len = (header & OPUS_LENGTH_MASK) as usize;
+                if buf.remaining() < len {
+                    return Err(VoiceError::Eof);
+                }
+                VoicePayload::Opus(buf.split_to(len), header & OPUS_TERMINATOR != 0)
+            }
+            _ => return Err(VoiceError::UnknownType(kind)),
+        };
+
+        let position_info = if buf.has_remaining() { Some(buf) } else { None };
+
+        Ok(VoicePacket::Audio(AudioPacket {
+            target,
+            session_id,
+            seq_num,
+            payload,
+            position_info,
+        }))
+    }
+
+    /// Appends the encoded packet to `dst`. The session is only written for clientbound packets.
+    ///
+    /// CELT and Speex frames are limited to 127 bytes, Opus packets to 8191 bytes.
+    pub fn encode(&self, direction: Direction, dst: &mut BytesMut) -> Result<(), VoiceError> {
+        match self {
+            VoicePacket::Ping { timestamp } => {
+                dst.put_u8(1 << 5);
+                write_varint(*timestamp, dst);
+            }
+            VoicePacket::Audio(audio) => {
+                audio.payload.check_lengths()?;
+                dst.put_u8(audio.payload.type_id() << 5 | (audio.target & 0x1f));
+                if direction == Direction::Clientbound {
+                    write_varint(u64::from(audio.session_id.unwrap_or(0)), dst);
+                }
+                write_varint(audio.seq_num, dst);
+
+                match &audio.payload {
+                    VoicePayload::CeltAlpha(frames)
+                    | VoicePayload::Speex(frames)
+                    | VoicePayload::CeltBeta(frames) => {
+                        for (index, frame) in frames.iter().enumerate() {
+                            let mut header = frame.len() as u8;
+                            if index + 1 < frames.len() {
+                                header |= FRAME_CONTINUATION;
+                            }
+                            dst.put_u8(header);
+                            dst.extend_from_slice(frame);
+                        }
+                    }
+                    VoicePayload::Opus(data, terminator) => {
+                        let mut header = data.len() as u64;
+                        if *terminator {
+                            header |= OPUS_TERMINATOR;
+                        }
+                        write_varint(header, dst);
+                        dst.extend_from_slice(data);
+                    }
+                }
+
+                if let Some(position_info) = &audio.position_info {
+                    dst.extend_from_slice(position_info);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the CELT or Speex frames, each prefixed by its length and a continuation flag.
+fn read_frames(buf: &mut Bytes) -> Result<Vec<Bytes>, VoiceError> {
+    let mut frames = Vec::new();
+    loop {
+        if !buf.has_remaining() {
+            return Err(VoiceError::Eof);
+        }
+        let header = buf.get_u8();
+        let len = (header & !FRAME_CONTINUATION) as usize;
+        if buf.remaining() < len {
+            return Err(VoiceError::Eof);
+        }
+        frames.push(buf.split_to(len));
+
+        if header & FRAME_CONTINUATION == 0 {
+            return Ok(frames);
+        }
+    }
+}
+
+/// Reads a variable length integer as encoded by Mumble's `PacketDataStream`.
+pub fn read_varint<B: Buf>(buf: &mut B) -> Result<u64, VoiceError> {
+    fn take<B: Buf>(buf: &mut B, len: usize) -> Result<u64, VoiceError> {
+        if buf.remaining() < len {
+            return Err(VoiceError::Eof);
+        }
+        Ok(buf.get_uint(len))
+    }
+
+    let first = u64::from(take(buf, 1)? as u8);
+    Ok(match first {
+        0x00..=0x7f => first,
+        0x80..=0xbf => (first & 0x3f) << 8 | take(buf, 1)?,
+        0xc0..=0xdf => (first & 0x1f) << 16 | take(buf, 2)?,
+        0xe0..=0xef => (first & 0x0f) << 24 | take(buf, 3)?,
+        0xf0..=0xf3 => take(buf, 4)?,
+        0xf4..=0xf7 => take(buf, 8)?,
+        // negative number, recursive
+        0xf8..=0xfb => !read_varint(buf)?,
+        // byte-inverted negative two bit number
+        _ => !(first & 0x03),
+    })
+}
+
+/// Writes a variable length integer as encoded by Mumble's `PacketDataStream`.
+pub fn write_varint(value: u64, dst: &mut BytesMut) {
+    if value < 0x80 {
+        dst.put_u8(value as u8);
+    } else if value < 0x4000 {
+        dst.put_u16(0x8000 | value as u16);
+    } else if value < 0x20_0000 {
+        dst.put_u8(0xc0 | (value >> 16) as u8);
+        dst.put_u16(value as u16);
+    } else if value < 0x1000_0000 {
+        dst.put_u32(0xe000_0000 | value as u32);
+    } else if value < 0x1_0000_0000 {
+        dst.put_u8(0xf0);
+        dst.put_u32(value as u32);
+    } else {
+        dst.put_u8(0xf4);
+        dst.put_u64(value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(packet: &VoicePacket, direction: Direction) -> Bytes {
+        let mut buf = BytesMut::new();
+        packet.encode(direction, &mut buf).unwrap();
+        buf.freeze()
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 0x7f, 0x80, 0x3fff, 0x4000, 0x1f_ffff, 0x20_0000, 0xfff_ffff, 0x1000_0000, u32::MAX as u64, u64::MAX] {
+            let mut buf = BytesMut::new();
+            write_varint(value, &mut buf);
+            assert_eq!(value, read_varint(&mut buf.freeze()).unwrap(), "{value:#x}");
+        }
+    }
+
+    #[test]
+    fn varint_encodings() {
+        assert_eq!(0x1234, read_varint(&mut &[0x92, 0x34][..]).unwrap());
+        assert_eq!(!2, read_varint(&mut &[0xfe][..]).unwrap());
+        assert_eq!(!0x10, read_varint(&mut &[0xf8, 0x10][..]).unwrap());
+        assert_eq!(Err(VoiceError::Eof), read_varint(&mut &[0xf0, 0, 0][..]));
+    }
+
+    #[test]
+    fn ping() {
+        let packet = VoicePacket::Ping { timestamp: 123_456 };
+        let bytes = encode(&packet, Direction::Serverbound);
+        assert_eq!(0x20, bytes[0]);
+        assert_eq!(packet, VoicePacket::decode(bytes, Direction::Clientbound).unwrap());
+    }
+
+    #[test]
+    fn opus_round_trip() {
+        let packet = VoicePacket::Audio(AudioPacket {
+            target: 2,
+            session_id: Some(7),
+            seq_num: 300,
+            payload: VoicePayload::Opus(Bytes::from_static(&[0xf8, 1, 2, 3]), true),
+            position_info: Some(Bytes::from_static(&[0; 12])),
+        });
+        let bytes = encode(&packet, Direction::Clientbound);
+        assert_eq!(&[0x82, 7, 0x81, 0x2c, 0xa0, 0x04][..], &bytes[..6]);
+        assert_eq!(packet, VoicePacket::decode(bytes, Direction::Clientbound).unwrap());
+    }
+
+    #[test]
+    fn position() {
+        let position = Vector3::new(1.0, 2.0, -3.5);
+        let mut audio = AudioPacket {
+            target: 0,
+            session_id: None,
+            seq_num: 1,
+            payload: VoicePayload::Opus(Bytes::from_static(&[0xf8, 1]), false),
+            position_info: Some(position.encode()),
+        };
+        assert_eq!(Some(position), audio.position());
+
+        audio.position_info = Some(Bytes::from_static(&[0; 4]));
+        assert_eq!(None, audio.position());
+    }
+
+    #[test]
+    fn serverbound_packets_have_no_session() {
+        let packet = VoicePacket::Audio(AudioPacket {
+            target: 0,
+            session_id: None,
+            seq_num: 1,
+            payload: VoicePayload::Opus(Bytes::from_static(&[0xf8, 1]), false),
+            position_info: None,
+        });
+        let bytes = encode(&packet, Direction::Serverbound);
+        assert_eq!(&[0x80, 1, 2, 0xf8, 1][..], &bytes[..]);
+        assert_eq!(packet, VoicePacket::decode(bytes, Direction::Serverbound).unwrap());
+    }
+
+    #[test]
+    fn celt_frames_round_trip() {
+        let packet = VoicePacket::Audio(AudioPacket {
+            target: 0,
+            session_id: None,
+            seq_num: 5,
+            payload: VoicePayload::CeltAlpha(vec![Bytes::from_static(b"abc"), Bytes::from_static(b"de")]),
+            position_info: None,
+        });
+        let bytes = encode(&packet, Direction::Serverbound);
+        assert_eq!(&[0x00, 5, 0x83, b'a', b'b', b'c', 0x02, b'd', b'e'][..], &bytes[..]);
+        assert_eq!(packet, VoicePacket::decode(bytes, Direction::Serverbound).unwrap());
+    }
+
+    #[test]
+    fn rejects_long_frames() {
+        let audio = |payload| {
+            VoicePacket::Audio(AudioPacket { target: 0, session_id: None, seq_num: 1, payload, position_info: None })
+        };
+        let mut buf = BytesMut::new();
+
+        let celt = audio(VoicePayload::CeltBeta(vec![Bytes::from_static(b"ok"), Bytes::from(vec![0; 128])]));
+        assert_eq!(Err(VoiceError::FrameTooLong(128)), celt.encode(Direction::Serverbound, &mut buf));
+        let opus = audio(VoicePayload::Opus(Bytes::from(vec![0; 0x2000]), false));
+        assert_eq!(Err(VoiceError::FrameTooLong(0x2000)), opus.encode(Direction::Serverbound, &mut buf));
+        assert!(buf.is_empty());
+
+        let longest = audio(VoicePayload::Speex(vec![Bytes::from(vec![1; 127])]));
+        assert_eq!(Ok(()), longest.encode(Direction::Serverbound, &mut buf));
+        assert_eq!(longest, VoicePacket::decode(buf.freeze(), Direction::Serverbound).unwrap());
+    }
+
+    #[test]
+    fn opus_info() {
+        let packet = VoicePacket::decode(Bytes::from_static(&[0x80, 1, 3, 31 << 3 | 1, 1, 2]), Direction::Serverbound);
+        let info = match packet.unwrap() {
+            VoicePacket::Audio(audio) => audio.opus_info().unwrap().unwrap(),
+            VoicePacket::Ping { .. } => unreachable!(),
+        };
+        assert_eq!((opus::Mode::Celt, opus::Bandwidth::Fullband), (info.mode(), info.bandwidth()));
+        assert_eq!(1920, info.total_samples());
+    }
+
+    #[test]
+    fn truncated_packets() {
+        assert_eq!(Err(VoiceError::Eof), VoicePacket::decode(Bytes::new(), Direction::Serverbound));
+        assert_eq!(Err(VoiceError::Eof), VoicePacket::decode(Bytes::from_static(&[0x80, 1, 5, 1]), Direction::Serverbound));
+        assert_eq!(Err(VoiceError::UnknownType(5)), VoicePacket::decode(Bytes::from_static(&[0xa0, 1]), Direction::Serverbound));
+    }
+
+    #[test]
+    fn ignores_unknown_opus_header_bits() {
+        // Length 2 with bit 14 set, which isn't part of the length
+        let packet = VoicePacket::decode(Bytes::from_static(&[0x80, 1, 0xc0, 0x40, 0x02, 0xf8, 1]), Direction::Serverbound);
+        match packet.unwrap() {
+            VoicePacket::Audio(audio) => assert_eq!(VoicePayload::Opus(Bytes::from_static(&[0xf8, 1]), false), audio.payload),
+            VoicePacket::Ping { .. } => unreachable!(),
+        }
+    }
+}
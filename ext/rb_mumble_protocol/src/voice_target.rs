@@ -0,0 +1,466 @@
+//! Routing of voice packets to the sessions hearing them
+//!
+//! The 5 bit target of a voice packet selects normal talking in the sender's channel (0), one
+//! of the voice targets the sender registered with `VoiceTarget` messages (1 to 30), or the
+//! server loopback (31). Recipients get the packet with the target rewritten to tell how they
+//! hear the sender: normal talking, a shout to their channel, or a whisper to them directly.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/1.4.287/src/murmur/Server.cpp
+//! (`Server::processMsg`)
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::acl::{AclStore, Identity};
+use crate::channel_tree::ChannelTree;
+use crate::mumble_proto as msgs;
+use crate::permissions::Permissions;
+use crate::user_registry::{User, UserRegistry};
+use crate::voice::{TARGET_LOOPBACK, TARGET_NORMAL};
+
+/// Lowest and highest voice target id a client may register.
+pub const MIN_TARGET_ID: u32 = 1;
+pub const MAX_TARGET_ID: u32 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceTargetError {
+    /// A `VoiceTarget` without an id in `MIN_TARGET_ID..=MAX_TARGET_ID`.
+    InvalidId(Option<u32>),
+}
+
+impl std::fmt::Display for VoiceTargetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoiceTargetError::InvalidId(Some(id)) => write!(f, "invalid voice target id {id}"),
+            VoiceTargetError::InvalidId(None) => write!(f, "voice target without id"),
+        }
+    }
+}
+
+impl std::error::Error for VoiceTargetError {}
+
+/// How a recipient hears the sender, i.e. the target of the packet sent to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Delivery {
+    /// The sender talks in the recipient's channel, a linked one or one it listens to.
+    Normal,
+    /// The sender whispers to a channel of the recipient.
+    Shout,
+    /// The sender whispers to the recipient's session.
+    Whisper,
+    /// The packet is echoed back to its sender.
+    Loopback,
+}
+
+impl Delivery {
+    /// The target to put into the header of the packet sent to the recipient.
+    pub fn target(self) -> u8 {
+        match self {
+            Delivery::Normal => TARGET_NORMAL,
+            Delivery::Shout => 1,
+            Delivery::Whisper => 2,
+            Delivery::Loopback => TARGET_LOOPBACK,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Delivery::Normal => "normal",
+            Delivery::Shout => "shout",
+            Delivery::Whisper => "whisper",
+            Delivery::Loopback => "loopback",
+        }
+    }
+}
+
+/// What a session's certificate and `Authenticate` message tell about it, beyond its
+/// `UserState`. Used to evaluate ACL groups.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub strong: bool,
+    pub access_tokens: Vec<String>,
+}
+
+/// Everything needed to resolve the recipients of a packet.
+#[derive(Clone, Copy)]
+pub struct Server<'a> {
+    pub tree: &'a ChannelTree,
+    pub users: &'a UserRegistry,
+    pub acls: &'a AclStore,
+}
+
+/// The voice targets registered by every session.
+#[derive(Clone, Debug, Default)]
+pub struct VoiceTargets {
+    targets: HashMap<u32, BTreeMap<u32, Vec<msgs::voice_target::Target>>>,
+    credentials: HashMap<u32, Credentials>,
+}
+
+impl VoiceTargets {
+    pub fn new() -> Self {
+        VoiceTargets::default()
+    }
+
+    /// Registers the voice target a session sent, a target without entries removes it.
+    pub fn apply(&mut self, session: u32, message: &msgs::VoiceTarget) -> Result<(), VoiceTargetError> {
+        let id = match message.id {
+            Some(id @ MIN_TARGET_ID..=MAX_TARGET_ID) => id,
+            id => return Err(VoiceTargetError::InvalidId(id)),
+        };
+
+        if message.targets.is_empty() {
+            if let Some(targets) = self.targets.get_mut(&session) {
+                targets.remove(&id);
+            }
+        } else {
+            self.targets.entry(session).or_default().insert(id, message.targets.clone());
+        }
+        Ok(())
+    }
+
+    /// Returns the entries of a registered voice target.
+    pub fn get(&self, session: u32, id: u32) -> Option<&[msgs::voice_target::Target]> {
+        self.targets.get(&session)?.get(&id).map(Vec::as_slice)
+    }
+
+    pub fn set_credentials(&mut self, session: u32, credentials: Credentials) {
+        self.credentials.insert(session, credentials);
+    }
+
+    /// Forgets the targets and credentials of a disconnected session.
+    pub fn remove(&mut self, session: u32) {
+        self.targets.remove(&session);
+        self.credentials.remove(&session);
+    }
+
+    /// Returns who receives a packet the session sent to the target, ordered by session.
+    ///
+    /// Muted, suppressed or self muted senders reach no one, deafened recipients are skipped.
+    pub fn resolve(&self, server: Server, sender: u32, target: u8) -> Vec<(u32, Delivery)> {
+        let user = match server.users.get(sender) {
+            Some(user) => user,
+            None => return Vec::new(),
+        };
+        if user.mute || user.suppress || user.self_mute {
+            return Vec::new();
+        }
+
+        let mut recipients = Recipients { server, targets: self, sender: user, found: BTreeMap::new() };
+        match target {
+            TARGET_NORMAL => recipients.talk(),
+            TARGET_LOOPBACK => {
+                recipients.found.insert(sender, Delivery::Loopback);
+            }
+            id => {
+                if let Some(entries) = self.get(sender, id.into()) {
+                    recipients.whisper(entries);
+                }
+            }
+        }
+
+        recipients.found.into_iter().collect()
+    }
+
+    fn identity(&self, user: &User) -> Identity {
+        let credentials = self.credentials.get(&user.session).cloned().unwrap_or_default();
+
+        Identity {
+            user_id: user.user_id,
+            channel_id: user.channel_id,
+            cert_hash: user.hash.clone(),
+            strong: credentials.strong,
+            access_tokens: credentials.access_tokens,
+        }
+    }
+}
+
+struct Recipients<'a> {
+    server: Server<'a>,
+    targets: &'a VoiceTargets,
+    sender: &'a User,
+    found: BTreeMap<u32, Delivery>,
+}
+
+impl Recipients<'_> {
+    fn talk(&mut self) {
+        let channel = self.sender.channel_id;
+        self.add_channel(channel, None, Delivery::Normal);
+
+        for linked in self.server.tree.linked(channel) {
+            if linked != channel && self.sender_may(linked, Permissions::SPEAK) {
+                self.add_channel(linked, None, Delivery::Normal);
+            }
+        }
+    }
+
+    fn whisper(&mut self, entries: &[msgs::voice_target::Target]) {
+        for entry in entries {
+            let channel = match entry.channel_id {
+                Some(channel) if self.server.tree.get(channel).is_some() => channel,
+                _ => continue,
+            };
+            let group = entry.group.as_deref().filter(|group| !group.is_empty());
+
+            let mut channels = if entry.links() {
+                self.server.tree.linked(channel)
+            } else {
+                BTreeSet::from([channel])
+            };
+            if entry.children() {
+                channels.extend(self.server.tree.descendants(channel));
+            }
+
+            for channel in channels {
+                if self.sender_may(channel, Permissions::WHISPER) {
+                    self.add_channel(channel, group, Delivery::Shout);
+                }
+            }
+        }
+
+        let sessions: BTreeSet<u32> = entries.iter().flat_map(|entry| entry.session.iter().copied()).collect();
+        for session in sessions {
+            let user = match self.server.users.get(session) {
+                Some(user) => user,
+                None => continue,
+            };
+            if self.sender_may(user.channel_id, Permissions::WHISPER) {
+                self.add(user, Delivery::Whisper);
+            }
+        }
+    }
+
+    /// Adds the users in the channel and those listening to it, optionally only the members of
+    /// an ACL group.
+    fn add_channel(&mut self, channel: u32, group: Option<&str>, delivery: Delivery) {
+        let Server { tree, users, acls } = self.server;
+        let is_member = |user: &User| {
+            group.is_none_or(|group| acls.is_member(tree, channel, channel, group, &self.targets.identity(user)))
+        };
+
+        let mut found = Vec::new();
+        for session in users.in_channel(channel) {
+            match users.get(session) {
+                Some(user) if is_member(user) => found.push(user),
+                _ => {}
+            }
+        }
+        for session in users.listeners(channel) {
+            match users.get(session) {
+                Some(user) if is_member(user) && self.may(user, channel, Permissions::LISTEN) => found.push(user),
+                _ => {}
+            }
+        }
+
+        for user in found {
+            self.add(user, delivery);
+        }
+    }
+
+    /// Adds a recipient unless it is the sender or deafened. A session reached in several ways
+    /// keeps the first one, e.g. a shout to its channel wins over a direct whisper.
+    fn add(&mut self, user: &User, delivery: Delivery) {
+        if user.session == self.sender.session || user.deaf || user.self_deaf {
+            return;
+        }
+        self.found.entry(user.session).or_insert(delivery);
+    }
+
+    fn sender_may(&self, channel: u32, permission: Permissions) -> bool {
+        self.may(self.sender, channel, permission)
+    }
+
+    fn may(&self, user: &User, channel: u32, permission: Permissions) -> bool {
+        let Server { tree, acls, .. } = self.server;
+        acls.effective_permissions(tree, &self.targets.identity(user), channel).contains(permission)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acl::{AclEntry, ChannelAcl};
+
+    /// Root (0)
+    /// ├── Lobby (1) <-> Stage (2)
+    /// │   └── Team (3)
+    /// └── Afk (4)
+    fn tree() -> ChannelTree {
+        let mut tree = ChannelTree::new();
+        for (id, parent) in [(0, None), (1, Some(0)), (2, Some(0)), (3, Some(1)), (4, Some(0))] {
+            tree.apply_state(&msgs::ChannelState { channel_id: Some(id), parent, ..Default::default() })
+                .unwrap();
+        }
+        tree.apply_state(&msgs::ChannelState { channel_id: Some(1), links_add: vec![2], ..Default::default() })
+            .unwrap();
+        tree
+    }
+
+    fn users(states: &[msgs::UserState]) -> UserRegistry {
+        let mut users = UserRegistry::new();
+        for state in states {
+            users.apply_state(state).unwrap();
+        }
+        users
+    }
+
+    fn user(session: u32, channel_id: u32) -> msgs::UserState {
+        msgs::UserState { session: Some(session), channel_id: Some(channel_id), ..Default::default() }
+    }
+
+    fn target(id: u32, targets: Vec<msgs::voice_target::Target>) -> msgs::VoiceTarget {
+        msgs::VoiceTarget { id: Some(id), targets }
+    }
+
+    fn deny(channel: u32, group: &str, permissions: Permissions) -> (u32, ChannelAcl) {
+        let entry = AclEntry {
+            apply_here: true,
+            apply_subs: true,
+            user_id: None,
+            group: Some(group.to_owned()),
+            grant: Permissions::NONE,
+            deny: permissions,
+        };
+        (channel, ChannelAcl { entries: vec![entry], ..Default::default() })
+    }
+
+    fn resolve(targets: &VoiceTargets, users: &UserRegistry, acls: &AclStore, sender: u32, id: u8) -> Vec<(u32, Delivery)> {
+        let tree = tree();
+        targets.resolve(Server { tree: &tree, users, acls }, sender, id)
+    }
+
+    #[test]
+    fn talking_reaches_channel_links_and_listeners() {
+        let listener = msgs::UserState { listening_channel_add: vec![1], ..user(6, 4) };
+        let deaf = msgs::UserState { self_deaf: Some(true), ..user(7, 1) };
+        let users = users(&[user(1, 1), user(2, 1), user(3, 2), user(4, 3), user(5, 4), listener, deaf]);
+        let targets = VoiceTargets::new();
+        let mut acls = AclStore::new();
+
+        assert_eq!(
+            vec![(2, Delivery::Normal), (3, Delivery::Normal), (6, Delivery::Normal)],
+            resolve(&targets, &users, &acls, 1, TARGET_NORMAL)
+        );
+
+        // without Speak in the linked channel, and without Listen for the listener
+        let (channel, acl) = deny(2, "all", Permissions::SPEAK);
+        acls.set(channel, acl);
+        let (channel, acl) = deny(1, "all", Permissions::LISTEN);
+        acls.set(channel, acl);
+        assert_eq!(vec![(2, Delivery::Normal)], resolve(&targets, &users, &acls, 1, TARGET_NORMAL));
+    }
+
+    #[test]
+    fn muted_senders_reach_no_one() {
+        let users = users(&[msgs::UserState { suppress: Some(true), ..user(1, 1) }, user(2, 1)]);
+
+        assert!(resolve(&VoiceTargets::new(), &users, &AclStore::new(), 1, TARGET_NORMAL).is_empty());
+        assert!(resolve(&VoiceTargets::new(), &users, &AclStore::new(), 9, TARGET_NORMAL).is_empty());
+    }
+
+    #[test]
+    fn loopback() {
+        let users = users(&[user(1, 1), user(2, 1)]);
+
+        assert_eq!(
+            vec![(1, Delivery::Loopback)],
+            resolve(&VoiceTargets::new(), &users, &AclStore::new(), 1, TARGET_LOOPBACK)
+        );
+    }
+
+    #[test]
+    fn whispers_to_sessions_and_channels() {
+        let users = users(&[user(1, 1), user(2, 1), user(3, 2), user(4, 3), user(5, 4), user(6, 0)]);
+        let mut targets = VoiceTargets::new();
+        let acls = AclStore::new();
+
+        targets
+            .apply(1, &target(1, vec![
+                msgs::voice_target::Target { session: vec![5, 3, 99], ..Default::default() },
+                msgs::voice_target::Target { channel_id: Some(3), ..Default::default() },
+            ]))
+            .unwrap();
+        targets
+            .apply(1, &target(2, vec![msgs::voice_target::Target {
+                channel_id: Some(1),
+                links: Some(true),
+                children: Some(true),
+                ..Default::default()
+            }]))
+            .unwrap();
+
+        assert_eq!(
+            vec![(3, Delivery::Whisper), (4, Delivery::Shout), (5, Delivery::Whisper)],
+            resolve(&targets, &users, &acls, 1, 1)
+        );
+        assert_eq!(
+            vec![(2, Delivery::Shout), (3, Delivery::Shout), (4, Delivery::Shout)],
+            resolve(&targets, &users, &acls, 1, 2)
+        );
+        assert!(resolve(&targets, &users, &acls, 1, 3).is_empty());
+        assert!(resolve(&targets, &users, &acls, 2, 1).is_empty());
+
+        targets.apply(1, &target(2, vec![])).unwrap();
+        assert!(resolve(&targets, &users, &acls, 1, 2).is_empty());
+        targets.remove(1);
+        assert!(targets.get(1, 1).is_none());
+    }
+
+    #[test]
+    fn whispers_need_permission() {
+        let users = users(&[user(1, 1), user(2, 1), user(3, 4)]);
+        let mut targets = VoiceTargets::new();
+        let mut acls = AclStore::new();
+        let (channel, acl) = deny(1, "all", Permissions::WHISPER);
+        acls.set(channel, acl);
+
+        targets
+            .apply(1, &target(1, vec![msgs::voice_target::Target { session: vec![2, 3], ..Default::default() }]))
+            .unwrap();
+
+        // checked in the recipient's channel, even if it is the sender's own
+        assert_eq!(vec![(3, Delivery::Whisper)], resolve(&targets, &users, &acls, 1, 1));
+    }
+
+    #[test]
+    fn group_targets() {
+        let users = users(&[
+            user(1, 0),
+            msgs::UserState { user_id: Some(20), ..user(2, 1) },
+            user(3, 1),
+            user(4, 3),
+        ]);
+        let mut targets = VoiceTargets::new();
+        targets.set_credentials(4, Credentials { strong: false, access_tokens: vec!["team".into()] });
+        let acls = AclStore::new();
+
+        targets
+            .apply(1, &target(1, vec![msgs::voice_target::Target {
+                channel_id: Some(1),
+                children: Some(true),
+                group: Some("auth".into()),
+                ..Default::default()
+            }]))
+            .unwrap();
+        targets
+            .apply(1, &target(2, vec![msgs::voice_target::Target {
+                channel_id: Some(1),
+                children: Some(true),
+                group: Some("#team".into()),
+                ..Default::default()
+            }]))
+            .unwrap();
+
+        assert_eq!(vec![(2, Delivery::Shout)], resolve(&targets, &users, &acls, 1, 1));
+        assert_eq!(vec![(4, Delivery::Shout)], resolve(&targets, &users, &acls, 1, 2));
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        let mut targets = VoiceTargets::new();
+
+        assert_eq!(Err(VoiceTargetError::InvalidId(Some(31))), targets.apply(1, &target(31, vec![])));
+        assert_eq!(
+            Err(VoiceTargetError::InvalidId(None)),
+            targets.apply(1, &msgs::VoiceTarget { id: None, targets: vec![] })
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Choice between UDP and the control channel for voice packets
+//!
+//! Voice is sent over UDP as long as the other end is known to receive it, and tunneled through
+//! the control channel (`UDPTunnel`) otherwise. UDP is considered working while packets keep
+//! arriving over it: answers to UDP pings or datagrams which decrypted successfully. A peer
+//! tunneling its own voice signals that it can't use UDP, as Murmur assumes.
+//!
+//! Based on https://github.com/mumble-voip/mumble/blob/v1.5.634/src/murmur/Server.cpp
+
+use crate::crypt_state::DecryptError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Time without anything received over UDP after which voice is tunneled.
+    pub silence_timeout_ms: u64,
+    /// Always tunnels voice, like the "Force TCP mode" setting of the official client.
+    pub force_tunnel: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            silence_timeout_ms: 10000,
+            force_tunnel: false,
+        }
+    }
+}
+
+/// How to send a voice packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoicePath {
+    /// Encrypted, in a UDP datagram.
+    Udp,
+    /// Unencrypted, in a `UDPTunnel` control message.
+    Tunnel,
+}
+
+impl VoicePath {
+    pub fn name(self) -> &'static str {
+        match self {
+            VoicePath::Udp => "udp",
+            VoicePath::Tunnel => "tunnel",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// UDP ping answers received.
+    pub udp_pings: u32,
+    /// Datagrams which decrypted successfully.
+    pub decrypted: u32,
+    /// Datagrams which failed to decrypt.
+    pub decrypt_failures: u32,
+    /// Voice packets received through the control channel.
+    pub tunneled: u32,
+}
+
+/// Tracks whether UDP works for one connection.
+#[derive(Clone, Debug, Default)]
+pub struct VoiceTransport {
+    config: Config,
+    /// When something was last received over UDP, `None` if not since the last tunneled packet.
+    last_udp_ms: Option<u64>,
+    /// The path reported by the last `poll`.
+    polled: Option<VoicePath>,
+    stats: Stats,
+}
+
+impl VoiceTransport {
+    pub fn new(config: Config) -> Self {
+        VoiceTransport { config, ..Default::default() }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Records the answer to a UDP ping.
+    pub fn udp_ping_received(&mut self, now_ms: u64) {
+        self.stats.udp_pings += 1;
+        self.last_udp_ms = Some(now_ms);
+    }
+
+    /// Records the result of `CryptState::decrypt` for a received datagram. Failures don't
+    /// prove anything about the path, e.g. stray or replayed packets.
+    pub fn decrypted(&mut self, result: Result<(), DecryptError>, now_ms: u64) {
+        match result {
+            Ok(()) => {
+                self.stats.decrypted += 1;
+                self.last_udp_ms = Some(now_ms);
+            }
+            Err(_) => self.stats.decrypt_failures += 1,
+        }
+    }
+
+    /// Records a voice packet received through the control channel: the peer doesn't use UDP.
+    pub fn tunnel_received(&mut self) {
+        self.stats.tunneled += 1;
+        self.last_udp_ms = None;
+    }
+
+    /// The path to send the next voice packet over.
+    pub fn path(&self, now_ms: u64) -> VoicePath {
+        match self.last_udp_ms {
+            _ if self.config.force_tunnel => VoicePath::Tunnel,
+            Some(last_udp_ms) if now_ms.saturating_sub(last_udp_ms) < self.config.silence_timeout_ms => VoicePath::Udp,
+            _ => VoicePath::Tunnel,
+        }
+    }
+
+    /// Returns the path if it changed since the last call, e.g. to log switches.
+    /// The first call reports the initial path.
+    pub fn poll(&mut self, now_ms: u64) -> Option<VoicePath> {
+        let path = self.path(now_ms);
+        if self.polled == Some(path) {
+            return None;
+        }
+
+        self.polled = Some(path);
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn starts_tunneled() {
+        let mut transport = VoiceTransport::new(Config::default());
+        assert_eq!(VoicePath::Tunnel, transport.path(0));
+        assert_eq!(Some(VoicePath::Tunnel), transport.poll(0));
+        assert_eq!(None, transport.poll(1000));
+    }
+
+    #[test]
+    fn switches_on_udp_activity_and_silence() {
+        let mut transport = VoiceTransport::new(Config { silence_timeout_ms: 5000, ..Default::default() });
+        transport.poll(0);
+
+        transport.udp_ping_received(1000);
+        assert_eq!(Some(VoicePath::Udp), transport.poll(1000));
+        assert_eq!(VoicePath::Udp, transport.path(5999));
+
+        transport.decrypted(Err(DecryptError::Mac), 5000);
+        assert_eq!(None, transport.poll(5999));
+        assert_eq!(Some(VoicePath::Tunnel), transport.poll(6000));
+
+        transport.decrypted(Ok(()), 7000);
+        assert_eq!(Some(VoicePath::Udp), transport.poll(7000));
+        assert_eq!(Stats { udp_pings: 1, decrypted: 1, decrypt_failures: 1, tunneled: 0 }, *transport.stats());
+    }
+
+    #[test]
+    fn tunneling_peer() {
+        let mut transport = VoiceTransport::new(Config::default());
+        transport.decrypted(Ok(()), 1000);
+        assert_eq!(VoicePath::Udp, transport.path(1000));
+
+        transport.tunnel_received();
+        assert_eq!(VoicePath::Tunnel, transport.path(1000));
+
+        transport.decrypted(Ok(()), 2000);
+        assert_eq!(VoicePath::Udp, transport.path(2000));
+    }
+
+    #[test]
+    fn forced_tunnel() {
+        let mut transport = VoiceTransport::new(Config { force_tunnel: true, ..Default::default() });
+        transport.udp_ping_received(0);
+        assert_eq!(VoicePath::Tunnel, transport.path(0));
+    }
+}
@@ -2,6 +2,29 @@
 
 require_relative "rb_mumble_protocol/version"
 require_relative "rb_mumble_protocol/crypt_state"
+require_relative "rb_mumble_protocol/control_stream"
+require_relative "rb_mumble_protocol/voice_packet"
+require_relative "rb_mumble_protocol/jitter_buffer"
+require_relative "rb_mumble_protocol/channel_tree"
+require_relative "rb_mumble_protocol/user_registry"
+require_relative "rb_mumble_protocol/codec_version"
+require_relative "rb_mumble_protocol/blob_store"
+require_relative "rb_mumble_protocol/plugin_data_router"
+require_relative "rb_mumble_protocol/permissions"
+require_relative "rb_mumble_protocol/acl_evaluator"
+require_relative "rb_mumble_protocol/voice_targets"
+require_relative "rb_mumble_protocol/ban_list"
+require_relative "rb_mumble_protocol/certificate"
+require_relative "rb_mumble_protocol/certificate_bundle"
+require_relative "rb_mumble_protocol/client_session"
+require_relative "rb_mumble_protocol/server_connection"
+require_relative "rb_mumble_protocol/voice_transport"
+require_relative "rb_mumble_protocol/udp_demux"
+require_relative "rb_mumble_protocol/text_message"
+require_relative "rb_mumble_protocol/positional"
+require_relative "rb_mumble_protocol/bandwidth_meter"
+require_relative "rb_mumble_protocol/ogg_opus_writer"
+require_relative "rb_mumble_protocol/pcap"
 require_relative "rb_mumble_protocol/rb_mumble_protocol"
 
 module RbMumbleProtocol
@@ -0,0 +1,20 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Computes permissions from the `ACL` messages of channels, the way Murmur does.
+  #
+  # Users are hashes as returned by UserRegistry#[], `strong: true` marks verified certificates
+  # and `access_tokens` lists the tokens sent in `Authenticate`.
+  #
+  #   stream.each_message { |type, message| evaluator.apply(type, message) }
+  #   evaluator.permissions(tree, registry[session].merge(access_tokens: ["secret"]), channel_id)
+  class AclEvaluator
+    # Applies a message read from a ControlStream, ignoring unrelated message types.
+    def apply(type, message)
+      case type
+      when :acl then apply_acl(message) unless message[:query]
+      when :channel_remove then remove(message.fetch(:channel_id))
+      end
+    end
+  end
+end
@@ -0,0 +1,22 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Server bans, read from and written to `BanList` messages.
+  #
+  # Bans are hashes with the subnet as `address` (`"10.0.0.0/8"`), `name`, `hash` (of the
+  # certificate), `reason`, `start` and `expires_at` (Unix times) and `duration` (seconds, 0 for
+  # permanent bans).
+  #
+  #   bans.add(address: "192.168.1.0/24", reason: "spam", duration: 3600)
+  #   bans.find(socket.remote_address.ip_address, user_hash) # => ban or nil
+  class BanList
+    # Applies a message read from a ControlStream, ignoring unrelated message types and queries.
+    def apply(type, message)
+      apply_list(message) if type == :ban_list && !message[:query]
+    end
+
+    def banned?(address, hash = nil, now = nil)
+      !find(address, hash, now).nil?
+    end
+  end
+end
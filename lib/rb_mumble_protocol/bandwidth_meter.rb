@@ -0,0 +1,20 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Measures the voice bandwidth of one session over its last 360 packets, counting IP, UDP and
+  # crypt overhead like Murmur, and tells which packets exceed the limit announced in ServerSync.
+  #
+  # Times are milliseconds of a monotonic clock. They may be omitted, in which case the time
+  # elapsed since the meter was created is used. Don't mix both.
+  #
+  #   meter = RbMumbleProtocol::BandwidthMeter.new(max_bandwidth: 72_000)
+  #   packet, reason = connection.decrypt(datagram)
+  #   relay(packet) if packet && meter.add(packet.bytesize)
+  #
+  #   RbMumbleProtocol::BandwidthMeter.network_bandwidth(40_000, 2) # => 54800
+  class BandwidthMeter
+    def kbit_per_sec(*now)
+      bandwidth(*now) * 8 / 1000.0
+    end
+  end
+end
@@ -0,0 +1,37 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Keeps textures, comments and channel descriptions of 128 bytes or more, which are sent as
+  # SHA-1 hashes and fetched with `RequestBlob`. Identical blobs are stored once.
+  #
+  # On the server, messages are compacted before being applied and broadcast:
+  #
+  #   message = blobs.compact(:user_state, message)
+  #   registry.apply(:user_state, message)
+  #   blobs.answer(request, registry, tree).each { |type, reply| stream.write_message(type, reply) }
+  #
+  # Clients store what they receive and request what they lack:
+  #
+  #   blobs.insert(message[:comment]) if message[:comment]
+  #   if (request = blobs.request_missing(registry, tree))
+  #     stream.write_message(*request)
+  #   end
+  class BlobStore
+    # The comment of a user, whether it was sent along or as a hash. `nil` if it isn't known.
+    def comment(registry, session)
+      user = registry[session]
+      return unless user
+      return user[:comment] if user[:comment]
+
+      self[user[:comment_hash]]&.dup&.force_encoding(Encoding::UTF_8) if user[:comment_hash]
+    end
+
+    # The texture of a user, whether it was sent along or as a hash. `nil` if it isn't known.
+    def texture(registry, session)
+      user = registry[session]
+      return unless user
+
+      user[:texture] || (self[user[:texture_hash]] if user[:texture_hash])
+    end
+  end
+end
@@ -0,0 +1,20 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # A DER or PEM encoded X.509 certificate, as presented by clients and servers.
+  #
+  # `mumble_hash` is the hex SHA-1 Mumble identifies users by, see `UserState#hash`.
+  #
+  #   Certificate.new(ssl_socket.peer_cert.to_der).mumble_hash
+  #   Certificate.hash_of(File.binread("client.pem"))
+  class Certificate
+    def self.from_openssl(certificate)
+      new(certificate.to_der)
+    end
+
+    # Whether the validity period ended before `now` (Unix time).
+    def expired?(now = Time.now.to_i)
+      now > not_after
+    end
+  end
+end
@@ -0,0 +1,17 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # A certificate with its private key, e.g. a generated identity for a bot or a test server.
+  #
+  #   bundle = CertificateBundle.generate("bot", email: "bot@example.com", key_type: :ed25519)
+  #   File.binwrite("bot.p12", bundle.to_pkcs12) # importable by Mumble clients
+  #   context.add_certificate(*bundle.to_openssl)
+  class CertificateBundle
+    # Returns the certificate and key as `OpenSSL::X509::Certificate` and `OpenSSL::PKey`.
+    def to_openssl
+      require "openssl"
+
+      [OpenSSL::X509::Certificate.new(certificate_pem), OpenSSL::PKey.read(private_key_pem)]
+    end
+  end
+end
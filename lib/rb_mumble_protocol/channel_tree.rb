@@ -0,0 +1,21 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # The channel hierarchy of a server, kept up to date with `ChannelState` and `ChannelRemove`
+  # messages. Invalid changes (unknown parents, cycles) raise RbMumbleProtocol::Error and leave
+  # the tree untouched.
+  #
+  #   stream.each_message { |type, message| tree.apply(type, message) }
+  #   tree.path(channel_id) # => [0, 4, channel_id]
+  class ChannelTree
+    ROOT_ID = 0
+
+    # Applies a message read from a ControlStream, ignoring unrelated message types.
+    def apply(type, message)
+      case type
+      when :channel_state then apply_state(message)
+      when :channel_remove then apply_remove(message.fetch(:channel_id))
+      end
+    end
+  end
+end
@@ -0,0 +1,37 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Client side of a connection: handshake, voice channel keys and pings, without any socket.
+  #
+  # Times are milliseconds of a monotonic clock. They may be omitted, in which case the time
+  # elapsed since the session was created is used. Don't mix both.
+  #
+  #   session = RbMumbleProtocol::ClientSession.new(username: "bot", bot: true)
+  #   session.start
+  #   session.flush(stream)
+  #   stream.each_message do |type, message|
+  #     session.handle(type, message)
+  #     session.drain_events # => [[:synced, { session: 7, ... }], [:message, [:text_message, {...}]]]
+  #     session.flush(stream)
+  #   end
+  #
+  # Events are `[:crypt_setup, nil]`, `[:synced, { session:, max_bandwidth:, welcome_text:, permissions: }]`,
+  # `[:rejected, { type:, reason: }]`, `[:kicked, { actor:, reason:, ban: }]`, `[:timed_out, nil]`,
+  # and `[:message, [type, message]]` for the messages the session doesn't handle itself.
+  class ClientSession
+    # Writes the pending messages to a ControlStream, returns how many were written.
+    def flush(stream)
+      messages = drain_outgoing
+      messages.each { |type, message| stream.write_message(type, message) }
+      messages.size
+    end
+
+    def synced?
+      state == :synced
+    end
+
+    def closed?
+      %i[rejected disconnected].include?(state)
+    end
+  end
+end
@@ -0,0 +1,18 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Selects the voice codecs a server announces in `CodecVersion` like Murmur: the CELT version
+  # most users support, and Opus once `opus_threshold` percent of them support it.
+  #
+  #   registry.set_codecs(session, credentials[:celt_versions], credentials[:opus])
+  #   message = codecs.recheck(registry)
+  #   connections.each_value { |connection| connection.write_message(*message) } if message
+  #
+  #   codecs.accepts?(registry, session, packet[:type]) # => false for CELT the client didn't advertise
+  class CodecVersion
+    # Sessions which can't decode Opus, Murmur warns them by text message when it is enabled.
+    def sessions_without_opus(registry)
+      registry.sessions.reject { |session| registry[session][:opus] }
+    end
+  end
+end
@@ -0,0 +1,43 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Reads and writes framed control messages on an IO.
+  #
+  # With `rate_limit:`, received messages are throttled like Murmur does with `messagelimit`,
+  # `messageburst`, `pluginmessagelimit` and `pluginmessageburst` (`{}` for Murmur's defaults).
+  # Dropped messages are skipped silently and counted by `dropped_messages`.
+  #
+  # Frames of an unknown type or with an invalid payload are skipped as well and counted by
+  # `skipped_messages`. Only frames over `max_message_size` raise, as the stream can't be read
+  # any further.
+  class ControlStream
+    # Blocks until a whole message arrived, returns `[type, message]` or nil on EOF.
+    # Waiting is done with IO#wait_readable/IO#wait_writable, so fiber schedulers are respected.
+    def read_message
+      loop do
+        result = read_nonblock(exception: false)
+
+        case result
+        when :wait_readable then wait_io.wait_readable
+        when :wait_writable then wait_io.wait_writable
+        else return result
+        end
+      end
+    end
+
+    def each_message
+      return enum_for(:each_message) unless block_given?
+
+      while (message = read_message)
+        yield(*message)
+      end
+    end
+
+    private
+
+    # OpenSSL::SSL::SSLSocket can't be waited on by itself
+    def wait_io
+      @wait_io ||= io.respond_to?(:to_io) ? io.to_io : io
+    end
+  end
+end
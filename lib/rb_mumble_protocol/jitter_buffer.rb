@@ -0,0 +1,13 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Reorders decoded voice packets per speaker session.
+  #
+  # Times are milliseconds of a monotonic clock. They may be omitted, in which case the time
+  # elapsed since the buffer was created is used. Don't mix both.
+  #
+  #   buffer.push(session, RbMumbleProtocol::VoicePacket.decode(data, :serverbound))
+  #   buffer.poll # => [[session, :audio, packet], [session, :gap, frames], [session, :end, nil]]
+  class JitterBuffer
+  end
+end
@@ -0,0 +1,18 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Records the Opus voice packets of a single speaker into an Ogg Opus file, without
+  # re-encoding them. Gaps in the sequence numbers are filled with silence, up to a minute.
+  #
+  #   File.open("recording.opus", "wb") do |file|
+  #     writer = RbMumbleProtocol::OggOpusWriter.new(file, comments: ["TITLE=General"])
+  #     packets.each { |packet| writer.write_packet(packet) }
+  #     writer.close
+  #   end
+  class OggOpusWriter
+    # Duration of the recording so far, in seconds.
+    def duration
+      samples / 48_000.0
+    end
+  end
+end
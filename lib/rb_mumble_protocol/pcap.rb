@@ -0,0 +1,44 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Reads the UDP datagrams and TCP segments of a pcap or pcapng capture.
+  #
+  #   RbMumbleProtocol::PcapReader.open("mumble.pcapng").each do |packet|
+  #     packet # => { timestamp: 1700000000123456, protocol: :udp, source: "10.0.0.2:50000", ... }
+  #   end
+  class PcapReader
+    include Enumerable
+
+    def self.open(path)
+      new(File.binread(path))
+    end
+
+    def each
+      return enum_for(:each) unless block_given?
+
+      while (packet = next_packet)
+        yield packet
+      end
+    end
+  end
+
+  # Writes synthetic captures, e.g. for tests. Timestamps are microseconds since the Unix epoch.
+  #
+  #   writer = RbMumbleProtocol::PcapWriter.new(io)
+  #   writer.write_udp("10.0.0.2:50000", "10.0.0.1:64738", crypt_state.encrypt(voice_packet))
+  class PcapWriter
+  end
+
+  # Decrypts and decodes the captured traffic between a server and one of its clients, using the
+  # values of the `CryptSetup` message sent to that client.
+  #
+  #   replay = RbMumbleProtocol::PcapReplay.new(server_port: 64738, **crypt_setup)
+  #   reader.flat_map { |packet| replay.feed(packet) }
+  class PcapReplay
+    # Decodes a whole capture file, returning the events in order.
+    def self.decode_file(path, server_port:, key:, client_nonce:, server_nonce:)
+      replay = new(server_port: server_port, key: key, client_nonce: client_nonce, server_nonce: server_nonce)
+      PcapReader.open(path).flat_map { |packet| replay.feed(packet) }
+    end
+  end
+end
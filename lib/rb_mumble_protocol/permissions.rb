@@ -0,0 +1,28 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Permission bitmasks of `ACL`, `PermissionQuery` and `PermissionDenied` messages.
+  #
+  # Each permission has a constant (`SPEAK`, `MUTE_DEAFEN`, ...) and a symbol (`:speak`,
+  # `:mute_deafen`, ...), see `names` and `from_names`.
+  #
+  #   Permissions.names(message[:permissions]) # => [:traverse, :enter, :speak, :cached]
+  #   Permissions.granted?(permissions, :speak, :whisper)
+  module Permissions
+    module_function
+
+    # Whether all named permissions are set.
+    def granted?(bits, *names)
+      required = from_names(names)
+      bits & required == required
+    end
+
+    # Whether the bitmask only contains permissions meaningful in the (root) channel.
+    def valid?(bits, root:)
+      validate(bits, root)
+      true
+    rescue Error
+      false
+    end
+  end
+end
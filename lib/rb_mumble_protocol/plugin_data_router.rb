@@ -0,0 +1,31 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Forwards :plugin_data_transmission messages to the users they are addressed to, like Murmur:
+  # messages without data or data ID, or over 1000 bytes of data or 100 bytes of data ID, are
+  # dropped, the sender's session is set and each connected receiver gets the message once.
+  #
+  # Data IDs conventionally start with a namespace, e.g. "mygame:position". With `namespaces:`,
+  # only data IDs in these namespaces are forwarded.
+  #
+  #   router = RbMumbleProtocol::PluginDataRouter.new(namespaces: ["mygame"])
+  #   router.forward(session, message, registry) do |receiver, type, forwarded|
+  #     connections[receiver].write_message(type, forwarded)
+  #   end
+  #
+  #   stream.write_message(*RbMumbleProtocol::PluginDataRouter.message("mygame:position", data, [2, 3]))
+  class PluginDataRouter
+    MAX_DATA_LENGTH = 1000
+    MAX_DATA_ID_LENGTH = 100
+
+    # Yields each connected receiver with the message to send it, returns the receivers or
+    # `nil` if the message was dropped.
+    def forward(sender, message, registry)
+      receivers, (type, forwarded) = route(sender, message, registry)
+      return unless receivers
+
+      receivers.each { |receiver| yield receiver, type, forwarded }
+      receivers
+    end
+  end
+end
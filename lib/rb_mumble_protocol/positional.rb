@@ -0,0 +1,24 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Positional audio helpers, see `distance`, `direction`, `gain` and `stereo_gains`.
+  #
+  # Positions are `[x, y, z]` arrays in meters, x pointing right, y up and z forward. A listener
+  # is a `{ position:, front:, top: }` hash, facing z with y up by default. Decoded voice packets
+  # carry the speaker's `position` when the client sent one, packets to encode may too.
+  #
+  #   packet = RbMumbleProtocol::VoicePacket.decode(data, :clientbound)
+  #   left, right = RbMumbleProtocol::Positional.packet_gains({ position: [0, 0, 0] }, packet)
+  #
+  # Attenuation settings are keyword arguments defaulting to the official client's:
+  # `min_distance: 1.0`, `max_distance: 15.0`, `max_distance_volume: 0.25` and `bloom: 0.5`.
+  module Positional
+    module_function
+
+    # Gains of the listener's left and right ear for a decoded voice packet, `nil` if it has no
+    # position.
+    def packet_gains(listener, packet, **settings)
+      stereo_gains(listener, packet[:position], **settings) if packet[:position]
+    end
+  end
+end
@@ -0,0 +1,65 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Server side of a single client connection: validation of Version and Authenticate, voice
+  # channel keys, initial sync and timeouts, without any socket.
+  #
+  # Times are milliseconds of a monotonic clock. They may be omitted, in which case the time
+  # elapsed since the connection was created is used. Don't mix both.
+  #
+  #   connection = RbMumbleProtocol::ServerConnection.new(password: "secret", welcome_text: "Hi!")
+  #   connection.start
+  #   stream.each_message do |type, message|
+  #     connection.handle(type, message)
+  #     connection.drain_events.each do |kind, details|
+  #       next unless kind == :authenticate
+  #
+  #       if users.include?(details[:username])
+  #         connection.reject(:username_in_use, "Username already in use")
+  #       else
+  #         connection.accept(next_session, permissions, [[:channel_state, { channel_id: 0, name: "Root" }]])
+  #       end
+  #     end
+  #     connection.flush(stream)
+  #   end
+  #
+  # Voice is encrypted with the CryptState whose keys `accept` sent, available as `crypt_state`.
+  # Pass your own with `accept(session, permissions, crypt_state: state)`.
+  #
+  # Events are `[:authenticate, credentials]`, `[:rejected, { type:, reason: }]`,
+  # `[:tokens, tokens]`, `[:timed_out, nil]`, and `[:message, [type, message]]` for the messages of
+  # synced clients the connection doesn't handle itself.
+  class ServerConnection
+    # Writes the pending messages to a ControlStream, returns how many were written.
+    def flush(stream)
+      messages = drain_outgoing
+      messages.each { |type, message| stream.write_message(type, message) }
+      messages.size
+    end
+
+    def synced?
+      state == :synced
+    end
+
+    def closed?
+      %i[rejected disconnected].include?(state)
+    end
+
+    def crypt_ready?
+      !crypt_state.nil?
+    end
+
+    # Encrypts a voice packet for UDP, returns nil until the client was accepted.
+    def encrypt(packet)
+      crypt_state&.encrypt(packet)
+    end
+
+    # Returns `[packet, reason]` like CryptState#decrypt, `[datagram, :eof]` until the client was
+    # accepted.
+    def decrypt(datagram)
+      return [datagram, :eof] if crypt_state.nil?
+
+      crypt_state.decrypt(datagram)
+    end
+  end
+end
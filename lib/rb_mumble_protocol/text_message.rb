@@ -0,0 +1,26 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # HTML bodies and recipients of :text_message messages, see `filter`, `sanitize`, `plain_text`,
+  # `length`, `images`, `channels` and `recipients`.
+  #
+  #   body = RbMumbleProtocol::TextMessage.filter(message[:message], server_config)
+  #   if body.nil?
+  #     stream.write_message(:permission_denied, { type: 6 }) # TextTooLong
+  #   else
+  #     RbMumbleProtocol::TextMessage.recipients(message, channel_tree, user_registry, session)
+  #   end
+  #
+  # Lengths are counted like Murmur does, in UTF-16 code units, the data of inline images only
+  # counting against `image_message_length`.
+  module TextMessage
+    DEFAULT_MESSAGE_LENGTH = 5_000
+    DEFAULT_IMAGE_MESSAGE_LENGTH = 131_072
+
+    module_function
+
+    def allowed?(text, server_config = nil)
+      !filter(text, *server_config).nil?
+    end
+  end
+end
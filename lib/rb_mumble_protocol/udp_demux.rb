@@ -0,0 +1,21 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Finds which session sent a UDP datagram. Known addresses map directly to their session, a
+  # datagram from an unknown address is decrypted with the crypt state of each session connected
+  # from the same IP, without modifying them, and the address is bound to the one that succeeds.
+  #
+  #   demux.add(session, tcp_socket.remote_address.ip_address)
+  #
+  #   datagram, addrinfo = udp_socket.recvfrom(1024)
+  #   states = connections.transform_values(&:crypt_state)
+  #   session, = demux.route("#{addrinfo[3]}:#{addrinfo[1]}", datagram, states)
+  #   packet, reason = states[session].decrypt(datagram) if session
+  #
+  # `states` maps sessions to their CryptState, nil for clients not accepted yet.
+  class UdpDemux
+    def known?(address)
+      !session_for(address).nil?
+    end
+  end
+end
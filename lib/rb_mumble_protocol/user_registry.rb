@@ -0,0 +1,43 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # The connected users of a server, kept up to date with `UserState` and `UserRemove` messages.
+  # Only the fields present in a `UserState` change.
+  #
+  #   registry.on_change { |event| puts "#{event[:session]} #{event[:event]} #{event[:changed]}" }
+  #   stream.each_message { |type, message| registry.apply(type, message) }
+  class UserRegistry
+    # Registers a block called with every event produced by #apply.
+    def on_change(&block)
+      change_listeners << block
+      self
+    end
+
+    # Applies a message read from a ControlStream, ignoring unrelated message types.
+    #
+    # Returns the event, e.g. `{ event: :updated, session: 3, changed: [:channel_id], previous_channel_id: 0 }`
+    # or `{ event: :disconnected, session: 3, user: {...}, reason: "bye" }`.
+    def apply(type, message)
+      event =
+        case type
+        when :user_state then apply_state(message)
+        when :user_remove then disconnected(message)
+        end
+      return unless event
+
+      change_listeners.each { |listener| listener.call(event) }
+      event
+    end
+
+    private
+
+    def disconnected(message)
+      user = apply_remove(message.fetch(:session))
+      { event: :disconnected, session: user[:session], user: user, **message.slice(:actor, :reason, :ban) }
+    end
+
+    def change_listeners
+      @change_listeners ||= []
+    end
+  end
+end
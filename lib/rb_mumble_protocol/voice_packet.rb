@@ -0,0 +1,13 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Plain (decrypted) UDP voice packets, see `decode`, `encode` and `opus_info`.
+  # Audio packets carry the speaker's `position` as `[x, y, z]` when the client sent one.
+  #
+  # Packets sent by clients are :serverbound and lack the session of the speaker,
+  # packets sent by the server are :clientbound.
+  module VoicePacket
+    TARGET_NORMAL = 0
+    TARGET_LOOPBACK = 31
+  end
+end
@@ -0,0 +1,23 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # The voice targets registered by every session, resolving who hears a voice packet.
+  #
+  #   targets.apply(session, type, message) # for each message received from a client
+  #   targets.resolve(tree, registry, acls, session, packet[:target]).each do |recipient, delivery|
+  #     # send the packet with its target set to TARGETS.fetch(delivery)
+  #   end
+  class VoiceTargets
+    # The packet target telling a recipient how it hears the sender.
+    TARGETS = { normal: 0, shout: 1, whisper: 2, loopback: 31 }.freeze
+
+    # Applies a message received from the session, ignoring unrelated message types.
+    def apply(session, type, message)
+      case type
+      when :voice_target then apply_target(session, message)
+      when :authenticate then set_credentials(session, access_tokens: message.fetch(:tokens, []))
+      when :user_remove then remove(message.fetch(:session))
+      end
+    end
+  end
+end
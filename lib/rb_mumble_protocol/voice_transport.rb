@@ -0,0 +1,24 @@
+# frozen_string_literal: true
+
+module RbMumbleProtocol
+  # Decides whether voice packets go over UDP or through the control channel (`:udp_tunnel`),
+  # from what was recently received over UDP.
+  #
+  # Times are milliseconds of a monotonic clock. They may be omitted, in which case the time
+  # elapsed since the transport was created is used. Don't mix both.
+  #
+  #   _, reason = crypt_state.decrypt(datagram)
+  #   transport.decrypted(reason)
+  #   transport.tunnel_received # when the peer sent a :udp_tunnel message
+  #
+  #   if transport.udp?
+  #     socket.send(crypt_state.encrypt(packet), 0)
+  #   else
+  #     stream.write_message(:udp_tunnel, packet)
+  #   end
+  class VoiceTransport
+    def udp?(now = nil)
+      path(*now) == :udp
+    end
+  end
+end
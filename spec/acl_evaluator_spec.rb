@@ -0,0 +1,59 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::AclEvaluator do
+  subject(:evaluator) { described_class.new }
+
+  let(:tree) do
+    RbMumbleProtocol::ChannelTree.new.tap do |tree|
+      tree.apply(:channel_state, { channel_id: 0, name: "Root" })
+      tree.apply(:channel_state, { channel_id: 1, parent: 0, name: "Lobby" })
+      tree.apply(:channel_state, { channel_id: 2, parent: 1, name: "Team" })
+    end
+  end
+
+  let(:guest) { { session: 1, channel_id: 0 } }
+  let(:admin) { { session: 2, user_id: 10, channel_id: 0 } }
+
+  before do
+    evaluator.apply(:acl, {
+                      channel_id: 0,
+                      groups: [{ name: "admin", inherited: false, add: [10] }],
+                      acls: [{ inherited: false, group: "admin", grant: 0x1 }]
+                    })
+    evaluator.apply(:acl, {
+                      channel_id: 1,
+                      acls: [{ inherited: false, group: "all", deny: 0x8 },
+                             { inherited: false, group: "#vip", grant: 0x8 }]
+                    })
+  end
+
+  it "computes default permissions" do
+    expect(evaluator.permissions(tree, guest, 0)).to eq(0xb0e)
+  end
+
+  it "applies inherited entries" do
+    expect(evaluator.permissions(tree, guest, 2) & 0x8).to eq(0)
+    expect(evaluator.permissions(tree, guest.merge(access_tokens: ["VIP"]), 2) & 0x8).to eq(0x8)
+  end
+
+  it "expands write permission" do
+    expect(evaluator.permissions(tree, admin, 0)).to eq(0x1f0fff)
+    expect(evaluator.permissions(tree, admin, 2) & 0xf0000).to eq(0)
+  end
+
+  it "gives the superuser everything but speaking" do
+    expect(evaluator.permissions(tree, { user_id: 0 }, 1)).to eq(0x1f0ef7)
+  end
+
+  it "checks group membership" do
+    expect(evaluator.member?(tree, admin, 2, "admin")).to be(true)
+    expect(evaluator.member?(tree, guest, 2, "auth")).to be(false)
+    expect(evaluator.member?(tree, { channel_id: 2 }, 1, "sub")).to be(true)
+  end
+
+  it "forgets removed channels" do
+    evaluator.apply(:channel_remove, { channel_id: 1 })
+
+    expect(evaluator.permissions(tree, guest, 2) & 0x8).to eq(0x8)
+  end
+end
@@ -0,0 +1,43 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::BanList do
+  subject(:bans) { described_class.new }
+
+  before do
+    bans.add({ address: "192.168.1.0/24", reason: "spam", start: 1000, duration: 60 })
+    bans.add({ address: "2001:db8::1", hash: "abcdef", start: 1000 })
+  end
+
+  it "matches addresses and certificate hashes" do
+    expect(bans.find("192.168.1.77", nil, 1030)).to include(address: "192.168.1.0/24", reason: "spam", expires_at: 1060)
+    expect(bans.banned?("192.168.2.1", nil, 1030)).to be(false)
+    expect(bans.banned?("2001:db8::1", nil, 1030)).to be(true)
+    expect(bans.banned?(nil, "abcdef", 1030)).to be(true)
+  end
+
+  it "handles expiry" do
+    expect(bans.banned?("192.168.1.77", nil, 1060)).to be(false)
+    expect(bans.remove_expired(1060).map { |ban| ban[:address] }).to eq(["192.168.1.0/24"])
+    expect(bans.size).to eq(1)
+  end
+
+  it "round-trips BanList messages" do
+    message = bans.to_message
+    copy = described_class.new
+    copy.apply(:ban_list, message)
+
+    expect(message[:bans].first).to include(mask: 120, start: "1970-01-01T00:16:40", duration: 60)
+    expect(copy.bans).to eq(bans.bans)
+  end
+
+  it "ignores queries" do
+    bans.apply(:ban_list, { query: true })
+
+    expect(bans.size).to eq(2)
+  end
+
+  it "rejects invalid bans" do
+    expect { bans.add({ address: "2001::/4" }) }.to raise_error(RbMumbleProtocol::Error)
+    expect { bans.add({ address: "nope" }) }.to raise_error(ArgumentError)
+  end
+end
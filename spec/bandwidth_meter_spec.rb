@@ -0,0 +1,25 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::BandwidthMeter do
+  it "estimates the bandwidth used by clients" do
+    expect(described_class.network_bandwidth(40_000, 2)).to eq(54_800)
+    expect(described_class.network_bandwidth(40_000, 2, positional: true, tunnel: true)).to eq(64_400)
+  end
+
+  it "measures the bandwidth" do
+    meter = described_class.new(now: 0)
+    50.times { |packet| expect(meter.add(36, 1000 + packet * 20)).to be(true) }
+
+    expect(meter.bandwidth(1980)).to eq(68 * 50 * 1000 / 980)
+    expect(meter.kbit_per_sec(1980)).to be_within(0.01).of(27.75)
+    expect(meter.user_stats(5000)).to eq(bandwidth: 0, onlinesecs: 5, idlesecs: 3)
+  end
+
+  it "drops packets over the limit" do
+    meter = described_class.new(max_bandwidth: 8_000, now: 0)
+
+    expect(meter.add(968, 1000)).to be(true)
+    expect(meter.add_encrypted(972, 1000)).to be(false)
+    expect(meter.bandwidth(1250)).to eq(4000)
+  end
+end
@@ -0,0 +1,53 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::BlobStore do
+  subject(:blobs) { described_class.new }
+
+  let(:registry) { RbMumbleProtocol::UserRegistry.new }
+  let(:tree) { RbMumbleProtocol::ChannelTree.new }
+  let(:comment) { "c" * 200 }
+  let(:comment_hash) { described_class.digest(comment) }
+
+  before do
+    tree.apply_state({ channel_id: 0, name: "Root", description: "short" })
+    message = blobs.compact(:user_state, { session: 1, name: "alice", channel_id: 0, comment: comment })
+    registry.apply(:user_state, message)
+  end
+
+  it "replaces large data by its hash" do
+    expect(registry[1]).to include(comment: nil, comment_hash: comment_hash)
+    expect(blobs[comment_hash]).to eq(comment)
+    expect(blobs.compact(:user_state, { session: 2, comment: "hi" })).to eq(session: 2, comment: "hi")
+    expect(blobs.compact(:text_message, { message: comment })).to eq(message: comment)
+    expect(blobs.comment(registry, 1)).to eq(comment)
+  end
+
+  it "stores identical blobs once" do
+    blobs.compact(:channel_state, { channel_id: 0, description: comment })
+
+    expect(blobs.size).to eq(1)
+    expect(blobs.store("small")).to be_nil
+    expect(blobs).to include(comment_hash)
+  end
+
+  it "answers requests" do
+    replies = blobs.answer({ session_comment: [1, 2], session_texture: [1] }, registry, tree)
+
+    expect(replies).to eq([[:user_state, { session: 1, comment: comment }]])
+  end
+
+  it "requests missing blobs" do
+    client = described_class.new
+    expect(client.request_missing(registry, tree)).to eq([:request_blob, { session_comment: [1] }])
+
+    client.insert(comment)
+    expect(client.request_missing(registry, tree)).to be_nil
+  end
+
+  it "prunes unreferenced blobs" do
+    registry.apply(:user_state, { session: 1, comment: "" })
+
+    expect(blobs.prune(registry, tree)).to eq(1)
+    expect(blobs.size).to eq(0)
+  end
+end
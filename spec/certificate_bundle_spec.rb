@@ -0,0 +1,42 @@
+# frozen_string_literal: true
+
+require "openssl"
+
+RSpec.describe RbMumbleProtocol::CertificateBundle do
+  subject(:bundle) { described_class.generate("bot", email: "bot@example.com", key_type: :ed25519) }
+
+  it "generates self-signed certificates" do
+    certificate = bundle.certificate
+
+    expect(certificate.common_name).to eq("bot")
+    expect(certificate.emails).to eq(["bot@example.com"])
+    expect(certificate).to be_self_signed
+    expect(certificate).to be_valid
+  end
+
+  it "generates RSA keys by default" do
+    _certificate, key = described_class.generate("server").to_openssl
+
+    expect(key).to be_a(OpenSSL::PKey::RSA)
+    expect(key.n.num_bits).to eq(2048)
+  end
+
+  it "exports PKCS#12 archives" do
+    archive = bundle.to_pkcs12("secret")
+    imported = described_class.from_pkcs12(archive, "secret")
+
+    expect(imported.certificate.mumble_hash).to eq(bundle.certificate.mumble_hash)
+    expect(imported.private_key_pem).to eq(bundle.private_key_pem)
+    expect { described_class.from_pkcs12(archive, "wrong") }.to raise_error(RbMumbleProtocol::Error)
+  end
+
+  it "converts to OpenSSL objects" do
+    certificate, key = bundle.to_openssl
+
+    expect(certificate.check_private_key(key)).to be(true)
+  end
+
+  it "rejects unknown key types" do
+    expect { described_class.generate("bot", key_type: :dsa) }.to raise_error(ArgumentError)
+  end
+end
@@ -0,0 +1,46 @@
+# frozen_string_literal: true
+
+require "openssl"
+
+RSpec.describe RbMumbleProtocol::Certificate do
+  subject(:certificate) { described_class.from_openssl(openssl_certificate) }
+
+  let(:key) { OpenSSL::PKey::EC.generate("prime256v1") }
+
+  let(:openssl_certificate) do
+    OpenSSL::X509::Certificate.new.tap do |cert|
+      cert.version = 2
+      cert.serial = 1
+      cert.subject = cert.issuer = OpenSSL::X509::Name.parse("/CN=alice")
+      cert.public_key = key
+      cert.not_before = Time.at(1_000_000_000)
+      cert.not_after = Time.at(2_000_000_000)
+      extensions = OpenSSL::X509::ExtensionFactory.new(cert, cert)
+      cert.add_extension(extensions.create_extension("subjectAltName", "email:alice@example.com"))
+      cert.sign(key, OpenSSL::Digest.new("SHA256"))
+    end
+  end
+
+  it "computes the Mumble hash of DER and PEM certificates" do
+    expected = OpenSSL::Digest::SHA1.hexdigest(openssl_certificate.to_der)
+
+    expect(certificate.mumble_hash).to eq(expected)
+    expect(described_class.hash_of(openssl_certificate.to_pem)).to eq(expected)
+  end
+
+  it "exposes the subject" do
+    expect(certificate.common_name).to eq("alice")
+    expect(certificate.emails).to eq(["alice@example.com"])
+    expect(certificate).to be_self_signed
+  end
+
+  it "checks the validity period" do
+    expect(certificate.not_after).to eq(2_000_000_000)
+    expect(certificate.valid?(1_500_000_000)).to be(true)
+    expect(certificate.expired?(2_000_000_001)).to be(true)
+  end
+
+  it "rejects malformed data" do
+    expect { described_class.new("garbage") }.to raise_error(RbMumbleProtocol::Error)
+  end
+end
@@ -0,0 +1,46 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::ChannelTree do
+  subject(:tree) { described_class.new }
+
+  before do
+    tree.apply(:channel_state, { channel_id: 0, name: "Root" })
+    tree.apply(:channel_state, { channel_id: 1, parent: 0, name: "Lobby" })
+    tree.apply(:channel_state, { channel_id: 2, parent: 1, name: "AFK", links_add: [0] })
+  end
+
+  it "answers queries about the hierarchy" do
+    expect(tree.path(2)).to eq([0, 1, 2])
+    expect(tree.descendants(0)).to eq([1, 2])
+    expect(tree.linked(0)).to eq([0, 2])
+    expect(tree[1]).to include(name: "Lobby", parent: 0, children: [2])
+  end
+
+  it "applies partial updates" do
+    expect(tree.apply(:channel_state, { channel_id: 1, max_users: 10 })).to eq(:updated)
+    expect(tree[1]).to include(name: "Lobby", max_users: 10)
+  end
+
+  it "rejects cycles" do
+    expect { tree.apply_state({ channel_id: 1, parent: 2 }) }.to raise_error(RbMumbleProtocol::Error, /cycle/)
+    expect(tree.path(2)).to eq([0, 1, 2])
+  end
+
+  it "removes whole subtrees" do
+    expect(tree.apply(:channel_remove, { channel_id: 1 })).to eq([2, 1])
+    expect(tree.ids).to eq([0])
+    expect(tree.linked(0)).to eq([0])
+  end
+
+  it "produces the messages for a new client" do
+    messages = tree.full_sync
+
+    expect(messages.map { |type, message| [type, message[:channel_id]] })
+      .to eq([[:channel_state, 0], [:channel_state, 1], [:channel_state, 2], [:channel_state, 0], [:channel_state, 2]])
+    expect(messages.last[1]).to eq({ channel_id: 2, links: [0] })
+  end
+
+  it "ignores other messages" do
+    expect(tree.apply(:ping, { timestamp: 1 })).to be_nil
+  end
+end
@@ -0,0 +1,93 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::ClientSession do
+  subject(:session) { described_class.new(username: "bot", tokens: ["secret"], bot: true) }
+
+  let(:crypt_setup) { { key: "k".b * 16, client_nonce: "c".b * 16, server_nonce: "s".b * 16 } }
+
+  before { session.start(0) }
+
+  it "authenticates" do
+    version, authenticate = session.drain_outgoing
+
+    expect(version).to match([:version, hash_including(version_v1: 0x10500)])
+    expect(authenticate).to eq([:authenticate, { username: "bot", tokens: ["secret"], opus: true, client_type: 1 }])
+    expect(session.state).to eq(:authenticating)
+  end
+
+  it "syncs" do
+    session.handle(:crypt_setup, crypt_setup, 10)
+    session.handle(:channel_state, { channel_id: 0, name: "Root" }, 20)
+    session.handle(:server_sync, { session: 7, max_bandwidth: 72_000, welcome_text: "hi", permissions: 0xf07 }, 30)
+
+    expect(session.drain_events).to eq(
+      [
+        [:crypt_setup, nil],
+        [:message, [:channel_state, { channel_id: 0, name: "Root" }]],
+        [:synced, { session: 7, max_bandwidth: 72_000, welcome_text: "hi", permissions: 0xf07 }]
+      ]
+    )
+    expect(session).to be_synced
+    expect(session.session).to eq(7)
+    expect(session).to be_crypt_ready
+  end
+
+  it "reports rejections" do
+    session.handle(:reject, { type: 3, reason: "Wrong password" }, 10)
+
+    expect(session.drain_events).to eq([[:rejected, { type: :wrong_user_pw, reason: "Wrong password" }]])
+    expect(session).to be_closed
+  end
+
+  it "reports kicks" do
+    session.handle(:server_sync, { session: 7 }, 10)
+    session.handle(:user_remove, { session: 7, actor: 1, reason: "bye" }, 20)
+
+    expect(session.drain_events.last).to eq([:kicked, { actor: 1, reason: "bye", ban: false }])
+    expect(session.state).to eq(:disconnected)
+  end
+
+  it "pings and times out" do
+    session.drain_outgoing
+    session.poll(5_000)
+
+    expect(session.drain_outgoing).to match([[:ping, hash_including(timestamp: 5_000, tcp_packets: 0)]])
+    expect(session.next_poll).to eq(10_000)
+
+    session.poll(30_000)
+    expect(session.drain_events).to eq([[:timed_out, nil]])
+  end
+
+  it "encrypts voice once the keys were received" do
+    expect(session.encrypt("voice")).to be_nil
+
+    session.handle(:crypt_setup, crypt_setup.merge(server_nonce: crypt_setup[:client_nonce]), 10)
+    expect(session.decrypt(session.encrypt("voice"))).to eq(["voice", :ok])
+  end
+
+  it "answers nonce requests" do
+    session.handle(:crypt_setup, crypt_setup, 10)
+    session.drain_outgoing
+    session.handle(:crypt_setup, {}, 20)
+
+    expect(session.drain_outgoing).to eq([[:crypt_setup, { client_nonce: "c".b * 16 }]])
+  end
+
+  it "measures voice channel round trips" do
+    timestamp = session.udp_ping(100)
+
+    expect(session.receive_udp_ping(timestamp + 1, 120)).to be_nil
+    expect(session.receive_udp_ping(timestamp, 125)).to eq(25)
+    expect(session.ping_stats[:udp]).to eq(count: 1, mean: 25.0, variance: 0.0)
+  end
+
+  describe "#flush" do
+    let(:stream) { instance_double(RbMumbleProtocol::ControlStream, write_message: 0) }
+
+    it "writes the pending messages" do
+      expect(session.flush(stream)).to eq(2)
+      expect(stream).to have_received(:write_message).with(:authenticate, hash_including(username: "bot"))
+      expect(session.flush(stream)).to eq(0)
+    end
+  end
+end
@@ -0,0 +1,51 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::CodecVersion do
+  subject(:codecs) { described_class.new }
+
+  let(:celt_0_7_0) { -0x7ffffff5 }
+  let(:registry) { RbMumbleProtocol::UserRegistry.new }
+
+  before do
+    registry.apply(:user_state, { session: 1, name: "alice", channel_id: 0 })
+    registry.apply(:user_state, { session: 2, name: "bob", channel_id: 0 })
+    registry.set_codecs(1, [], true)
+    registry.set_codecs(2, [], true)
+  end
+
+  it "announces changes of the selection" do
+    expect(codecs.recheck(registry)).to eq([:codec_version, { alpha: celt_0_7_0, beta: 0, prefer_alpha: true, opus: true }])
+    expect(codecs.recheck(registry)).to be_nil
+
+    registry.set_codecs(2, [celt_0_7_0], false)
+    expect(codecs.recheck(registry)).to eq([:codec_version, { alpha: celt_0_7_0, beta: 0, prefer_alpha: true, opus: false }])
+    expect(codecs.sessions_without_opus(registry)).to eq([2])
+    expect(registry[2]).to include(celt_versions: [celt_0_7_0], opus: false)
+  end
+
+  it "enables Opus over the threshold" do
+    registry.set_codecs(2, [], false)
+
+    expect(described_class.new(opus_threshold: 50).recheck(registry)[1]).to include(opus: true)
+  end
+
+  it "only accepts advertised codecs" do
+    registry.set_codecs(2, [celt_0_7_0], false)
+    codecs.recheck(registry)
+
+    expect(codecs.accepts?(registry, 1, :opus)).to be(true)
+    # no CELT version listed, 0.7.0 is assumed
+    expect(codecs.accepts?(registry, 1, :celt_alpha)).to be(true)
+    registry.set_codecs(1, [-0x7ffffff0], true)
+    expect(codecs.accepts?(registry, 1, :celt_alpha)).to be(false)
+    expect(codecs.accepts?(registry, 2, :celt_alpha)).to be(true)
+    expect(codecs.accepts?(registry, 2, :celt_beta)).to be(false)
+    expect { codecs.accepts?(registry, 2, :vorbis) }.to raise_error(ArgumentError)
+  end
+
+  it "takes over announced selections" do
+    codecs.apply({ alpha: celt_0_7_0, beta: 0, prefer_alpha: true, opus: false })
+
+    expect(codecs.message).to eq([:codec_version, { alpha: celt_0_7_0, beta: 0, prefer_alpha: true, opus: false }])
+  end
+end
@@ -0,0 +1,103 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::ControlStream do
+  subject(:stream) { described_class.new(reader) }
+
+  let(:pipe) { IO.pipe }
+  let(:reader) { pipe[0] }
+  let(:writer) { pipe[1] }
+
+  # Version { release: "1.5" }
+  let(:version_frame) { [0, 5].pack("nN") + "\x12\x031.5".b }
+
+  after { pipe.each(&:close) }
+
+  describe "#read_nonblock" do
+    context "when nothing was received" do
+      it { expect(stream.read_nonblock(exception: false)).to eq(:wait_readable) }
+      it { expect { stream.read_nonblock }.to raise_error(IO::WaitReadable) }
+    end
+
+    context "when a message was received in parts" do
+      before { writer.write(version_frame[0, 4]) }
+
+      it "waits for the rest" do
+        expect(stream.read_nonblock(exception: false)).to eq(:wait_readable)
+
+        writer.write(version_frame[4..])
+        expect(stream.read_nonblock(exception: false)).to eq([:version, { release: "1.5" }])
+      end
+    end
+
+    context "when the stream ended" do
+      before { writer.close }
+
+      it { expect(stream.read_nonblock(exception: false)).to be_nil }
+    end
+
+    context "when the stream ended inside a message" do
+      before do
+        writer.write(version_frame[0, 4])
+        writer.close
+      end
+
+      it { expect { stream.read_nonblock(exception: false) }.to raise_error(EOFError) }
+    end
+  end
+
+  describe "#each_message" do
+    before do
+      writer.write(version_frame * 2)
+      writer.close
+    end
+
+    it "yields every message until EOF" do
+      expect(stream.each_message.to_a).to eq([[:version, { release: "1.5" }]] * 2)
+    end
+  end
+
+  describe "undecodable frames" do
+    before do
+      writer.write([99, 2].pack("nN") + "\x08\x01".b + version_frame + [0, 2].pack("nN") + "\xff\xff".b + version_frame)
+      writer.close
+    end
+
+    it "skips them" do
+      expect(stream.each_message.to_a).to eq([[:version, { release: "1.5" }]] * 2)
+      expect(stream.skipped_messages).to eq(2)
+    end
+  end
+
+  describe "rate limiting" do
+    subject(:stream) { described_class.new(reader, rate_limit: { message_burst: 2 }) }
+
+    # TextMessage { message: "hi" }
+    let(:text_frame) { [11, 4].pack("nN") + "\x2a\x02hi".b }
+
+    before do
+      writer.write((text_frame * 3) + version_frame)
+      writer.close
+    end
+
+    it "drops floods before decoding them" do
+      expect(stream.each_message.map(&:first)).to eq(%i[text_message text_message version])
+      expect(stream.dropped_messages).to eq(1)
+    end
+  end
+
+  describe "#write_message" do
+    let(:writing_stream) { described_class.new(writer) }
+
+    it "is readable by the other side" do
+      writing_stream.write_message(:channel_state, { channel_id: 1, name: "Lobby", links: [2, 3] })
+      writing_stream.write_message(:udp_tunnel, "\x20\x00\x01".b)
+
+      expect(stream.read_message).to eq([:channel_state, { channel_id: 1, name: "Lobby", links: [2, 3] }])
+      expect(stream.read_message).to eq([:udp_tunnel, "\x20\x00\x01".b])
+    end
+
+    it "rejects unknown message types" do
+      expect { writing_stream.write_message(:nope, {}) }.to raise_error(ArgumentError)
+    end
+  end
+end
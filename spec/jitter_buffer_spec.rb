@@ -0,0 +1,45 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::JitterBuffer do
+  subject(:buffer) { described_class.new(min_delay: 20) }
+
+  # CELT fullband, 20 ms
+  def packet(sequence, terminator: false)
+    { type: :opus, target: 0, sequence: sequence, payload: [31 << 3, 0xaa].pack("C*"), terminator: terminator }
+  end
+
+  def sequences(events)
+    events.map { |_session, kind, value| kind == :audio ? value[:sequence] : [kind, value] }
+  end
+
+  it "plays packets in order once the delay passed" do
+    buffer.push(5, packet(2), 0)
+    buffer.push(5, packet(0), 5)
+
+    expect(buffer.poll(10)).to be_empty
+    expect(sequences(buffer.poll(40))).to eq([0, 2])
+  end
+
+  it "reports gaps and the end of talk spurts" do
+    buffer.push(5, packet(0), 0)
+    buffer.push(5, packet(4, terminator: true), 10)
+
+    expect(sequences(buffer.poll(100))).to eq([0, [:gap, 2], 4, [:end, nil]])
+    expect(buffer.stats(5)).to include(lost: 2)
+  end
+
+  it "rejects late and duplicate packets" do
+    buffer.push(5, packet(4), 0)
+    expect(buffer.push(5, packet(4), 0)).to eq(:duplicate)
+
+    buffer.poll(20)
+    expect(buffer.push(5, packet(2), 25)).to eq(:late)
+  end
+
+  it "forgets removed speakers" do
+    buffer.push(5, packet(0), 0)
+
+    expect(buffer.remove(5)).to be(true)
+    expect(buffer.stats(5)).to be_nil
+  end
+end
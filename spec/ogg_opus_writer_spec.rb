@@ -0,0 +1,41 @@
+# frozen_string_literal: true
+
+require "stringio"
+
+RSpec.describe RbMumbleProtocol::OggOpusWriter do
+  subject(:writer) { described_class.new(io, comments: ["TITLE=General"]) }
+
+  let(:io) { StringIO.new("".b) }
+
+  # CELT fullband, 20 ms
+  def packet(sequence)
+    { type: :opus, target: 0, sequence: sequence, payload: [31 << 3, 0xaa].pack("C*"), terminator: false }
+  end
+
+  it "writes the Opus headers and an end of stream page" do
+    writer.write_packet(packet(0))
+    writer.close
+
+    expect(io.string).to start_with("OggS")
+    expect(io.string).to include("OpusHead", "OpusTags", "TITLE=General")
+    expect(writer).to be_closed
+  end
+
+  it "fills gaps with silence" do
+    expect(writer.write_packet(packet(0))).to eq(:appended)
+    expect(writer.write_packet(packet(6))).to eq(:appended)
+
+    expect(writer.samples).to eq(8 * 480)
+    expect(writer.duration).to eq(0.08)
+  end
+
+  it "skips packets which were already written" do
+    writer.write_packet(packet(4))
+
+    expect(writer.write_packet(packet(2))).to eq(:skipped)
+  end
+
+  it "rejects other codecs" do
+    expect { writer.write_packet({ type: :speex, sequence: 0, frames: ["\x01"] }) }.to raise_error(ArgumentError)
+  end
+end
@@ -0,0 +1,80 @@
+# frozen_string_literal: true
+
+require "stringio"
+
+RSpec.describe RbMumbleProtocol::PcapReplay do
+  let(:client) { "10.0.0.2:50000" }
+  let(:server) { "10.0.0.1:64738" }
+
+  let(:server_state) { RbMumbleProtocol::CryptState.new }
+  let(:client_state) { RbMumbleProtocol::CryptState.new_from(server_state) }
+
+  let(:replay) do
+    described_class.new(
+      server_port: 64_738,
+      key: server_state.key,
+      client_nonce: server_state.decrypt_nonce,
+      server_nonce: server_state.encrypt_nonce
+    )
+  end
+
+  def voice(sequence)
+    { type: :opus, target: 0, sequence: sequence, payload: [31 << 3, 0xaa].pack("C*"), terminator: false }
+  end
+
+  def capture
+    io = StringIO.new("".b)
+    writer = RbMumbleProtocol::PcapWriter.new(io)
+    yield writer
+    RbMumbleProtocol::PcapReader.new(io.string)
+  end
+
+  it "decrypts voice packets sent over UDP" do
+    packets = capture do |writer|
+      data = RbMumbleProtocol::VoicePacket.encode(voice(0), :serverbound)
+      writer.write_udp(client, server, client_state.encrypt(data), 1_000_000)
+    end
+
+    events = packets.flat_map { |packet| replay.feed(packet) }
+
+    expect(events).to match([
+      include(kind: :voice, direction: :serverbound, protocol: :udp, timestamp: 1_000_000, packet: include(sequence: 0))
+    ])
+    expect(replay.stats(:serverbound)).to include(good: 1)
+  end
+
+  it "reports datagrams which can't be decrypted" do
+    packets = capture { |writer| writer.write_udp(server, client, "\x00" * 12) }
+
+    expect(packets.flat_map { |packet| replay.feed(packet) }).to match([include(kind: :decrypt_failed)])
+  end
+
+  it "decodes plain text control messages sent over TCP" do
+    io = StringIO.new("".b)
+    RbMumbleProtocol::ControlStream.new(io).write_message(:ping, { timestamp: 5 })
+
+    packets = capture do |writer|
+      writer.write_tcp(server, client, io.string[0, 3])
+      writer.write_tcp(server, client, io.string[3..])
+    end
+
+    expect(packets.flat_map { |packet| replay.feed(packet) }).to match([
+      include(kind: :control, direction: :clientbound, type: :ping, message: { timestamp: 5 })
+    ])
+  end
+end
+
+RSpec.describe RbMumbleProtocol::PcapReader do
+  it "reads back written packets" do
+    io = StringIO.new("".b)
+    RbMumbleProtocol::PcapWriter.new(io).write_udp("[::1]:1", "[::2]:2", "hello", 42)
+
+    expect(described_class.new(io.string).to_a).to eq([
+      { timestamp: 42, protocol: :udp, source: "[::1]:1", destination: "[::2]:2", payload: "hello".b }
+    ])
+  end
+
+  it "rejects files which aren't captures" do
+    expect { described_class.new("GIF89a...") }.to raise_error(RbMumbleProtocol::Error)
+  end
+end
@@ -0,0 +1,31 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::Permissions do
+  it "defines a constant per permission" do
+    expect(described_class::SPEAK).to eq(0x8)
+    expect(described_class::CACHED).to eq(0x8000000)
+    expect(described_class::ALL).to eq(0x1f0fff)
+  end
+
+  it "converts to and from symbols" do
+    bits = described_class::MUTE_DEAFEN | described_class::KICK | described_class::CACHED
+
+    expect(described_class.names(bits)).to eq(%i[mute_deafen kick cached])
+    expect(described_class.from_names(%i[kick cached mute_deafen])).to eq(bits)
+    expect { described_class.from_names(%i[fly]) }.to raise_error(ArgumentError, /fly/)
+  end
+
+  it "checks for permissions" do
+    expect(described_class.granted?(0xb0e, :speak, :whisper)).to be(true)
+    expect(described_class.granted?(0xb0e, :speak, :write)).to be(false)
+  end
+
+  it "validates bitmasks for root and sub-channels" do
+    expect(described_class.valid?(described_class::BAN | described_class::SPEAK, root: true)).to be(true)
+    expect(described_class.valid?(described_class::BAN | described_class::SPEAK, root: false)).to be(false)
+    expect(described_class.valid?(0x1000, root: true)).to be(false)
+    expect { described_class.validate(described_class::KICK, false) }
+      .to raise_error(RbMumbleProtocol::Error, "kick can only be granted in the root channel")
+    expect(described_class.applicable(false)).to eq(0xfff)
+  end
+end
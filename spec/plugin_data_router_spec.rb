@@ -0,0 +1,38 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::PluginDataRouter do
+  subject(:router) { described_class.new }
+
+  let(:registry) { RbMumbleProtocol::UserRegistry.new }
+
+  before do
+    [1, 2, 3].each { |session| registry.apply(:user_state, { session: session, name: "user#{session}", channel_id: 0 }) }
+  end
+
+  it "builds messages" do
+    expect(described_class.message("game:state", "data", [2])).to eq(
+      [:plugin_data_transmission, { receiver_sessions: [2], data: "data", data_id: "game:state" }]
+    )
+    expect { described_class.message("game:state", "x" * 1001) }.to raise_error(ArgumentError)
+    expect(described_class.validate({ data: "x" })).to eq("plugin data without data ID")
+    expect(described_class.validate({ data: "x", data_id: "id" })).to be_nil
+  end
+
+  it "forwards to connected receivers" do
+    message = { sender_session: 9, receiver_sessions: [3, 2, 3, 7], data: "x", data_id: "game:state" }
+    forwarded = []
+
+    expect(router.forward(1, message, registry) { |*args| forwarded << args }).to eq([3, 2])
+    expect(forwarded).to eq([3, 2].map { |receiver| [receiver, :plugin_data_transmission, { sender_session: 1, data: "x", data_id: "game:state" }] })
+  end
+
+  it "drops invalid messages" do
+    router = described_class.new(namespaces: ["game"])
+
+    expect(router.route(1, { receiver_sessions: [2], data: "x", data_id: "other:state" }, registry)).to be_nil
+    expect(router.route(1, { receiver_sessions: [2], data_id: "game:state" }, registry)).to be_nil
+    expect(router.route(1, { receiver_sessions: [2], data: "x", data_id: "game:state" }, registry)&.first).to eq([2])
+    expect(router.dropped).to eq(2)
+    expect(router.namespaces).to eq(["game"])
+  end
+end
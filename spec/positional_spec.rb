@@ -0,0 +1,33 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::Positional do
+  it "computes distances and directions" do
+    listener = { position: [1, 0, 1], front: [2, 0, 0] }
+
+    expect(described_class.distance([1, 0, 1], [4, 4, 1])).to be_within(1e-5).of(5)
+    expect(described_class.direction(listener, [5, 0, 1])).to eq([0, 0, 1])
+    expect(described_class.direction(listener, [1, 0, -3])).to eq([1, 0, 0])
+    expect { described_class.direction(listener, [1, 2]) }.to raise_error(ArgumentError)
+  end
+
+  it "attenuates by distance and direction" do
+    expect(described_class.gain(1, 8)).to be_within(1e-5).of(0.5)
+    expect(described_class.gain(-1, 8)).to be_within(1e-5).of(0.025)
+    expect(described_class.gain(-1, 0.5)).to be_within(1e-5).of(0.3)
+    expect(described_class.gain(1, 100, max_distance_volume: 1.0)).to eq(1.0)
+
+    left, right = described_class.stereo_gains({}, [4, 0, 0])
+    expect(left).to be_within(1e-5).of(right / 20)
+  end
+
+  it "reads positions of voice packets" do
+    packet = { type: :opus, target: 0, sequence: 1, payload: "\xf8\x01".b, position: [1.0, 2.0, -3.5] }
+    data = RbMumbleProtocol::VoicePacket.encode(packet, :serverbound)
+    decoded = RbMumbleProtocol::VoicePacket.decode(data, :serverbound)
+
+    expect(decoded[:position]).to eq([1.0, 2.0, -3.5])
+    expect(decoded[:position_info].bytesize).to eq(12)
+    expect(described_class.packet_gains({ position: [1, 2, -3.5] }, decoded)).to eq([1.0, 1.0])
+    expect(described_class.packet_gains({}, decoded.except(:position))).to be_nil
+  end
+end
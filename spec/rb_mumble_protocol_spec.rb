@@ -33,6 +33,7 @@ RSpec.describe RbMumbleProtocol do
         decrypt_nonce
         encrypt
         decrypt
+        try_decrypt
         set_decrypt_nonce
         stats
       ].freeze
@@ -50,6 +51,18 @@ RSpec.describe RbMumbleProtocol do
       end
     end
 
+    describe "#try_decrypt" do
+      let(:encrypted) { client_state.encrypt(bytes) }
+
+      it "leaves the state untouched" do
+        expect(server_state.try_decrypt(encrypted)).to eq([bytes, :ok])
+        expect(server_state.stats).to eq(good: 0, late: 0, lost: 0)
+        expect(server_state.decrypt(encrypted)).to eq([bytes, :ok])
+      end
+
+      it { expect(server_state.try_decrypt("abc")).to eq([nil, :eof]) }
+    end
+
     describe "#set_decrypt_nonce" do
       context "with correct nonce" do
         before do
@@ -0,0 +1,117 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::ServerConnection do
+  subject(:connection) { described_class.new(password: "secret", max_bandwidth: 72_000) }
+
+  let(:version) { { version_v2: 0x1_0005_0000_0000 } }
+  let(:authenticate) { { username: "alice", password: "secret", tokens: ["t"], opus: true } }
+
+  before { connection.start(0) }
+
+  it "announces its version" do
+    expect(connection.drain_outgoing).to match([[:version, hash_including(version_v2: 0x1_0005_0000_0000)]])
+  end
+
+  context "when the client authenticated" do
+    before do
+      connection.drain_outgoing
+      connection.handle(:version, version, 10)
+      connection.handle(:authenticate, authenticate, 20)
+    end
+
+    it "asks for a decision" do
+      expect(connection.drain_events).to match(
+        [[:authenticate, hash_including(username: "alice", tokens: ["t"], opus: true, bot: false)]]
+      )
+      expect(connection.state).to eq(:authenticating)
+    end
+
+    it "sends the keys, the server state and ServerSync when accepted" do
+      connection.accept(7, 0x6, [[:channel_state, { channel_id: 0, name: "Root" }]])
+      crypt_setup, channel_state, server_sync = connection.drain_outgoing
+
+      expect(crypt_setup).to match([:crypt_setup, { key: String, client_nonce: String, server_nonce: String }])
+      expect(channel_state).to eq([:channel_state, { channel_id: 0, name: "Root" }])
+      expect(server_sync).to eq([:server_sync, { session: 7, max_bandwidth: 72_000, permissions: 0x6 }])
+      expect(connection).to be_synced
+      expect(connection).to be_crypt_ready
+      expect(crypt_setup[1][:key]).to eq(connection.crypt_state.key)
+    end
+
+    it "sends the keys of the given CryptState" do
+      crypt_state = RbMumbleProtocol::CryptState.new
+      connection.accept(7, 0x6, crypt_state: crypt_state)
+      crypt_setup, = connection.drain_outgoing
+
+      expect(crypt_setup[1]).to eq(
+        key: crypt_state.key, client_nonce: crypt_state.decrypt_nonce, server_nonce: crypt_state.encrypt_nonce
+      )
+      expect(connection.crypt_state).to be(crypt_state)
+    end
+
+    it "sends a Reject when rejected" do
+      connection.reject(:username_in_use, "Username already in use")
+
+      expect(connection.drain_outgoing).to eq([[:reject, { type: 5, reason: "Username already in use" }]])
+      expect(connection).to be_closed
+    end
+
+    it { expect { connection.reject(:busy, "nope") }.to raise_error(ArgumentError) }
+  end
+
+  it "rejects wrong server passwords" do
+    connection.handle(:authenticate, authenticate.merge(password: "guess"), 10)
+
+    expect(connection.drain_events).to eq([[:rejected, { type: :wrong_server_pw, reason: "Invalid server password" }]])
+    expect(connection.drain_outgoing.last).to eq([:reject, { type: 4, reason: "Invalid server password" }])
+  end
+
+  it "rejects invalid usernames" do
+    connection.handle(:authenticate, authenticate.merge(username: "a/b"), 10)
+
+    expect(connection.drain_events).to eq([[:rejected, { type: :invalid_username, reason: "Invalid username" }]])
+  end
+
+  it "answers pings and times out" do
+    connection.drain_outgoing
+    connection.handle(:ping, { timestamp: 42, good: 3 }, 1_000)
+
+    expect(connection.drain_outgoing).to eq([[:ping, { timestamp: 42, good: 0, late: 0, lost: 0, resync: 0 }]])
+    expect(connection.ping_stats[:peer]).to include(good: 3, lost: 0)
+
+    connection.poll(31_000)
+    expect(connection.drain_events).to eq([[:timed_out, nil]])
+    expect(connection.state).to eq(:disconnected)
+  end
+
+  it "shares working voice keys with a ClientSession" do
+    client = RbMumbleProtocol::ClientSession.new(username: "alice", password: "secret")
+    client.start(0)
+    client.drain_outgoing.each { |type, message| connection.handle(type, message, 10) }
+    connection.accept(1, 0)
+    connection.drain_outgoing.each { |type, message| client.handle(type, message, 20) }
+
+    expect(client).to be_synced
+    expect(connection.decrypt(client.encrypt("voice"))).to eq(["voice", :ok])
+  end
+
+  it "reports the statistics of the client in UserStats" do
+    client = RbMumbleProtocol::ClientSession.new(username: "alice", password: "secret")
+    client.start(0)
+    client.drain_outgoing.each { |type, message| connection.handle(type, message, 10) }
+    connection.accept(1, 0)
+    connection.drain_outgoing.each { |type, message| client.handle(type, message, 20) }
+    connection.decrypt(client.encrypt("voice"))
+    client.poll(5_000)
+    client.drain_outgoing.each { |type, message| connection.handle(type, message, 5_010) }
+    connection.drain_outgoing.each { |type, message| client.handle(type, message, 5_020) }
+
+    expect(connection.user_stats).to include(
+      session: 1,
+      from_client: { good: 0, late: 0, lost: 0, resync: 0 },
+      from_server: { good: 1, late: 0, lost: 0, resync: 0 }
+    )
+    expect(client.ping_stats[:tcp]).to eq(count: 1, mean: 20.0, variance: 0.0)
+    expect(client.ping_stats[:peer]).to include(good: 1)
+  end
+end
@@ -0,0 +1,72 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::TextMessage do
+  let(:png) { "data:image/png;base64,iVBORw0KGgo=" }
+  let(:image) { "<img src=\"#{png}\">" }
+
+  it "sanitizes HTML" do
+    html = "<b onclick=\"x()\">Hi</b><script>alert(1)</script><a href=\"javascript:x()\">link</a>"
+
+    expect(described_class.sanitize(html)).to eq("<b>Hi</b><a>link</a>")
+  end
+
+  it "reduces HTML to text" do
+    expect(described_class.plain_text("<p>Hi<br>you &amp; me</p>")).to eq("Hi\nyou &amp; me")
+  end
+
+  it "counts lengths without image data" do
+    expect(described_class.length("text#{image}")).to eq(9)
+    expect(described_class.length("🎉")).to eq(2)
+  end
+
+  it "enforces the limits of ServerConfig" do
+    config = { allow_html: true, message_length: 20, image_message_length: 100 }
+
+    expect(described_class.filter("text#{image}", config)).to eq("text#{image}")
+    expect(described_class.filter("a" * 21, config)).to be_nil
+    expect(described_class.filter(image * 3, config)).to be_nil
+    expect(described_class.filter("<i>hello</i>", { allow_html: false })).to eq("hello")
+    expect(described_class).to be_allowed("a" * 5_000)
+    expect(described_class).not_to be_allowed("a" * 5_001)
+  end
+
+  it "extracts inline images" do
+    expect(described_class.images("#{image}<img src='data:image/JPG;base64,%2F9j%2F'>")).to eq(
+      [{ mime: "image/png", data: "\x89PNG\r\n\x1a\n".b }, { mime: "image/jpeg", data: "\xff\xd8\xff".b }]
+    )
+    expect { described_class.images("<img src=\"https://example.com/a.png\">") }
+      .to raise_error(RbMumbleProtocol::Error, "image isn't inline")
+  end
+
+  context "with channels and users" do
+    let(:tree) do
+      RbMumbleProtocol::ChannelTree.new.tap do |tree|
+        tree.apply(:channel_state, { channel_id: 0, name: "Root" })
+        tree.apply(:channel_state, { channel_id: 1, parent: 0, name: "Lobby" })
+        tree.apply(:channel_state, { channel_id: 2, parent: 1, name: "Team" })
+      end
+    end
+
+    let(:registry) do
+      RbMumbleProtocol::UserRegistry.new.tap do |registry|
+        registry.apply(:user_state, { session: 1, channel_id: 0 })
+        registry.apply(:user_state, { session: 2, channel_id: 1 })
+        registry.apply(:user_state, { session: 3, channel_id: 2 })
+        registry.apply(:user_state, { session: 4, channel_id: 0, listening_channel_add: [1] })
+      end
+    end
+
+    it "resolves channels and trees" do
+      message = { tree_id: [1], message: "hi" }
+
+      expect(described_class.channels(message, tree)).to eq([1, 2])
+      expect(described_class.recipients(message, tree, registry, 1)).to eq([2, 3])
+    end
+
+    it "includes listeners and direct sessions" do
+      message = { session: [1, 9], channel_id: [1], message: "hi" }
+
+      expect(described_class.recipients(message, tree, registry, 1)).to eq([2, 4])
+    end
+  end
+end
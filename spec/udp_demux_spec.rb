@@ -0,0 +1,47 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::UdpDemux do
+  subject(:demux) { described_class.new }
+
+  let(:states) { { 1 => RbMumbleProtocol::CryptState.new, 2 => RbMumbleProtocol::CryptState.new } }
+  let(:datagram) { RbMumbleProtocol::CryptState.new_from(states[2]).encrypt("voice") }
+
+  before do
+    demux.add(1, "10.0.0.1")
+    demux.add(2, "10.0.0.1")
+  end
+
+  it "matches unknown addresses by trial decryption" do
+    expect(demux.route("10.0.0.1:5000", datagram, states)).to eq([2, :matched])
+    expect(demux.address(2)).to eq("10.0.0.1:5000")
+    expect(states[2].stats).to eq(good: 0, late: 0, lost: 0)
+
+    expect(demux.route("10.0.0.1:5000", datagram, {})).to eq([2, :known])
+    expect(demux).to be_known("10.0.0.1:5000")
+    expect(states[2].decrypt(datagram)).to eq(["voice", :ok])
+  end
+
+  it "ignores sessions from other IPs" do
+    expect(demux.route("10.0.0.2:5000", datagram, states)).to be_nil
+  end
+
+  it "tries the crypt states of accepted server connections" do
+    connection = RbMumbleProtocol::ServerConnection.new
+
+    expect(demux.route("10.0.0.1:5000", datagram, { 1 => connection.crypt_state })).to be_nil
+    expect(demux.route("10.0.0.1:5000", datagram, { 1 => nil, 2 => states[2] })).to eq([2, :matched])
+  end
+
+  it "binds and removes sessions" do
+    expect(demux.bind(1, "10.0.0.1:6000")).to be(true)
+    expect(demux.bind(3, "10.0.0.1:6000")).to be(false)
+    expect(demux.session_for("10.0.0.1:6000")).to eq(1)
+
+    expect(demux.remove(1)).to be(true)
+    expect(demux.session_for("10.0.0.1:6000")).to be_nil
+    expect(demux.size).to eq(1)
+  end
+
+  it { expect { demux.add(3, "nowhere") }.to raise_error(ArgumentError) }
+  it { expect { demux.route("10.0.0.1:5000", datagram, { 1 => "key" }) }.to raise_error(ArgumentError) }
+end
@@ -0,0 +1,45 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::UserRegistry do
+  subject(:registry) { described_class.new }
+
+  before do
+    registry.apply(:user_state, { session: 1, name: "alice", channel_id: 0, self_mute: true })
+  end
+
+  it "applies partial updates" do
+    event = registry.apply(:user_state, { session: 1, channel_id: 3, actor: 2 })
+
+    expect(event).to eq({ event: :updated, session: 1, changed: [:channel_id], previous_channel_id: 0, actor: 2 })
+    expect(registry[1]).to include(name: "alice", channel_id: 3, self_mute: true)
+    expect(registry.in_channel(3)).to eq([1])
+  end
+
+  it "notifies listeners" do
+    events = []
+    registry.on_change { |event| events << event }
+
+    registry.apply(:user_state, { session: 2, name: "bob" })
+    registry.apply(:user_remove, { session: 1, reason: "bye" })
+
+    expect(events.map { |event| event[:event] }).to eq(%i[connected disconnected])
+    expect(events.last).to include(session: 1, reason: "bye", user: include(name: "alice"))
+    expect(registry.sessions).to eq([2])
+  end
+
+  it "tracks listened channels" do
+    registry.apply(:user_state, { session: 1, listening_channel_add: [4, 5] })
+    registry.apply(:user_state, { session: 1, listening_channel_remove: [4] })
+
+    expect(registry.listeners(5)).to eq([1])
+    expect(registry[1][:listening_channels]).to eq([5])
+  end
+
+  it "produces the messages for a new client" do
+    expect(registry.full_sync).to eq([[:user_state, { session: 1, name: "alice", channel_id: 0, self_mute: true }]])
+  end
+
+  it "raises on unknown sessions" do
+    expect { registry.apply(:user_remove, { session: 9 }) }.to raise_error(RbMumbleProtocol::Error)
+  end
+end
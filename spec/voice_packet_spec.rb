@@ -0,0 +1,55 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::VoicePacket do
+  # CELT fullband 20 ms, two frames of 1 byte
+  let(:opus_payload) { [(31 << 3) | 1, 1, 2].pack("C*") }
+  let(:packet) do
+    { type: :opus, target: 0, session_id: 7, sequence: 300, payload: opus_payload, terminator: false }
+  end
+
+  describe ".encode" do
+    it "writes the session only for clientbound packets" do
+      expect(described_class.encode(packet, :clientbound).bytes.first(2)).to eq([0x80, 7])
+      expect(described_class.encode(packet, :serverbound).bytes.first(3)).to eq([0x80, 0x81, 0x2c])
+    end
+
+    it "rejects unknown directions" do
+      expect { described_class.encode(packet, :sideways) }.to raise_error(ArgumentError)
+    end
+
+    it "raises on frames too long for their header" do
+      celt = { type: :celt_alpha, target: 0, sequence: 1, frames: ["a" * 128] }
+      expect { described_class.encode(celt, :serverbound) }.to raise_error(RbMumbleProtocol::Error)
+    end
+  end
+
+  describe ".decode" do
+    subject(:decoded) { described_class.decode(described_class.encode(packet, :clientbound), :clientbound) }
+
+    it { expect(decoded).to include(packet) }
+
+    it "describes the opus payload" do
+      expect(decoded[:opus]).to include(
+        mode: :celt, bandwidth: :fullband, stereo: false, frame_sizes: [1, 1], samples: 1920
+      )
+      expect(decoded[:opus][:frame_duration]).to eq(20.0)
+      expect(decoded[:opus][:duration]).to eq(40.0)
+    end
+
+    it "decodes pings" do
+      ping = described_class.encode({ type: :ping, timestamp: 42 }, :serverbound)
+
+      expect(described_class.decode(ping, :clientbound)).to eq(type: :ping, timestamp: 42)
+    end
+
+    it "raises on truncated packets" do
+      expect { described_class.decode("\x80\x01\x05".b, :serverbound) }.to raise_error(RbMumbleProtocol::Error)
+    end
+  end
+
+  describe ".opus_info" do
+    it "raises on malformed packets" do
+      expect { described_class.opus_info("") }.to raise_error(RbMumbleProtocol::Error)
+    end
+  end
+end
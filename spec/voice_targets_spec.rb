@@ -0,0 +1,58 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::VoiceTargets do
+  subject(:targets) { described_class.new }
+
+  let(:tree) do
+    RbMumbleProtocol::ChannelTree.new.tap do |tree|
+      tree.apply(:channel_state, { channel_id: 0, name: "Root" })
+      tree.apply(:channel_state, { channel_id: 1, parent: 0, name: "Lobby" })
+      tree.apply(:channel_state, { channel_id: 2, parent: 1, name: "Team" })
+    end
+  end
+
+  let(:registry) do
+    RbMumbleProtocol::UserRegistry.new.tap do |registry|
+      registry.apply(:user_state, { session: 1, channel_id: 1 })
+      registry.apply(:user_state, { session: 2, channel_id: 1 })
+      registry.apply(:user_state, { session: 3, channel_id: 2 })
+      registry.apply(:user_state, { session: 4, channel_id: 0, self_deaf: true })
+      registry.apply(:user_state, { session: 5, channel_id: 0, listening_channel_add: [1] })
+    end
+  end
+
+  let(:acls) { RbMumbleProtocol::AclEvaluator.new }
+
+  it "routes normal talking to the channel and its listeners" do
+    expect(targets.resolve(tree, registry, acls, 1, 0)).to eq([[2, :normal], [5, :normal]])
+  end
+
+  it "echoes loopback packets" do
+    expect(targets.resolve(tree, registry, acls, 1, 31)).to eq([[1, :loopback]])
+  end
+
+  it "routes whispers to registered targets" do
+    targets.apply(1, :voice_target, { id: 1, targets: [{ session: [4, 3] }, { channel_id: 1, children: true }] })
+
+    expect(targets.resolve(tree, registry, acls, 1, 1)).to eq([[2, :shout], [3, :shout], [5, :shout]])
+    expect(targets.target(1, 1)).to eq([{ session: [4, 3] }, { channel_id: 1, children: true }])
+  end
+
+  it "filters by group using access tokens" do
+    targets.apply(2, :authenticate, { username: "bob", tokens: ["vip"] })
+    targets.apply(1, :voice_target, { id: 5, targets: [{ channel_id: 1, group: "#vip" }] })
+
+    expect(targets.resolve(tree, registry, acls, 1, 5)).to eq([[2, :shout]])
+  end
+
+  it "forgets removed sessions" do
+    targets.apply(1, :voice_target, { id: 1, targets: [{ session: [2] }] })
+    targets.apply(0, :user_remove, { session: 1 })
+
+    expect(targets.target(1, 1)).to be_nil
+  end
+
+  it "rejects invalid target ids" do
+    expect { targets.apply(1, :voice_target, { id: 31, targets: [] }) }.to raise_error(RbMumbleProtocol::Error)
+  end
+end
@@ -0,0 +1,46 @@
+# frozen_string_literal: true
+
+RSpec.describe RbMumbleProtocol::VoiceTransport do
+  subject(:transport) { described_class.new(silence_timeout: 5_000) }
+
+  it "tunnels until UDP works" do
+    expect(transport.path(0)).to eq(:tunnel)
+
+    transport.udp_ping_received(100)
+    expect(transport.path(100)).to eq(:udp)
+    expect(transport).to be_udp(100)
+  end
+
+  it "falls back to the tunnel after silence" do
+    transport.decrypted(:ok, 1_000)
+    transport.decrypted(:bad_mac, 5_000)
+
+    expect(transport.path(5_999)).to eq(:udp)
+    expect(transport.path(6_000)).to eq(:tunnel)
+    expect(transport.stats).to eq(udp_pings: 0, decrypted: 1, decrypt_failures: 1, tunneled: 0)
+  end
+
+  it "follows a peer tunneling its voice" do
+    transport.decrypted(:ok, 1_000)
+    transport.tunnel_received
+
+    expect(transport.path(1_000)).to eq(:tunnel)
+  end
+
+  it "reports switches" do
+    expect(transport.poll(0)).to eq(:tunnel)
+    expect(transport.poll(10)).to be_nil
+
+    transport.udp_ping_received(20)
+    expect(transport.poll(20)).to eq(:udp)
+  end
+
+  it "can be forced to tunnel" do
+    forced = described_class.new(force_tunnel: true)
+    forced.udp_ping_received(0)
+
+    expect(forced.path(0)).to eq(:tunnel)
+  end
+
+  it { expect { transport.decrypted(:maybe) }.to raise_error(ArgumentError) }
+end